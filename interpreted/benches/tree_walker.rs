@@ -0,0 +1,34 @@
+//! Times the benchmark suite's programs on the tree-walker (run with
+//! `cargo +nightly bench`). `jlox --bench` runs the same programs without
+//! needing a build of the benches.
+#![feature(test)]
+extern crate test;
+
+use lox_interpreted::{benchmark::SUITE, Session};
+use test::Bencher;
+
+fn run(b: &mut Bencher, name: &str) {
+    let benchmark = SUITE
+        .iter()
+        .find(|benchmark| benchmark.name == name)
+        .unwrap();
+    let session = Session::without_prelude();
+    let statements = session.parse(&benchmark.source.to_string());
+    assert!(!session.errors.has_errors());
+    b.iter(|| session.interpreter.interpret(&statements));
+}
+
+#[bench]
+fn fib(b: &mut Bencher) {
+    run(b, "fib");
+}
+
+#[bench]
+fn loops(b: &mut Bencher) {
+    run(b, "loops");
+}
+
+#[bench]
+fn string_concat(b: &mut Bencher) {
+    run(b, "string_concat");
+}
@@ -1,83 +1,485 @@
-// use std::rc::Rc;
-
-// use crate::{
-//     expr::{Binary, Expr, ExprVisitor, Grouping, Literal, Unary, VisitorTarget},
-//     token::TokenLiteral,
-// };
-
-// pub struct AstPrinter;
-
-// impl AstPrinter {
-//     fn parenthesize(&self, name: impl Into<String>, exprs: &[&Rc<dyn Expr>]) -> String {
-//         let mut s = "".to_string();
-//         for expr in exprs {
-//             s = s + " ";
-//             s = s + expr.accept(self).as_str();
-//         }
-//         format!("({}{})", name.into(), s)
-//     }
-// }
-
-// impl ExprVisitor<String> for &AstPrinter {
-//     fn visit_binary_expr(&self, expr: &Binary) -> String {
-//         self.parenthesize(expr.operator.lexeme.clone(), &[&expr.left, &expr.right])
-//     }
-
-//     fn visit_grouping_expr(&self, expr: &Grouping) -> String {
-//         self.parenthesize("group", &[&expr.expression])
-//     }
-
-//     fn visit_literal_expr(&self, expr: &Literal) -> String {
-//         if expr.value == TokenLiteral::None {
-//             return "nil".to_string();
-//         }
-//         return expr.value.to_string();
-//     }
-
-//     fn visit_unary_expr(&self, expr: &Unary) -> String {
-//         self.parenthesize(expr.operator.lexeme.clone(), &[&expr.right])
-//     }
-// }
-
-// #[cfg(test)]
-// mod tests {
-//     use crate::ast_printer::AstPrinter;
-//     use crate::expr::Expr;
-//     use crate::expr::{self, VisitorTarget};
-//     use crate::token;
-//     use crate::token::TokenLiteral;
-//     use crate::token_type::TokenType;
-//     use std::rc::Rc;
-
-//     #[test]
-//     pub fn test_ast_printer() {
-//         let expression: Rc<dyn Expr> = Rc::new(expr::Binary {
-//             left: Rc::new(expr::Unary {
-//                 operator: token::Token {
-//                     ty: TokenType::Minus,
-//                     lexeme: "-".to_string(),
-//                     literal: TokenLiteral::None,
-//                     line: 1,
-//                 },
-//                 right: Rc::new(expr::Literal {
-//                     value: TokenLiteral::Number(123.0),
-//                 }),
-//             }),
-//             operator: token::Token {
-//                 ty: TokenType::Star,
-//                 lexeme: "*".to_string(),
-//                 literal: token::TokenLiteral::None,
-//                 line: 1,
-//             },
-//             right: Rc::new(expr::Grouping {
-//                 expression: Rc::new(expr::Literal {
-//                     value: TokenLiteral::Number(45.62),
-//                 }),
-//             }),
-//         });
-
-//         let printer = AstPrinter;
-//         // printer.v
-//         assert_eq!("(* (- 123) (group 45.62))", expression.accept(&printer))
-//     }
-// }
+use std::{cell::Cell, rc::Rc};
+
+use crate::{
+    expr::{
+        Assign, Binary, Call, CompoundAssign, Expr, ExprVisitor, Function, Get, Grouping, Index,
+        IndexAssign, List, Literal, Logical, Map, Set, Unary, Variable, VisitorTarget,
+    },
+    stmt::{
+        BlockStmt, DeferStmt, ExprStmt, FunStmt, IfStmt, MeasureStmt, PrintStmt, ReturnStmt,
+        Statement, StmtVisitor, StmtVisitorTarget, VariableStmt, WhileStmt,
+    },
+    token::TokenLiteral,
+};
+
+/// Prints syntax trees as S-expressions, e.g. `(* (- 123) (group 45.67))`.
+pub struct AstPrinter;
+
+impl AstPrinter {
+    /// Prints each statement on its own line.
+    pub fn print(&self, statements: &[Statement]) -> String {
+        statements
+            .iter()
+            .map(|statement| statement.accept(self))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn parenthesize(&self, name: impl Into<String>, exprs: &[&Rc<Expr>]) -> String {
+        let mut s = "".to_string();
+        for expr in exprs {
+            s += " ";
+            s += expr.accept(self).as_str();
+        }
+        format!("({}{})", name.into(), s)
+    }
+
+    fn parenthesize_statements(&self, name: impl Into<String>, statements: &[Statement]) -> String {
+        let mut s = "".to_string();
+        for statement in statements {
+            s += " ";
+            s += statement.accept(self).as_str();
+        }
+        format!("({}{})", name.into(), s)
+    }
+}
+
+impl ExprVisitor<String> for &AstPrinter {
+    fn visit_binary_expr(&self, expr: &Binary) -> String {
+        self.parenthesize(expr.operator.lexeme.clone(), &[&expr.left, &expr.right])
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping) -> String {
+        self.parenthesize("group", &[&expr.expression])
+    }
+
+    fn visit_literal_expr(&self, expr: &Literal) -> String {
+        match &expr.value {
+            TokenLiteral::None => "nil".to_string(),
+            TokenLiteral::True => "true".to_string(),
+            TokenLiteral::False => "false".to_string(),
+            TokenLiteral::String(value) => format!("\"{}\"", value),
+            TokenLiteral::Number(value) => value.to_string(),
+        }
+    }
+
+    fn visit_unary_expr(&self, expr: &Unary) -> String {
+        self.parenthesize(expr.operator.lexeme.clone(), &[&expr.right])
+    }
+
+    fn visit_variable_expr(&self, expr: &Variable) -> String {
+        expr.name.lexeme.clone()
+    }
+
+    fn visit_assign_expr(&self, expr: &Assign) -> String {
+        self.parenthesize(format!("= {}", expr.name.lexeme), &[&expr.value])
+    }
+
+    fn visit_logical_expr(&self, expr: &Logical) -> String {
+        self.parenthesize(expr.operator.lexeme.clone(), &[&expr.left, &expr.right])
+    }
+
+    fn visit_call_expr(&self, expr: &Call) -> String {
+        let mut exprs = vec![&expr.callee];
+        exprs.extend(expr.arguments.iter());
+        self.parenthesize("call", &exprs)
+    }
+
+    fn visit_function_expr(&self, expr: &Function) -> String {
+        let params: Vec<&str> = expr
+            .params
+            .iter()
+            .map(|param| param.lexeme.as_str())
+            .collect();
+        self.parenthesize_statements(format!("fun ({})", params.join(" ")), &expr.body)
+    }
+
+    fn visit_list_expr(&self, expr: &List) -> String {
+        let elements: Vec<&Rc<Expr>> = expr.elements.iter().collect();
+        self.parenthesize("list", &elements)
+    }
+
+    fn visit_map_expr(&self, expr: &Map) -> String {
+        let entries: Vec<&Rc<Expr>> = expr
+            .entries
+            .iter()
+            .flat_map(|(key, value)| [key, value])
+            .collect();
+        self.parenthesize("map", &entries)
+    }
+
+    fn visit_index_expr(&self, expr: &Index) -> String {
+        self.parenthesize("[]", &[&expr.object, &expr.index])
+    }
+
+    fn visit_index_assign_expr(&self, expr: &IndexAssign) -> String {
+        self.parenthesize("[]=", &[&expr.object, &expr.index, &expr.value])
+    }
+
+    fn visit_compound_assign_expr(&self, expr: &CompoundAssign) -> String {
+        self.parenthesize(expr.operator.lexeme.clone(), &[&expr.target, &expr.value])
+    }
+
+    fn visit_get_expr(&self, expr: &Get) -> String {
+        self.parenthesize(format!(". {}", expr.name.lexeme), &[&expr.object])
+    }
+
+    fn visit_set_expr(&self, expr: &Set) -> String {
+        self.parenthesize(
+            format!(".= {}", expr.name.lexeme),
+            &[&expr.object, &expr.value],
+        )
+    }
+}
+
+/// Prints expressions back as Lox source, e.g. `-123 * (45.67)`, so that
+/// diagnostics can quote the code they're about. Whitespace and comments
+/// aren't kept, so the text may not match the original exactly, and lambdas'
+/// bodies are left out.
+///
+/// `SourcePrinter::program()` makes one that prints whole programs instead:
+/// each statement on its own line, with the contents of blocks indented and
+/// lambdas in full. Since the program is printed from its syntax tree, sugar
+/// like `for` loops shows up as what it desugars to.
+#[derive(Default)]
+pub struct SourcePrinter {
+    bodies: bool,
+    // How many blocks deep the statement being printed is.
+    depth: Cell<usize>,
+}
+
+impl SourcePrinter {
+    pub fn program() -> Self {
+        Self {
+            bodies: true,
+            depth: Cell::new(0),
+        }
+    }
+
+    /// Prints each statement on its own line.
+    pub fn print(&self, statements: &[Statement]) -> String {
+        statements
+            .iter()
+            .map(|statement| statement.accept(self))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    // The statements in braces, each on its own line one level deeper than
+    // the braces.
+    fn block(&self, statements: &[Statement]) -> String {
+        if statements.is_empty() {
+            return "{}".to_string();
+        }
+        self.depth.set(self.depth.get() + 1);
+        let lines: Vec<String> = statements
+            .iter()
+            .map(|statement| format!("{}{}", self.indent(), statement.accept(self)))
+            .collect();
+        self.depth.set(self.depth.get() - 1);
+        format!("{{\n{}\n{}}}", lines.join("\n"), self.indent())
+    }
+
+    fn indent(&self) -> String {
+        "  ".repeat(self.depth.get())
+    }
+}
+
+impl ExprVisitor<String> for &SourcePrinter {
+    fn visit_binary_expr(&self, expr: &Binary) -> String {
+        format!(
+            "{} {} {}",
+            expr.left.accept(*self),
+            expr.operator.lexeme,
+            expr.right.accept(*self)
+        )
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping) -> String {
+        format!("({})", expr.expression.accept(*self))
+    }
+
+    fn visit_literal_expr(&self, expr: &Literal) -> String {
+        (&AstPrinter).visit_literal_expr(expr)
+    }
+
+    fn visit_unary_expr(&self, expr: &Unary) -> String {
+        format!("{}{}", expr.operator.lexeme, expr.right.accept(*self))
+    }
+
+    fn visit_variable_expr(&self, expr: &Variable) -> String {
+        expr.name.lexeme.clone()
+    }
+
+    fn visit_assign_expr(&self, expr: &Assign) -> String {
+        format!("{} = {}", expr.name.lexeme, expr.value.accept(*self))
+    }
+
+    fn visit_logical_expr(&self, expr: &Logical) -> String {
+        format!(
+            "{} {} {}",
+            expr.left.accept(*self),
+            expr.operator.lexeme,
+            expr.right.accept(*self)
+        )
+    }
+
+    fn visit_call_expr(&self, expr: &Call) -> String {
+        let arguments: Vec<String> = expr
+            .arguments
+            .iter()
+            .map(|argument| argument.accept(*self))
+            .collect();
+        format!("{}({})", expr.callee.accept(*self), arguments.join(", "))
+    }
+
+    // Unless printing a program, the body is left out, since diagnostics
+    // only quote expressions.
+    fn visit_function_expr(&self, expr: &Function) -> String {
+        let params: Vec<&str> = expr
+            .params
+            .iter()
+            .map(|param| param.lexeme.as_str())
+            .collect();
+        match self.bodies {
+            true => format!("fun ({}) {}", params.join(", "), self.block(&expr.body)),
+            false => format!("fun ({}) {{ ... }}", params.join(", ")),
+        }
+    }
+
+    fn visit_list_expr(&self, expr: &List) -> String {
+        let elements: Vec<String> = expr
+            .elements
+            .iter()
+            .map(|element| element.accept(*self))
+            .collect();
+        format!("[{}]", elements.join(", "))
+    }
+
+    fn visit_map_expr(&self, expr: &Map) -> String {
+        let entries: Vec<String> = expr
+            .entries
+            .iter()
+            .map(|(key, value)| format!("{}: {}", key.accept(*self), value.accept(*self)))
+            .collect();
+        format!("{{{}}}", entries.join(", "))
+    }
+
+    fn visit_index_expr(&self, expr: &Index) -> String {
+        format!(
+            "{}[{}]",
+            expr.object.accept(*self),
+            expr.index.accept(*self)
+        )
+    }
+
+    fn visit_index_assign_expr(&self, expr: &IndexAssign) -> String {
+        format!(
+            "{}[{}] = {}",
+            expr.object.accept(*self),
+            expr.index.accept(*self),
+            expr.value.accept(*self)
+        )
+    }
+
+    fn visit_compound_assign_expr(&self, expr: &CompoundAssign) -> String {
+        format!(
+            "{} {} {}",
+            expr.target.accept(*self),
+            expr.operator.lexeme,
+            expr.value.accept(*self)
+        )
+    }
+
+    fn visit_get_expr(&self, expr: &Get) -> String {
+        format!("{}.{}", expr.object.accept(*self), expr.name.lexeme)
+    }
+
+    fn visit_set_expr(&self, expr: &Set) -> String {
+        format!(
+            "{}.{} = {}",
+            expr.object.accept(*self),
+            expr.name.lexeme,
+            expr.value.accept(*self)
+        )
+    }
+}
+
+impl StmtVisitor<String> for &SourcePrinter {
+    fn visit_expression_stmt(&self, stmt: &ExprStmt) -> String {
+        format!("{};", stmt.expression.accept(*self))
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> String {
+        format!("print {};", stmt.expression.accept(*self))
+    }
+
+    fn visit_variable_stmt(&self, stmt: &VariableStmt) -> String {
+        match &stmt.initializer {
+            Some(initializer) => {
+                format!("var {} = {};", stmt.name.lexeme, initializer.accept(*self))
+            }
+            None => format!("var {};", stmt.name.lexeme),
+        }
+    }
+
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> String {
+        self.block(&stmt.statements)
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> String {
+        let else_branch = match &stmt.else_branch {
+            Some(else_branch) => format!(" else {}", else_branch.accept(*self)),
+            None => "".to_string(),
+        };
+        format!(
+            "if ({}) {}{}",
+            stmt.condition.accept(*self),
+            stmt.then_branch.accept(*self),
+            else_branch
+        )
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> String {
+        format!(
+            "while ({}) {}",
+            stmt.condition.accept(*self),
+            stmt.body.accept(*self)
+        )
+    }
+
+    fn visit_fun_stmt(&self, stmt: &FunStmt) -> String {
+        let params: Vec<&str> = stmt
+            .params
+            .iter()
+            .map(|param| param.lexeme.as_str())
+            .collect();
+        format!(
+            "fun {}({}) {}",
+            stmt.name.lexeme,
+            params.join(", "),
+            self.block(&stmt.body)
+        )
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> String {
+        format!("return {};", stmt.value.accept(*self))
+    }
+
+    fn visit_measure_stmt(&self, stmt: &MeasureStmt) -> String {
+        format!("measure {}", self.block(&stmt.body))
+    }
+
+    fn visit_defer_stmt(&self, stmt: &DeferStmt) -> String {
+        format!("defer {}", stmt.body.accept(*self))
+    }
+}
+
+impl StmtVisitor<String> for &AstPrinter {
+    fn visit_expression_stmt(&self, stmt: &ExprStmt) -> String {
+        self.parenthesize(";", &[&stmt.expression])
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> String {
+        self.parenthesize("print", &[&stmt.expression])
+    }
+
+    fn visit_variable_stmt(&self, stmt: &VariableStmt) -> String {
+        let name = format!("var {}", stmt.name.lexeme);
+        match &stmt.initializer {
+            Some(initializer) => self.parenthesize(name, &[initializer]),
+            None => self.parenthesize(name, &[]),
+        }
+    }
+
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> String {
+        self.parenthesize_statements("block", &stmt.statements)
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> String {
+        let else_branch = match &stmt.else_branch {
+            Some(else_branch) => " ".to_string() + else_branch.accept(*self).as_str(),
+            None => "".to_string(),
+        };
+        format!(
+            "(if {} {}{})",
+            stmt.condition.accept(*self),
+            stmt.then_branch.accept(*self),
+            else_branch
+        )
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> String {
+        format!(
+            "(while {} {})",
+            stmt.condition.accept(*self),
+            stmt.body.accept(*self)
+        )
+    }
+
+    fn visit_fun_stmt(&self, stmt: &FunStmt) -> String {
+        let params: Vec<&str> = stmt
+            .params
+            .iter()
+            .map(|param| param.lexeme.as_str())
+            .collect();
+        self.parenthesize_statements(
+            format!("fun {}({})", stmt.name.lexeme, params.join(" ")),
+            &stmt.body,
+        )
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> String {
+        self.parenthesize("return", &[&stmt.value])
+    }
+
+    fn visit_measure_stmt(&self, stmt: &MeasureStmt) -> String {
+        self.parenthesize_statements("measure", &stmt.body)
+    }
+
+    fn visit_defer_stmt(&self, stmt: &DeferStmt) -> String {
+        format!("(defer {})", stmt.body.accept(*self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast_printer::{AstPrinter, SourcePrinter};
+    use crate::expr::{self, VisitorTarget};
+    use crate::golden;
+    use crate::session::Session;
+    use crate::token;
+    use crate::token::TokenLiteral;
+    use crate::token_type::TokenType;
+
+    #[test]
+    pub fn test_ast_printer() {
+        let expression = expr::Binary::new(
+            expr::Unary::new(
+                token::Token::new(TokenType::Minus, "-", TokenLiteral::None, 1usize),
+                expr::Literal::new(TokenLiteral::Number(123.0)),
+            ),
+            token::Token::new(TokenType::Star, "*", token::TokenLiteral::None, 1usize),
+            expr::Grouping::new(expr::Literal::new(TokenLiteral::Number(45.62))),
+        );
+
+        let printer = AstPrinter;
+        assert_eq!("(* (- 123) (group 45.62))", expression.accept(&printer));
+        assert_eq!(
+            "-123 * (45.62)",
+            expression.accept(&SourcePrinter::default())
+        );
+    }
+
+    // Programs as `--dump-desugared` prints them, followed by any diagnostics.
+    #[test]
+    fn golden() {
+        golden::check_suite("desugared", |source| {
+            let session = Session::without_prelude();
+            let statements = session.parse(&source.to_string());
+            let mut lines = vec![SourcePrinter::program().print(&statements)];
+            lines.extend(session.errors.messages());
+            golden::lines(lines)
+        });
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{session::Session, RunError};
+
+/// A Lox program that exercises one part of the tree-walker, for timing
+/// refactors of it.
+#[derive(Debug, Clone, Copy)]
+pub struct Benchmark {
+    pub name: &'static str,
+    pub source: &'static str,
+}
+
+/// The programs `jlox --bench` and `cargo +nightly bench` time. None of them
+/// print anything.
+pub const SUITE: [Benchmark; 3] = [
+    Benchmark {
+        name: "fib",
+        source: include_str!("benchmarks/fib.lox"),
+    },
+    Benchmark {
+        name: "loops",
+        source: include_str!("benchmarks/loops.lox"),
+    },
+    Benchmark {
+        name: "string_concat",
+        source: include_str!("benchmarks/string_concat.lox"),
+    },
+];
+
+/// How many times `jlox --bench` runs each program by default.
+pub const DEFAULT_RUNS: usize = 10;
+
+/// How long each run of a program took.
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    pub name: String,
+    pub runs: Vec<Duration>,
+}
+
+impl Measurement {
+    /// The quickest run, which is the least affected by whatever else the
+    /// machine was doing, so the best to compare against a budget.
+    pub fn fastest(&self) -> Duration {
+        self.runs.iter().min().copied().unwrap_or_default()
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.runs.len() {
+            0 => Duration::ZERO,
+            runs => self.runs.iter().sum::<Duration>() / runs as u32,
+        }
+    }
+}
+
+impl fmt::Display for Measurement {
+    /// E.g. `fib  12.345ms (mean of 10 runs, fastest 11.900ms)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<16}{:>10.3?} (mean of {} runs, fastest {:.3?})",
+            self.name,
+            self.mean(),
+            self.runs.len(),
+            self.fastest()
+        )
+    }
+}
+
+/// Runs the source `runs` times on the tree-walker and times each run. It's
+/// parsed and resolved once, beforehand, so only running it is timed, and
+/// every run shares the same globals.
+///
+/// Returns the diagnostics instead if the source has errors, or a run fails.
+pub fn measure(name: &str, source: &str, runs: usize) -> Result<Measurement, RunError> {
+    let session = Session::without_prelude();
+    let statements = session.parse(&source.to_string());
+    let mut measurement = Measurement {
+        name: name.to_string(),
+        runs: vec![],
+    };
+    if !session.errors.has_errors() {
+        for _ in 0..runs {
+            let start = Instant::now();
+            let result = session.interpreter.interpret(&statements);
+            measurement.runs.push(start.elapsed());
+            if let Err(error) = result {
+                session.errors.runtime_error(error);
+                break;
+            }
+        }
+    }
+    match session.errors.has_errors() || session.errors.has_runtime_error() {
+        true => Err(RunError {
+            diagnostics: session.errors.diagnostics(),
+            messages: session.errors.messages(),
+        }),
+        false => Ok(measurement),
+    }
+}
+
+/// Measures every program in the suite, in order.
+pub fn run_suite(runs: usize) -> Result<Vec<Measurement>, RunError> {
+    SUITE
+        .iter()
+        .map(|benchmark| measure(benchmark.name, benchmark.source, runs))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{measure, run_suite, SUITE};
+
+    #[test]
+    fn the_suite_runs_within_a_generous_budget() {
+        let measurements = run_suite(1).unwrap();
+        assert_eq!(measurements.len(), SUITE.len());
+        for measurement in measurements {
+            assert_eq!(measurement.runs.len(), 1);
+            // Far slower than any of them should be, even in a debug build,
+            // so this only fails on a real regression.
+            assert!(
+                measurement.fastest() < Duration::from_secs(10),
+                "{}",
+                measurement
+            );
+        }
+    }
+
+    #[test]
+    fn reports_errors_instead_of_timing_them() {
+        let error = measure("broken", "print 1 +;", 3).unwrap_err();
+        assert_eq!(error.diagnostics[0].message.code(), "E023");
+        let error = measure("failing", "print nil - 1;", 3).unwrap_err();
+        assert_eq!(error.diagnostics[0].message.code(), "E024");
+    }
+}
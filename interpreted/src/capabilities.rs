@@ -0,0 +1,171 @@
+use std::{
+    env,
+    path::{Component, Path, PathBuf},
+};
+
+/// A kind of access a native can ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Reading files, e.g. with `read_bytes` or `dofile`.
+    Read,
+    /// Creating or replacing files, e.g. with `write_bytes`. Being allowed to
+    /// write somewhere also allows reading there.
+    Write,
+}
+
+impl Capability {
+    /// The verb used in permission errors, e.g. "read".
+    pub fn verb(self) -> &'static str {
+        match self {
+            Capability::Read => "read",
+            Capability::Write => "write",
+        }
+    }
+}
+
+/// What the natives a script calls are allowed to do. Natives ask before
+/// touching anything outside the interpreter, via `NativeContext::require`, so
+/// a host can sandbox a script by choosing what to grant here.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// The directories each capability is granted in, or None if the
+    /// filesystem isn't restricted at all.
+    fs: Option<Vec<(PathBuf, Capability)>>,
+}
+
+impl Capabilities {
+    /// Everything is allowed. This is what scripts run with by default.
+    pub fn unrestricted() -> Self {
+        Self { fs: None }
+    }
+
+    /// Nothing is allowed until it's granted with `allow`.
+    pub fn none() -> Self {
+        Self { fs: Some(vec![]) }
+    }
+
+    /// Grants the capability for the directory and everything inside it.
+    /// Restricts the filesystem to what's been granted, if it wasn't already.
+    pub fn allow(&mut self, capability: Capability, dir: impl AsRef<Path>) {
+        let dir = normalize(dir.as_ref());
+        self.fs.get_or_insert_with(Vec::new).push((dir, capability));
+    }
+
+    /// The path to use for the access if the capability has been granted for
+    /// it, or None if it hasn't. Paths are checked after resolving `.`, `..`,
+    /// and (for the parts that exist) symlinks, so neither can be used to
+    /// escape an allowed directory – and the resolved path is what's returned,
+    /// so it can't be changed between the check and the access.
+    pub fn resolve(&self, capability: Capability, path: &str) -> Option<PathBuf> {
+        let grants = match &self.fs {
+            Some(grants) => grants,
+            None => return Some(PathBuf::from(path)),
+        };
+        let path = normalize(Path::new(path));
+        let allowed = grants.iter().any(|(dir, granted)| {
+            (*granted == capability || *granted == Capability::Write) && path.starts_with(dir)
+        });
+        allowed.then_some(path)
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::unrestricted()
+    }
+}
+
+// The path made absolute, with `.` and `..` resolved and the longest prefix
+// that exists on disk canonicalized.
+fn normalize(path: &Path) -> PathBuf {
+    let absolute = match env::current_dir() {
+        Ok(cwd) => cwd.join(path),
+        Err(_) => path.to_path_buf(),
+    };
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    // Canonicalize as much as exists, so symlinks are followed, and keep the
+    // rest (e.g. a file about to be written) as it is.
+    let mut existing = normalized.as_path();
+    let mut rest = vec![];
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return rest
+                .iter()
+                .rev()
+                .fold(canonical, |path, part| path.join(part));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => return normalized,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{Capabilities, Capability};
+
+    #[test]
+    fn unrestricted_allows_everything() {
+        let capabilities = Capabilities::unrestricted();
+        assert!(capabilities
+            .resolve(Capability::Read, "/etc/passwd")
+            .is_some());
+        assert_eq!(
+            capabilities.resolve(Capability::Write, "anywhere.bin"),
+            Some("anywhere.bin".into())
+        );
+    }
+
+    #[test]
+    fn grants_are_per_capability_and_directory() {
+        let root = env::temp_dir().join(format!("lox_capabilities_{}", std::process::id()));
+        fs::create_dir_all(root.join("data")).unwrap();
+        fs::create_dir_all(root.join("out")).unwrap();
+        let root = root.canonicalize().unwrap();
+        let allows = |capabilities: &Capabilities, capability, path: &str| {
+            capabilities
+                .resolve(capability, root.join(path).to_str().unwrap())
+                .is_some()
+        };
+
+        let mut capabilities = Capabilities::none();
+        assert!(!allows(&capabilities, Capability::Read, "data/a.txt"));
+        capabilities.allow(Capability::Read, root.join("data"));
+        capabilities.allow(Capability::Write, root.join("out"));
+
+        assert!(allows(&capabilities, Capability::Read, "data/a.txt"));
+        assert!(!allows(&capabilities, Capability::Write, "data/a.txt"));
+        // Writing somewhere implies being able to read it back.
+        assert!(allows(&capabilities, Capability::Read, "out/new/b.bin"));
+        assert!(allows(&capabilities, Capability::Write, "out/new/b.bin"));
+        // `..` can't climb out of an allowed directory.
+        assert!(!allows(&capabilities, Capability::Read, "data/../secret"));
+        assert!(!allows(&capabilities, Capability::Read, "database"));
+
+        assert_eq!(
+            capabilities.resolve(
+                Capability::Read,
+                root.join("data/./a.txt").to_str().unwrap()
+            ),
+            Some(root.join("data/a.txt"))
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,698 @@
+//! Compiles a program into bytecode for the `vm` backend.
+//!
+//! Each function (and the script itself) compiles to its own `Chunk` of
+//! instructions. Like clox, variables are resolved here rather than at
+//! runtime: top-level variables are globals looked up by name, locals live in
+//! stack slots, and locals of an enclosing function are reached through
+//! upvalues. The scoping rules are the tree-walking interpreter's, so e.g.
+//! `var a = a;` in a block reads the `a` from an enclosing scope.
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    rc::Rc,
+};
+
+use crate::{
+    ast_printer::SourcePrinter,
+    errors::{Diagnostic, ErrorManager},
+    expr::{
+        Assign, AssignTarget, Binary, Call, CompoundAssign, ExprVisitor, Expression, Function, Get,
+        Grouping, Index, IndexAssign, List, Literal, Logical, Map, Set, Unary, Variable,
+        VisitorTarget,
+    },
+    messages,
+    object::LoxObject,
+    stmt::{
+        BlockStmt, DeferStmt, ExprStmt, FunStmt, IfStmt, MeasureStmt, PrintStmt, Program,
+        ReturnStmt, Statement, StmtVisitor, StmtVisitorTarget, VariableStmt, WhileStmt,
+    },
+    token::{Token, TokenLiteral},
+    token_type::TokenType,
+};
+
+/// One bytecode instruction. Instructions that work on values pop their
+/// operands off the VM's stack and push their result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Pushes the constant at this index in the chunk's constants.
+    Constant(usize),
+    Nil,
+    True,
+    False,
+    Pop,
+    /// Pushes a copy of the top value.
+    Dup,
+    /// Pushes copies of the top two values, in the same order.
+    DupTwo,
+    /// Reads or writes the local in this stack slot of the running call.
+    GetLocal(usize),
+    SetLocal(usize),
+    /// Reads, defines, or writes the global named by the instruction's token.
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    /// Reads or writes the running closure's upvalue at this index.
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    /// Pops this many values into a new list, the deepest first.
+    BuildList(usize),
+    /// Pops this many keys and values (each key below its value) into a new
+    /// map, the deepest first.
+    BuildMap(usize),
+    /// Pops an index and a list (or a key and a map), and pushes the element
+    /// at it.
+    GetIndex,
+    /// Pops a value, an index, and a list (or a key and a map), stores the
+    /// value there, and pushes the value.
+    SetIndex,
+    /// Pops a map and pushes its property named by the instruction's token.
+    GetProperty,
+    /// Pops a value and a map, stores the value as the map's property named by
+    /// the instruction's token, and pushes the value.
+    SetProperty,
+    /// Continues at the instruction at this index.
+    Jump(usize),
+    /// Jumps if the value on top of the stack is falsey, leaving it there.
+    JumpIfFalse(usize),
+    /// Jumps back to the start of a loop, stopping the script instead if it
+    /// has been interrupted or is past its deadline.
+    Loop(usize),
+    /// Calls the value below the arguments. `callee` is the index of the
+    /// callee's source in the constants, for errors.
+    Call {
+        arguments: usize,
+        callee: usize,
+    },
+    /// Pushes a closure of the function at this index in the chunk's
+    /// functions.
+    Closure(usize),
+    /// Moves the local on top of the stack out to the closures that captured
+    /// it, then pops it.
+    CloseUpvalue,
+    Return,
+    StartMeasure,
+    EndMeasure,
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    /// The token each instruction was compiled from, which runtime errors are
+    /// reported at (and which names the global for global instructions).
+    pub tokens: Vec<Token>,
+    pub constants: Vec<LoxObject>,
+    pub functions: Vec<Rc<VmFunction>>,
+}
+
+/// Where a closure's upvalue is captured from when the closure is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpvalueSource {
+    /// A local in this stack slot of the enclosing call.
+    Local(usize),
+    /// The enclosing closure's upvalue at this index.
+    Upvalue(usize),
+}
+
+/// A compiled function, or the script itself.
+pub struct VmFunction {
+    pub name: String,
+    pub params: Vec<String>,
+    pub line: usize,
+    /// How many scopes in from the globals the function was declared, for
+    /// `debug`. Each enclosing function counts as two (its parameters and its
+    /// body), as it does in the tree-walker.
+    pub depth: usize,
+    pub chunk: Chunk,
+    pub upvalues: Vec<UpvalueSource>,
+}
+
+impl VmFunction {
+    fn new(name: &Token, params: &[Token]) -> Self {
+        Self {
+            name: name.lexeme.clone(),
+            params: params.iter().map(|param| param.lexeme.clone()).collect(),
+            line: name.line,
+            depth: 0,
+            chunk: Chunk::default(),
+            upvalues: vec![],
+        }
+    }
+
+    /// Matches `LoxFunction`'s signature, for call errors.
+    pub fn signature(&self) -> String {
+        format!(
+            "fn {}({}) declared on line {}",
+            self.name,
+            self.params.join(", "),
+            self.line
+        )
+    }
+}
+
+// The chunk can be arbitrarily large, so only show what identifies the
+// function.
+impl fmt::Debug for VmFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VmFunction")
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .field("line", &self.line)
+            .finish()
+    }
+}
+
+struct Local {
+    name: String,
+    // False while the local's initializer is compiled, so the initializer
+    // reads the name from an enclosing scope.
+    defined: bool,
+    depth: usize,
+    captured: bool,
+}
+
+// A function being compiled.
+struct FunctionState {
+    function: VmFunction,
+    locals: Vec<Local>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(function: VmFunction) -> Self {
+        Self {
+            function,
+            // Slot 0 holds the function being called.
+            locals: vec![Local {
+                name: "".to_string(),
+                defined: true,
+                depth: 0,
+                captured: false,
+            }],
+            scope_depth: 0,
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<usize> {
+        self.locals
+            .iter()
+            .rposition(|local| local.defined && local.name == name)
+    }
+
+    fn add_upvalue(&mut self, source: UpvalueSource) -> usize {
+        let upvalues = &mut self.function.upvalues;
+        match upvalues.iter().position(|upvalue| *upvalue == source) {
+            Some(index) => index,
+            None => {
+                upvalues.push(source);
+                upvalues.len() - 1
+            }
+        }
+    }
+}
+
+/// Compiles the program into the script's function, or returns None after
+/// reporting any features the VM doesn't support.
+pub fn compile(program: &Program, errors: &ErrorManager) -> Option<Rc<VmFunction>> {
+    let script = Token::new(TokenType::Eof, "script", TokenLiteral::None, 1usize);
+    let compiler = Compiler {
+        errors,
+        functions: RefCell::new(vec![FunctionState::new(VmFunction::new(&script, &[]))]),
+        token: RefCell::new(script),
+        had_error: Cell::new(false),
+    };
+    compiler.statements(program);
+    compiler.emit(Op::Nil);
+    compiler.emit(Op::Return);
+
+    let script = compiler.functions.borrow_mut().pop().unwrap().function;
+    match compiler.had_error.get() {
+        true => None,
+        false => Some(Rc::new(script)),
+    }
+}
+
+struct Compiler<'a> {
+    errors: &'a ErrorManager,
+    // The function being compiled is last, after the ones enclosing it.
+    functions: RefCell<Vec<FunctionState>>,
+    // The most recent token compiled, for instructions (like literals) that
+    // don't have one of their own.
+    token: RefCell<Token>,
+    had_error: Cell<bool>,
+}
+
+impl Compiler<'_> {
+    fn statements(&self, statements: &[Statement]) {
+        for statement in statements {
+            statement.accept(self);
+        }
+    }
+
+    fn expression(&self, expr: &Expression) {
+        expr.accept(self);
+    }
+
+    fn emit(&self, op: Op) -> usize {
+        let token = self.token.borrow().clone();
+        let mut functions = self.functions.borrow_mut();
+        let chunk = &mut functions.last_mut().unwrap().function.chunk;
+        chunk.code.push(op);
+        chunk.tokens.push(token);
+        chunk.code.len() - 1
+    }
+
+    fn emit_at(&self, op: Op, token: &Token) -> usize {
+        *self.token.borrow_mut() = token.clone();
+        self.emit(op)
+    }
+
+    // The index the next instruction will have, for jumps back to it.
+    fn next_index(&self) -> usize {
+        self.functions
+            .borrow()
+            .last()
+            .unwrap()
+            .function
+            .chunk
+            .code
+            .len()
+    }
+
+    // Points the jump at the next instruction.
+    fn patch_jump(&self, jump: usize) {
+        let target = self.next_index();
+        let mut functions = self.functions.borrow_mut();
+        let code = &mut functions.last_mut().unwrap().function.chunk.code;
+        code[jump] = match code[jump] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            op => unreachable!("{:?} isn't a jump", op),
+        };
+    }
+
+    fn add_constant(&self, value: LoxObject) -> usize {
+        let mut functions = self.functions.borrow_mut();
+        let constants = &mut functions.last_mut().unwrap().function.chunk.constants;
+        constants.push(value);
+        constants.len() - 1
+    }
+
+    fn begin_scope(&self) {
+        self.functions.borrow_mut().last_mut().unwrap().scope_depth += 1;
+    }
+
+    fn end_scope(&self) {
+        let mut closing = vec![];
+        {
+            let mut functions = self.functions.borrow_mut();
+            let function = functions.last_mut().unwrap();
+            function.scope_depth -= 1;
+            while let Some(local) = function.locals.last() {
+                if local.depth <= function.scope_depth {
+                    break;
+                }
+                closing.push(function.locals.pop().unwrap().captured);
+            }
+        }
+        for captured in closing {
+            self.emit(if captured { Op::CloseUpvalue } else { Op::Pop });
+        }
+    }
+
+    // Top-level variables are globals; anything else is a local.
+    fn in_local_scope(&self) -> bool {
+        self.functions.borrow().last().unwrap().scope_depth > 0
+    }
+
+    // Adds a local for the value on top of the stack. It's visible once it's
+    // defined.
+    fn declare_local(&self, name: &Token) {
+        let mut functions = self.functions.borrow_mut();
+        let function = functions.last_mut().unwrap();
+        let depth = function.scope_depth;
+        function.locals.push(Local {
+            name: name.lexeme.clone(),
+            defined: false,
+            depth,
+            captured: false,
+        });
+    }
+
+    fn define_local(&self) {
+        let mut functions = self.functions.borrow_mut();
+        let function = functions.last_mut().unwrap();
+        function.locals.last_mut().unwrap().defined = true;
+    }
+
+    // Declares the variable whose value is on top of the stack.
+    fn define_variable(&self, name: &Token) {
+        if self.in_local_scope() {
+            self.declare_local(name);
+            self.define_local();
+        } else {
+            self.emit_at(Op::DefineGlobal, name);
+        }
+    }
+
+    // Finds the upvalue the function at `level` reaches the name through,
+    // capturing it from the enclosing functions as needed.
+    fn resolve_upvalue(functions: &mut [FunctionState], level: usize, name: &str) -> Option<usize> {
+        if level == 0 {
+            return None;
+        }
+        if let Some(slot) = functions[level - 1].resolve_local(name) {
+            functions[level - 1].locals[slot].captured = true;
+            return Some(functions[level].add_upvalue(UpvalueSource::Local(slot)));
+        }
+        let upvalue = Self::resolve_upvalue(functions, level - 1, name)?;
+        Some(functions[level].add_upvalue(UpvalueSource::Upvalue(upvalue)))
+    }
+
+    // The get and set instructions for the variable.
+    fn variable_ops(&self, name: &Token) -> (Op, Op) {
+        let mut functions = self.functions.borrow_mut();
+        let level = functions.len() - 1;
+        if let Some(slot) = functions[level].resolve_local(&name.lexeme) {
+            return (Op::GetLocal(slot), Op::SetLocal(slot));
+        }
+        match Self::resolve_upvalue(&mut functions, level, &name.lexeme) {
+            Some(upvalue) => (Op::GetUpvalue(upvalue), Op::SetUpvalue(upvalue)),
+            None => (Op::GetGlobal, Op::SetGlobal),
+        }
+    }
+
+    fn function(&self, stmt: &FunStmt) {
+        // The script's scopes, then each enclosing function's, which has a
+        // scope for its parameters and body that the tree-walker splits in
+        // two.
+        let depth = self
+            .functions
+            .borrow()
+            .iter()
+            .enumerate()
+            .map(|(level, function)| function.scope_depth + (level > 0) as usize)
+            .sum();
+        self.functions
+            .borrow_mut()
+            .push(FunctionState::new(VmFunction {
+                depth,
+                ..VmFunction::new(&stmt.name, &stmt.params)
+            }));
+        self.begin_scope();
+        for param in &stmt.params {
+            self.declare_local(param);
+            self.define_local();
+        }
+        self.statements(&stmt.body);
+        self.emit(Op::Nil);
+        self.emit(Op::Return);
+
+        let function = self.functions.borrow_mut().pop().unwrap().function;
+        let index = {
+            let mut functions = self.functions.borrow_mut();
+            let chunk = &mut functions.last_mut().unwrap().function.chunk;
+            chunk.functions.push(Rc::new(function));
+            chunk.functions.len() - 1
+        };
+        self.emit_at(Op::Closure(index), &stmt.name);
+    }
+
+    // The instruction for a binary operator (`ty`), reporting errors at the
+    // operator's token.
+    fn binary_op(&self, ty: TokenType, operator: &Token) {
+        let op = match ty {
+            TokenType::EqualEqual => Op::Equal,
+            TokenType::BangEqual => Op::NotEqual,
+            TokenType::Greater => Op::Greater,
+            TokenType::GreaterEqual => Op::GreaterEqual,
+            TokenType::Less => Op::Less,
+            TokenType::LessEqual => Op::LessEqual,
+            TokenType::Plus => Op::Add,
+            TokenType::Minus => Op::Subtract,
+            TokenType::Star => Op::Multiply,
+            TokenType::Slash => Op::Divide,
+            _ => unreachable!(),
+        };
+        self.emit_at(op, operator);
+    }
+
+    fn unsupported(&self, feature: &str, token: &Token) {
+        self.had_error.set(true);
+        Diagnostic::error(token.line, messages::UNSUPPORTED_BY_VM.with(&[feature]))
+            .at(format!("at \"{}\"", token.lexeme))
+            .pointing_at(token)
+            .emit(self.errors);
+    }
+}
+
+impl ExprVisitor<()> for &Compiler<'_> {
+    fn visit_binary_expr(&self, expr: &Binary) {
+        self.expression(&expr.left);
+        self.expression(&expr.right);
+        self.binary_op(expr.operator.ty, &expr.operator);
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping) {
+        self.expression(&expr.expression);
+    }
+
+    fn visit_literal_expr(&self, expr: &Literal) {
+        let op = match &expr.value {
+            TokenLiteral::String(value) => {
+                Op::Constant(self.add_constant(LoxObject::String(value.as_str().into())))
+            }
+            TokenLiteral::Number(value) => {
+                Op::Constant(self.add_constant(LoxObject::Number(*value)))
+            }
+            TokenLiteral::True => Op::True,
+            TokenLiteral::False => Op::False,
+            TokenLiteral::None => Op::Nil,
+        };
+        self.emit(op);
+    }
+
+    fn visit_unary_expr(&self, expr: &Unary) {
+        self.expression(&expr.right);
+        let op = match expr.operator.ty {
+            TokenType::Minus => Op::Negate,
+            TokenType::Bang => Op::Not,
+            _ => unreachable!(),
+        };
+        self.emit_at(op, &expr.operator);
+    }
+
+    fn visit_variable_expr(&self, expr: &Variable) {
+        let (get, _) = self.variable_ops(&expr.name);
+        self.emit_at(get, &expr.name);
+    }
+
+    fn visit_assign_expr(&self, expr: &Assign) {
+        self.expression(&expr.value);
+        let (_, set) = self.variable_ops(&expr.name);
+        self.emit_at(set, &expr.name);
+    }
+
+    fn visit_logical_expr(&self, expr: &Logical) {
+        self.expression(&expr.left);
+        // The left value is the result if it short-circuits.
+        let end = if expr.operator.ty == TokenType::Or {
+            let right = self.emit_at(Op::JumpIfFalse(0), &expr.operator);
+            let end = self.emit(Op::Jump(0));
+            self.patch_jump(right);
+            end
+        } else {
+            self.emit_at(Op::JumpIfFalse(0), &expr.operator)
+        };
+        self.emit(Op::Pop);
+        self.expression(&expr.right);
+        self.patch_jump(end);
+    }
+
+    fn visit_call_expr(&self, expr: &Call) {
+        self.expression(&expr.callee);
+        for argument in &expr.arguments {
+            self.expression(argument);
+        }
+        let callee = self.add_constant(LoxObject::String(
+            expr.callee.accept(&SourcePrinter::default()).into(),
+        ));
+        self.emit_at(
+            Op::Call {
+                arguments: expr.arguments.len(),
+                callee,
+            },
+            &expr.paren,
+        );
+    }
+
+    fn visit_function_expr(&self, expr: &Function) {
+        self.function(&expr.declaration());
+    }
+
+    fn visit_list_expr(&self, expr: &List) {
+        for element in &expr.elements {
+            self.expression(element);
+        }
+        self.emit_at(Op::BuildList(expr.elements.len()), &expr.bracket);
+    }
+
+    fn visit_map_expr(&self, expr: &Map) {
+        for (key, value) in &expr.entries {
+            self.expression(key);
+            self.expression(value);
+        }
+        self.emit_at(Op::BuildMap(expr.entries.len()), &expr.brace);
+    }
+
+    fn visit_index_expr(&self, expr: &Index) {
+        self.expression(&expr.object);
+        self.expression(&expr.index);
+        self.emit_at(Op::GetIndex, &expr.bracket);
+    }
+
+    fn visit_index_assign_expr(&self, expr: &IndexAssign) {
+        self.expression(&expr.object);
+        self.expression(&expr.index);
+        self.expression(&expr.value);
+        self.emit_at(Op::SetIndex, &expr.bracket);
+    }
+
+    fn visit_compound_assign_expr(&self, expr: &CompoundAssign) {
+        let operator = expr.operator.ty.compound_operator().unwrap();
+        match expr.target() {
+            AssignTarget::Variable(variable) => {
+                let (get, set) = self.variable_ops(&variable.name);
+                self.emit_at(get, &variable.name);
+                self.expression(&expr.value);
+                self.binary_op(operator, &expr.operator);
+                self.emit_at(set, &variable.name);
+            }
+            AssignTarget::Index(index) => {
+                // The list and index stay on the stack for the store.
+                self.expression(&index.object);
+                self.expression(&index.index);
+                self.emit(Op::DupTwo);
+                self.emit_at(Op::GetIndex, &index.bracket);
+                self.expression(&expr.value);
+                self.binary_op(operator, &expr.operator);
+                self.emit_at(Op::SetIndex, &index.bracket);
+            }
+            AssignTarget::Property(get) => {
+                // The map stays on the stack for the store.
+                self.expression(&get.object);
+                self.emit(Op::Dup);
+                self.emit_at(Op::GetProperty, &get.name);
+                self.expression(&expr.value);
+                self.binary_op(operator, &expr.operator);
+                self.emit_at(Op::SetProperty, &get.name);
+            }
+        }
+    }
+
+    fn visit_get_expr(&self, expr: &Get) {
+        self.expression(&expr.object);
+        self.emit_at(Op::GetProperty, &expr.name);
+    }
+
+    fn visit_set_expr(&self, expr: &Set) {
+        self.expression(&expr.object);
+        self.expression(&expr.value);
+        self.emit_at(Op::SetProperty, &expr.name);
+    }
+}
+
+impl StmtVisitor<()> for &Compiler<'_> {
+    fn visit_expression_stmt(&self, stmt: &ExprStmt) {
+        self.expression(&stmt.expression);
+        self.emit(Op::Pop);
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) {
+        self.expression(&stmt.expression);
+        self.emit(Op::Print);
+    }
+
+    fn visit_variable_stmt(&self, stmt: &VariableStmt) {
+        match &stmt.initializer {
+            Some(initializer) => self.expression(initializer),
+            None => {
+                self.emit_at(Op::Nil, &stmt.name);
+            }
+        }
+        self.define_variable(&stmt.name);
+    }
+
+    fn visit_block_stmt(&self, stmt: &BlockStmt) {
+        self.begin_scope();
+        self.statements(&stmt.statements);
+        self.end_scope();
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) {
+        self.expression(&stmt.condition);
+        let else_branch = self.emit(Op::JumpIfFalse(0));
+        self.emit(Op::Pop);
+        stmt.then_branch.accept(*self);
+        let end = self.emit(Op::Jump(0));
+        self.patch_jump(else_branch);
+        self.emit(Op::Pop);
+        if let Some(else_branch) = &stmt.else_branch {
+            else_branch.accept(*self);
+        }
+        self.patch_jump(end);
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) {
+        let start = self.next_index();
+        self.expression(&stmt.condition);
+        let exit = self.emit(Op::JumpIfFalse(0));
+        self.emit(Op::Pop);
+        stmt.body.accept(*self);
+        self.emit_at(Op::Loop(start), &stmt.keyword);
+        self.patch_jump(exit);
+        self.emit(Op::Pop);
+    }
+
+    fn visit_fun_stmt(&self, stmt: &FunStmt) {
+        if self.in_local_scope() {
+            // Defined before the body is compiled, so functions can recurse.
+            self.declare_local(&stmt.name);
+            self.define_local();
+            self.function(stmt);
+        } else {
+            self.function(stmt);
+            self.emit_at(Op::DefineGlobal, &stmt.name);
+        }
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) {
+        self.expression(&stmt.value);
+        self.emit_at(Op::Return, &stmt.keyword);
+    }
+
+    fn visit_measure_stmt(&self, stmt: &MeasureStmt) {
+        self.emit_at(Op::StartMeasure, &stmt.keyword);
+        self.begin_scope();
+        self.statements(&stmt.body);
+        self.end_scope();
+        self.emit_at(Op::EndMeasure, &stmt.keyword);
+    }
+
+    fn visit_defer_stmt(&self, stmt: &DeferStmt) {
+        self.unsupported("defer", &stmt.keyword);
+    }
+}
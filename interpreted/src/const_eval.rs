@@ -0,0 +1,187 @@
+use std::{ops::Range, rc::Rc};
+
+use crate::{
+    errors::ErrorManager,
+    expr::{
+        Assign, Binary, Call, CompoundAssign, ExprVisitor, Expression, Function, Get, Grouping,
+        Index, IndexAssign, List, Literal, Logical, Map, Set, Unary, Variable, VisitorTarget,
+    },
+    interpreter::Interpreter,
+    object::LoxObject,
+    parser::Parser,
+    scanner::Scanner,
+    shared_traits::Substring,
+};
+
+/// Evaluates a side-effect-free expression (literals, and arithmetic, logic,
+/// and comparisons on literals) and returns its display string, e.g. for
+/// showing the value of a subexpression when hovering over it in an editor.
+///
+/// Returns None if the expression isn't constant (it reads a variable, calls a
+/// function, or assigns), or if evaluating it would produce a runtime error.
+pub fn evaluate_const(expr: &Expression) -> Option<String> {
+    constant_values(&[expr])
+        .remove(0)
+        .map(|value| value.stringify())
+}
+
+/// Like `evaluate_const`, for the expression in the span of the source (in
+/// characters, like a token's span), e.g. the selection an editor is hovering
+/// over. Returns None if the span doesn't hold exactly one expression.
+pub fn evaluate_const_at(source: &str, span: Range<usize>) -> Option<String> {
+    let text = source.substring(span.start, span.end).to_string();
+    let errors = Rc::new(ErrorManager::new());
+    let tokens = Scanner::new(&text, errors.clone()).scan_tokens();
+    let expression = Parser::new(tokens, errors.clone()).parse_expression()?;
+    match errors.has_errors() {
+        true => None,
+        false => evaluate_const(&expression),
+    }
+}
+
+/// The value of each constant expression, or None for any that isn't
+/// constant or would produce a runtime error. All of them are evaluated with
+/// one interpreter, e.g. to compare the keys of a map literal.
+pub fn constant_values(exprs: &[&Expression]) -> Vec<Option<LoxObject>> {
+    let mut interpreter = None;
+    exprs
+        .iter()
+        .map(|expr| {
+            if !is_constant(expr) {
+                return None;
+            }
+            // Constant expressions can't observe the environment, so a
+            // throwaway interpreter gives us exactly the same semantics as a
+            // real run.
+            interpreter
+                .get_or_insert_with(|| Interpreter::new(Rc::new(ErrorManager::new())))
+                .evaluate(expr)
+                .ok()
+        })
+        .collect()
+}
+
+/// Whether the expression can be evaluated without side effects, and without
+/// depending on any runtime state.
+pub fn is_constant(expr: &Expression) -> bool {
+    expr.accept(&ConstantChecker)
+}
+
+struct ConstantChecker;
+
+impl ExprVisitor<bool> for &ConstantChecker {
+    fn visit_binary_expr(&self, expr: &Binary) -> bool {
+        is_constant(&expr.left) && is_constant(&expr.right)
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping) -> bool {
+        is_constant(&expr.expression)
+    }
+
+    fn visit_literal_expr(&self, _expr: &Literal) -> bool {
+        true
+    }
+
+    fn visit_unary_expr(&self, expr: &Unary) -> bool {
+        is_constant(&expr.right)
+    }
+
+    fn visit_variable_expr(&self, _expr: &Variable) -> bool {
+        false
+    }
+
+    fn visit_assign_expr(&self, _expr: &Assign) -> bool {
+        false
+    }
+
+    fn visit_logical_expr(&self, expr: &Logical) -> bool {
+        is_constant(&expr.left) && is_constant(&expr.right)
+    }
+
+    fn visit_call_expr(&self, _expr: &Call) -> bool {
+        false
+    }
+
+    fn visit_function_expr(&self, _expr: &Function) -> bool {
+        false
+    }
+
+    // Each evaluation makes a new list, which isn't equal to any other.
+    fn visit_list_expr(&self, _expr: &List) -> bool {
+        false
+    }
+
+    fn visit_map_expr(&self, _expr: &Map) -> bool {
+        false
+    }
+
+    fn visit_index_expr(&self, _expr: &Index) -> bool {
+        false
+    }
+
+    fn visit_index_assign_expr(&self, _expr: &IndexAssign) -> bool {
+        false
+    }
+
+    fn visit_compound_assign_expr(&self, _expr: &CompoundAssign) -> bool {
+        false
+    }
+
+    fn visit_get_expr(&self, _expr: &Get) -> bool {
+        false
+    }
+
+    fn visit_set_expr(&self, _expr: &Set) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::evaluate_const_at;
+
+    fn evaluate(source: &str) -> Option<String> {
+        evaluate_const_at(source, 0..source.chars().count())
+    }
+
+    #[test]
+    fn folds_arithmetic_on_literals() {
+        assert_eq!(evaluate("1 + 2 * 3"), Some("7.00".to_string()));
+        assert_eq!(evaluate("-(4 - 6) > 1"), Some("true".to_string()));
+        assert_eq!(evaluate("nil or !false"), Some("true".to_string()));
+    }
+
+    #[test]
+    fn concatenates_strings() {
+        assert_eq!(evaluate("\"lo\" + \"x\""), Some("lox".to_string()));
+    }
+
+    #[test]
+    fn variables_and_calls_are_not_constant() {
+        assert_eq!(evaluate("a + 1"), None);
+        assert_eq!(evaluate("clock()"), None);
+        assert_eq!(evaluate("a = 1"), None);
+    }
+
+    #[test]
+    fn division_by_zero_folds_like_a_run() {
+        assert_eq!(evaluate("1 / 0"), Some("inf".to_string()));
+        // Errors a run would report aren't folded.
+        assert_eq!(evaluate("1 / \"zero\""), None);
+    }
+
+    #[test]
+    fn folds_nested_groupings() {
+        assert_eq!(evaluate("((1 + (2)) * ((3)))"), Some("9.00".to_string()));
+    }
+
+    #[test]
+    fn evaluates_the_expression_in_the_span() {
+        let source = "print a + (2 * 3);";
+        assert_eq!(evaluate_const_at(source, 10..17), Some("6.00".to_string()));
+        // Part of an expression isn't one.
+        assert_eq!(evaluate_const_at(source, 10..14), None);
+        assert_eq!(evaluate_const_at(source, 6..17), None);
+        assert_eq!(evaluate(""), None);
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// What running a script produced, as far as the differential test cares.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub stdout: String,
+    pub code: i32,
+}
+
+/// Where the expected behaviour comes from: either a reference interpreter
+/// that is run on every script (e.g. `java -jar jlox.jar`), or outputs it
+/// recorded earlier, stored next to each script as `<name>.jlox.out` (stdout)
+/// and `<name>.jlox.code` (exit code).
+pub enum Reference {
+    Command(Vec<String>),
+    Recorded,
+}
+
+/// Runs every `.lox` file in the corpus through this interpreter and the
+/// reference, reporting each script whose stdout or exit code differs. With
+/// `save`, the reference's outputs are recorded for later runs. Returns
+/// whether every script matched.
+pub fn run(corpus: &Path, reference: &Reference, save: bool) -> io::Result<bool> {
+    let this = vec![env::current_exe()?.to_string_lossy().to_string()];
+
+    let (mut passed, mut differed, mut skipped) = (0, 0, 0);
+    for script in scripts(corpus)? {
+        let expected = match reference {
+            Reference::Command(command) => {
+                let outcome = execute(command, &script)?;
+                if save {
+                    fs::write(recorded(&script, "out"), &outcome.stdout)?;
+                    fs::write(recorded(&script, "code"), format!("{}\n", outcome.code))?;
+                }
+                outcome
+            }
+            Reference::Recorded => match load(&script) {
+                Some(outcome) => outcome,
+                None => {
+                    println!("skip  {} (no recorded output)", script.display());
+                    skipped += 1;
+                    continue;
+                }
+            },
+        };
+
+        let actual = execute(&this, &script)?;
+        if actual == expected {
+            println!("ok    {}", script.display());
+            passed += 1;
+        } else {
+            println!("DIFF  {}", script.display());
+            print_diff(&expected, &actual);
+            differed += 1;
+        }
+    }
+
+    println!();
+    println!(
+        "{} passed, {} differed, {} skipped",
+        passed, differed, skipped
+    );
+    Ok(differed == 0)
+}
+
+// The corpus's scripts, sorted so reports are stable between runs.
+fn scripts(corpus: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut scripts = Vec::new();
+    for entry in fs::read_dir(corpus)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "lox") {
+            scripts.push(path);
+        }
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+fn execute(command: &[String], script: &Path) -> io::Result<Outcome> {
+    let output = Command::new(&command[0])
+        .args(&command[1..])
+        .arg(script)
+        .output()?;
+    Ok(Outcome {
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        // Killed by a signal: use a code no interpreter exits with.
+        code: output.status.code().unwrap_or(-1),
+    })
+}
+
+fn recorded(script: &Path, kind: &str) -> PathBuf {
+    script.with_extension(format!("jlox.{}", kind))
+}
+
+fn load(script: &Path) -> Option<Outcome> {
+    let stdout = fs::read_to_string(recorded(script, "out")).ok()?;
+    let code = match fs::read_to_string(recorded(script, "code")) {
+        Ok(code) => code.trim().parse().ok()?,
+        Err(_) => 0,
+    };
+    Some(Outcome { stdout, code })
+}
+
+// Shows the differing lines, prefixed with "-" for the reference and "+" for
+// this interpreter.
+fn print_diff(expected: &Outcome, actual: &Outcome) {
+    if expected.code != actual.code {
+        println!(
+            "      exit code: expected {}, got {}",
+            expected.code, actual.code
+        );
+    }
+
+    let expected_lines: Vec<&str> = expected.stdout.lines().collect();
+    let actual_lines: Vec<&str> = actual.stdout.lines().collect();
+    for idx in 0..expected_lines.len().max(actual_lines.len()) {
+        let (expected_line, actual_line) = (expected_lines.get(idx), actual_lines.get(idx));
+        if expected_line == actual_line {
+            continue;
+        }
+        if let Some(line) = expected_line {
+            println!("      {:>4} - {}", idx + 1, line);
+        }
+        if let Some(line) = actual_line {
+            println!("      {:>4} + {}", idx + 1, line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(stdout: &str, code: i32) -> Outcome {
+        Outcome {
+            stdout: stdout.to_string(),
+            code,
+        }
+    }
+
+    #[test]
+    fn loads_recorded_outputs() {
+        let dir = env::temp_dir().join(format!("lox-difftest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("hello.lox");
+        fs::write(&script, "print 1;").unwrap();
+        assert_eq!(load(&script), None);
+
+        fs::write(dir.join("hello.jlox.out"), "1\n").unwrap();
+        assert_eq!(load(&script), Some(outcome("1\n", 0)));
+
+        fs::write(dir.join("hello.jlox.code"), "70\n").unwrap();
+        assert_eq!(load(&script), Some(outcome("1\n", 70)));
+        assert_eq!(scripts(&dir).unwrap(), vec![script]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! Finds what evaluating an expression can change besides producing its
+//! value, for lints that care whether (or when) a piece of code runs.
+
+use crate::{
+    expr::{
+        Assign, Binary, Call, CompoundAssign, ExprVisitor, Expression, Function, Get, Grouping,
+        Index, IndexAssign, List, Literal, Logical, Map, Set, Unary, Variable, VisitorTarget,
+    },
+    token::Token,
+};
+
+/// Something an expression does besides producing a value.
+#[derive(Debug, Clone)]
+pub struct SideEffect {
+    pub kind: SideEffectKind,
+    /// Where it happens: the assigned name, the operator, or the call's (or
+    /// index's) closing delimiter.
+    pub token: Token,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SideEffectKind {
+    /// Assigning to a variable or an element, including with `+=` and the
+    /// like.
+    Assignment,
+    /// Calling a function, which could do anything.
+    Call,
+}
+
+impl SideEffectKind {
+    /// The kind as a noun, e.g. for "the call on the right of 'and'".
+    pub fn name(self) -> &'static str {
+        match self {
+            SideEffectKind::Assignment => "assignment",
+            SideEffectKind::Call => "call",
+        }
+    }
+}
+
+/// The first side effect evaluating the expression could have, in evaluation
+/// order. An assignment or call containing others is reported itself, rather
+/// than what's inside it.
+///
+/// Creating a function isn't a side effect, and neither is anything in its
+/// body, which only runs once it's called. Runtime errors (like indexing past
+/// the end of a list) aren't counted either.
+pub fn side_effect(expr: &Expression) -> Option<SideEffect> {
+    expr.accept(&EffectFinder)
+}
+
+/// Whether evaluating the expression could do anything besides produce a
+/// value.
+pub fn has_side_effects(expr: &Expression) -> bool {
+    side_effect(expr).is_some()
+}
+
+struct EffectFinder;
+
+fn effect(kind: SideEffectKind, token: &Token) -> Option<SideEffect> {
+    Some(SideEffect {
+        kind,
+        token: token.clone(),
+    })
+}
+
+fn first<'e>(exprs: impl IntoIterator<Item = &'e Expression>) -> Option<SideEffect> {
+    exprs.into_iter().find_map(side_effect)
+}
+
+impl ExprVisitor<Option<SideEffect>> for &EffectFinder {
+    fn visit_binary_expr(&self, expr: &Binary) -> Option<SideEffect> {
+        first([&expr.left, &expr.right])
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping) -> Option<SideEffect> {
+        side_effect(&expr.expression)
+    }
+
+    fn visit_literal_expr(&self, _expr: &Literal) -> Option<SideEffect> {
+        None
+    }
+
+    fn visit_unary_expr(&self, expr: &Unary) -> Option<SideEffect> {
+        side_effect(&expr.right)
+    }
+
+    fn visit_variable_expr(&self, _expr: &Variable) -> Option<SideEffect> {
+        None
+    }
+
+    fn visit_assign_expr(&self, expr: &Assign) -> Option<SideEffect> {
+        effect(SideEffectKind::Assignment, &expr.name)
+    }
+
+    fn visit_logical_expr(&self, expr: &Logical) -> Option<SideEffect> {
+        first([&expr.left, &expr.right])
+    }
+
+    fn visit_call_expr(&self, expr: &Call) -> Option<SideEffect> {
+        effect(SideEffectKind::Call, &expr.paren)
+    }
+
+    fn visit_function_expr(&self, _expr: &Function) -> Option<SideEffect> {
+        None
+    }
+
+    fn visit_list_expr(&self, expr: &List) -> Option<SideEffect> {
+        first(&expr.elements)
+    }
+
+    fn visit_map_expr(&self, expr: &Map) -> Option<SideEffect> {
+        expr.entries
+            .iter()
+            .find_map(|(key, value)| first([key, value]))
+    }
+
+    fn visit_index_expr(&self, expr: &Index) -> Option<SideEffect> {
+        first([&expr.object, &expr.index])
+    }
+
+    fn visit_index_assign_expr(&self, expr: &IndexAssign) -> Option<SideEffect> {
+        effect(SideEffectKind::Assignment, &expr.bracket)
+    }
+
+    fn visit_compound_assign_expr(&self, expr: &CompoundAssign) -> Option<SideEffect> {
+        effect(SideEffectKind::Assignment, &expr.operator)
+    }
+
+    fn visit_get_expr(&self, expr: &Get) -> Option<SideEffect> {
+        side_effect(&expr.object)
+    }
+
+    fn visit_set_expr(&self, expr: &Set) -> Option<SideEffect> {
+        effect(SideEffectKind::Assignment, &expr.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{side_effect, SideEffectKind};
+    use crate::{
+        errors::ErrorManager,
+        parser::Parser,
+        scanner::Scanner,
+        stmt::{Statement, Stmt},
+    };
+
+    fn effect_of(source: &str) -> Option<(SideEffectKind, String)> {
+        let errors = Rc::new(ErrorManager::new());
+        let tokens = Scanner::new(&format!("{};", source), errors.clone()).scan_tokens();
+        let statements: Vec<Statement> = Parser::new(tokens, errors.clone()).parse();
+        assert!(!errors.has_errors(), "{:?}", errors.messages());
+        match &*statements[0] {
+            Stmt::Expression(stmt) => {
+                side_effect(&stmt.expression).map(|effect| (effect.kind, effect.token.lexeme))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn finds_the_first_assignment_or_call() {
+        assert_eq!(effect_of("1 + x * -y"), None);
+        assert_eq!(effect_of("[a, {\"k\": b}][0]"), None);
+        assert_eq!(
+            effect_of("x = f()"),
+            Some((SideEffectKind::Assignment, "x".to_string()))
+        );
+        assert_eq!(
+            effect_of("a or (f(1) and (b += 1))"),
+            Some((SideEffectKind::Call, ")".to_string()))
+        );
+        assert_eq!(
+            effect_of("list[0] and (list[1] *= 2)"),
+            Some((SideEffectKind::Assignment, "*=".to_string()))
+        );
+    }
+
+    #[test]
+    fn function_bodies_only_run_when_called() {
+        assert_eq!(effect_of("fun () { x = 1; }"), None);
+    }
+}
@@ -1,7 +1,34 @@
+//! Where variables live at runtime.
+//!
+//! The model, innermost first:
+//!
+//! - An `EnvironmentBase` is one scope: a map from names to values.
+//! - An `EnvironmentStack` is the chain of scopes visible to one function
+//!   call: the globals at the bottom, then one scope per block being run.
+//! - The `EnvironmentManager` holds one `EnvironmentStack` per active call,
+//!   with the script's own (top-level) stack at the bottom.
+//!
+//! Scopes are shared (`Rc`), not copied. Every stack shares the same globals
+//! scope, so a global defined or assigned anywhere is seen everywhere. A
+//! function captures the scopes that were visible where it was declared, and
+//! each call to it gets a stack made of those scopes plus its own. So a
+//! function sees the locals around its declaration – and keeps them alive
+//! after the block that declared them is done – but never its caller's locals,
+//! and each recursive call gets fresh locals.
+//!
+//! Before a program runs, the resolver works out which scope each variable
+//! reference refers to, so lookups go straight to that scope (`get_at`) or to
+//! the globals (`get_global`). Code that wasn't resolved falls back to walking
+//! the current call's stack from the innermost scope out to the globals.
+//! Defining a name always targets the innermost scope, so shadowing works per
+//! block, and redefining a name in the same scope replaces it.
+
 use crate::{
     exceptions::{RuntimeError, RuntimeException},
+    messages,
     object::LoxObject,
     token::Token,
+    trace::{self, TraceEvent, Tracer},
 };
 use std::{collections::HashMap, rc::Rc, sync::RwLock};
 
@@ -32,8 +59,7 @@ impl EnvironmentTrait for EnvironmentBase {
         self.values.insert(name.clone(), value);
     }
 
-    /// Get a variable's value. This will return a runtime error if the variable
-    /// hasn't been set yet.
+    /// Get a variable's value, or None if it isn't defined in this scope.
     ///
     /// We have to do this at runtime to support lazy references to variables in
     /// functions. We could statically check all of this (I believe) – but it's
@@ -42,8 +68,8 @@ impl EnvironmentTrait for EnvironmentBase {
         self.values.get(&name.lexeme).map(|v| v.clone())
     }
 
-    /// Similar to 'get', but this doesn't let you create a new variable. If a
-    /// new variable creation is attempted, this will throw a 'RuntimeError'.
+    /// Similar to 'get', but this doesn't let you create a new variable. If the
+    /// variable isn't defined in this scope, this returns None.
     fn assign(&mut self, name: &Token, value: LoxObject) -> Option<()> {
         // If the key exists, replace it with new value.
         if let None = self.values.remove_entry(&name.lexeme) {
@@ -69,17 +95,19 @@ impl EnvironmentStack {
         }
     }
 
-    pub fn enter_new_scope(&self) {
+    pub fn enter_new_scope(&self, tracer: &Tracer) {
         if let Ok(mut inner) = self.inner.try_write() {
             inner.push(Rc::new(RwLock::new(EnvironmentBase::new_global())));
+            trace::emit(tracer, || TraceEvent::ScopePush { depth: inner.len() });
         }
     }
 
-    pub fn exit_scope(&self) {
+    pub fn exit_scope(&self, tracer: &Tracer) {
         if let Ok(mut inner) = self.inner.try_write() {
             let len = inner.len();
             if len > 1 as usize {
                 inner.remove(len - 1);
+                trace::emit(tracer, || TraceEvent::ScopePop { depth: len - 1 });
             }
         }
     }
@@ -96,62 +124,123 @@ impl EnvironmentStack {
         panic!("Unable to define new value in [EnvironmentStack::define]");
     }
 
+    /// Looks the variable up from the innermost scope out to the globals.
     pub fn get(&self, name: &Token) -> Result<LoxObject, RuntimeException> {
-        let mut ret = None;
-        if let Ok(inner) = self.inner.try_read() {
-            let mut idx = inner.len() - 1;
-            while idx >= 0 as usize && ret.is_none() {
-                let v = inner.get(idx).unwrap();
-                if let Ok(env) = v.try_write() {
-                    match env.get(name) {
-                        Some(value) => {
-                            ret = Some(value);
-                            break;
-                        }
-                        None => {
-                            idx = idx - 1;
-                        }
-                    }
+        let inner = match self.inner.try_read() {
+            Ok(inner) => inner,
+            Err(_) => {
+                return Err(RuntimeError::new(
+                    name.clone(),
+                    messages::UNABLE_TO_GET.with(&[&name.lexeme]),
+                ))
+            }
+        };
+
+        for scope in inner.iter().rev() {
+            let value = match scope.try_read() {
+                Ok(env) => env.get(name),
+                Err(_) => {
+                    return Err(RuntimeError::new(
+                        name.clone(),
+                        messages::UNABLE_TO_GET.with(&[&name.lexeme]),
+                    ))
                 }
+            };
+            if let Some(value) = value {
+                return Ok(value);
             }
         }
 
-        match ret {
-            Some(obj) => Ok(obj),
-            None => Err(RuntimeError::new(
-                name.clone(),
-                format!("[internal] Unable to get '{}'.", name.lexeme),
-            )),
-        }
+        Err(RuntimeError::new(
+            name.clone(),
+            messages::UNDEFINED_VARIABLE.with(&[&name.lexeme]),
+        ))
     }
 
+    /// Assigns to the innermost scope that already has the variable.
     pub fn assign(&self, name: &Token, value: LoxObject) -> Result<(), RuntimeException> {
-        let mut ret = None;
-        if let Ok(inner) = self.inner.try_read() {
-            let mut idx = inner.len() - 1;
-
-            while idx >= 0 as usize && ret.is_none() {
-                let v = inner.get(idx).unwrap();
-                if let Ok(mut env) = v.try_write() {
-                    match env.assign(name, value.clone()) {
-                        Some(_) => {
-                            ret = Some(());
-                            break;
-                        }
-                        None => {
-                            idx = idx - 1;
-                        }
-                    }
+        let inner = match self.inner.try_read() {
+            Ok(inner) => inner,
+            Err(_) => {
+                return Err(RuntimeError::new(
+                    name.clone(),
+                    messages::UNABLE_TO_ASSIGN.with(&[&name.lexeme]),
+                ))
+            }
+        };
+
+        for scope in inner.iter().rev() {
+            let assigned = match scope.try_write() {
+                Ok(mut env) => env.assign(name, value.clone()),
+                Err(_) => {
+                    return Err(RuntimeError::new(
+                        name.clone(),
+                        messages::UNABLE_TO_ASSIGN.with(&[&name.lexeme]),
+                    ))
                 }
+            };
+            if assigned.is_some() {
+                return Ok(());
             }
         }
 
-        match ret {
-            Some(_) => Ok(()),
-            None => Err(RuntimeError::new(
-                name.clone(),
-                format!("[internal] Unable to assign '{}'.", name.lexeme),
-            )),
+        Err(RuntimeError::new(
+            name.clone(),
+            messages::UNDEFINED_VARIABLE.with(&[&name.lexeme]),
+        ))
+    }
+
+    // The scope `depth` scopes out from the innermost one. The globals are the
+    // outermost scope.
+    fn scope_at(&self, depth: usize) -> Option<Rc<RwLock<EnvironmentBase>>> {
+        let inner = self.inner.try_read().ok()?;
+        let idx = inner.len().checked_sub(depth + 1)?;
+        inner.get(idx).cloned()
+    }
+
+    fn globals(&self) -> Option<Rc<RwLock<EnvironmentBase>>> {
+        let inner = self.inner.try_read().ok()?;
+        inner.first().cloned()
+    }
+
+    /// Gets the variable from the scope `depth` scopes out from the innermost
+    /// one, where the resolver found its declaration.
+    pub fn get_at(&self, depth: usize, name: &Token) -> Result<LoxObject, RuntimeException> {
+        get_from(self.scope_at(depth), name)
+    }
+
+    pub fn get_global(&self, name: &Token) -> Result<LoxObject, RuntimeException> {
+        get_from(self.globals(), name)
+    }
+
+    /// Assigns to the variable in the scope `depth` scopes out from the
+    /// innermost one, where the resolver found its declaration.
+    pub fn assign_at(
+        &self,
+        depth: usize,
+        name: &Token,
+        value: LoxObject,
+    ) -> Result<(), RuntimeException> {
+        assign_in(self.scope_at(depth), name, value)
+    }
+
+    pub fn assign_global(&self, name: &Token, value: LoxObject) -> Result<(), RuntimeException> {
+        assign_in(self.globals(), name, value)
+    }
+
+    /// The scopes in this stack, globals first, for a function declared here
+    /// to capture.
+    pub fn scopes(&self) -> Vec<Rc<RwLock<EnvironmentBase>>> {
+        match self.inner.try_read() {
+            Ok(inner) => inner.clone(),
+            Err(_) => panic!("Unable to capture scopes in [EnvironmentStack::scopes]"),
+        }
+    }
+
+    /// A stack made of the given scopes, which it shares rather than copies.
+    pub fn from_scopes(scopes: Vec<Rc<RwLock<EnvironmentBase>>>) -> Self {
+        Self {
+            inner: Rc::new(RwLock::new(scopes)),
         }
     }
 
@@ -171,8 +260,82 @@ impl EnvironmentStack {
     }
 }
 
+impl Default for EnvironmentStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The variable isn't in the scope if, say, an error skipped its declaration
+// before a deferred statement read it.
+fn get_from(
+    scope: Option<Rc<RwLock<EnvironmentBase>>>,
+    name: &Token,
+) -> Result<LoxObject, RuntimeException> {
+    let scope = match scope {
+        Some(scope) => scope,
+        None => {
+            return Err(RuntimeError::new(
+                name.clone(),
+                messages::UNABLE_TO_GET.with(&[&name.lexeme]),
+            ))
+        }
+    };
+    let value = match scope.try_read() {
+        Ok(env) => env.get(name),
+        Err(_) => {
+            return Err(RuntimeError::new(
+                name.clone(),
+                messages::UNABLE_TO_GET.with(&[&name.lexeme]),
+            ))
+        }
+    };
+    match value {
+        Some(value) => Ok(value),
+        None => Err(RuntimeError::new(
+            name.clone(),
+            messages::UNDEFINED_VARIABLE.with(&[&name.lexeme]),
+        )),
+    }
+}
+
+fn assign_in(
+    scope: Option<Rc<RwLock<EnvironmentBase>>>,
+    name: &Token,
+    value: LoxObject,
+) -> Result<(), RuntimeException> {
+    let scope = match scope {
+        Some(scope) => scope,
+        None => {
+            return Err(RuntimeError::new(
+                name.clone(),
+                messages::UNABLE_TO_ASSIGN.with(&[&name.lexeme]),
+            ))
+        }
+    };
+    let assigned = match scope.try_write() {
+        Ok(mut env) => env.assign(name, value),
+        Err(_) => {
+            return Err(RuntimeError::new(
+                name.clone(),
+                messages::UNABLE_TO_ASSIGN.with(&[&name.lexeme]),
+            ))
+        }
+    };
+    match assigned {
+        Some(_) => Ok(()),
+        None => Err(RuntimeError::new(
+            name.clone(),
+            messages::UNDEFINED_VARIABLE.with(&[&name.lexeme]),
+        )),
+    }
+}
+
 pub struct EnvironmentManager {
     pub environments: Rc<RwLock<Vec<EnvironmentStack>>>,
+    /// Where entering and exiting block scopes is traced, set along with the
+    /// interpreter's by `Interpreter::set_tracer`.
+    pub(crate) tracer: Tracer,
 }
 
 impl EnvironmentManager {
@@ -180,10 +343,45 @@ impl EnvironmentManager {
     pub fn new() -> Self {
         Self {
             environments: Rc::new(RwLock::new(vec![EnvironmentStack::new()])),
+            tracer: Tracer::default(),
+        }
+    }
+
+    /// Enters a new block scope, which is exited when the returned guard is
+    /// dropped – however the block is left, including by an error or return.
+    pub fn scope(&self) -> ScopeGuard<'_> {
+        self.enter_new_scope();
+        ScopeGuard {
+            environment: self,
+            function: false,
         }
     }
 
-    /// Enters a new function scope
+    /// Enters a new function scope, which is exited when the returned guard is
+    /// dropped.
+    pub fn function_scope(&self) -> ScopeGuard<'_> {
+        self.enter_function_scope();
+        ScopeGuard {
+            environment: self,
+            function: true,
+        }
+    }
+
+    /// Enters a closure's scope: a fresh stack holding the scopes the function
+    /// captured, which is exited when the returned guard is dropped.
+    pub fn closure_scope(&self, captured: &[Rc<RwLock<EnvironmentBase>>]) -> ScopeGuard<'_> {
+        if let Ok(mut environments) = self.environments.try_write() {
+            environments.push(EnvironmentStack::from_scopes(captured.to_vec()));
+            return ScopeGuard {
+                environment: self,
+                function: true,
+            };
+        }
+
+        panic!("Unable to enter closure scope.")
+    }
+
+    /// Enters a new function scope: a fresh stack holding only the globals.
     pub fn enter_function_scope(&self) {
         if let Ok(mut environments) = self.environments.try_write() {
             let new_env = environments.get(0).unwrap().new_from_current_global();
@@ -213,7 +411,7 @@ impl EnvironmentManager {
     pub fn enter_new_scope(&self) {
         if let Ok(environments) = self.environments.try_read() {
             if let Some(environment_stack) = environments.last() {
-                environment_stack.enter_new_scope();
+                environment_stack.enter_new_scope(&self.tracer);
             }
         }
     }
@@ -221,7 +419,7 @@ impl EnvironmentManager {
     pub fn exit_current_scope(&self) {
         if let Ok(environments) = self.environments.try_read() {
             if let Some(environment_stack) = environments.last() {
-                environment_stack.exit_scope();
+                environment_stack.exit_scope(&self.tracer);
             }
         }
     }
@@ -243,7 +441,7 @@ impl EnvironmentManager {
 
         Err(RuntimeError::new(
             name.clone(),
-            format!("[internal] Unable to get '{}'.", name.lexeme),
+            messages::UNABLE_TO_GET.with(&[&name.lexeme]),
         ))
     }
 
@@ -256,7 +454,145 @@ impl EnvironmentManager {
 
         Err(RuntimeError::new(
             name.clone(),
-            format!("[internal] Unable to assign '{}'.", name.lexeme),
+            messages::UNABLE_TO_ASSIGN.with(&[&name.lexeme]),
         ))
     }
 }
+
+impl Default for EnvironmentManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EnvironmentManager {
+    /// The scopes visible in the running call, for a function declared now to
+    /// capture.
+    pub fn capture(&self) -> Vec<Rc<RwLock<EnvironmentBase>>> {
+        match self.with_current(|stack| stack.scopes()) {
+            Some(scopes) => scopes,
+            None => panic!("Unable to capture the current scopes."),
+        }
+    }
+
+    // The running call's stack.
+    fn with_current<T>(&self, f: impl FnOnce(&EnvironmentStack) -> T) -> Option<T> {
+        let environments = self.environments.try_read().ok()?;
+        environments.last().map(f)
+    }
+
+    pub fn get_at(&self, depth: usize, name: &Token) -> Result<LoxObject, RuntimeException> {
+        match self.with_current(|stack| stack.get_at(depth, name)) {
+            Some(result) => result,
+            None => Err(RuntimeError::new(
+                name.clone(),
+                messages::UNABLE_TO_GET.with(&[&name.lexeme]),
+            )),
+        }
+    }
+
+    pub fn get_global(&self, name: &Token) -> Result<LoxObject, RuntimeException> {
+        match self.with_current(|stack| stack.get_global(name)) {
+            Some(result) => result,
+            None => Err(RuntimeError::new(
+                name.clone(),
+                messages::UNABLE_TO_GET.with(&[&name.lexeme]),
+            )),
+        }
+    }
+
+    pub fn assign_at(
+        &self,
+        depth: usize,
+        name: &Token,
+        value: LoxObject,
+    ) -> Result<(), RuntimeException> {
+        match self.with_current(|stack| stack.assign_at(depth, name, value)) {
+            Some(result) => result,
+            None => Err(RuntimeError::new(
+                name.clone(),
+                messages::UNABLE_TO_ASSIGN.with(&[&name.lexeme]),
+            )),
+        }
+    }
+
+    pub fn assign_global(&self, name: &Token, value: LoxObject) -> Result<(), RuntimeException> {
+        match self.with_current(|stack| stack.assign_global(name, value)) {
+            Some(result) => result,
+            None => Err(RuntimeError::new(
+                name.clone(),
+                messages::UNABLE_TO_ASSIGN.with(&[&name.lexeme]),
+            )),
+        }
+    }
+}
+
+/// Exits the scope it was created for when it's dropped. See
+/// `EnvironmentManager::scope` and `EnvironmentManager::function_scope`.
+pub struct ScopeGuard<'a> {
+    environment: &'a EnvironmentManager,
+    function: bool,
+}
+
+impl Drop for ScopeGuard<'_> {
+    fn drop(&mut self) {
+        if self.function {
+            self.environment.exit_function_scope();
+        } else {
+            self.environment.exit_current_scope();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EnvironmentManager;
+    use crate::{
+        object::LoxObject,
+        token::{Token, TokenLiteral},
+        token_type::TokenType,
+    };
+
+    fn name(lexeme: &str) -> Token {
+        Token::new(TokenType::Identifier, lexeme, TokenLiteral::None, 1usize)
+    }
+
+    #[test]
+    fn scope_guard_exits_scope_when_dropped() {
+        let environment = EnvironmentManager::new();
+        environment.define(&"a".to_string(), LoxObject::Number(1.0));
+        {
+            let _outer = environment.scope();
+            environment.define(&"a".to_string(), LoxObject::Number(2.0));
+            {
+                let _inner = environment.scope();
+                environment.define(&"a".to_string(), LoxObject::Number(3.0));
+            }
+            assert_eq!(environment.get(&name("a")).unwrap(), LoxObject::Number(2.0));
+        }
+        assert_eq!(environment.get(&name("a")).unwrap(), LoxObject::Number(1.0));
+    }
+
+    #[test]
+    fn function_scope_guard_exits_every_scope_inside_it() {
+        let environment = EnvironmentManager::new();
+        environment.define(&"a".to_string(), LoxObject::Number(1.0));
+        {
+            let _function = environment.function_scope();
+            // Left open, e.g. by a block that errored before the guard existed.
+            environment.enter_new_scope();
+            environment.define(&"a".to_string(), LoxObject::Number(2.0));
+        }
+        assert_eq!(environment.get(&name("a")).unwrap(), LoxObject::Number(1.0));
+    }
+
+    #[test]
+    fn undefined_variables_are_errors() {
+        let environment = EnvironmentManager::new();
+        environment.enter_new_scope();
+        assert!(environment.get(&name("missing")).is_err());
+        assert!(environment
+            .assign(&name("missing"), LoxObject::Number(1.0))
+            .is_err());
+    }
+}
@@ -1,171 +1,614 @@
-use lazy_static::lazy_static;
-use std::sync::{atomic::AtomicBool, Arc, RwLock};
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc, RwLock,
+    },
+};
 
-use crate::{exceptions::RuntimeException, token::Token};
+use crate::{
+    exceptions::{RuntimeException, TraceFrame},
+    messages::{Message, MessageCatalog},
+    shared_traits::Substring,
+    token::Token,
+};
 
+/// How many diagnostics `print_all` shows by default.
+pub const DEFAULT_MAX_ERRORS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+            Severity::Note => "Note",
+        })
+    }
+}
+
+/// A related location attached to a diagnostic, e.g. "variable first declared
+/// here".
 #[derive(Debug, Clone)]
+pub struct RelatedNote {
+    pub line: usize,
+    pub message: Message,
+    /// The token the note is about, if there's one to point at.
+    pub token: Option<Token>,
+    /// The source line the token is on, filled in like the diagnostic's.
+    pub snippet: Option<Snippet>,
+}
+
+/// The line of source a diagnostic points into, and which of its columns to
+/// underline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snippet {
+    pub text: String,
+    /// The first underlined column, counting from 1.
+    pub column: usize,
+    pub width: usize,
+}
+
+impl Snippet {
+    /// The snippet for the token, if the source still has the token's lexeme
+    /// where the token says it is. (It won't if the token came from another
+    /// source, like the prelude or an earlier REPL line.)
+    fn of(source: &str, token: &Token) -> Option<Self> {
+        if token.column == 0 || source.substring(token.span.start, token.span.end) != token.lexeme {
+            return None;
+        }
+
+        // A token from another source could claim a column past its start.
+        let line_start = (token.span.start + 1).checked_sub(token.column)?;
+        let text: String = source
+            .chars()
+            .skip(line_start)
+            .take_while(|c| *c != '\n')
+            .collect();
+        // A lexeme running onto later lines (a multi-line string) is
+        // underlined to the end of its first line.
+        let width = token
+            .lexeme
+            .chars()
+            .take_while(|c| *c != '\n')
+            .count()
+            .max(1);
+        Some(Self {
+            text,
+            column: token.column,
+            width,
+        })
+    }
+
+    /// The line and, under it, the carets. Tabs before the carets are kept,
+    /// so the carets line up however wide tabs are shown.
+    fn render(&self) -> Vec<String> {
+        let padding: String = self
+            .text
+            .chars()
+            .take(self.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        vec![
+            format!("    {}", self.text),
+            format!("    {}{}", padding, "^".repeat(self.width)),
+        ]
+    }
+}
 
-struct Error {
-    line: usize,
-    _where: String,
-    msg: String,
+/// A single diagnostic. The scanner, parser, and interpreter all build these
+/// and `emit` them to an `ErrorManager`:
+///
+/// ```ignore
+/// Diagnostic::warning(line, messages::UNUSED_VARIABLE.with(&[&name.lexeme]))
+///     .at(format!("at \"{}\"", name.lexeme))
+///     .note(declared_line, messages::DECLARED_HERE)
+///     .emit(&errors);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: usize,
+    /// The file the line is in, if it isn't the main script (i.e. it was
+    /// loaded with `dofile`).
+    pub file: Option<String>,
+    pub _where: String,
+    pub message: Message,
+    pub notes: Vec<RelatedNote>,
+    pub trace: Vec<TraceFrame>,
+    /// The token the diagnostic is about, if there's one to point at.
+    pub token: Option<Token>,
+    /// The source line the token is on, filled in when the diagnostic is
+    /// reported.
+    pub snippet: Option<Snippet>,
 }
 
+impl Diagnostic {
+    pub fn new(severity: Severity, line: usize, message: impl Into<Message>) -> Self {
+        Self {
+            severity,
+            line,
+            file: None,
+            _where: "".to_string(),
+            message: message.into(),
+            notes: vec![],
+            trace: vec![],
+            token: None,
+            snippet: None,
+        }
+    }
+
+    pub fn error(line: usize, message: impl Into<Message>) -> Self {
+        Self::new(Severity::Error, line, message)
+    }
+
+    pub fn warning(line: usize, message: impl Into<Message>) -> Self {
+        Self::new(Severity::Warning, line, message)
+    }
+
+    /// Where on the line the diagnostic applies (e.g. `at "foo"`).
+    pub fn at(mut self, _where: impl Into<String>) -> Self {
+        self._where = _where.into();
+        self
+    }
+
+    /// Points the diagnostic at the token, so it's printed with the token's
+    /// source line underneath and the token underlined.
+    pub fn pointing_at(mut self, token: &Token) -> Self {
+        self.token = Some(token.clone());
+        self
+    }
+
+    /// The file the diagnostic's line is in.
+    pub fn in_file(mut self, file: Option<String>) -> Self {
+        self.file = file;
+        self
+    }
+
+    /// The `[line N]` prefix the diagnostic is printed with.
+    pub fn location(&self) -> String {
+        match &self.file {
+            Some(file) => format!("[line {} in {}]", self.line, file),
+            None => format!("[line {}]", self.line),
+        }
+    }
+
+    /// Attaches a related location to the diagnostic.
+    pub fn note(mut self, line: usize, message: impl Into<Message>) -> Self {
+        self.notes.push(RelatedNote {
+            line,
+            message: message.into(),
+            token: None,
+            snippet: None,
+        });
+        self
+    }
+
+    /// Attaches a related location to the diagnostic, underlining the token
+    /// like the diagnostic's own.
+    pub fn note_at(mut self, token: &Token, message: impl Into<Message>) -> Self {
+        self.notes.push(RelatedNote {
+            line: token.line,
+            message: message.into(),
+            token: Some(token.clone()),
+            snippet: None,
+        });
+        self
+    }
+
+    pub fn with_trace(mut self, trace: Vec<TraceFrame>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    pub fn emit(self, errors: &ErrorManager) {
+        errors.emit(self);
+    }
+}
+
+/// Collects (or immediately prints) the diagnostics produced while scanning,
+/// parsing, and interpreting. Each session owns its own manager, so several
+/// interpreters can run in one process without sharing error state.
 #[derive(Debug)]
-struct ErrorManager {
-    errors: Arc<RwLock<Vec<Error>>>,
+pub struct ErrorManager {
+    errors: Arc<RwLock<Vec<Diagnostic>>>,
+    /// Lines of the source most recently scanned on which warnings are
+    /// suppressed by a `// lox: ignore-next-line` comment on the line before.
+    /// They only apply to that source: scanning another one, or resetting the
+    /// errors, clears them.
+    suppressed_lines: RwLock<HashSet<usize>>,
+    /// The source most recently scanned, which diagnostics' snippets are
+    /// taken from.
+    source: RwLock<Arc<str>>,
+    /// Overrides for the built-in message templates.
+    catalog: RwLock<MessageCatalog>,
     immediate: AtomicBool,
+    full_trace: AtomicBool,
+    /// The most diagnostics `print_all` shows before summarizing the rest.
+    max_errors: AtomicUsize,
     had_errors: AtomicBool,
     had_runtime_error: AtomicBool,
 }
 
 impl ErrorManager {
+    /// Create a manager that collects errors until `print_all` is called.
     pub fn new() -> Self {
         Self {
             errors: Arc::new(RwLock::new(Vec::new())),
+            suppressed_lines: RwLock::new(HashSet::new()),
+            source: RwLock::new("".into()),
+            catalog: RwLock::new(MessageCatalog::new()),
             immediate: AtomicBool::new(false),
+            full_trace: AtomicBool::new(false),
+            max_errors: AtomicUsize::new(DEFAULT_MAX_ERRORS),
             had_errors: AtomicBool::new(false),
             had_runtime_error: AtomicBool::new(false),
         }
     }
 
+    /// Whether errors are printed as soon as they're reported (e.g. in the
+    /// REPL), rather than being collected.
     pub fn set_immediate(&self, immediate: bool) {
         self.immediate
             .store(immediate, std::sync::atomic::Ordering::SeqCst);
     }
 
-    pub fn error(&self, line: usize, message: String) {
-        self.had_errors
-            .store(true, std::sync::atomic::Ordering::SeqCst);
-        if self.immediate.load(std::sync::atomic::Ordering::SeqCst) {
-            return Self::display_error(line, "".to_string(), message);
+    pub fn set_full_trace(&self, full_trace: bool) {
+        self.full_trace
+            .store(full_trace, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Caps how many diagnostics `print_all` shows (0 shows all of them).
+    pub fn set_max_errors(&self, max_errors: usize) {
+        self.max_errors
+            .store(max_errors, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Replaces the built-in message templates with the catalog's, e.g. to
+    /// show translated diagnostics.
+    pub fn set_catalog(&self, catalog: MessageCatalog) {
+        if let Ok(mut writable) = self.catalog.try_write() {
+            *writable = catalog;
         }
+    }
 
-        if let Ok(mut writable) = self.errors.try_write() {
-            writable.push(Error {
-                line,
-                _where: "".to_string(),
-                msg: message,
-            })
+    /// The message's text, using the catalog's template if it has one.
+    pub fn render_message(&self, message: &Message) -> String {
+        match self.catalog.try_read() {
+            Ok(catalog) => catalog.render(message),
+            Err(_) => message.text(),
         }
     }
 
-    pub fn runtime_error(&self, token: Token, message: String) {
-        self.had_runtime_error
-            .store(true, std::sync::atomic::Ordering::SeqCst);
-        if self.immediate.load(std::sync::atomic::Ordering::SeqCst) {
-            return Self::display_error(
-                token.line,
-                "RuntimeError(".to_string() + token.lexeme.as_str() + ")",
-                message,
-            );
+    /// The source that diagnostics reported from now on point into.
+    pub fn set_source(&self, source: Arc<str>) {
+        if let Ok(mut writable) = self.source.try_write() {
+            *writable = source;
         }
+        self.clear_suppressions();
+    }
 
-        if let Ok(mut writable) = self.errors.try_write() {
-            writable.push(Error {
-                line: token.line,
-                _where: "(".to_string() + token.lexeme.as_str() + ")",
-                msg: message,
-            })
+    /// Suppresses warnings reported on the given line.
+    pub fn suppress_line(&self, line: usize) {
+        if let Ok(mut suppressed_lines) = self.suppressed_lines.try_write() {
+            suppressed_lines.insert(line);
         }
     }
 
-    pub fn report(&self, line: usize, _where: String, message: String) {
-        self.had_errors
-            .store(true, std::sync::atomic::Ordering::SeqCst);
+    fn clear_suppressions(&self) {
+        if let Ok(mut suppressed_lines) = self.suppressed_lines.try_write() {
+            suppressed_lines.clear();
+        }
+    }
+
+    /// Reports a static (scan or parse time) diagnostic.
+    pub fn emit(&self, diagnostic: Diagnostic) {
+        if diagnostic.severity == Severity::Error {
+            self.had_errors
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+        self.record(diagnostic);
+    }
+
+    /// Records (or immediately prints) a diagnostic. Warnings and notes on a
+    /// suppressed line are dropped.
+    fn record(&self, mut diagnostic: Diagnostic) {
+        // Diagnostics from included files come with their snippets, and
+        // runtime errors in them have none (their source isn't kept).
+        if diagnostic.snippet.is_none() && diagnostic.file.is_none() {
+            if let Ok(source) = self.source.try_read() {
+                if let Some(token) = &diagnostic.token {
+                    diagnostic.snippet = Snippet::of(&source, token);
+                }
+                for note in &mut diagnostic.notes {
+                    if let Some(token) = &note.token {
+                        note.snippet = Snippet::of(&source, token);
+                    }
+                }
+            }
+        }
+
+        // Included files' suppressions were applied by their own manager.
+        if diagnostic.severity != Severity::Error && diagnostic.file.is_none() {
+            if let Ok(suppressed_lines) = self.suppressed_lines.try_read() {
+                if suppressed_lines.contains(&diagnostic.line) {
+                    return;
+                }
+            }
+        }
+
         if self.immediate.load(std::sync::atomic::Ordering::SeqCst) {
-            return Self::display_error(line, _where, message);
+            return self.display_diagnostic(&diagnostic);
         }
 
         if let Ok(mut writable) = self.errors.try_write() {
-            writable.push(Error {
-                line,
-                _where,
-                msg: message,
-            })
+            writable.push(diagnostic)
         }
     }
 
-    fn display_error(line: usize, _where: String, message: String) {
-        println!("[line {}] Error {}: {}", line, _where, message);
+    pub fn runtime_error(&self, error: RuntimeException) {
+        let error = match error {
+            RuntimeException::RuntimeError(error) => error,
+            RuntimeException::ReturnException(_) => {
+                // We should be catching all of these.
+                panic!("Unhandled return exception.")
+            }
+        };
+
+        self.had_runtime_error
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        let _where = if self.immediate.load(std::sync::atomic::Ordering::SeqCst) {
+            "RuntimeError(".to_string() + error.token.lexeme.as_str() + ")"
+        } else {
+            "(".to_string() + error.token.lexeme.as_str() + ")"
+        };
+
+        self.record(
+            Diagnostic::error(error.token.line, error.message)
+                .pointing_at(&error.token)
+                .in_file(error.file)
+                .at(_where)
+                .with_trace(error.trace),
+        );
     }
 
-    pub fn reset_had_errors(&self) {
+    // The diagnostic with its notes and trace. When printing, it's followed
+    // by its snippet.
+    fn render_diagnostic(&self, diagnostic: &Diagnostic, snippet: bool) -> String {
+        let full_trace = self.full_trace.load(std::sync::atomic::Ordering::SeqCst);
+        let mut lines = vec![format!(
+            "{} {}[{}] {}: {}",
+            diagnostic.location(),
+            diagnostic.severity.to_string(),
+            diagnostic.message.code(),
+            diagnostic._where,
+            self.render_message(&diagnostic.message)
+        )];
+        if let (true, Some(snippet)) = (snippet, &diagnostic.snippet) {
+            lines.append(&mut snippet.render());
+        }
+        for note in &diagnostic.notes {
+            lines.push(format!(
+                "    [line {}] Note: {}",
+                note.line,
+                self.render_message(&note.message)
+            ));
+            if let (true, Some(snippet)) = (snippet, &note.snippet) {
+                for line in snippet.render() {
+                    lines.push(format!("    {}", line));
+                }
+            }
+        }
+        lines.append(&mut render_trace(&diagnostic.trace, full_trace));
+        lines.join("\n")
+    }
+
+    fn display_diagnostic(&self, diagnostic: &Diagnostic) {
+        println!("{}", self.render_diagnostic(diagnostic, true));
+    }
+
+    /// Resets the error flags (and any suppressed lines), so a REPL can keep
+    /// going after a failed line.
+    pub fn reset_errors(&self) {
+        self.clear_suppressions();
         self.had_errors
             .store(false, std::sync::atomic::Ordering::SeqCst);
         self.had_runtime_error
             .store(false, std::sync::atomic::Ordering::SeqCst);
     }
 
-    pub fn print_all(&self) {
+    pub fn has_errors(&self) -> bool {
+        self.had_errors.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    pub fn has_runtime_error(&self) -> bool {
+        self.had_runtime_error
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Renders every collected diagnostic (and its stack trace) without
+    /// printing it, for hosts that display diagnostics themselves. Snippets
+    /// are left out; hosts can show them from `diagnostics`.
+    pub fn messages(&self) -> Vec<String> {
+        let mut messages = vec![];
         if let Ok(readable) = self.errors.try_read() {
-            if readable.is_empty() {
-                return;
-            }
-            println!("Found {:?} errors:", readable.len());
-            for Error { line, _where, msg } in readable.iter() {
-                Self::display_error(*line, _where.clone(), msg.clone());
+            for diagnostic in readable.iter() {
+                messages.push(self.render_diagnostic(diagnostic, false));
             }
         }
+
+        messages
+    }
+
+    /// The collected diagnostics, in the order they were reported.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self.errors.try_read() {
+            Ok(readable) => readable.clone(),
+            Err(_) => vec![],
+        }
     }
-}
 
-unsafe impl Sync for ErrorManager {}
+    /// Removes all of the collected diagnostics.
+    pub fn clear(&self) {
+        if let Ok(mut writable) = self.errors.try_write() {
+            writable.clear();
+        }
+        self.reset_errors();
+    }
 
-lazy_static! {
-    /// This is an example for using doc comment attributes
-    static ref ERROR_MANAGER: ErrorManager = {
-        ErrorManager::new()
-    };
-}
+    /// Prints the collected diagnostics ordered by file and line, with
+    /// diagnostics on the same line grouped under a single `[line N]` prefix. At most
+    /// `max_errors` are shown, followed by a count of the ones left out.
+    pub fn print_all(&self) {
+        if self.immediate.load(std::sync::atomic::Ordering::SeqCst) {
+            return;
+        }
 
-pub fn initialize_immediate() {
-    ERROR_MANAGER.set_immediate(true);
-}
+        for line in self.render_all() {
+            println!("{}", line);
+        }
+    }
 
-pub fn initialize_managed() {}
+    // The lines `print_all` prints.
+    fn render_all(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if let Ok(readable) = self.errors.try_read() {
+            if readable.is_empty() {
+                return lines;
+            }
+            let error_count = readable
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .count();
+            if error_count > 0 {
+                lines.push(format!("Found {:?} errors:", error_count));
+            } else {
+                lines.push(format!("Found {:?} warnings:", readable.len()));
+            }
 
-pub fn error(line: usize, message: impl Into<String>) {
-    ERROR_MANAGER.error(line, message.into());
-}
+            // A stable sort, so diagnostics on one line keep their order. The
+            // main script (with no file) sorts before any included files.
+            let mut sorted: Vec<&Diagnostic> = readable.iter().collect();
+            sorted.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
 
-pub fn runtime_error(error: RuntimeException) {
-    match error {
-        RuntimeException::RuntimeError(error) => {
-            ERROR_MANAGER.runtime_error(error.token, error.message);
-        }
-        RuntimeException::ReturnException(_) => {
-            // We should be catching all of these.
-            panic!("Unhandled return exception.")
+            let max_errors = match self.max_errors.load(std::sync::atomic::Ordering::SeqCst) {
+                0 => sorted.len(),
+                max_errors => max_errors.min(sorted.len()),
+            };
+
+            let mut previous_location = None;
+            for diagnostic in &sorted[..max_errors] {
+                let rendered = self.render_diagnostic(diagnostic, true);
+                let location = diagnostic.location();
+                if previous_location.as_ref() == Some(&location) {
+                    // Blank out the "[line N]" prefix we've already printed.
+                    let prefix_len = location.len();
+                    lines.push(format!(
+                        "{}{}",
+                        " ".repeat(prefix_len),
+                        &rendered[prefix_len..]
+                    ));
+                } else {
+                    lines.push(rendered);
+                }
+                previous_location = Some(location);
+            }
+
+            // Errors and warnings left out are counted separately, so the
+            // count says what's missing.
+            let left_out = &sorted[max_errors..];
+            let errors_left_out = left_out
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .count();
+            let counts: Vec<String> = [
+                (errors_left_out, "errors"),
+                (left_out.len() - errors_left_out, "warnings"),
+            ]
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, kind)| format!("{} more {}", count, kind))
+            .collect();
+            if !counts.is_empty() {
+                lines.push(format!("...and {}", counts.join(" and ")));
+            }
         }
+        lines
     }
 }
 
-pub fn report(line: usize, _where: impl Into<String>, message: impl Into<String>) {
-    ERROR_MANAGER.report(line, _where.into(), message.into());
+impl Default for ErrorManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-pub fn print_all() {
-    if !ERROR_MANAGER
-        .immediate
-        .load(std::sync::atomic::Ordering::SeqCst)
-    {
-        ERROR_MANAGER.print_all();
+/// Renders a stack trace, one line per frame. Unless `full_trace` is set, runs
+/// of identical frames (as produced by deep recursion) are collapsed into a
+/// single "... previous frame repeated N times ..." line.
+fn render_trace(trace: &[TraceFrame], full_trace: bool) -> Vec<String> {
+    let mut lines = vec![];
+    let mut idx = 0;
+    while idx < trace.len() {
+        let frame = &trace[idx];
+        lines.push(match &frame.file {
+            Some(file) => format!(
+                "    in {} called on line {} in {}",
+                frame.function, frame.line, file
+            ),
+            None => format!("    in {} called on line {}", frame.function, frame.line),
+        });
+
+        // Count how many times this exact frame repeats directly after itself.
+        let mut repeated = 0;
+        while idx + repeated + 1 < trace.len() && trace[idx + repeated + 1] == *frame {
+            repeated += 1;
+        }
+
+        if full_trace || repeated == 0 {
+            idx += 1;
+        } else {
+            lines.push(format!(
+                "    ... previous frame repeated {} times ...",
+                repeated
+            ));
+            idx += repeated + 1;
+        }
     }
-}
 
-pub fn reset_errors() {
-    ERROR_MANAGER.reset_had_errors();
+    lines
 }
 
-pub fn has_errors() -> bool {
-    ERROR_MANAGER
-        .had_errors
-        .load(std::sync::atomic::Ordering::SeqCst)
-}
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostic, ErrorManager};
+    use crate::messages;
+
+    #[test]
+    fn counts_left_out_errors_and_warnings_separately() {
+        let errors = ErrorManager::new();
+        errors.set_max_errors(1);
+        Diagnostic::error(1, messages::EXPECT_EXPRESSION).emit(&errors);
+        Diagnostic::warning(2, messages::MISPLACED_PRAGMA.with(&["lox: strict"])).emit(&errors);
+        Diagnostic::error(3, messages::EXPECT_EXPRESSION).emit(&errors);
+        Diagnostic::warning(4, messages::MISPLACED_PRAGMA.with(&["lox: strict"])).emit(&errors);
+        assert_eq!(
+            errors.render_all().last().unwrap(),
+            "...and 1 more errors and 2 more warnings"
+        );
 
-pub fn has_runtime_error() -> bool {
-    ERROR_MANAGER
-        .had_runtime_error
-        .load(std::sync::atomic::Ordering::SeqCst)
+        // Only warnings were left out.
+        errors.set_max_errors(3);
+        assert_eq!(
+            errors.render_all().last().unwrap(),
+            "...and 1 more warnings"
+        );
+    }
 }
@@ -1,17 +1,38 @@
-use crate::{object::LoxObject, token::Token};
+use crate::{messages::Message, object::LoxObject, token::Token};
+
+/// A single call frame that a runtime error unwound through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// The display name of the function that was called (e.g. "<fn fib>").
+    pub function: String,
+    /// The line of the call site.
+    pub line: usize,
+    /// The file of the call site, if it was loaded with `dofile`.
+    pub file: Option<String>,
+}
 
 #[derive(Debug, Clone)]
 pub struct RuntimeError {
     pub token: Token,
-    pub message: String,
+    pub message: Message,
+    /// The call frames the error unwound through, innermost first.
+    pub trace: Vec<TraceFrame>,
+    /// The file the error was raised in, if it was loaded with `dofile`.
+    pub file: Option<String>,
+    /// Whether `file` has been filled in yet. That happens at the innermost
+    /// statement the error unwinds through, since tokens don't know their file.
+    pub located: bool,
 }
 
 impl RuntimeError {
-    pub fn new(token: Token, message: impl Into<String>) -> RuntimeException {
-        RuntimeException::RuntimeError(Self {
+    pub fn new(token: Token, message: impl Into<Message>) -> RuntimeException {
+        RuntimeException::RuntimeError(Box::new(Self {
             token,
             message: message.into(),
-        })
+            trace: vec![],
+            file: None,
+            located: false,
+        }))
     }
 }
 
@@ -28,6 +49,7 @@ impl ReturnException {
 
 #[derive(Debug, Clone)]
 pub enum RuntimeException {
-    RuntimeError(RuntimeError),
+    // Boxed, since errors carry a lot more than the values being returned.
+    RuntimeError(Box<RuntimeError>),
     ReturnException(ReturnException),
 }
@@ -1,12 +1,32 @@
-use std::{fmt::Debug, rc::Rc};
+use std::rc::Rc;
 
 use crate::{
-    shared_traits::Named,
+    resolver::ExprId,
+    stmt::{FunStmt, Statement},
     token::{Token, TokenLiteral},
 };
-use downcast::{downcast, Any};
 
-pub trait Expr: Any + Debug + Named {}
+/// An expression. Each kind's fields are in a struct of its own, which is
+/// what visitors get, so `accept` dispatches with a plain match.
+#[derive(Debug)]
+pub enum Expr {
+    Binary(Binary),
+    Grouping(Grouping),
+    Literal(Literal),
+    Unary(Unary),
+    Variable(Variable),
+    Assign(Assign),
+    Logical(Logical),
+    Call(Call),
+    Function(Function),
+    List(List),
+    Map(Map),
+    Index(Index),
+    IndexAssign(IndexAssign),
+    CompoundAssign(CompoundAssign),
+    Get(Get),
+    Set(Set),
+}
 
 pub trait ExprVisitor<T> {
     fn visit_binary_expr(&self, expr: &Binary) -> T;
@@ -17,31 +37,83 @@ pub trait ExprVisitor<T> {
     fn visit_assign_expr(&self, expr: &Assign) -> T;
     fn visit_logical_expr(&self, expr: &Logical) -> T;
     fn visit_call_expr(&self, expr: &Call) -> T;
+    fn visit_function_expr(&self, expr: &Function) -> T;
+    fn visit_list_expr(&self, expr: &List) -> T;
+    fn visit_map_expr(&self, expr: &Map) -> T;
+    fn visit_index_expr(&self, expr: &Index) -> T;
+    fn visit_index_assign_expr(&self, expr: &IndexAssign) -> T;
+    fn visit_compound_assign_expr(&self, expr: &CompoundAssign) -> T;
+    fn visit_get_expr(&self, expr: &Get) -> T;
+    fn visit_set_expr(&self, expr: &Set) -> T;
 }
 
 pub trait VisitorTarget {
     fn accept<T>(&self, visitor: impl ExprVisitor<T>) -> T;
 }
 
-impl VisitorTarget for Rc<dyn Expr> {
+impl VisitorTarget for Rc<Expr> {
     fn accept<T>(&self, visitor: impl ExprVisitor<T>) -> T {
-        match self.name() {
-            "Binary" => visitor.visit_binary_expr(self.downcast_ref::<Binary>().unwrap()),
-            "Grouping" => visitor.visit_grouping_expr(self.downcast_ref::<Grouping>().unwrap()),
-            "Literal" => visitor.visit_literal_expr(self.downcast_ref::<Literal>().unwrap()),
-            "Logical" => visitor.visit_logical_expr(self.downcast_ref::<Logical>().unwrap()),
-            "Unary" => visitor.visit_unary_expr(self.downcast_ref::<Unary>().unwrap()),
-            "Variable" => visitor.visit_variable_expr(self.downcast_ref::<Variable>().unwrap()),
-            "Assign" => visitor.visit_assign_expr(self.downcast_ref::<Assign>().unwrap()),
-            "Call" => visitor.visit_call_expr(self.downcast_ref::<Call>().unwrap()),
-            _ => unreachable!(),
+        match &**self {
+            Expr::Binary(expr) => visitor.visit_binary_expr(expr),
+            Expr::Grouping(expr) => visitor.visit_grouping_expr(expr),
+            Expr::Literal(expr) => visitor.visit_literal_expr(expr),
+            Expr::Unary(expr) => visitor.visit_unary_expr(expr),
+            Expr::Variable(expr) => visitor.visit_variable_expr(expr),
+            Expr::Assign(expr) => visitor.visit_assign_expr(expr),
+            Expr::Logical(expr) => visitor.visit_logical_expr(expr),
+            Expr::Call(expr) => visitor.visit_call_expr(expr),
+            Expr::Function(expr) => visitor.visit_function_expr(expr),
+            Expr::List(expr) => visitor.visit_list_expr(expr),
+            Expr::Map(expr) => visitor.visit_map_expr(expr),
+            Expr::Index(expr) => visitor.visit_index_expr(expr),
+            Expr::IndexAssign(expr) => visitor.visit_index_assign_expr(expr),
+            Expr::CompoundAssign(expr) => visitor.visit_compound_assign_expr(expr),
+            Expr::Get(expr) => visitor.visit_get_expr(expr),
+            Expr::Set(expr) => visitor.visit_set_expr(expr),
         }
     }
 }
 
-downcast!(dyn Expr);
+pub type Expression = Rc<Expr>;
+
+/// Something an assignment can store into: a variable, an element of a list
+/// or map, or a property. Every kind of assignment (`=` and the compound operators like
+/// `+=`) takes the same targets.
+#[derive(Debug, Clone, Copy)]
+pub enum AssignTarget<'a> {
+    Variable(&'a Variable),
+    Index(&'a Index),
+    Property(&'a Get),
+}
 
-pub type Expression = Rc<dyn Expr>;
+impl Expr {
+    /// The expression as the left-hand side of an assignment, or `None` if it
+    /// can't be assigned to.
+    pub fn assign_target(&self) -> Option<AssignTarget<'_>> {
+        match self {
+            Expr::Variable(variable) => Some(AssignTarget::Variable(variable)),
+            Expr::Index(index) => Some(AssignTarget::Index(index)),
+            Expr::Get(get) => Some(AssignTarget::Property(get)),
+            _ => None,
+        }
+    }
+}
+
+impl AssignTarget<'_> {
+    /// A plain assignment of the value to the target.
+    pub fn assign(&self, value: Expression) -> Expression {
+        match self {
+            AssignTarget::Variable(variable) => Assign::new(variable.name.clone(), value),
+            AssignTarget::Index(index) => IndexAssign::new(
+                index.object.clone(),
+                index.bracket.clone(),
+                index.index.clone(),
+                value,
+            ),
+            AssignTarget::Property(get) => Set::new(get.object.clone(), get.name.clone(), value),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Binary {
@@ -52,17 +124,11 @@ pub struct Binary {
 
 impl Binary {
     pub fn new(left: Expression, operator: Token, right: Expression) -> Expression {
-        Rc::new(Binary {
+        Rc::new(Expr::Binary(Binary {
             left,
             operator,
             right,
-        })
-    }
-}
-impl Expr for Binary {}
-impl Named for Binary {
-    fn name(&self) -> &'static str {
-        "Binary"
+        }))
     }
 }
 
@@ -72,13 +138,7 @@ pub struct Grouping {
 }
 impl Grouping {
     pub fn new(expression: Expression) -> Expression {
-        Rc::new(Grouping { expression })
-    }
-}
-impl Expr for Grouping {}
-impl Named for Grouping {
-    fn name(&self) -> &'static str {
-        "Grouping"
+        Rc::new(Expr::Grouping(Grouping { expression }))
     }
 }
 
@@ -88,13 +148,7 @@ pub struct Literal {
 }
 impl Literal {
     pub fn new(value: TokenLiteral) -> Expression {
-        Rc::new(Literal { value })
-    }
-}
-impl Expr for Literal {}
-impl Named for Literal {
-    fn name(&self) -> &'static str {
-        "Literal"
+        Rc::new(Expr::Literal(Literal { value }))
     }
 }
 
@@ -105,46 +159,37 @@ pub struct Unary {
 }
 impl Unary {
     pub fn new(operator: Token, right: Expression) -> Expression {
-        Rc::new(Unary { operator, right })
-    }
-}
-impl Expr for Unary {}
-impl Named for Unary {
-    fn name(&self) -> &'static str {
-        "Unary"
+        Rc::new(Expr::Unary(Unary { operator, right }))
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Variable {
+    pub id: ExprId,
     pub name: Token,
 }
 impl Variable {
     pub fn new(name: Token) -> Expression {
-        Rc::new(Variable { name })
-    }
-}
-impl Expr for Variable {}
-impl Named for Variable {
-    fn name(&self) -> &'static str {
-        "Variable"
+        Rc::new(Expr::Variable(Variable {
+            id: ExprId::next(),
+            name,
+        }))
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Assign {
+    pub id: ExprId,
     pub name: Token,
     pub value: Expression,
 }
 impl Assign {
     pub fn new(name: Token, value: Expression) -> Expression {
-        Rc::new(Assign { name, value })
-    }
-}
-impl Expr for Assign {}
-impl Named for Assign {
-    fn name(&self) -> &'static str {
-        "Assign"
+        Rc::new(Expr::Assign(Assign {
+            id: ExprId::next(),
+            name,
+            value,
+        }))
     }
 }
 
@@ -156,22 +201,17 @@ pub struct Logical {
 }
 impl Logical {
     pub fn new(left: Expression, operator: Token, right: Expression) -> Expression {
-        Rc::new(Logical {
+        Rc::new(Expr::Logical(Logical {
             left,
             operator,
             right,
-        })
-    }
-}
-impl Expr for Logical {}
-impl Named for Logical {
-    fn name(&self) -> &'static str {
-        "Logical"
+        }))
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Call {
+    pub id: ExprId,
     pub callee: Expression,
     pub paren: Token,
     pub arguments: Vec<Expression>,
@@ -179,16 +219,187 @@ pub struct Call {
 
 impl Call {
     pub fn new(callee: Expression, paren: Token, arguments: Vec<Expression>) -> Expression {
-        Rc::new(Call {
+        Rc::new(Expr::Call(Call {
+            id: ExprId::next(),
             callee,
             paren,
             arguments,
-        })
+        }))
+    }
+}
+
+/// A function without a name (a lambda), e.g. `fun (a, b) { return a + b; }`.
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// The `fun` keyword.
+    pub keyword: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Statement>,
+}
+
+impl Function {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(keyword: Token, params: Vec<Token>, body: Vec<Statement>) -> Expression {
+        Rc::new(Expr::Function(Function {
+            keyword,
+            params,
+            body,
+        }))
+    }
+
+    /// The lambda as a function declaration, named "anonymous" (which isn't a
+    /// name any code can refer to it by), so it can be called and reported
+    /// on like any other function.
+    pub fn declaration(&self) -> FunStmt {
+        FunStmt {
+            name: Token {
+                lexeme: "anonymous".to_string(),
+                ..self.keyword.clone()
+            },
+            params: self.params.clone(),
+            body: self.body.clone(),
+        }
+    }
+}
+
+/// A list literal, e.g. `[1, 2, 3]`.
+#[derive(Debug, Clone)]
+pub struct List {
+    /// The opening bracket.
+    pub bracket: Token,
+    pub elements: Vec<Expression>,
+}
+
+impl List {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(bracket: Token, elements: Vec<Expression>) -> Expression {
+        Rc::new(Expr::List(List { bracket, elements }))
+    }
+}
+
+/// A map literal, e.g. `{"a": 1, "b": 2}`.
+#[derive(Debug, Clone)]
+pub struct Map {
+    /// The opening brace.
+    pub brace: Token,
+    /// The keys and values, in the order they're written.
+    pub entries: Vec<(Expression, Expression)>,
+}
+
+impl Map {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(brace: Token, entries: Vec<(Expression, Expression)>) -> Expression {
+        Rc::new(Expr::Map(Map { brace, entries }))
     }
 }
-impl Expr for Call {}
-impl Named for Call {
-    fn name(&self) -> &'static str {
-        "Call"
+
+/// Getting an element, e.g. `list[0]` or `map["key"]`.
+#[derive(Debug, Clone)]
+pub struct Index {
+    pub object: Expression,
+    /// The closing bracket, which errors are reported at.
+    pub bracket: Token,
+    pub index: Expression,
+}
+
+impl Index {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(object: Expression, bracket: Token, index: Expression) -> Expression {
+        Rc::new(Expr::Index(Index {
+            object,
+            bracket,
+            index,
+        }))
+    }
+}
+
+/// Setting an element, e.g. `list[0] = value`.
+#[derive(Debug, Clone)]
+pub struct IndexAssign {
+    pub object: Expression,
+    /// The closing bracket, which errors are reported at.
+    pub bracket: Token,
+    pub index: Expression,
+    pub value: Expression,
+}
+
+impl IndexAssign {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        object: Expression,
+        bracket: Token,
+        index: Expression,
+        value: Expression,
+    ) -> Expression {
+        Rc::new(Expr::IndexAssign(IndexAssign {
+            object,
+            bracket,
+            index,
+            value,
+        }))
+    }
+}
+
+/// Getting a property, e.g. `point.x`.
+#[derive(Debug, Clone)]
+pub struct Get {
+    pub object: Expression,
+    /// The property's name, which errors are reported at.
+    pub name: Token,
+}
+
+impl Get {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(object: Expression, name: Token) -> Expression {
+        Rc::new(Expr::Get(Get { object, name }))
+    }
+}
+
+/// Setting a property, e.g. `point.x = 1`.
+#[derive(Debug, Clone)]
+pub struct Set {
+    pub object: Expression,
+    pub name: Token,
+    pub value: Expression,
+}
+
+impl Set {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(object: Expression, name: Token, value: Expression) -> Expression {
+        Rc::new(Expr::Set(Set {
+            object,
+            name,
+            value,
+        }))
+    }
+}
+
+/// Updating a target in place, e.g. `count += 1` or `list[i] *= 2`. The
+/// target's parts (a list and its index) are evaluated once, then its value is
+/// read, combined with the value, and stored back.
+#[derive(Debug, Clone)]
+pub struct CompoundAssign {
+    /// The left-hand side, which is always an `AssignTarget`.
+    pub target: Expression,
+    /// The compound operator, e.g. `+=`.
+    pub operator: Token,
+    pub value: Expression,
+}
+
+impl CompoundAssign {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(target: Expression, operator: Token, value: Expression) -> Expression {
+        Rc::new(Expr::CompoundAssign(CompoundAssign {
+            target,
+            operator,
+            value,
+        }))
+    }
+
+    /// What the assignment stores into.
+    pub fn target(&self) -> AssignTarget<'_> {
+        self.target
+            .assign_target()
+            .expect("compound assignments are only made with assignable targets")
     }
 }
@@ -1,87 +1,255 @@
-use std::{rc::Rc, sync::RwLock};
+use std::{fmt, path::PathBuf, rc::Rc, sync::RwLock};
 
 use crate::{
-    exceptions::RuntimeException,
+    capabilities::Capability,
+    environment::EnvironmentBase,
+    exceptions::{RuntimeError, RuntimeException},
     interpreter::Interpreter,
-    object::{CallableLoxObject, LoxNil, LoxObject, LoxObjectBase, PrimitiveLoxObject},
+    messages,
+    object::{CallableLoxObject, LoxObject},
     stmt::FunStmt,
+    token::Token,
 };
 
+/// The signature of a native function.
+///
+/// Natives that can block or run for a long time – waiting on I/O, sleeping,
+/// or calling back into Lox in a loop – must call `context.check()` between
+/// steps and return its error, so an interrupt or deadline can stop them.
+///
+/// Natives that touch anything outside the interpreter, like files, must ask
+/// for the capability first with `context.require`, and use the path it
+/// returns.
+pub type NativeFn = fn(&NativeContext, Vec<LoxObject>) -> Result<LoxObject, RuntimeException>;
+
+/// What a native is called with, besides its arguments.
+pub struct NativeContext<'a> {
+    pub interpreter: &'a Interpreter,
+    /// The closing paren of the call, which natives report runtime errors at.
+    pub paren: &'a Token,
+}
+
+impl NativeContext<'_> {
+    /// Errors if the script has been interrupted or has run past its
+    /// deadline.
+    pub fn check(&self) -> Result<(), RuntimeException> {
+        self.interpreter.check_limits(self.paren)
+    }
+
+    /// The path to access, if the interpreter's capabilities allow the access,
+    /// or an error saying it isn't allowed.
+    pub fn require(&self, capability: Capability, path: &str) -> Result<PathBuf, RuntimeException> {
+        match self.interpreter.capabilities.resolve(capability, path) {
+            Some(path) => Ok(path),
+            None => Err(RuntimeError::new(
+                self.paren.clone(),
+                messages::NOT_ALLOWED.with(&[capability.verb(), path]),
+            )),
+        }
+    }
+}
+
+/// How many arguments a callable takes. Functions declared in Lox take
+/// exactly as many as they have parameters; natives can take a range, or any
+/// number from a minimum up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arity {
+    pub min: usize,
+    /// The most arguments, or None if there's no most.
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    pub fn exactly(count: usize) -> Self {
+        Self {
+            min: count,
+            max: Some(count),
+        }
+    }
+
+    /// From `min` to `max` arguments, inclusive. Natives check how many they
+    /// got to know which of the optional ones were passed.
+    pub fn between(min: usize, max: usize) -> Self {
+        Self {
+            min,
+            max: Some(max),
+        }
+    }
+
+    /// Any number of arguments from `min` up.
+    pub fn at_least(min: usize) -> Self {
+        Self { min, max: None }
+    }
+
+    /// Whether a call can pass that many arguments.
+    pub fn accepts(&self, count: usize) -> bool {
+        count >= self.min && self.max.is_none_or(|max| count <= max)
+    }
+}
+
+impl From<usize> for Arity {
+    fn from(count: usize) -> Self {
+        Self::exactly(count)
+    }
+}
+
+/// As error messages show it, e.g. "2", "1 to 3", or "at least 1".
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{} to {}", self.min, max),
+            None => write!(f, "at least {}", self.min),
+        }
+    }
+}
+
+/// A native as it was defined with `Interpreter::define_native`, for
+/// listing what's available (like `:builtins` in the REPL does).
+#[derive(Debug, Clone)]
+pub struct NativeInfo {
+    pub name: String,
+    pub arity: Arity,
+    /// What it does, in a sentence.
+    pub doc: String,
+    pub function: NativeFn,
+}
+
 #[derive(Clone)]
 pub struct LoxNativeCallable {
-    pub arity: usize,
-    pub call_fun: fn(&Interpreter, Vec<LoxObject>) -> Result<LoxObject, RuntimeException>,
+    pub arity: Arity,
+    pub call_fun: NativeFn,
 }
 
 impl LoxNativeCallable {
-    pub fn new(
-        arity: usize,
-        call_fun: fn(&Interpreter, Vec<LoxObject>) -> Result<LoxObject, RuntimeException>,
-    ) -> LoxObject {
-        LoxObject(Rc::new(RwLock::new(LoxNativeCallable { arity, call_fun })))
+    pub fn new(arity: impl Into<Arity>, call_fun: NativeFn) -> LoxObject {
+        let arity = arity.into();
+        LoxObject::Callable(Rc::new(LoxNativeCallable { arity, call_fun }))
     }
 }
 
-impl std::fmt::Debug for LoxNativeCallable {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl fmt::Debug for LoxNativeCallable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("LoxNativeCallable")
             .field("arity", &self.arity)
             .finish()
     }
 }
 
-impl LoxObjectBase for LoxNativeCallable {}
-impl PrimitiveLoxObject for LoxNativeCallable {
-    fn instance_name(&self) -> &'static str {
-        "NativeCallable"
+impl fmt::Display for LoxNativeCallable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn>")
     }
 }
+
 impl CallableLoxObject for LoxNativeCallable {
-    fn arity_self(&self) -> usize {
+    fn instance_name(&self) -> &'static str {
+        "NativeCallable"
+    }
+
+    fn arity_self(&self) -> Arity {
         self.arity
     }
 
+    fn signature_self(&self) -> String {
+        "<native fn>".to_string()
+    }
+
     fn call_self(
         &self,
         interpreter: &Interpreter,
+        paren: &Token,
         arguments: Vec<LoxObject>,
     ) -> Result<LoxObject, RuntimeException> {
-        (self.call_fun)(interpreter, arguments)
+        let context = NativeContext { interpreter, paren };
+        (self.call_fun)(&context, arguments)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct LoxFunction {
     pub declaration: FunStmt,
+    /// The file the function was declared in, if it was loaded with `dofile`.
+    pub file: Option<String>,
+    /// The scopes visible where the function was declared, globals first.
+    pub closure: Vec<Rc<RwLock<EnvironmentBase>>>,
 }
 
 impl LoxFunction {
-    pub fn new(declaration: &FunStmt) -> LoxObject {
-        LoxObject(Rc::new(RwLock::new(LoxFunction {
+    pub fn new(
+        declaration: &FunStmt,
+        file: Option<String>,
+        closure: Vec<Rc<RwLock<EnvironmentBase>>>,
+    ) -> LoxObject {
+        LoxObject::Callable(Rc::new(LoxFunction {
             declaration: declaration.clone(),
-        })))
+            file,
+            closure,
+        }))
     }
 }
 
-impl LoxObjectBase for LoxFunction {}
-impl PrimitiveLoxObject for LoxFunction {
-    fn instance_name(&self) -> &'static str {
-        "Function"
+// The declaration's body can be arbitrarily large, so only show what
+// identifies the function.
+impl fmt::Debug for LoxFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params: Vec<&str> = self
+            .declaration
+            .params
+            .iter()
+            .map(|param| param.lexeme.as_str())
+            .collect();
+
+        f.debug_struct("LoxFunction")
+            .field("name", &self.declaration.name.lexeme)
+            .field("params", &params)
+            .field("line", &self.declaration.name.line)
+            .finish()
+    }
+}
+
+impl fmt::Display for LoxFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<fn {}>", self.declaration.name.lexeme)
     }
 }
 
 impl CallableLoxObject for LoxFunction {
-    fn arity_self(&self) -> usize {
-        self.declaration.params.len()
+    fn instance_name(&self) -> &'static str {
+        "Function"
+    }
+
+    fn arity_self(&self) -> Arity {
+        Arity::exactly(self.declaration.params.len())
+    }
+
+    fn signature_self(&self) -> String {
+        let params: Vec<&str> = self
+            .declaration
+            .params
+            .iter()
+            .map(|param| param.lexeme.as_str())
+            .collect();
+
+        format!(
+            "fn {}({}) declared on line {}",
+            self.declaration.name.lexeme,
+            params.join(", "),
+            self.declaration.name.line
+        )
     }
 
     fn call_self(
         &self,
         interpreter: &Interpreter,
+        paren: &Token,
         arguments: Vec<LoxObject>,
     ) -> Result<LoxObject, RuntimeException> {
-        interpreter.environment.enter_function_scope();
+        interpreter.enter_call(paren)?;
+        let scope = interpreter.environment.closure_scope(&self.closure);
         interpreter.environment.enter_new_scope();
+        // Errors in the body belong to the file the function was declared in.
+        let caller_file = interpreter.swap_file(self.file.clone());
         // This would typically be able to panic, but because we're checking the
         // arity and the arguments beforehand, we're good.
 
@@ -95,7 +263,9 @@ impl CallableLoxObject for LoxFunction {
         // Execute our function in the correct scope.
         let execution_result = interpreter.execute_block(&self.declaration.body);
         // Return to the normal environment's scope.
-        interpreter.environment.exit_function_scope();
+        drop(scope);
+        interpreter.swap_file(caller_file);
+        interpreter.exit_call();
 
         match execution_result {
             Err(RuntimeException::RuntimeError(err)) => {
@@ -107,7 +277,7 @@ impl CallableLoxObject for LoxFunction {
                 return Ok(return_exception.value);
             }
             // No return value was emitted
-            _ => Ok(LoxNil::new()),
+            _ => Ok(LoxObject::Nil),
         }
     }
 }
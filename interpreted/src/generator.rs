@@ -0,0 +1,291 @@
+/// Generates large random-but-valid Lox programs, for benchmarking and fuzzing
+/// the scanner and parser at scale. The same seed always produces the same
+/// program.
+///
+/// Generated programs also run cleanly: every variable is declared before it's
+/// used, every call matches its function's arity, loops are bounded, and
+/// functions only call functions declared before them (so there's no
+/// recursion).
+pub fn generate(statements: usize, seed: u64) -> String {
+    let mut generator = ProgramGenerator {
+        rng: Rng::new(seed),
+        scopes: vec![vec![]],
+        function_scope: None,
+        functions: vec![],
+        next_name: 0,
+        depth: 0,
+        out: String::new(),
+    };
+
+    for _ in 0..statements {
+        generator.statement();
+    }
+
+    generator.out
+}
+
+// Limits that keep generated programs reasonably shaped.
+const MAX_DEPTH: usize = 4;
+const MAX_EXPR_DEPTH: usize = 3;
+const MAX_PARAMS: usize = 4;
+const MAX_BLOCK_STATEMENTS: usize = 5;
+
+/// A small xorshift64* generator – we don't need anything better than this,
+/// but we do need it to be deterministic across platforms.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift gets stuck on zero, so mix the seed into a non-zero state.
+        Self(seed ^ 0x9E37_79B9_7F4A_7C15 | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A random number in `0.0..1.0`.
+    pub fn unit(&mut self) -> f64 {
+        // The top 53 bits, which is all an f64's mantissa can hold.
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A random number in `0..bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    /// True with a probability of one in `n`.
+    fn one_in(&mut self, n: usize) -> bool {
+        self.below(n) == 0
+    }
+}
+
+struct ProgramGenerator {
+    rng: Rng,
+    /// Variables visible at each nesting level, outermost (global) first.
+    scopes: Vec<Vec<String>>,
+    /// While generating a function body, the index of its first scope. Scopes
+    /// between the globals and this one aren't visible inside the function.
+    function_scope: Option<usize>,
+    /// Declared functions and their arities.
+    functions: Vec<(String, usize)>,
+    next_name: usize,
+    depth: usize,
+    out: String,
+}
+
+impl ProgramGenerator {
+    fn statement(&mut self) {
+        let at_top = self.depth == 0;
+        match self.rng.below(10) {
+            0..=2 => self.var_declaration(),
+            3 if at_top => self.fun_declaration(),
+            3 | 4 => self.assignment(),
+            5 if self.depth < MAX_DEPTH => self.block(),
+            6 if self.depth < MAX_DEPTH => self.if_statement(),
+            7 if self.depth < MAX_DEPTH => self.while_statement(),
+            8 => self.print_statement(),
+            _ => self.call_statement(),
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let name = self.fresh_name("v");
+        let value = self.expression(0);
+        self.line(format!("var {} = {};", name, value));
+        self.scopes.last_mut().unwrap().push(name);
+    }
+
+    fn fun_declaration(&mut self) {
+        let name = self.fresh_name("f");
+        let arity = self.rng.below(MAX_PARAMS + 1);
+        let params: Vec<String> = (0..arity).map(|_| self.fresh_name("p")).collect();
+
+        self.line(format!("fun {}({}) {{", name, params.join(", ")));
+        self.function_scope = Some(self.scopes.len());
+        self.enter_scope(params);
+        let statements = 1 + self.rng.below(MAX_BLOCK_STATEMENTS);
+        for _ in 0..statements {
+            self.statement();
+        }
+        let value = self.expression(0);
+        self.line(format!("return {};", value));
+        self.exit_scope();
+        self.function_scope = None;
+        self.line("}".to_string());
+
+        self.functions.push((name, arity));
+    }
+
+    fn assignment(&mut self) {
+        match self.pick_variable() {
+            Some(name) => {
+                let value = self.expression(0);
+                self.line(format!("{} = {};", name, value));
+            }
+            None => self.var_declaration(),
+        }
+    }
+
+    fn block(&mut self) {
+        self.line("{".to_string());
+        self.enter_scope(vec![]);
+        let statements = self.rng.below(MAX_BLOCK_STATEMENTS + 1);
+        for _ in 0..statements {
+            self.statement();
+        }
+        self.exit_scope();
+        self.line("}".to_string());
+    }
+
+    fn if_statement(&mut self) {
+        let left = self.expression(0);
+        let right = self.expression(0);
+        let operator = ["<", "<=", ">", ">=", "==", "!="][self.rng.below(6)];
+        self.line(format!("if ({} {} {}) {{", left, operator, right));
+        self.enter_scope(vec![]);
+        self.statement();
+        self.exit_scope();
+        if self.rng.one_in(2) {
+            self.line("} else {".to_string());
+            self.enter_scope(vec![]);
+            self.statement();
+            self.exit_scope();
+        }
+        self.line("}".to_string());
+    }
+
+    fn while_statement(&mut self) {
+        // Loops always count up to a small bound, so they terminate.
+        let counter = self.fresh_name("i");
+        let bound = 1 + self.rng.below(5);
+        self.line(format!("var {} = 0;", counter));
+        self.line(format!("while ({} < {}) {{", counter, bound));
+        self.enter_scope(vec![]);
+        self.statement();
+        self.line(format!("{} = {} + 1;", counter, counter));
+        self.exit_scope();
+        self.line("}".to_string());
+        // Declared after the body so the body never reassigns the counter.
+        self.scopes.last_mut().unwrap().push(counter);
+    }
+
+    fn print_statement(&mut self) {
+        let value = self.expression(0);
+        self.line(format!("print {};", value));
+    }
+
+    fn call_statement(&mut self) {
+        match self.call() {
+            Some(call) => self.line(format!("{};", call)),
+            None => self.print_statement(),
+        }
+    }
+
+    /// A numeric expression built from literals, variables, and calls.
+    fn expression(&mut self, depth: usize) -> String {
+        if depth >= MAX_EXPR_DEPTH || self.rng.one_in(3) {
+            return self.operand();
+        }
+
+        match self.rng.below(6) {
+            0 => format!("({})", self.expression(depth + 1)),
+            1 => format!("-{}", self.operand()),
+            _ => {
+                let operator = ["+", "-", "*", "/"][self.rng.below(4)];
+                let left = self.expression(depth + 1);
+                let right = self.expression(depth + 1);
+                format!("{} {} {}", left, operator, right)
+            }
+        }
+    }
+
+    fn operand(&mut self) -> String {
+        match self.rng.below(4) {
+            0 => match self.pick_variable() {
+                Some(name) => name,
+                None => self.number(),
+            },
+            1 => match self.call() {
+                Some(call) => call,
+                None => self.number(),
+            },
+            _ => self.number(),
+        }
+    }
+
+    fn number(&mut self) -> String {
+        if self.rng.one_in(4) {
+            format!("{}.{}", self.rng.below(100), 1 + self.rng.below(99))
+        } else {
+            // Avoid zero so division stays finite.
+            format!("{}", 1 + self.rng.below(1000))
+        }
+    }
+
+    fn call(&mut self) -> Option<String> {
+        if self.functions.is_empty() {
+            return None;
+        }
+
+        let (name, arity) = self.functions[self.rng.below(self.functions.len())].clone();
+        let arguments: Vec<String> = (0..arity).map(|_| self.operand_without_calls()).collect();
+        Some(format!("{}({})", name, arguments.join(", ")))
+    }
+
+    // Arguments don't nest calls, so expression size stays bounded.
+    fn operand_without_calls(&mut self) -> String {
+        match self.rng.one_in(2) {
+            true => match self.pick_variable() {
+                Some(name) => name,
+                None => self.number(),
+            },
+            false => self.number(),
+        }
+    }
+
+    fn pick_variable(&mut self) -> Option<String> {
+        let visible: Vec<&String> = self
+            .scopes
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| match self.function_scope {
+                Some(function_scope) => *idx == 0 || *idx >= function_scope,
+                None => true,
+            })
+            .flat_map(|(_, scope)| scope.iter())
+            .collect();
+
+        if visible.is_empty() {
+            return None;
+        }
+
+        Some(visible[self.rng.below(visible.len())].clone())
+    }
+
+    fn fresh_name(&mut self, prefix: &str) -> String {
+        self.next_name += 1;
+        format!("{}{}", prefix, self.next_name)
+    }
+
+    fn enter_scope(&mut self, variables: Vec<String>) {
+        self.scopes.push(variables);
+        self.depth += 1;
+    }
+
+    fn exit_scope(&mut self) {
+        self.scopes.pop();
+        self.depth -= 1;
+    }
+
+    fn line(&mut self, line: String) {
+        let indent = self.depth * 2;
+        self.out.push_str(&" ".repeat(indent));
+        self.out.push_str(&line);
+        self.out.push('\n');
+    }
+}
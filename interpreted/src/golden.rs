@@ -0,0 +1,81 @@
+//! Golden file tests. Each suite is a directory under `test/golden`, holding
+//! pairs of `name.lox` and `name.expected` files. To add a case, drop in a
+//! `.lox` file and run the tests with `UPDATE_GOLDEN=1` to write its
+//! `.expected` file (then check that the output is actually right).
+
+use std::{env, fs, path::PathBuf};
+
+/// Runs `render` on every `.lox` file in the suite, and checks the result
+/// against the `.expected` file next to it. All mismatches are reported at
+/// once.
+pub fn check_suite(suite: &str, render: impl Fn(&str) -> String) {
+    check(suite, &[], env::var("UPDATE_GOLDEN").is_ok(), render);
+}
+
+/// Checks a second implementation against a suite's `.expected` files, which
+/// it never writes. The cases named in `skip` aren't run.
+pub fn check_suite_against(suite: &str, skip: &[&str], render: impl Fn(&str) -> String) {
+    check(suite, skip, false, render);
+}
+
+fn check(suite: &str, skip: &[&str], update: bool, render: impl Fn(&str) -> String) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("test")
+        .join("golden")
+        .join(suite);
+
+    let mut cases: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|error| panic!("Unable to read {}: {}", dir.display(), error))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .filter(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy();
+            !skip.contains(&name.as_ref())
+        })
+        .collect();
+    cases.sort();
+    assert!(!cases.is_empty(), "No cases in {}", dir.display());
+
+    let mut failures = vec![];
+    for case in &cases {
+        let source = fs::read_to_string(case).unwrap();
+        let actual = render(&source);
+        let expected_path = case.with_extension("expected");
+
+        if update {
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+
+        match fs::read_to_string(&expected_path) {
+            Ok(expected) if expected == actual => {}
+            Ok(expected) => failures.push(format!(
+                "{}\n--- expected\n{}--- actual\n{}",
+                case.display(),
+                expected,
+                actual
+            )),
+            Err(_) => failures.push(format!(
+                "{}\nMissing {} (run with UPDATE_GOLDEN=1 to create it)",
+                case.display(),
+                expected_path.display()
+            )),
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} golden cases failed:\n\n{}",
+        failures.len(),
+        cases.len(),
+        failures.join("\n")
+    );
+}
+
+/// Joins lines into the contents of an expected file.
+pub fn lines(lines: Vec<String>) -> String {
+    lines
+        .into_iter()
+        .map(|line| line + "\n")
+        .collect::<String>()
+}
@@ -0,0 +1,71 @@
+use crate::object::LoxObject;
+
+// 32-bit FNV-1a. 32 bits keeps every hash exactly representable as a Lox
+// number, so `hash()` can hand them to scripts unchanged.
+const FNV_OFFSET_BASIS: u32 = 0x811c9dc5;
+const FNV_PRIME: u32 = 0x01000193;
+
+/// Hashes a value the way maps do, so that values which are equal (`==`) hash
+/// the same. The hash only depends on the value, so it's the same on every
+/// run and every platform. Returns None for values that can't be hashed:
+/// functions are only equal to themselves, and their identity isn't stable
+/// between runs.
+pub fn hash(value: &LoxObject) -> Option<u32> {
+    // A tag for the type comes first, so e.g. nil and the empty string differ.
+    let bytes = match value {
+        LoxObject::Nil => vec![0],
+        LoxObject::Boolean(value) => vec![1, *value as u8],
+        LoxObject::Number(value) => {
+            // -0 == 0, so they need the same hash.
+            let number = if *value == 0.0 { 0.0 } else { *value };
+            [&[2], &number.to_bits().to_le_bytes()[..]].concat()
+        }
+        LoxObject::String(value) => [&[3], value.as_bytes()].concat(),
+        LoxObject::Bytes(value) => [&[4], &value[..]].concat(),
+        _ => return None,
+    };
+    Some(fnv1a(&bytes))
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::LoxNativeCallable;
+
+    #[test]
+    fn matches_the_reference_fnv1a_vectors() {
+        assert_eq!(fnv1a(b""), 0x811c9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c292c);
+        assert_eq!(fnv1a(b"foobar"), 0xbf9cf968);
+    }
+
+    #[test]
+    fn equal_values_hash_the_same() {
+        assert_eq!(
+            hash(&LoxObject::Number(0.0)),
+            hash(&LoxObject::Number(-0.0))
+        );
+        assert_eq!(
+            hash(&LoxObject::String("key".into())),
+            hash(&LoxObject::String("key".into()))
+        );
+        assert_ne!(hash(&LoxObject::Nil), hash(&LoxObject::Boolean(false)));
+        assert_ne!(
+            hash(&LoxObject::Number(1.0)),
+            hash(&LoxObject::Boolean(true))
+        );
+        assert_eq!(
+            hash(&LoxNativeCallable::new(0, |_, _| Ok(LoxObject::Nil))),
+            None
+        );
+    }
+}
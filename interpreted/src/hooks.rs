@@ -0,0 +1,22 @@
+use crate::{exceptions::RuntimeError, object::LoxObject, stmt::Statement};
+
+/// Hook points into the interpreter, for embedders building profilers,
+/// debuggers, or audit logs on top of it. Every method has a no-op default, so
+/// implementations only need to override the events they care about.
+///
+/// Hooks are registered with `Interpreter::add_hooks`, and are invoked in the
+/// order they were registered.
+pub trait InterpreterHooks {
+    /// Called before each statement is executed.
+    fn on_statement(&self, _stmt: &Statement) {}
+
+    /// Called before a callable is invoked, after its arguments have been
+    /// evaluated and its arity has been checked.
+    fn on_call(&self, _callee: &LoxObject, _arguments: &[LoxObject], _line: usize) {}
+
+    /// Called after a callable returns successfully.
+    fn on_return(&self, _callee: &LoxObject, _value: &LoxObject) {}
+
+    /// Called when a runtime error reaches the top level, before it's reported.
+    fn on_error(&self, _error: &RuntimeError) {}
+}
@@ -1,86 +1,867 @@
+use crate::ast_printer::SourcePrinter;
+use crate::capabilities::{Capabilities, Capability};
 use crate::environment::EnvironmentManager;
-use crate::errors;
-use crate::exceptions::{ReturnException, RuntimeError, RuntimeException};
-use crate::expr::{Expr, ExprVisitor, Literal, VisitorTarget};
-use crate::function::{LoxFunction, LoxNativeCallable};
-use crate::object::{LoxBoolean, LoxNil, LoxNumber, LoxObject, LoxString};
-use crate::stmt::{Statement, StmtVisitor, StmtVisitorTarget};
+use crate::errors::ErrorManager;
+use crate::exceptions::{ReturnException, RuntimeError, RuntimeException, TraceFrame};
+use crate::expr::{AssignTarget, Expr, ExprVisitor, Literal, VisitorTarget};
+use crate::function::{Arity, LoxFunction, LoxNativeCallable, NativeContext, NativeFn, NativeInfo};
+use crate::generator::Rng;
+use crate::hash;
+use crate::hooks::InterpreterHooks;
+use crate::limits::Limits;
+use crate::messages;
+use crate::object::{show_element, CallableLoxObject, LoxList, LoxMap, LoxObject};
+use crate::output::{Output, Stdout};
+use crate::parser::Parser;
+use crate::replay::Replay;
+use crate::resolver::{Binding, ExprId, Resolver};
+use crate::scanner::Scanner;
+use crate::stdlib;
+use crate::stmt::{Program, Statement, StmtVisitor, StmtVisitorTarget};
 use crate::token::{Token, TokenLiteral};
 use crate::token_type::TokenType;
-use std::rc::Rc;
+use crate::trace::{self, TraceEvent, Tracer};
+use crate::transform::AstTransformer;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+// A callable a call expression has called, and what calling it again needs.
+struct CallSite {
+    // Held weakly, so the cache doesn't keep the callable alive, but its
+    // allocation can't be reused for another object that would then match.
+    callee: Weak<dyn CallableLoxObject>,
+    arity: Arity,
+    // The function to call directly, if the callable is a native.
+    native: Option<NativeFn>,
+}
 
 pub struct Interpreter {
     pub environment: EnvironmentManager,
+    /// The natives defined with `define_native`, in the order they were
+    /// defined.
+    natives: RefCell<Vec<NativeInfo>>,
+    /// Where runtime errors are reported.
+    pub errors: Rc<ErrorManager>,
+    /// Source of nondeterministic inputs (clock values, etc.), which can be
+    /// recorded and replayed.
+    pub replay: Replay,
+    /// Where `print` (and printing natives) write to.
+    pub output: Rc<dyn Output>,
+    /// What natives are allowed to do outside the interpreter.
+    pub capabilities: Capabilities,
+    /// How big the strings and collections the script makes can get.
+    pub limits: Limits,
+    hooks: Vec<Rc<dyn InterpreterHooks>>,
+    transformers: Vec<Rc<dyn AstTransformer>>,
+    /// The file the running code was loaded from with `dofile`, or None for
+    /// the main script.
+    file: RefCell<Option<String>>,
+    /// Statements registered with `defer`, one frame per block (or function
+    /// body, or script) being run.
+    deferred: RefCell<Vec<Vec<Statement>>>,
+    /// How many decimal places printed numbers get, set with `set_precision`.
+    precision: Cell<usize>,
+    /// Where each variable reference finds its variable, filled in by the
+    /// resolver.
+    bindings: RefCell<HashMap<ExprId, Binding>>,
+    /// What each call expression last called, so calling the same callable
+    /// from it again doesn't have to work out its kind and arity again.
+    call_sites: RefCell<HashMap<ExprId, CallSite>>,
+    /// The VM's value stack while a native it called is running, so any
+    /// closures the native calls back into run on the same stack.
+    pub vm_stack: RefCell<Vec<LoxObject>>,
+    /// Set by the host (possibly from another thread) to stop the script.
+    interrupt: Arc<AtomicBool>,
+    /// When the script is stopped for taking too long, if ever.
+    deadline: Cell<Option<Instant>>,
+    /// How many calls to Lox functions are running.
+    pub(crate) call_depth: Cell<usize>,
+    /// How many steps (loop iterations and calls) have been taken.
+    steps: Cell<u64>,
+    /// Where `random()` gets its numbers from.
+    pub rng: RefCell<Rng>,
+    /// Where this interpreter's internal events are traced.
+    tracer: Tracer,
 }
 
+/// The precision numbers are printed with until a script changes it.
+const DEFAULT_PRECISION: usize = 2;
+/// The most decimal places `set_precision` and `format_number` accept. An f64
+/// has no more than 17 significant digits, so more than this is just noise.
+const MAX_PRECISION: usize = 20;
+
 impl Interpreter {
-    pub fn new() -> Self {
-        let environment = EnvironmentManager::new();
-        // Add native clock function
-        environment.define(
-            &"clock".to_string(),
-            LoxNativeCallable::new(0, native_clock),
+    pub fn new(errors: Rc<ErrorManager>) -> Self {
+        let interpreter = Self {
+            environment: EnvironmentManager::new(),
+            natives: RefCell::new(vec![]),
+            errors,
+            replay: Replay::live(),
+            output: Rc::new(Stdout),
+            capabilities: Capabilities::default(),
+            limits: Limits::default(),
+            hooks: vec![],
+            transformers: vec![],
+            file: RefCell::new(None),
+            deferred: RefCell::new(vec![]),
+            precision: Cell::new(DEFAULT_PRECISION),
+            bindings: RefCell::new(HashMap::new()),
+            call_sites: RefCell::new(HashMap::new()),
+            vm_stack: RefCell::new(vec![]),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            deadline: Cell::new(None),
+            call_depth: Cell::new(0),
+            steps: Cell::new(0),
+            rng: RefCell::new(Rng::new(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |now| now.as_nanos() as u64),
+            )),
+            tracer: Tracer::default(),
+        };
+        let natives: [(&str, Arity, NativeFn, &str); 29] = [
+            (
+                "clock",
+                Arity::exactly(0),
+                native_clock,
+                "The number of seconds since the Unix epoch.",
+            ),
+            // This lives alongside the 'print' statement so that printing can
+            // be passed around as a value.
+            (
+                "print",
+                Arity::exactly(1),
+                native_print,
+                "Prints a value, like the `print` statement, and returns nil.",
+            ),
+            (
+                "debug",
+                Arity::exactly(1),
+                native_debug,
+                "Prints a value's internal representation, e.g. `String(\"a\")`, and returns nil.",
+            ),
+            (
+                "timeit",
+                Arity::exactly(2),
+                native_timeit,
+                "Calls a function a number of times, and returns the average time per call in milliseconds.",
+            ),
+            (
+                "dofile",
+                Arity::exactly(1),
+                native_dofile,
+                "Runs another script in the global environment, and returns the value it returns at its top level, if any.",
+            ),
+            (
+                "set_precision",
+                Arity::exactly(1),
+                native_set_precision,
+                "Sets how many decimal places printed numbers get, and returns the previous setting.",
+            ),
+            (
+                "format_number",
+                Arity::exactly(2),
+                native_format_number,
+                "A number as a string with the given number of decimal places.",
+            ),
+            (
+                "trim",
+                Arity::exactly(1),
+                native_trim,
+                "A string without whitespace at either end.",
+            ),
+            (
+                "pad_left",
+                Arity::between(2, 3),
+                native_pad_left,
+                "A string padded at the start with a character (a space by default) to at least a length.",
+            ),
+            (
+                "pad_right",
+                Arity::between(2, 3),
+                native_pad_right,
+                "A string padded at the end with a character (a space by default) to at least a length.",
+            ),
+            (
+                "split",
+                Arity::exactly(2),
+                native_split,
+                "A new list of the parts of a string between each separator, which can't be empty.",
+            ),
+            (
+                "join",
+                Arity::exactly(2),
+                native_join,
+                "A list's elements as strings, with a separator between them.",
+            ),
+            (
+                "is_digit",
+                Arity::exactly(1),
+                native_is_digit,
+                "Whether a character is 0 to 9.",
+            ),
+            (
+                "is_alpha",
+                Arity::exactly(1),
+                native_is_alpha,
+                "Whether a character can start an identifier: a to z, A to Z, or an underscore.",
+            ),
+            (
+                "is_space",
+                Arity::exactly(1),
+                native_is_space,
+                "Whether a character is a space, tab, carriage return, or newline.",
+            ),
+            (
+                "is_upper",
+                Arity::exactly(1),
+                native_is_upper,
+                "Whether a character is A to Z.",
+            ),
+            (
+                "is_lower",
+                Arity::exactly(1),
+                native_is_lower,
+                "Whether a character is a to z.",
+            ),
+            (
+                "hash",
+                Arity::exactly(1),
+                native_hash,
+                "A stable hash of a nil, boolean, number, string, or bytes, the way maps hash keys.",
+            ),
+            (
+                "read_bytes",
+                Arity::exactly(1),
+                native_read_bytes,
+                "A whole file's contents, as bytes.",
+            ),
+            (
+                "write_bytes",
+                Arity::exactly(2),
+                native_write_bytes,
+                "Writes bytes to a file, replacing anything that was in it.",
+            ),
+            (
+                "byte_at",
+                Arity::exactly(2),
+                native_byte_at,
+                "The byte at an index in a bytes value, from 0 to 255.",
+            ),
+            (
+                "len",
+                Arity::exactly(1),
+                native_len,
+                "How many characters a string has, bytes a bytes value has, elements a list has, or keys a map has.",
+            ),
+            (
+                "append",
+                Arity::exactly(2),
+                native_append,
+                "Adds a value to the end of a list, and returns nil.",
+            ),
+            (
+                "keys",
+                Arity::exactly(1),
+                native_keys,
+                "A new list of a map's keys, in the order they were added.",
+            ),
+            (
+                "has",
+                Arity::exactly(2),
+                native_has,
+                "Whether a map has a key.",
+            ),
+            (
+                "sort",
+                Arity::exactly(1),
+                native_sort,
+                "A new list of a list's numbers, or strings, in ascending order.",
+            ),
+            (
+                "sort_by",
+                Arity::exactly(2),
+                native_sort_by,
+                "A new list of a list's elements ordered by a function comparing two of them, which returns a number below 0 if the first goes first, above 0 if the second does, or 0 to keep them in order.",
+            ),
+            (
+                "reverse",
+                Arity::exactly(1),
+                native_reverse,
+                "A new list of a list's elements in reverse order.",
+            ),
+            (
+                "unique",
+                Arity::exactly(1),
+                native_unique,
+                "A new list of a list's elements without any equal (==) to an earlier one.",
+            ),
+        ];
+        for (name, arity, native, doc) in natives {
+            interpreter.define_native(name, arity, doc, native);
+        }
+        // Add the string and math functions.
+        stdlib::define(&interpreter);
+        interpreter
+    }
+
+    /// Defines a native function as a global, and lists it (with its
+    /// documentation) in `natives`. A native defined again under the same
+    /// name replaces the earlier one.
+    pub fn define_native(
+        &self,
+        name: &str,
+        arity: impl Into<Arity>,
+        doc: &str,
+        function: NativeFn,
+    ) {
+        let arity = arity.into();
+        self.environment
+            .define(&name.to_string(), LoxNativeCallable::new(arity, function));
+        let info = NativeInfo {
+            name: name.to_string(),
+            arity,
+            doc: doc.to_string(),
+            function,
+        };
+        let mut natives = self.natives.borrow_mut();
+        match natives.iter_mut().find(|native| native.name == name) {
+            Some(native) => *native = info,
+            None => natives.push(info),
+        }
+    }
+
+    /// Every native defined with `define_native` (including the built-in
+    /// ones), sorted by name, e.g. for listing them in the REPL or generating
+    /// documentation. Natives a script has since redefined are still listed.
+    pub fn natives(&self) -> Vec<NativeInfo> {
+        let mut natives = self.natives.borrow().clone();
+        natives.sort_by(|a, b| a.name.cmp(&b.name));
+        natives
+    }
+
+    /// Calls a native by the name it was defined with, from the host, as if
+    /// a script had called it at line 0. Errors if there's no such native, or
+    /// it doesn't take that many arguments.
+    pub fn call_native(
+        &self,
+        name: &str,
+        arguments: Vec<LoxObject>,
+    ) -> Result<LoxObject, RuntimeException> {
+        let paren = Token::new(TokenType::RightParen, ")", TokenLiteral::None, 0usize);
+        let native = self
+            .natives
+            .borrow()
+            .iter()
+            .find(|native| native.name == name)
+            .cloned();
+        let native = match native {
+            Some(native) => native,
+            None => {
+                return Err(RuntimeError::new(
+                    paren,
+                    messages::UNDEFINED_VARIABLE.with(&[name]),
+                ))
+            }
+        };
+        if !native.arity.accepts(arguments.len()) {
+            return Err(RuntimeError::new(
+                paren,
+                messages::WRONG_ARGUMENT_COUNT.with(&[
+                    native.arity.to_string(),
+                    arguments.len().to_string(),
+                    "<native fn>".to_string(),
+                ]),
+            ));
+        }
+        let context = NativeContext {
+            interpreter: self,
+            paren: &paren,
+        };
+        (native.function)(&context, arguments)
+    }
+
+    /// Records where a variable reference finds its variable. Called by the
+    /// resolver.
+    pub fn resolve(&self, expr: ExprId, binding: Binding) {
+        self.bindings.borrow_mut().insert(expr, binding);
+    }
+
+    /// Where the variable reference finds its variable, or None if it wasn't
+    /// resolved.
+    pub fn binding(&self, expr: ExprId) -> Option<Binding> {
+        self.bindings.borrow().get(&expr).copied()
+    }
+
+    /// The value as `print` shows it: like `stringify`, but with numbers
+    /// printed at the precision set with `set_precision`.
+    pub fn stringify(&self, value: &LoxObject) -> String {
+        match value {
+            LoxObject::Number(number) => format!("{:.*}", self.precision.get(), number),
+            _ => value.stringify(),
+        }
+    }
+
+    /// Traces what this interpreter (and the scanner, parser, and VM running
+    /// code for it) does to the tracer, instead of to the one it has now.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.environment.tracer = tracer.clone();
+        self.tracer = tracer;
+    }
+
+    pub fn tracer(&self) -> &Tracer {
+        &self.tracer
+    }
+
+    /// Registers a set of hooks that will be notified as the interpreter runs.
+    pub fn add_hooks(&mut self, hooks: Rc<dyn InterpreterHooks>) {
+        self.hooks.push(hooks);
+    }
+
+    /// Registers a transformer to rewrite programs before they're resolved.
+    pub fn add_transformer(&mut self, transformer: Rc<dyn AstTransformer>) {
+        self.transformers.push(transformer);
+    }
+
+    /// The statements as rewritten by each of the registered transformers in
+    /// turn. Everything that parses a program calls this before resolving it.
+    pub fn transform(&self, statements: Vec<Statement>) -> Vec<Statement> {
+        self.transformers
+            .iter()
+            .fold(statements, |statements, transformer| {
+                transformer.transform_statements(&statements)
+            })
+    }
+
+    /// Runs the program, returning the runtime error that stopped it, if one
+    /// did. The error isn't reported anywhere; that's up to the caller (e.g.
+    /// with `ErrorManager::runtime_error`).
+    ///
+    /// The program must have been resolved without errors: like any code
+    /// that failed to compile, a program with a `return` outside a function
+    /// isn't meant to be run.
+    pub fn interpret(&self, program: &Program) -> Result<(), RuntimeException> {
+        let result = self.execute_deferring(program);
+        if let Err(RuntimeException::RuntimeError(error)) = &result {
+            for hooks in &self.hooks {
+                hooks.on_error(error);
+            }
+        }
+        result
+    }
+
+    /// A flag the host can set, from any thread, to stop the script at the
+    /// next loop iteration, call, or check by a blocking native.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Stops the script once the deadline passes, or never if it's None.
+    pub fn set_deadline(&self, deadline: Option<Instant>) {
+        self.deadline.set(deadline);
+    }
+
+    /// Returns an error at the token if the script has been interrupted or has
+    /// run past its deadline. Loops, calls, and blocking natives call this so
+    /// that no script can run forever.
+    pub fn check_limits(&self, token: &Token) -> Result<(), RuntimeException> {
+        let reason = if self.interrupt.load(Ordering::Relaxed) {
+            "it was interrupted"
+        } else if self
+            .deadline
+            .get()
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            "it ran out of time"
+        } else {
+            return Ok(());
+        };
+        Err(RuntimeError::new(
+            token.clone(),
+            messages::INTERRUPTED.with(&[reason]),
+        ))
+    }
+
+    /// Counts a step (a loop iteration or a call) taken at the token, then
+    /// checks the limits, returning an error if the script has taken all the
+    /// steps it's allowed.
+    pub fn step(&self, token: &Token) -> Result<(), RuntimeException> {
+        let steps = self.steps.get() + 1;
+        self.steps.set(steps);
+        if self.limits.max_steps.is_some_and(|max| steps > max) {
+            return Err(RuntimeError::new(
+                token.clone(),
+                messages::INTERRUPTED.with(&["it took more than its limit of steps"]),
+            ));
+        }
+        self.check_limits(token)
+    }
+
+    /// How many steps (loop iterations and calls) the interpreter has taken.
+    pub fn steps(&self) -> u64 {
+        self.steps.get()
+    }
+
+    /// Counts a call into a Lox function, made at the paren, returning a
+    /// stack overflow error instead if calls are already nested as deep as
+    /// the limits allow. Every call counted must be matched by `exit_call`.
+    pub fn enter_call(&self, paren: &Token) -> Result<(), RuntimeException> {
+        let depth = self.call_depth.get();
+        if self.limits.max_call_depth.is_some_and(|max| depth >= max) {
+            return Err(RuntimeError::new(paren.clone(), messages::STACK_OVERFLOW));
+        }
+        self.call_depth.set(depth + 1);
+        Ok(())
+    }
+
+    /// Counts a call entered with `enter_call` as returned from.
+    pub fn exit_call(&self) {
+        self.call_depth.set(self.call_depth.get() - 1);
+    }
+
+    // The arity of the callee, and the native to call if it's a native, if
+    // the call expression has called it before.
+    fn cached_call(
+        &self,
+        expr: &crate::expr::Call,
+        callee: &Rc<dyn CallableLoxObject>,
+    ) -> Option<(Arity, Option<NativeFn>)> {
+        match self.call_sites.borrow().get(&expr.id) {
+            Some(site) if site.callee.as_ptr() as *const () == Rc::as_ptr(callee) as *const () => {
+                Some((site.arity, site.native))
+            }
+            _ => None,
+        }
+    }
+
+    // Works out how to call the callee, remembering it for the next call from
+    // the same expression.
+    fn cache_call(
+        &self,
+        expr: &crate::expr::Call,
+        callee: &Rc<dyn CallableLoxObject>,
+    ) -> (Arity, Option<NativeFn>) {
+        let arity = callee.arity_self();
+        let native = match callee.downcast_ref::<LoxNativeCallable>() {
+            Ok(native) => Some(native.call_fun),
+            Err(_) => None,
+        };
+        self.call_sites.borrow_mut().insert(
+            expr.id,
+            CallSite {
+                callee: Rc::downgrade(callee),
+                arity,
+                native,
+            },
         );
+        (arity, native)
+    }
 
-        Self { environment }
+    /// Returns an error at the token if a string of that many bytes would be
+    /// longer than the limits allow. Anything making a string that could be
+    /// arbitrarily long calls this before making it.
+    pub fn check_string_length(
+        &self,
+        token: &Token,
+        length: usize,
+    ) -> Result<(), RuntimeException> {
+        match self.limits.max_string_length {
+            Some(max) if length > max => Err(RuntimeError::new(
+                token.clone(),
+                messages::STRING_TOO_LONG.with(&[max.to_string(), length.to_string()]),
+            )),
+            _ => Ok(()),
+        }
     }
 
-    pub fn interpret(&self, statements: Vec<Statement>) {
-        for stmt in statements {
-            match self.execute(stmt) {
-                Ok(_) => {}
-                Err(runtime_error) => {
-                    errors::runtime_error(runtime_error);
-                    break;
-                }
+    /// Returns an error at the token if a list or map with that many elements
+    /// would be bigger than the limits allow. Anything adding to a collection
+    /// calls this before adding.
+    pub fn check_collection_size(
+        &self,
+        token: &Token,
+        size: usize,
+    ) -> Result<(), RuntimeException> {
+        match self.limits.max_collection_size {
+            Some(max) if size > max => Err(RuntimeError::new(
+                token.clone(),
+                messages::COLLECTION_TOO_LARGE.with(&[max.to_string()]),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The file the running code was loaded from, or None for the main
+    /// script.
+    pub fn current_file(&self) -> Option<String> {
+        self.file.borrow().clone()
+    }
+
+    /// Sets the file the running code was loaded from, returning the previous
+    /// one so it can be restored.
+    pub fn swap_file(&self, file: Option<String>) -> Option<String> {
+        self.file.replace(file)
+    }
+
+    pub fn execute(&self, stmt: &Statement) -> Result<(), RuntimeException> {
+        for hooks in &self.hooks {
+            hooks.on_statement(stmt);
+        }
+        match stmt.accept(self) {
+            // The innermost statement an error unwinds through is the one that
+            // raised it, so that's where we find out which file it's in.
+            Err(RuntimeException::RuntimeError(mut runtime_error)) if !runtime_error.located => {
+                runtime_error.file = self.current_file();
+                runtime_error.located = true;
+                Err(RuntimeException::RuntimeError(runtime_error))
             }
+            result => result,
         }
     }
 
-    pub fn execute(&self, stmt: Statement) -> Result<(), RuntimeException> {
-        stmt.accept(self)
+    pub fn execute_block(&self, statements: &[Statement]) -> Result<(), RuntimeException> {
+        // Popped when this returns, even if a statement errors or returns.
+        let _scope = self.environment.scope();
+        self.execute_deferring(statements)
     }
 
-    pub fn execute_block(&self, statements: &Vec<Statement>) -> Result<(), RuntimeException> {
-        self.environment.enter_new_scope();
+    /// Runs the statements until one fails (or returns), then runs whatever
+    /// they deferred, most recent first. Deferred statements run even if an
+    /// earlier one fails. The statements' own error wins over a deferred
+    /// one, and any error wins over a return.
+    fn execute_deferring(&self, statements: &[Statement]) -> Result<(), RuntimeException> {
+        self.deferred.borrow_mut().push(vec![]);
 
+        let mut result = Ok(());
         for statement in statements {
-            if let Err(runtime_error) = self.execute(statement.clone()) {
-                return Err(runtime_error);
+            result = self.execute(statement);
+            if result.is_err() {
+                break;
             }
         }
 
-        self.environment.exit_current_scope();
+        let deferred = self.deferred.borrow_mut().pop().unwrap_or_default();
+        for statement in deferred.iter().rev() {
+            match (self.execute(statement), &result) {
+                // A return inside a deferred block only ends that block.
+                (Ok(_), _) | (Err(RuntimeException::ReturnException(_)), _) => {}
+                (Err(_), Err(RuntimeException::RuntimeError(_))) => {}
+                (Err(runtime_error), _) => result = Err(runtime_error),
+            }
+        }
 
-        Ok(())
+        result
     }
 
     // Sends the expression back through the visitor implementation
-    pub fn evaluate(&self, expr: &Rc<dyn Expr>) -> Result<LoxObject, RuntimeException> {
+    pub fn evaluate(&self, expr: &Rc<Expr>) -> Result<LoxObject, RuntimeException> {
         expr.accept(self)
     }
 
-    pub fn check_number_operand<'a>(
+    pub fn check_number_operand(
         &self,
         operator: &Token,
-        operand: &'a LoxObject,
-    ) -> Result<&'a LoxObject, RuntimeException> {
-        if operand.instance_name() == "Number" {
-            Ok(operand)
-        } else {
-            Err(RuntimeError::new(
+        operand: &LoxObject,
+    ) -> Result<f64, RuntimeException> {
+        match operand {
+            LoxObject::Number(number) => Ok(*number),
+            _ => Err(RuntimeError::new(
                 operator.clone(),
-                "Operand must be a number.",
-            ))
+                messages::OPERAND_MUST_BE_NUMBER,
+            )),
+        }
+    }
+
+    /// The element of a list at an index, e.g. `list[1]`, or the value of a
+    /// map at a key, e.g. `map["key"]`. Errors are reported at the bracket.
+    pub fn get_index(
+        &self,
+        bracket: &Token,
+        object: &LoxObject,
+        index: &LoxObject,
+    ) -> Result<LoxObject, RuntimeException> {
+        if let LoxObject::Map(map) = object {
+            return match map.borrow().get(index) {
+                Some(value) => Ok(value),
+                None => Err(RuntimeError::new(
+                    bracket.clone(),
+                    messages::MISSING_KEY.with(&[show_element(index)]),
+                )),
+            };
+        }
+        let length = self.check_indexable(bracket, object)?;
+        let index = self.check_index(bracket, index, length)?;
+        match object {
+            LoxObject::List(list) => Ok(list.borrow().0[index].clone()),
+            _ => unreachable!(),
         }
     }
 
-    pub fn check_number_operands<'a, 'b>(
+    /// Replaces the element of a list at an index, e.g. `list[1] = value`, or
+    /// sets the value of a map at a key, e.g. `map["key"] = value`. Errors are
+    /// reported at the bracket.
+    pub fn set_index(
+        &self,
+        bracket: &Token,
+        object: &LoxObject,
+        index: &LoxObject,
+        value: LoxObject,
+    ) -> Result<(), RuntimeException> {
+        if let LoxObject::Map(_) = object {
+            return self.insert_entry(bracket, object, index.clone(), value);
+        }
+        let length = self.check_indexable(bracket, object)?;
+        let index = self.check_index(bracket, index, length)?;
+        if let LoxObject::List(list) = object {
+            list.borrow_mut().0[index] = value;
+        }
+        Ok(())
+    }
+
+    /// Reads a property, e.g. `point.x`: the value of a map at the key `"x"`.
+    /// Errors are reported at the property's name.
+    pub fn get_property(
+        &self,
+        name: &Token,
+        object: &LoxObject,
+    ) -> Result<LoxObject, RuntimeException> {
+        let map = match object {
+            LoxObject::Map(map) => map,
+            _ => {
+                return Err(RuntimeError::new(
+                    name.clone(),
+                    messages::NO_PROPERTIES.with(&[object.describe_type()]),
+                ))
+            }
+        };
+        match map
+            .borrow()
+            .get(&LoxObject::String(name.lexeme.as_str().into()))
+        {
+            Some(value) => Ok(value),
+            None => Err(RuntimeError::new(
+                name.clone(),
+                messages::UNDEFINED_PROPERTY.with(&[&name.lexeme]),
+            )),
+        }
+    }
+
+    /// Sets a property, e.g. `point.x = value`, adding it if it's new.
+    /// Errors are reported at the property's name.
+    pub fn set_property(
+        &self,
+        name: &Token,
+        object: &LoxObject,
+        value: LoxObject,
+    ) -> Result<(), RuntimeException> {
+        match object {
+            LoxObject::Map(_) => self.insert_entry(
+                name,
+                object,
+                LoxObject::String(name.lexeme.as_str().into()),
+                value,
+            ),
+            _ => Err(RuntimeError::new(
+                name.clone(),
+                messages::NO_PROPERTIES.with(&[object.describe_type()]),
+            )),
+        }
+    }
+
+    /// A new map with the entries, in order; a later entry for the same key
+    /// replaces an earlier one. Errors are reported at the brace.
+    pub fn build_map(
+        &self,
+        brace: &Token,
+        entries: Vec<(LoxObject, LoxObject)>,
+    ) -> Result<LoxObject, RuntimeException> {
+        let map = LoxMap::new();
+        for (key, value) in entries {
+            self.insert_entry(brace, &map, key, value)?;
+        }
+        Ok(map)
+    }
+
+    fn insert_entry(
+        &self,
+        token: &Token,
+        map: &LoxObject,
+        key: LoxObject,
+        value: LoxObject,
+    ) -> Result<(), RuntimeException> {
+        let map = match map {
+            LoxObject::Map(map) => map,
+            _ => unreachable!(),
+        };
+        // Only a new key adds an entry.
+        let size = map
+            .borrow()
+            .get(&key)
+            .is_none()
+            .then(|| map.borrow().len() + 1);
+        if let Some(size) = size {
+            self.check_collection_size(token, size)?;
+        }
+        let error = messages::UNHASHABLE_VALUE.with(&[key.stringify()]);
+        match map.borrow_mut().insert(key, value) {
+            true => Ok(()),
+            false => Err(RuntimeError::new(token.clone(), error)),
+        }
+    }
+
+    // The length of the list, or an error if the object isn't one (or a map).
+    fn check_indexable(
+        &self,
+        bracket: &Token,
+        object: &LoxObject,
+    ) -> Result<usize, RuntimeException> {
+        match object {
+            LoxObject::List(list) => Ok(list.borrow().0.len()),
+            _ => Err(RuntimeError::new(
+                bracket.clone(),
+                messages::NOT_INDEXABLE.with(&[object.describe_type()]),
+            )),
+        }
+    }
+
+    // The index as a position in a list of the length, or an error if it
+    // isn't one.
+    fn check_index(
+        &self,
+        bracket: &Token,
+        index: &LoxObject,
+        length: usize,
+    ) -> Result<usize, RuntimeException> {
+        let number = match index {
+            LoxObject::Number(number) if number.fract() == 0.0 => *number,
+            _ => {
+                return Err(RuntimeError::new(
+                    bracket.clone(),
+                    messages::LIST_INDEX_NOT_WHOLE.with(&[index.stringify()]),
+                ))
+            }
+        };
+        if number < 0.0 || number >= length as f64 {
+            return Err(RuntimeError::new(
+                bracket.clone(),
+                messages::LIST_INDEX_OUT_OF_RANGE.with(&[index.stringify(), length.to_string()]),
+            ));
+        }
+        Ok(number as usize)
+    }
+
+    pub fn check_number_operands(
         &self,
         operator: &Token,
-        operand_a: &'a LoxObject,
-        operand_b: &'b LoxObject,
-    ) -> Result<(&'a LoxObject, &'b LoxObject), RuntimeException> {
+        operand_a: &LoxObject,
+        operand_b: &LoxObject,
+    ) -> Result<(f64, f64), RuntimeException> {
         match (
             self.check_number_operand(operator, operand_a),
             self.check_number_operand(operator, operand_b),
@@ -91,70 +872,106 @@ impl Interpreter {
             (Err(err), Err(_)) => Err(err),
         }
     }
-}
 
-impl ExprVisitor<Result<LoxObject, RuntimeException>> for &Interpreter {
-    fn visit_binary_expr(&self, expr: &crate::expr::Binary) -> Result<LoxObject, RuntimeException> {
-        let (left, right) = (self.evaluate(&expr.left), self.evaluate(&expr.right));
-        if left.is_err() {
-            return left;
-        } else if right.is_err() {
-            return right;
-        }
+    // What a compound assignment stores: the target's current value combined
+    // with the assignment's value, which is evaluated after the target is read.
+    fn compound_value(
+        &self,
+        expr: &crate::expr::CompoundAssign,
+        current: LoxObject,
+    ) -> Result<LoxObject, RuntimeException> {
+        let value = self.evaluate(&expr.value)?;
+        let operator = expr.operator.ty.compound_operator().unwrap();
+        self.binary_operation(&expr.operator, operator, current, value)
+    }
 
-        let lft = left.unwrap();
-        let rgt = right.unwrap();
+    /// Applies a binary operator (`ty`, which may differ from the type of
+    /// `operator`, the token errors are reported at, for compound assignments)
+    /// to its evaluated operands.
+    fn binary_operation(
+        &self,
+        operator: &Token,
+        ty: TokenType,
+        lft: LoxObject,
+        rgt: LoxObject,
+    ) -> Result<LoxObject, RuntimeException> {
         let l_ty = lft.instance_name();
         let r_ty = rgt.instance_name();
 
-        match expr.operator.ty {
-            TokenType::Minus => match self.check_number_operands(&expr.operator, &lft, &rgt) {
-                Ok((left, right)) => Ok(LoxNumber::new(left.get_number() - right.get_number())),
+        match ty {
+            TokenType::Minus => match self.check_number_operands(operator, &lft, &rgt) {
+                Ok((left, right)) => Ok(LoxObject::Number(left - right)),
                 Err(err) => Err(err),
             },
-            TokenType::Plus => match self.check_number_operands(&expr.operator, &lft, &rgt) {
-                Ok((left, right)) => Ok(LoxNumber::new(left.get_number() + right.get_number())),
+            TokenType::Plus => match self.check_number_operands(operator, &lft, &rgt) {
+                Ok((left, right)) => Ok(LoxObject::Number(left + right)),
                 _ => {
                     if l_ty == "String" && r_ty == "String" {
-                        Ok(LoxString::new(lft.get_string() + rgt.get_string().as_str()))
+                        match (lft.as_string(operator), rgt.as_string(operator)) {
+                            (Ok(left), Ok(right)) => {
+                                let length = left.len() + right.len();
+                                match self.check_string_length(operator, length) {
+                                    Ok(()) => Ok(LoxObject::String((left + right.as_str()).into())),
+                                    Err(err) => Err(err),
+                                }
+                            }
+                            (Err(err), _) | (_, Err(err)) => Err(err),
+                        }
                     } else {
                         Err(RuntimeError::new(
-                            expr.operator.clone(),
-                            "Operands must both be numbers or strings.",
+                            operator.clone(),
+                            messages::OPERANDS_MUST_BE_NUMBERS_OR_STRINGS,
                         ))
                     }
                 }
             },
-            TokenType::Slash => match self.check_number_operands(&expr.operator, &lft, &rgt) {
-                Ok((left, right)) => Ok(LoxNumber::new(left.get_number() / right.get_number())),
+            TokenType::Slash => match self.check_number_operands(operator, &lft, &rgt) {
+                Ok((left, right)) => Ok(LoxObject::Number(left / right)),
                 Err(err) => Err(err),
             },
-            TokenType::Star => match self.check_number_operands(&expr.operator, &lft, &rgt) {
-                Ok((left, right)) => Ok(LoxNumber::new(left.get_number() * right.get_number())),
+            TokenType::Star => match self.check_number_operands(operator, &lft, &rgt) {
+                Ok((left, right)) => Ok(LoxObject::Number(left * right)),
                 Err(err) => Err(err),
             },
-            TokenType::Greater => match self.check_number_operands(&expr.operator, &lft, &rgt) {
-                Ok((left, right)) => Ok(LoxBoolean::new(left.get_number() > right.get_number())),
+            TokenType::Greater => match self.check_number_operands(operator, &lft, &rgt) {
+                Ok((left, right)) => Ok(LoxObject::Boolean(left > right)),
                 Err(err) => Err(err),
             },
-            TokenType::GreaterEqual => match self.check_number_operands(&expr.operator, &lft, &rgt)
-            {
-                Ok((left, right)) => Ok(LoxBoolean::new(left.get_number() >= right.get_number())),
+            TokenType::GreaterEqual => match self.check_number_operands(operator, &lft, &rgt) {
+                Ok((left, right)) => Ok(LoxObject::Boolean(left >= right)),
                 Err(err) => Err(err),
             },
-            TokenType::Less => match self.check_number_operands(&expr.operator, &lft, &rgt) {
-                Ok((left, right)) => Ok(LoxBoolean::new(left.get_number() < right.get_number())),
+            TokenType::Less => match self.check_number_operands(operator, &lft, &rgt) {
+                Ok((left, right)) => Ok(LoxObject::Boolean(left < right)),
                 Err(err) => Err(err),
             },
-            TokenType::LessEqual => match self.check_number_operands(&expr.operator, &lft, &rgt) {
-                Ok((left, right)) => Ok(LoxBoolean::new(left.get_number() <= right.get_number())),
+            TokenType::LessEqual => match self.check_number_operands(operator, &lft, &rgt) {
+                Ok((left, right)) => Ok(LoxObject::Boolean(left <= right)),
                 Err(err) => Err(err),
             },
-            TokenType::BangEqual => Ok(LoxBoolean::new(lft != rgt)),
-            TokenType::EqualEqual => Ok(LoxBoolean::new(lft == rgt)),
+            TokenType::BangEqual => Ok(LoxObject::Boolean(lft != rgt)),
+            TokenType::EqualEqual => Ok(LoxObject::Boolean(lft == rgt)),
             _ => unreachable!(),
         }
     }
+}
+
+impl ExprVisitor<Result<LoxObject, RuntimeException>> for &Interpreter {
+    fn visit_binary_expr(&self, expr: &crate::expr::Binary) -> Result<LoxObject, RuntimeException> {
+        let (left, right) = (self.evaluate(&expr.left), self.evaluate(&expr.right));
+        if left.is_err() {
+            return left;
+        } else if right.is_err() {
+            return right;
+        }
+
+        self.binary_operation(
+            &expr.operator,
+            expr.operator.ty,
+            left.unwrap(),
+            right.unwrap(),
+        )
+    }
 
     fn visit_grouping_expr(
         &self,
@@ -168,34 +985,36 @@ impl ExprVisitor<Result<LoxObject, RuntimeException>> for &Interpreter {
         expr: &crate::expr::Literal,
     ) -> Result<LoxObject, RuntimeException> {
         Ok(match &expr.value {
-            TokenLiteral::String(value) => LoxString::new(value.clone()),
-            TokenLiteral::Number(value) => LoxNumber::new(*value),
-            TokenLiteral::False => LoxBoolean::new(false),
-            TokenLiteral::True => LoxBoolean::new(true),
-            TokenLiteral::None => LoxNil::new(),
+            TokenLiteral::String(value) => LoxObject::String(value.as_str().into()),
+            TokenLiteral::Number(value) => LoxObject::Number(*value),
+            TokenLiteral::False => LoxObject::Boolean(false),
+            TokenLiteral::True => LoxObject::Boolean(true),
+            TokenLiteral::None => LoxObject::Nil,
         })
     }
 
     fn visit_unary_expr(&self, expr: &crate::expr::Unary) -> Result<LoxObject, RuntimeException> {
-        self.evaluate(&expr.right)
-            .map(|right| match expr.operator.ty {
-                TokenType::Minus => {
-                    if let "Number" = right.instance_name() {
-                        return LoxNumber::new(-right.get_number());
-                    }
+        let right = self.evaluate(&expr.right)?;
 
-                    right
-                }
-                TokenType::Bang => LoxBoolean::new(right.is_truthy()),
-                _ => unreachable!(),
-            })
+        match expr.operator.ty {
+            TokenType::Minus => match self.check_number_operand(&expr.operator, &right) {
+                Ok(right) => Ok(LoxObject::Number(-right)),
+                Err(err) => Err(err),
+            },
+            TokenType::Bang => Ok(LoxObject::Boolean(!right.is_truthy())),
+            _ => unreachable!(),
+        }
     }
 
     fn visit_variable_expr(
         &self,
         expr: &crate::expr::Variable,
     ) -> Result<LoxObject, RuntimeException> {
-        self.environment.get(&expr.name)
+        match self.binding(expr.id) {
+            Some(Binding::Local(depth)) => self.environment.get_at(depth, &expr.name),
+            Some(Binding::Global) => self.environment.get_global(&expr.name),
+            None => self.environment.get(&expr.name),
+        }
     }
 
     fn visit_assign_expr(&self, expr: &crate::expr::Assign) -> Result<LoxObject, RuntimeException> {
@@ -204,9 +1023,14 @@ impl ExprVisitor<Result<LoxObject, RuntimeException>> for &Interpreter {
             Err(runtime_error) => return Err(runtime_error),
         };
 
-        if let Err(runtime_error) = self.environment.assign(&expr.name, value.clone()) {
-            return Err(runtime_error);
-        }
+        let assigned = match self.binding(expr.id) {
+            Some(Binding::Local(depth)) => {
+                self.environment.assign_at(depth, &expr.name, value.clone())
+            }
+            Some(Binding::Global) => self.environment.assign_global(&expr.name, value.clone()),
+            None => self.environment.assign(&expr.name, value.clone()),
+        };
+        assigned?;
 
         return Ok(value);
     }
@@ -251,27 +1075,187 @@ impl ExprVisitor<Result<LoxObject, RuntimeException>> for &Interpreter {
             };
         }
 
-        if !callee.is_callable() {
-            return Err(RuntimeError::new(
-                expr.paren.clone(),
-                "Can only call functions and classes.",
-            ));
-        }
+        let callable = match &callee {
+            LoxObject::Callable(callable) => callable,
+            _ => {
+                return Err(RuntimeError::new(
+                    expr.paren.clone(),
+                    messages::NOT_CALLABLE.with(&[
+                        expr.callee.accept(&SourcePrinter::default()),
+                        callee.describe_type(),
+                    ]),
+                ));
+            }
+        };
+        let (arity, native) = match self.cached_call(expr, callable) {
+            Some(cached) => cached,
+            None => self.cache_call(expr, callable),
+        };
 
         let function = callee;
 
-        if arguments.len() != function.arity() {
+        if !arity.accepts(arguments.len()) {
             return Err(RuntimeError::new(
                 expr.paren.clone(),
-                format!(
-                    "Expected {} arguments but got {}.",
-                    function.arity(),
-                    arguments.len()
-                ),
+                messages::WRONG_ARGUMENT_COUNT.with(&[
+                    arity.to_string(),
+                    arguments.len().to_string(),
+                    function.signature(),
+                ]),
             ));
         }
 
-        function.call(&self, arguments)
+        self.step(&expr.paren)?;
+
+        for hooks in &self.hooks {
+            hooks.on_call(&function, &arguments, expr.paren.line);
+        }
+        trace::emit(&self.tracer, || TraceEvent::CallEnter {
+            function: function.stringify(),
+            line: expr.paren.line,
+        });
+
+        let result = match native {
+            Some(native) => {
+                let context = NativeContext {
+                    interpreter: self,
+                    paren: &expr.paren,
+                };
+                native(&context, arguments)
+            }
+            None => function.call(self, &expr.paren, arguments),
+        };
+        trace::emit(&self.tracer, || TraceEvent::CallExit {
+            function: function.stringify(),
+        });
+        match result {
+            Ok(value) => {
+                for hooks in &self.hooks {
+                    hooks.on_return(&function, &value);
+                }
+                Ok(value)
+            }
+            // Record the call frame as the error unwinds, so we can print a
+            // stack trace once it reaches the top level.
+            Err(RuntimeException::RuntimeError(mut runtime_error)) => {
+                runtime_error.trace.push(TraceFrame {
+                    function: function.stringify(),
+                    line: expr.paren.line,
+                    file: self.current_file(),
+                });
+                Err(RuntimeException::RuntimeError(runtime_error))
+            }
+            result => result,
+        }
+    }
+
+    fn visit_function_expr(
+        &self,
+        expr: &crate::expr::Function,
+    ) -> Result<LoxObject, RuntimeException> {
+        Ok(LoxFunction::new(
+            &expr.declaration(),
+            self.current_file(),
+            self.environment.capture(),
+        ))
+    }
+
+    fn visit_list_expr(&self, expr: &crate::expr::List) -> Result<LoxObject, RuntimeException> {
+        let mut elements = vec![];
+        for element in &expr.elements {
+            elements.push(self.evaluate(element)?);
+        }
+        match self.check_collection_size(&expr.bracket, elements.len()) {
+            Ok(()) => Ok(LoxList::new(elements)),
+            Err(runtime_error) => Err(runtime_error),
+        }
+    }
+
+    fn visit_map_expr(&self, expr: &crate::expr::Map) -> Result<LoxObject, RuntimeException> {
+        let mut entries = vec![];
+        for (key, value) in &expr.entries {
+            let key = self.evaluate(key)?;
+            let value = self.evaluate(value)?;
+            entries.push((key, value));
+        }
+        self.build_map(&expr.brace, entries)
+    }
+
+    fn visit_index_expr(&self, expr: &crate::expr::Index) -> Result<LoxObject, RuntimeException> {
+        let object = self.evaluate(&expr.object)?;
+        let index = self.evaluate(&expr.index)?;
+        self.get_index(&expr.bracket, &object, &index)
+    }
+
+    fn visit_index_assign_expr(
+        &self,
+        expr: &crate::expr::IndexAssign,
+    ) -> Result<LoxObject, RuntimeException> {
+        let object = self.evaluate(&expr.object)?;
+        let index = self.evaluate(&expr.index)?;
+        let value = self.evaluate(&expr.value)?;
+        match self.set_index(&expr.bracket, &object, &index, value.clone()) {
+            Ok(_) => Ok(value),
+            Err(runtime_error) => Err(runtime_error),
+        }
+    }
+
+    fn visit_compound_assign_expr(
+        &self,
+        expr: &crate::expr::CompoundAssign,
+    ) -> Result<LoxObject, RuntimeException> {
+        match expr.target() {
+            AssignTarget::Variable(variable) => {
+                let current = self.visit_variable_expr(variable)?;
+                let value = self.compound_value(expr, current)?;
+
+                let name = &variable.name;
+                let assigned = match self.binding(variable.id) {
+                    Some(Binding::Local(depth)) => {
+                        self.environment.assign_at(depth, name, value.clone())
+                    }
+                    Some(Binding::Global) => self.environment.assign_global(name, value.clone()),
+                    None => self.environment.assign(name, value.clone()),
+                };
+                match assigned {
+                    Ok(_) => Ok(value),
+                    Err(runtime_error) => Err(runtime_error),
+                }
+            }
+            AssignTarget::Index(target) => {
+                let object = self.evaluate(&target.object)?;
+                let index = self.evaluate(&target.index)?;
+                let current = self.get_index(&target.bracket, &object, &index)?;
+                let value = self.compound_value(expr, current)?;
+                match self.set_index(&target.bracket, &object, &index, value.clone()) {
+                    Ok(_) => Ok(value),
+                    Err(runtime_error) => Err(runtime_error),
+                }
+            }
+            AssignTarget::Property(target) => {
+                let object = self.evaluate(&target.object)?;
+                let current = self.get_property(&target.name, &object)?;
+                let value = self.compound_value(expr, current)?;
+                match self.set_property(&target.name, &object, value.clone()) {
+                    Ok(_) => Ok(value),
+                    Err(runtime_error) => Err(runtime_error),
+                }
+            }
+        }
+    }
+
+    fn visit_get_expr(&self, expr: &crate::expr::Get) -> Result<LoxObject, RuntimeException> {
+        let object = self.evaluate(&expr.object)?;
+        self.get_property(&expr.name, &object)
+    }
+
+    fn visit_set_expr(&self, expr: &crate::expr::Set) -> Result<LoxObject, RuntimeException> {
+        let object = self.evaluate(&expr.object)?;
+        let value = self.evaluate(&expr.value)?;
+        match self.set_property(&expr.name, &object, value.clone()) {
+            Ok(_) => Ok(value),
+            Err(runtime_error) => Err(runtime_error),
+        }
     }
 }
 
@@ -287,7 +1271,7 @@ impl StmtVisitor<Result<(), RuntimeException>> for &Interpreter {
         let value = self.evaluate(&stmt.expression);
         match value {
             Ok(print_value) => {
-                println!("{}", print_value.stringify());
+                self.output.print(&self.stringify(&print_value));
                 Ok(())
             }
             Err(runtime_error) => Err(runtime_error),
@@ -308,7 +1292,7 @@ impl StmtVisitor<Result<(), RuntimeException>> for &Interpreter {
                 }
             },
             // If we don't have an initializer, we set the variable equal to nil.
-            None => LoxNil::new(),
+            None => LoxObject::Nil,
         };
 
         self.environment.define(&stmt.name.lexeme, value);
@@ -331,11 +1315,11 @@ impl StmtVisitor<Result<(), RuntimeException>> for &Interpreter {
         };
 
         match condition {
-            true => match self.execute(stmt.then_branch.clone()) {
+            true => match self.execute(&stmt.then_branch) {
                 Err(runtime_error) => Err(runtime_error),
                 _ => Ok(()),
             },
-            false => match stmt.else_branch.clone() {
+            false => match &stmt.else_branch {
                 Some(stmt) => match self.execute(stmt) {
                     Err(runtime_error) => Err(runtime_error),
                     _ => Ok(()),
@@ -352,24 +1336,31 @@ impl StmtVisitor<Result<(), RuntimeException>> for &Interpreter {
             // If we can't evaluate the truthiness of the condition, we'll return.
             Err(runtime_error) => return Err(runtime_error),
         } {
-            match self.execute(stmt.body.clone()) {
+            match self.execute(&stmt.body) {
                 Err(runtime_error) => return Err(runtime_error),
                 _ => {}
             }
+            // Each time around the loop is a step, counted as it loops back
+            // (like the VM's `Loop` instruction).
+            self.step(&stmt.keyword)?;
         }
 
         Ok(())
     }
 
     fn visit_fun_stmt(&self, stmt: &crate::stmt::FunStmt) -> Result<(), RuntimeException> {
-        let function = LoxFunction::new(stmt);
+        let function = LoxFunction::new(stmt, self.current_file(), self.environment.capture());
         self.environment.define(&stmt.name.lexeme, function);
         Ok(())
     }
 
     fn visit_return_stmt(&self, stmt: &crate::stmt::ReturnStmt) -> Result<(), RuntimeException> {
-        let is_null = stmt.value.name() == "Literal"
-            && stmt.value.clone().downcast_rc::<Literal>().unwrap().value == TokenLiteral::None;
+        let is_null = matches!(
+            &*stmt.value,
+            Expr::Literal(Literal {
+                value: TokenLiteral::None
+            })
+        );
 
         let value = if !is_null {
             match self.evaluate(&stmt.value) {
@@ -384,29 +1375,993 @@ impl StmtVisitor<Result<(), RuntimeException>> for &Interpreter {
                 _ => {
                     return Err(RuntimeError::new(
                         stmt.keyword.clone(),
-                        "Cannot use nested return values.",
+                        messages::NESTED_RETURN,
                     ))
                 }
             }
         } else {
-            LoxNil::new()
+            LoxObject::Nil
         };
 
         // This is the successful code path, but we have to wrap it with an
         // exception so we can unwind.
         Err(ReturnException::new(value))
     }
+
+    fn visit_defer_stmt(&self, stmt: &crate::stmt::DeferStmt) -> Result<(), RuntimeException> {
+        if let Some(frame) = self.deferred.borrow_mut().last_mut() {
+            frame.push(stmt.body.clone());
+        }
+        Ok(())
+    }
+
+    fn visit_measure_stmt(&self, stmt: &crate::stmt::MeasureStmt) -> Result<(), RuntimeException> {
+        let start = Instant::now();
+        self.execute_block(&stmt.body)?;
+
+        // Timings go through the replay log, so replayed runs print the same.
+        let elapsed = self
+            .replay
+            .number("measure", || start.elapsed().as_secs_f64() * 1000.0);
+        match elapsed {
+            Ok(elapsed) => {
+                self.output.print(&format!(
+                    "measure (line {}): {:.3} ms",
+                    stmt.keyword.line, elapsed
+                ));
+                Ok(())
+            }
+            Err(message) => Err(RuntimeError::new(stmt.keyword.clone(), message)),
+        }
+    }
 }
 
 /// Native Clock Function
 fn native_clock(
-    _interpreter: &Interpreter,
+    context: &NativeContext,
     _args: Vec<LoxObject>,
 ) -> Result<LoxObject, RuntimeException> {
-    Ok(LoxNumber::new(
+    let now = context.interpreter.replay.number("clock", || {
         std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
-            .as_secs_f64(),
-    ))
+            .as_secs_f64()
+    });
+
+    match now {
+        Ok(now) => Ok(LoxObject::Number(now)),
+        Err(message) => Err(RuntimeError::new(context.paren.clone(), message)),
+    }
+}
+
+/// Native Print Function
+fn native_print(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    context
+        .interpreter
+        .output
+        .print(&context.interpreter.stringify(&args[0]));
+    Ok(LoxObject::Nil)
+}
+
+/// Native Debug Function
+fn native_debug(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    context.interpreter.output.print(&args[0].inspect());
+    Ok(LoxObject::Nil)
+}
+
+/// Native Timeit Function: calls a function a number of times, and returns
+/// the average time per call in milliseconds.
+fn native_timeit(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let (function, iterations) = (&args[0], &args[1]);
+    if !function.is_callable() || !function.arity().accepts(0) {
+        return Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::TIMEIT_EXPECTS_FUNCTION,
+        ));
+    }
+    let iterations = match iterations.as_number(context.paren) {
+        Ok(iterations) if iterations >= 1.0 && iterations.fract() == 0.0 => iterations,
+        _ => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::TIMEIT_EXPECTS_ITERATIONS,
+            ))
+        }
+    };
+
+    let start = Instant::now();
+    for _ in 0..iterations as u64 {
+        context.check()?;
+        function.call(context.interpreter, context.paren, vec![])?;
+    }
+
+    let per_call = context.interpreter.replay.number("timeit", || {
+        start.elapsed().as_secs_f64() * 1000.0 / iterations
+    });
+    match per_call {
+        Ok(per_call) => Ok(LoxObject::Number(per_call)),
+        Err(message) => Err(RuntimeError::new(context.paren.clone(), message)),
+    }
+}
+
+/// Native Dofile Function: reads, parses, and runs another script in the
+/// global environment. Relative paths are resolved against the working
+/// directory. A top-level `return` in the script ends it early, and its value
+/// is returned from `dofile`.
+fn native_dofile(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let path = match args[0].as_string(context.paren) {
+        Ok(path) => path,
+        Err(_) => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::DOFILE_EXPECTS_PATH,
+            ))
+        }
+    };
+    let file = context.require(Capability::Read, &path)?;
+    let source = match fs::read_to_string(&file) {
+        Ok(source) => source,
+        Err(error) => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::UNABLE_TO_READ_FILE.with(&[&path, &error.to_string()]),
+            ))
+        }
+    };
+
+    // Scan and parse into a separate manager, so its diagnostics can be
+    // attributed to the included file before they're reported.
+    let errors = Rc::new(ErrorManager::new());
+    let tracer = context.interpreter.tracer();
+    let (tokens, pragmas) = Scanner::new(&source, errors.clone())
+        .with_tracer(tracer.clone())
+        .scan_with_pragmas();
+    let statements = Parser::new(tokens, errors.clone())
+        .with_tracer(tracer.clone())
+        .parse();
+    let statements = context.interpreter.transform(statements);
+    Resolver::new(context.interpreter)
+        .report_to(errors.clone())
+        .allowing_top_level_return()
+        .with_pragmas(pragmas)
+        .resolve(&statements);
+    for diagnostic in errors.diagnostics() {
+        context
+            .interpreter
+            .errors
+            .emit(diagnostic.in_file(Some(path.clone())));
+    }
+    if errors.has_errors() {
+        return Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::DOFILE_HAS_ERRORS.with(&[&path]),
+        ));
+    }
+
+    let caller_file = context.interpreter.swap_file(Some(path));
+    // A fresh stack on top of the globals, so the script's top-level
+    // declarations are global even if dofile is called inside a function.
+    let scope = context.interpreter.environment.function_scope();
+    let result = context.interpreter.execute_deferring(&statements);
+    drop(scope);
+    context.interpreter.swap_file(caller_file);
+
+    match result {
+        Ok(_) => Ok(LoxObject::Nil),
+        Err(RuntimeException::ReturnException(return_exception)) => Ok(return_exception.value),
+        Err(runtime_error) => Err(runtime_error),
+    }
+}
+
+// A number of decimal places, if the value is a whole number from 0 to
+// MAX_PRECISION.
+fn precision(value: &LoxObject, paren: &Token) -> Option<usize> {
+    match value.as_number(paren) {
+        Ok(places) if places >= 0.0 && places <= MAX_PRECISION as f64 && places.fract() == 0.0 => {
+            Some(places as usize)
+        }
+        _ => None,
+    }
+}
+
+/// Native Set Precision Function: sets how many decimal places printed numbers
+/// get, and returns the previous setting so it can be restored.
+fn native_set_precision(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    match precision(&args[0], context.paren) {
+        Some(places) => Ok(LoxObject::Number(
+            context.interpreter.precision.replace(places) as f64,
+        )),
+        None => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::EXPECT_PRECISION.with(&["set_precision", &MAX_PRECISION.to_string()]),
+        )),
+    }
+}
+
+/// Native Format Number Function: formats a number as a string with the given
+/// number of decimal places, whatever the print precision is.
+fn native_format_number(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let number = match args[0].as_number(context.paren) {
+        Ok(number) => number,
+        Err(_) => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::FORMAT_NUMBER_EXPECTS_NUMBER,
+            ))
+        }
+    };
+    match precision(&args[1], context.paren) {
+        Some(places) => Ok(LoxObject::String(format!("{:.*}", places, number).into())),
+        None => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::EXPECT_PRECISION.with(&["format_number", &MAX_PRECISION.to_string()]),
+        )),
+    }
+}
+
+/// Native Trim Function: removes whitespace from both ends of a string.
+fn native_trim(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    match args[0].as_string(context.paren) {
+        Ok(string) => Ok(LoxObject::String(string.trim().into())),
+        Err(_) => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::EXPECT_STRING.with(&["trim"]),
+        )),
+    }
+}
+
+/// Native Pad Left Function: pads the start of a string with a character (a
+/// space if none is given) until it's at least the given number of
+/// characters long.
+fn native_pad_left(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    match padding("pad_left", context, &args) {
+        Ok((string, padding)) => Ok(LoxObject::String((padding + &string).into())),
+        Err(runtime_error) => Err(runtime_error),
+    }
+}
+
+/// Native Pad Right Function: pads the end of a string with a character (a
+/// space if none is given) until it's at least the given number of characters
+/// long.
+fn native_pad_right(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    match padding("pad_right", context, &args) {
+        Ok((string, padding)) => Ok(LoxObject::String((string + &padding).into())),
+        Err(runtime_error) => Err(runtime_error),
+    }
+}
+
+/// Native Split Function: a new list of the parts of a string between each
+/// occurrence of a separator. An empty separator is an error, since it would
+/// split between every character (or nowhere).
+fn native_split(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let paren = context.paren;
+    let (string, separator) = match (args[0].as_string(paren), args[1].as_string(paren)) {
+        (Ok(string), Ok(separator)) => (string, separator),
+        _ => {
+            return Err(RuntimeError::new(
+                paren.clone(),
+                messages::EXPECT_STRING.with(&["split"]),
+            ))
+        }
+    };
+    if separator.is_empty() {
+        return Err(RuntimeError::new(
+            paren.clone(),
+            messages::EMPTY_SEPARATOR.with(&["split"]),
+        ));
+    }
+
+    let parts: Vec<LoxObject> = string
+        .split(separator.as_str())
+        .map(|part| LoxObject::String(part.into()))
+        .collect();
+    context
+        .interpreter
+        .check_collection_size(paren, parts.len())?;
+    Ok(LoxList::new(parts))
+}
+
+/// Native Join Function: a list's elements as `print` would show them, with a
+/// separator between each one.
+fn native_join(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let paren = context.paren;
+    let list = match &args[0] {
+        LoxObject::List(list) => list,
+        _ => {
+            return Err(RuntimeError::new(
+                paren.clone(),
+                messages::EXPECT_LIST.with(&["join"]),
+            ))
+        }
+    };
+    let separator = match args[1].as_string(paren) {
+        Ok(separator) => separator,
+        Err(_) => {
+            return Err(RuntimeError::new(
+                paren.clone(),
+                messages::EXPECT_STRING.with(&["join"]),
+            ))
+        }
+    };
+
+    let parts: Vec<String> = list
+        .borrow()
+        .0
+        .iter()
+        .map(|element| context.interpreter.stringify(element))
+        .collect();
+    let joined = parts.join(&separator);
+    context
+        .interpreter
+        .check_string_length(paren, joined.len())?;
+    Ok(LoxObject::String(joined.into()))
+}
+
+// Checks the (string, width, optional character) arguments to a pad
+// function, and returns the string along with the padding it needs.
+fn padding(
+    name: &str,
+    context: &NativeContext,
+    args: &[LoxObject],
+) -> Result<(String, String), RuntimeException> {
+    let paren = context.paren;
+    let (string, width) = (&args[0], &args[1]);
+    let string = match string.as_string(paren) {
+        Ok(string) => string,
+        Err(_) => {
+            return Err(RuntimeError::new(
+                paren.clone(),
+                messages::EXPECT_STRING.with(&[name]),
+            ))
+        }
+    };
+    let width = match width.as_number(paren) {
+        Ok(width) if width >= 0.0 && width.fract() == 0.0 => width,
+        _ => {
+            return Err(RuntimeError::new(
+                paren.clone(),
+                messages::PAD_EXPECTS_WIDTH.with(&[name]),
+            ))
+        }
+    };
+    let character = match args.get(2).map(|character| character.as_string(paren)) {
+        None => " ".to_string(),
+        Some(Ok(character)) if character.chars().count() == 1 => character,
+        _ => {
+            return Err(RuntimeError::new(
+                paren.clone(),
+                messages::PAD_EXPECTS_CHARACTER.with(&[name]),
+            ))
+        }
+    };
+
+    let missing = (width as usize).saturating_sub(string.chars().count());
+    let length = string.len() + character.len() * missing;
+    context.interpreter.check_string_length(paren, length)?;
+    Ok((string, character.repeat(missing)))
+}
+
+// The character classes match what Lox's own scanner accepts, so a tokenizer
+// written in Lox splits source the same way.
+
+/// Native Is Digit Function: whether a character is 0 to 9.
+fn native_is_digit(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    character_class("is_digit", context.paren, &args, |c| c.is_ascii_digit())
+}
+
+/// Native Is Alpha Function: whether a character can start an identifier – a
+/// to z, A to Z, or an underscore.
+fn native_is_alpha(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    character_class("is_alpha", context.paren, &args, |c| {
+        c.is_ascii_alphabetic() || c == '_'
+    })
+}
+
+/// Native Is Space Function: whether a character is whitespace – a space, tab,
+/// carriage return, or newline.
+fn native_is_space(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    character_class("is_space", context.paren, &args, |c| {
+        matches!(c, ' ' | '\t' | '\r' | '\n')
+    })
+}
+
+/// Native Is Upper Function: whether a character is A to Z.
+fn native_is_upper(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    character_class("is_upper", context.paren, &args, |c| c.is_ascii_uppercase())
+}
+
+/// Native Is Lower Function: whether a character is a to z.
+fn native_is_lower(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    character_class("is_lower", context.paren, &args, |c| c.is_ascii_lowercase())
+}
+
+// Checks that the argument is a single character, and tests it.
+fn character_class(
+    name: &str,
+    paren: &Token,
+    args: &[LoxObject],
+    test: fn(char) -> bool,
+) -> Result<LoxObject, RuntimeException> {
+    match args[0].as_string(paren) {
+        Ok(string) if string.chars().count() == 1 => {
+            Ok(LoxObject::Boolean(test(string.chars().next().unwrap())))
+        }
+        _ => Err(RuntimeError::new(
+            paren.clone(),
+            messages::EXPECT_CHARACTER.with(&[name]),
+        )),
+    }
+}
+
+/// Native Hash Function: a stable hash of a nil, boolean, number, string, or
+/// bytes, from 0 to 2^32 - 1.
+fn native_hash(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    match hash::hash(&args[0]) {
+        Some(hash) => Ok(LoxObject::Number(hash as f64)),
+        None => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::UNHASHABLE_VALUE.with(&[args[0].stringify()]),
+        )),
+    }
+}
+
+/// Native Read Bytes Function: reads a whole file as bytes.
+fn native_read_bytes(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let path = match args[0].as_string(context.paren) {
+        Ok(path) => path,
+        Err(_) => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::EXPECT_PATH.with(&["read_bytes"]),
+            ))
+        }
+    };
+    let file = context.require(Capability::Read, &path)?;
+    match fs::read(&file) {
+        Ok(bytes) => Ok(LoxObject::Bytes(bytes.into())),
+        Err(error) => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::UNABLE_TO_READ_FILE.with(&[&path, &error.to_string()]),
+        )),
+    }
+}
+
+/// Native Write Bytes Function: writes bytes to a file, replacing anything
+/// that was in it.
+fn native_write_bytes(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let path = match args[0].as_string(context.paren) {
+        Ok(path) => path,
+        Err(_) => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::EXPECT_PATH.with(&["write_bytes"]),
+            ))
+        }
+    };
+    let bytes = match args[1].as_bytes(context.paren) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::EXPECT_BYTES.with(&["write_bytes"]),
+            ))
+        }
+    };
+    let file = context.require(Capability::Write, &path)?;
+    match fs::write(&file, bytes) {
+        Ok(_) => Ok(LoxObject::Nil),
+        Err(error) => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::UNABLE_TO_WRITE_FILE.with(&[&path, &error.to_string()]),
+        )),
+    }
+}
+
+/// Native Byte At Function: the byte at an index, from 0 to 255.
+fn native_byte_at(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let (bytes, index) = (&args[0], &args[1]);
+    let bytes = match bytes.as_bytes(context.paren) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::EXPECT_BYTES.with(&["byte_at"]),
+            ))
+        }
+    };
+    let byte = match index.as_number(context.paren) {
+        Ok(index) if index >= 0.0 && index.fract() == 0.0 => bytes.get(index as usize),
+        _ => None,
+    };
+    match byte {
+        Some(byte) => Ok(LoxObject::Number(*byte as f64)),
+        None => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::BYTE_INDEX_OUT_OF_RANGE.with(&[index.stringify(), bytes.len().to_string()]),
+        )),
+    }
+}
+
+/// Native Len Function: how many characters a string has, how many bytes a
+/// bytes value has, how many elements a list has, or how many keys a map has.
+fn native_len(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let len = match &args[0] {
+        LoxObject::String(string) => string.chars().count(),
+        LoxObject::Bytes(bytes) => bytes.len(),
+        LoxObject::List(list) => list.borrow().0.len(),
+        LoxObject::Map(map) => map.borrow().len(),
+        _ => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::LEN_EXPECTS_SEQUENCE,
+            ))
+        }
+    };
+    Ok(LoxObject::Number(len as f64))
+}
+
+/// Native Append Function: adds a value to the end of a list, returning nil.
+fn native_append(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let (list, value) = (&args[0], args[1].clone());
+    let list = match list {
+        LoxObject::List(list) => list,
+        _ => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::EXPECT_LIST.with(&["append"]),
+            ))
+        }
+    };
+    let length = list.borrow().0.len();
+    context
+        .interpreter
+        .check_collection_size(context.paren, length + 1)?;
+    list.borrow_mut().0.push(value);
+    Ok(LoxObject::Nil)
+}
+
+/// Native Keys Function: a new list of a map's keys, in the order they were
+/// added, for looping over the map.
+fn native_keys(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    match &args[0] {
+        LoxObject::Map(map) => Ok(LoxList::new(map.borrow().keys())),
+        _ => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::EXPECT_MAP.with(&["keys"]),
+        )),
+    }
+}
+
+/// Native Has Function: whether a map has a key. A value that can't be a key
+/// is never in the map.
+fn native_has(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    match &args[0] {
+        LoxObject::Map(map) => Ok(LoxObject::Boolean(map.borrow().get(&args[1]).is_some())),
+        _ => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::EXPECT_MAP.with(&["has"]),
+        )),
+    }
+}
+
+// The elements of the list argument to a list native, copied so the list
+// can be changed (e.g. by a comparator) while they're worked on.
+fn list_elements(
+    name: &str,
+    context: &NativeContext,
+    list: &LoxObject,
+) -> Result<Vec<LoxObject>, RuntimeException> {
+    match list {
+        LoxObject::List(list) => Ok(list.borrow().0.clone()),
+        _ => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::EXPECT_LIST.with(&[name]),
+        )),
+    }
+}
+
+/// Native Sort Function: a new list of a list's elements in ascending order.
+/// They have to be all numbers or all strings, which are compared the way `<`
+/// compares them.
+fn native_sort(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let mut elements = list_elements("sort", context, &args[0])?;
+    let numbers = elements
+        .iter()
+        .all(|element| matches!(element, LoxObject::Number(_)));
+    let strings = elements
+        .iter()
+        .all(|element| matches!(element, LoxObject::String(_)));
+    if !numbers && !strings {
+        return Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::SORT_EXPECTS_LIKE_ELEMENTS,
+        ));
+    }
+
+    elements.sort_by(|a, b| match (a, b) {
+        (LoxObject::Number(a), LoxObject::Number(b)) => a.total_cmp(b),
+        (LoxObject::String(a), LoxObject::String(b)) => a.cmp(b),
+        _ => unreachable!(),
+    });
+    Ok(LoxList::new(elements))
+}
+
+/// Native Sort By Function: a new list of a list's elements ordered by a Lox
+/// function comparing two of them. The sort is stable, so elements the
+/// function says are equal keep their order. The first error the function
+/// raises stops the sort.
+fn native_sort_by(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let mut elements = list_elements("sort_by", context, &args[0])?;
+    let compare = &args[1];
+    if !compare.is_callable() || !compare.arity().accepts(2) {
+        return Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::SORT_BY_EXPECTS_FUNCTION,
+        ));
+    }
+
+    let mut error = None;
+    elements.sort_by(|a, b| {
+        if error.is_some() {
+            return cmp::Ordering::Equal;
+        }
+        let order = context.check().and_then(|_| {
+            compare.call(
+                context.interpreter,
+                context.paren,
+                vec![a.clone(), b.clone()],
+            )
+        });
+        match order {
+            Ok(LoxObject::Number(order)) if order < 0.0 => cmp::Ordering::Less,
+            Ok(LoxObject::Number(order)) if order > 0.0 => cmp::Ordering::Greater,
+            Ok(LoxObject::Number(_)) => cmp::Ordering::Equal,
+            Ok(order) => {
+                error = Some(RuntimeError::new(
+                    context.paren.clone(),
+                    messages::SORT_BY_EXPECTS_NUMBER.with(&[order.describe_type()]),
+                ));
+                cmp::Ordering::Equal
+            }
+            Err(runtime_error) => {
+                error = Some(runtime_error);
+                cmp::Ordering::Equal
+            }
+        }
+    });
+    match error {
+        Some(runtime_error) => Err(runtime_error),
+        None => Ok(LoxList::new(elements)),
+    }
+}
+
+/// Native Reverse Function: a new list of a list's elements in reverse order.
+fn native_reverse(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let mut elements = list_elements("reverse", context, &args[0])?;
+    elements.reverse();
+    Ok(LoxList::new(elements))
+}
+
+/// Native Unique Function: a new list of a list's elements, leaving out any
+/// that are equal (==) to an earlier one.
+fn native_unique(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let mut unique: Vec<LoxObject> = vec![];
+    for element in list_elements("unique", context, &args[0])? {
+        if !unique.contains(&element) {
+            unique.push(element);
+        }
+    }
+    Ok(LoxList::new(unique))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        capabilities::Capability, exceptions::RuntimeException, function::Arity, golden,
+        object::LoxObject, output::CapturedOutput, replay::Replay, session::Session,
+    };
+
+    #[test]
+    fn lists_and_calls_natives() {
+        let session = Session::without_prelude();
+        let natives = session.interpreter.natives();
+        let names: Vec<&str> = natives.iter().map(|native| native.name.as_str()).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+        assert!(natives.iter().all(|native| !native.doc.is_empty()));
+        let len = natives.iter().find(|native| native.name == "len").unwrap();
+        assert_eq!(len.arity, Arity::exactly(1));
+
+        let value = session
+            .interpreter
+            .call_native("len", vec![LoxObject::String("abc".into())]);
+        assert!(matches!(value, Ok(LoxObject::Number(n)) if n == 3.0));
+        for (name, arguments, code) in [("len", vec![], "E027"), ("nope", vec![], "E040")] {
+            match session.interpreter.call_native(name, arguments) {
+                Err(RuntimeException::RuntimeError(error)) => {
+                    assert_eq!(error.message.code(), code)
+                }
+                _ => panic!("expected {} calling {}", code, name),
+            }
+        }
+    }
+
+    #[test]
+    fn hosts_define_documented_natives() {
+        let mut session = Session::without_prelude();
+        let output = Rc::new(CapturedOutput::new());
+        session.interpreter.output = output.clone();
+        session
+            .interpreter
+            .define_native("answer", 0, "The answer.", |_, _| {
+                Ok(LoxObject::Number(42.0))
+            });
+        session.run(&"print answer();".to_string());
+        assert_eq!(output.lines(), vec!["42.00"]);
+
+        // Defining it again replaces it, in the list too.
+        session
+            .interpreter
+            .define_native("answer", 1, "The answer, again.", |_, args| {
+                Ok(args[0].clone())
+            });
+        let answers: Vec<String> = session
+            .interpreter
+            .natives()
+            .into_iter()
+            .filter(|native| native.name == "answer")
+            .map(|native| format!("{} {}", native.arity, native.doc))
+            .collect();
+        assert_eq!(answers, vec!["1 The answer, again."]);
+    }
+
+    // Everything the script prints, followed by any diagnostics.
+    #[test]
+    fn golden() {
+        golden::check_suite("interpreter", |source| {
+            let mut session = Session::new();
+            let output = Rc::new(CapturedOutput::new());
+            session.interpreter.output = output.clone();
+            session.run(&source.to_string());
+
+            let mut lines = output.lines();
+            lines.extend(session.errors.messages());
+            golden::lines(lines)
+        });
+    }
+
+    #[test]
+    fn measure_prints_elapsed_time() {
+        let mut session = Session::new();
+        let output = Rc::new(CapturedOutput::new());
+        session.interpreter.output = output.clone();
+        session.interpreter.replay =
+            Replay::from_json(r#"{"events": [{"kind": "measure", "value": 1.5}]}"#).unwrap();
+        session.run(&"measure {\n  print 1;\n}".to_string());
+
+        assert_eq!(
+            output.lines(),
+            vec!["1.00".to_string(), "measure (line 1): 1.500 ms".to_string()]
+        );
+    }
+
+    #[test]
+    fn natives_only_touch_files_their_capabilities_allow() {
+        let mut session = Session::new();
+        let output = Rc::new(CapturedOutput::new());
+        session.interpreter.output = output.clone();
+        session
+            .interpreter
+            .capabilities
+            .allow(Capability::Read, "test/golden/interpreter/bytes");
+        session.run(&concat!(
+            "print read_bytes(\"test/golden/interpreter/bytes/small.bin\");\n",
+            "write_bytes(\"test/golden/interpreter/bytes/copy.bin\", read_bytes(\"test/golden/interpreter/bytes/small.bin\"));",
+        ).to_string());
+        assert_eq!(output.lines(), vec!["<bytes 5: 00 01 7f 80 ff>"]);
+        assert_eq!(
+            session.errors.messages(),
+            vec!["[line 2] Error[E057] ()): Not allowed to write \"test/golden/interpreter/bytes/copy.bin\".\n    in <native fn> called on line 2"]
+        );
+
+        session.errors.clear();
+        session.run(&"dofile(\"test/golden/interpreter/dofile.lox\");".to_string());
+        assert_eq!(
+            session.errors.messages(),
+            vec!["[line 1] Error[E057] ()): Not allowed to read \"test/golden/interpreter/dofile.lox\".\n    in <native fn> called on line 1"]
+        );
+    }
+
+    // Runs each source in turn in one session, like lines typed into the
+    // REPL, returning what was printed.
+    fn run_lines(sources: &[&str]) -> Vec<String> {
+        let mut session = Session::new();
+        let output = Rc::new(CapturedOutput::new());
+        session.interpreter.output = output.clone();
+        for source in sources {
+            session.run(&source.to_string());
+            session.errors.clear();
+        }
+        output.lines()
+    }
+
+    #[test]
+    fn write_bytes_round_trips_read_bytes() {
+        let path = std::env::temp_dir().join(format!("lox-bytes-{}.bin", std::process::id()));
+        let path = path.to_string_lossy().replace('\\', "/");
+        let lines = run_lines(&[&format!(
+            "write_bytes(\"{0}\", read_bytes(\"test/golden/interpreter/bytes/small.bin\"));\nprint read_bytes(\"{0}\");",
+            path
+        )]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lines, vec!["<bytes 5: 00 01 7f 80 ff>"]);
+    }
+
+    #[test]
+    fn errors_in_nested_blocks_exit_their_scopes() {
+        let lines = run_lines(&[
+            "var a = \"global\";",
+            "{ var a = \"outer\"; { var a = \"inner\"; -a; } }",
+            "print a;",
+        ]);
+        assert_eq!(lines, vec!["global".to_string()]);
+    }
+
+    #[test]
+    fn errors_in_functions_called_from_blocks_exit_their_scopes() {
+        let lines = run_lines(&[
+            "var a = \"global\";",
+            "fun fail() { { var a = \"body\"; return -a; } }",
+            "{ var a = \"block\"; { fail(); } }",
+            "print a;",
+            "{ var a = \"next\"; print a; }",
+            "print a;",
+        ]);
+        assert_eq!(
+            lines,
+            vec![
+                "global".to_string(),
+                "next".to_string(),
+                "global".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn returns_from_nested_blocks_exit_their_scopes() {
+        let lines = run_lines(&[
+            "fun find() { { var a = \"found\"; { return a; } } }",
+            "var a = \"global\";",
+            "print find();",
+            "print a;",
+        ]);
+        assert_eq!(lines, vec!["found".to_string(), "global".to_string()]);
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    extern crate test;
+
+    use test::Bencher;
+
+    use crate::session::Session;
+
+    // Time for the source to run (with `cargo +nightly bench`).
+    fn run(b: &mut Bencher, source: &str) {
+        let session = Session::without_prelude();
+        let statements = session.parse(&source.to_string());
+        b.iter(|| session.interpreter.interpret(&statements));
+        assert!(!session.errors.has_errors());
+    }
+
+    #[bench]
+    fn call_functions(b: &mut Bencher) {
+        run(
+            b,
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\nfib(15);",
+        );
+    }
+
+    #[bench]
+    fn call_natives(b: &mut Bencher) {
+        run(
+            b,
+            "for (var i = 0; i < 1000; i = i + 1) { abs(i); len(\"abc\"); }",
+        );
+    }
+    #[bench]
+    fn arithmetic(b: &mut Bencher) {
+        run(
+            b,
+            "var sum = 0;\nfor (var i = 0; i < 1000; i = i + 1) { sum = sum + i * 2 - 1; }",
+        );
+    }
 }
@@ -0,0 +1,177 @@
+//! A tree-walking interpreter (and a bytecode VM) for Lox, from
+//! [Crafting Interpreters](https://craftinginterpreters.com/), as a library
+//! that other Rust programs can embed. `jlox` is a command line interface over
+//! it.
+//!
+//! `Lox` runs source with the defaults `jlox` uses. For more control, a
+//! `Session` exposes its interpreter and diagnostics, and the stages
+//! (`Scanner`, `Parser`, `Resolver`, `Interpreter`) can be driven one at a
+//! time.
+#![feature(once_cell)]
+#![feature(box_into_inner)]
+#![cfg_attr(test, feature(test))]
+pub mod ast_printer;
+pub mod benchmark;
+pub mod capabilities;
+pub mod compiler;
+pub mod const_eval;
+pub mod difftest;
+pub mod effects;
+pub mod environment;
+pub mod errors;
+pub mod exceptions;
+pub mod expr;
+pub mod function;
+pub mod generator;
+#[cfg(test)]
+pub mod golden;
+pub mod hash;
+pub mod hooks;
+pub mod interpreter;
+pub mod limits;
+pub mod messages;
+pub mod object;
+pub mod output;
+pub mod parser;
+pub mod profiler;
+pub mod replay;
+pub mod resolver;
+pub mod sarif;
+pub mod scanner;
+pub mod semantic_tokens;
+pub mod session;
+pub mod shared_traits;
+pub mod source;
+pub mod stdlib;
+pub mod stmt;
+pub mod test_runner;
+pub mod token;
+pub mod token_type;
+pub mod trace;
+pub mod transform;
+pub mod tutorial;
+pub mod vm;
+
+use std::{error::Error, fmt};
+
+pub use errors::{Diagnostic, ErrorManager};
+pub use interpreter::Interpreter;
+pub use parser::Parser;
+pub use resolver::Resolver;
+pub use scanner::Scanner;
+pub use session::{Session, SessionManager};
+
+/// Runs Lox source. Each `Lox` has its own globals, which persist from one
+/// `run` to the next, so definitions made by earlier source can be used by
+/// later source.
+pub struct Lox {
+    pub session: Session,
+}
+
+impl Lox {
+    /// A fresh interpreter, with the prelude defined, that prints to stdout.
+    pub fn new() -> Self {
+        Self {
+            session: Session::new(),
+        }
+    }
+
+    /// Runs the source, or returns the diagnostics that stopped it: errors
+    /// found before running stop it from running at all, and a runtime error
+    /// stops it where it happened. Warnings alone don't make it fail.
+    pub fn run(&self, source: &str) -> Result<(), RunError> {
+        let errors = &self.session.errors;
+        errors.clear();
+        self.session.run(&source.to_string());
+        if errors.has_errors() || errors.has_runtime_error() {
+            return Err(RunError {
+                diagnostics: errors.diagnostics(),
+                messages: errors.messages(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Why `Lox::run` failed.
+#[derive(Debug)]
+pub struct RunError {
+    /// Everything the source was reported for, in the order it was reported.
+    pub diagnostics: Vec<Diagnostic>,
+    /// The diagnostics as `jlox` prints them, e.g.
+    /// `[line 1] Error[E002] at "=": Expect variable name.`
+    pub messages: Vec<String>,
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.messages.join("\n"))
+    }
+}
+
+impl Error for RunError {}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::Lox;
+    use crate::output::CapturedOutput;
+
+    #[test]
+    fn runs_source_keeping_globals_between_runs() {
+        let mut lox = Lox::new();
+        let output = Rc::new(CapturedOutput::new());
+        lox.session.interpreter.output = output.clone();
+
+        assert!(lox.run("var greeting = \"hi\";").is_ok());
+        assert!(lox.run("print greeting;").is_ok());
+        assert_eq!(output.lines(), vec!["hi"]);
+
+        let error = lox.run("var = 1;").unwrap_err();
+        assert_eq!(error.diagnostics.len(), 1);
+        assert_eq!(
+            error.to_string(),
+            "[line 1] Error[E002] at \"=\": Expect variable name."
+        );
+        // A failed run doesn't stop the next one.
+        let error = lox.run("print greeting - 1;").unwrap_err();
+        assert_eq!(error.diagnostics[0].message.code(), "E024");
+        assert!(lox.run("print greeting;").is_ok());
+    }
+
+    #[test]
+    fn source_with_errors_does_not_run_at_all() {
+        let mut lox = Lox::new();
+        let output = Rc::new(CapturedOutput::new());
+        lox.session.interpreter.output = output.clone();
+
+        let error = lox.run("print \"ran\"; var = 1;").unwrap_err();
+        assert_eq!(error.diagnostics[0].message.code(), "E002");
+        assert!(output.lines().is_empty());
+    }
+
+    #[test]
+    fn suppressions_only_apply_to_the_run_they_are_in() {
+        let mut lox = Lox::new();
+        lox.session.lints.short_circuit_side_effects = true;
+        let errors = lox.session.errors.clone();
+
+        assert!(lox
+            .run("var a = 1;\n// lox: ignore-next-line\nprint a or (a = 2);")
+            .is_ok());
+        assert!(errors.messages().is_empty());
+
+        assert!(lox.run("var b = 1;\nprint b;\nprint b or (b = 2);").is_ok());
+        assert_eq!(
+            errors.messages(),
+            vec!["[line 3] Warning[E078] at \"b\": This assignment only happens if the left side of 'or' doesn't decide the result."]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+/// How big the strings and collections a script makes can get, and how deep
+/// and how long it can run, so a host can keep an untrusted script (say, one
+/// doubling a string in a loop, or recursing forever) from using up its
+/// memory or crashing it. Going over a limit is a runtime error, at the
+/// operator, loop, or call that would have gone over it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The most bytes (of UTF-8) a string can have, or None for no limit.
+    pub max_string_length: Option<usize>,
+    /// The most elements a list, or entries a map, can have, or None for no
+    /// limit.
+    pub max_collection_size: Option<usize>,
+    /// The most calls to Lox functions that can be running at once, or None
+    /// for no limit. The tree-walker recurses on the host's stack, so without
+    /// a limit a script recursing too deeply crashes the host.
+    pub max_call_depth: Option<usize>,
+    /// The most steps (loop iterations and calls) the interpreter takes, over
+    /// everything it runs, or None for no limit.
+    pub max_steps: Option<u64>,
+}
+
+/// How deep calls can go by default. Each call takes a few kilobytes of the
+/// host's stack in a debug build, so this fits in a 2 MiB thread's stack
+/// with room to spare.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 256;
+
+impl Limits {
+    /// No limits at all, not even on call depth, so a script recursing too
+    /// deeply crashes the host.
+    pub fn unlimited() -> Self {
+        Self {
+            max_string_length: None,
+            max_collection_size: None,
+            max_call_depth: None,
+            max_steps: None,
+        }
+    }
+}
+
+impl Default for Limits {
+    /// What scripts run with by default: calls can't go deeper than
+    /// `DEFAULT_MAX_CALL_DEPTH`, and nothing else is limited.
+    fn default() -> Self {
+        Self {
+            max_call_depth: Some(DEFAULT_MAX_CALL_DEPTH),
+            ..Self::unlimited()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::Limits;
+    use crate::{
+        output::CapturedOutput,
+        session::{Backend, Session},
+    };
+
+    // What the script prints, then its errors, with the limits, on each
+    // backend.
+    fn run(source: &str, limits: Limits) -> Vec<Vec<String>> {
+        [Backend::TreeWalker, Backend::Vm]
+            .into_iter()
+            .map(|backend| {
+                let mut session = Session::new();
+                session.backend = backend;
+                session.interpreter.limits = limits;
+                let output = Rc::new(CapturedOutput::new());
+                session.interpreter.output = output.clone();
+                session.run(&source.to_string());
+                let mut lines = output.lines();
+                lines.extend(session.errors.messages());
+                lines
+            })
+            .collect()
+    }
+
+    #[test]
+    fn strings_stop_growing_at_the_limit() {
+        let limits = Limits {
+            max_string_length: Some(16),
+            ..Limits::default()
+        };
+        let doubling = "var s = \"ab\";\nwhile (true) {\n  s = s + s;\n  print len(s);\n}";
+        for lines in run(doubling, limits) {
+            assert_eq!(
+                lines,
+                vec![
+                    "4.00",
+                    "8.00",
+                    "16.00",
+                    "[line 3] Error[E073] (+): Strings can't be longer than 16 bytes, but this one would be 32."
+                ]
+            );
+        }
+
+        for lines in run("print pad_left(\"x\", 20, \" \");", limits) {
+            assert_eq!(
+                lines,
+                vec!["[line 1] Error[E073] ()): Strings can't be longer than 16 bytes, but this one would be 20.\n    in <native fn> called on line 1"]
+            );
+        }
+    }
+
+    #[test]
+    fn collections_stop_growing_at_the_limit() {
+        let limits = Limits {
+            max_collection_size: Some(3),
+            ..Limits::default()
+        };
+        for lines in run(
+            "var l = [1, 2];\nappend(l, 3);\nprint l;\nappend(l, 4);",
+            limits,
+        ) {
+            assert_eq!(
+                lines,
+                vec![
+                    "[1.00, 2.00, 3.00]",
+                    "[line 4] Error[E074] ()): Lists and maps can't have more than 3 entries.\n    in <native fn> called on line 4"
+                ]
+            );
+        }
+
+        // Replacing a map's value doesn't add an entry.
+        let source = "var m = {1: 1, 2: 2, 3: 3};\nm[3] = 4;\nprint m;\nm[4] = 4;";
+        for lines in run(source, limits) {
+            assert_eq!(
+                lines,
+                vec![
+                    "{1.00: 1.00, 2.00: 2.00, 3.00: 4.00}",
+                    "[line 4] Error[E074] (]): Lists and maps can't have more than 3 entries."
+                ]
+            );
+        }
+
+        for lines in run("print [1, 2, 3, 4];", limits) {
+            assert_eq!(
+                lines,
+                vec!["[line 1] Error[E074] ([): Lists and maps can't have more than 3 entries."]
+            );
+        }
+    }
+
+    #[test]
+    fn runaway_recursion_is_a_stack_overflow() {
+        // Deep enough to crash the host without the default limit.
+        let source = "fun f(n) {\n  return f(n + 1);\n}\nf(0);";
+        for lines in run(source, Limits::default()) {
+            assert_eq!(lines.len(), 1);
+            assert!(
+                lines[0].starts_with("[line 2] Error[E077] ()): Stack overflow."),
+                "{}",
+                lines[0]
+            );
+        }
+
+        let limits = Limits {
+            max_call_depth: Some(3),
+            ..Limits::default()
+        };
+        let source = "fun depth(n) {\n  print n;\n  return depth(n + 1);\n}\ndepth(1);";
+        for lines in run(source, limits) {
+            assert_eq!(lines[..3], ["1.00", "2.00", "3.00"]);
+            assert!(lines[3].starts_with("[line 3] Error[E077]"), "{}", lines[3]);
+        }
+
+        // Calls that return don't count against the limit.
+        let source = "fun one() { return 1; }\nvar i = 0;\nwhile (i < 10) i = i + one();\nprint i;";
+        for lines in run(source, limits) {
+            assert_eq!(lines, vec!["10.00"]);
+        }
+    }
+
+    #[test]
+    fn scripts_stop_after_their_steps() {
+        let limits = Limits {
+            max_steps: Some(3),
+            ..Limits::default()
+        };
+        let source = "var i = 0;\nwhile (true) {\n  print i;\n  i = i + 1;\n}";
+        // The loop is stopped as it comes around for the fourth time.
+        for lines in run(source, limits) {
+            assert_eq!(
+                lines,
+                vec![
+                    "0.00",
+                    "1.00",
+                    "2.00",
+                    "3.00",
+                    "[line 2] Error[E055] (while): The script was stopped because it took more than its limit of steps."
+                ]
+            );
+        }
+    }
+}
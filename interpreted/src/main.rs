@@ -1,78 +1,625 @@
-#![feature(once_cell)]
-#![feature(box_into_inner)]
-mod ast_printer;
-mod environment;
-mod errors;
-mod exceptions;
-mod expr;
-mod function;
-mod interpreter;
-mod object;
-mod parser;
-mod scanner;
-mod shared_traits;
-mod stmt;
-mod token;
-mod token_type;
-use std::{env, fs, io};
-
-use interpreter::Interpreter;
-use parser::Parser;
-use scanner::Scanner;
+use std::{
+    env, fs,
+    io::{self, IsTerminal, Read},
+    path::Path,
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "trace")]
+use lox_interpreted::trace;
+use lox_interpreted::{
+    ast_printer::{AstPrinter, SourcePrinter},
+    benchmark,
+    capabilities::Capability,
+    difftest, generator, messages,
+    profiler::Profiler,
+    replay::Replay,
+    resolver::ScopeTree,
+    sarif, session,
+    session::{Backend, Session},
+    source,
+    stmt::Statement,
+    test_runner,
+    token::TokenLiteral,
+    tutorial,
+};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        l if l > 2 => {
-            println!("Usage: jlox [script]");
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(|arg| arg.as_str()) {
+        Some("gen") => {
+            args.next();
+            return gen(args);
+        }
+        Some("explain") => {
+            args.next();
+            return explain(args);
+        }
+        Some("builtins") => {
+            args.next();
+            return match args.next() {
+                Some(_) => print_usage(),
+                None => print_builtins_reference(),
+            };
+        }
+        Some("difftest") => {
+            args.next();
+            return difftest(args);
+        }
+        Some("test") => {
+            args.next();
+            return test(args);
+        }
+        Some("tutorial") => {
+            args.next();
+            return match args.next() {
+                Some(_) => print_usage(),
+                None => tutorial::run(io::stdin().lock()),
+            };
+        }
+        _ => {}
+    }
+
+    let mut script = None;
+    let mut record = None;
+    let mut explain_slow = None;
+    let mut bench = None;
+    let mut replay = None;
+    let mut timeout = None;
+    let mut sarif = false;
+    let mut scopes = false;
+    let mut dump_desugared = false;
+    let mut check = false;
+    let mut print_ast = false;
+    let mut tokens = false;
+    let mut lossy_utf8 = false;
+
+    // The prelude is loaded when the session is made, so this has to be known
+    // before any of the flags that configure it are handled.
+    let args: Vec<String> = args.collect();
+    let mut session = match args.iter().any(|arg| arg == "--no-prelude") {
+        true => Session::without_prelude(),
+        false => Session::new(),
+    };
+    let mut args = args.into_iter().peekable();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--full-trace" => session.errors.set_full_trace(true),
+            "--max-errors" => match args.next().and_then(|max| max.parse().ok()) {
+                Some(max_errors) => session.errors.set_max_errors(max_errors),
+                None => return print_usage(),
+            },
+            "--explain-slow" => {
+                // The number of entries to show is optional.
+                let top = args.next_if(|top| top.parse::<usize>().is_ok());
+                explain_slow = Some(top.map_or(DEFAULT_EXPLAIN_SLOW, |top| top.parse().unwrap()));
+            }
+            "--bench" => {
+                // So is the number of runs.
+                let runs = args.next_if(|runs| runs.parse::<usize>().is_ok());
+                bench = Some(runs.map_or(benchmark::DEFAULT_RUNS, |runs| runs.parse().unwrap()));
+            }
+            "--timeout" => match args.next().and_then(|seconds| seconds.parse().ok()) {
+                Some(seconds) => timeout = Some(Duration::from_secs_f64(seconds)),
+                None => return print_usage(),
+            },
+            // Any of these restricts files to the directories they allow.
+            "--allow-read" => match args.next() {
+                Some(dir) => session
+                    .interpreter
+                    .capabilities
+                    .allow(Capability::Read, dir),
+                None => return print_usage(),
+            },
+            "--allow-write" => match args.next() {
+                Some(dir) => session
+                    .interpreter
+                    .capabilities
+                    .allow(Capability::Write, dir),
+                None => return print_usage(),
+            },
+            "--max-string-length" => match args.next().and_then(|max| max.parse().ok()) {
+                Some(max) => session.interpreter.limits.max_string_length = Some(max),
+                None => return print_usage(),
+            },
+            "--max-collection-size" => match args.next().and_then(|max| max.parse().ok()) {
+                Some(max) => session.interpreter.limits.max_collection_size = Some(max),
+                None => return print_usage(),
+            },
+            "--max-call-depth" => match args.next().and_then(|max| max.parse().ok()) {
+                Some(max) => session.interpreter.limits.max_call_depth = Some(max),
+                None => return print_usage(),
+            },
+            "--max-steps" => match args.next().and_then(|max| max.parse().ok()) {
+                Some(max) => session.interpreter.limits.max_steps = Some(max),
+                None => return print_usage(),
+            },
+            #[cfg(feature = "trace")]
+            "--trace" => match args.next() {
+                Some(path) => match trace::JsonLinesLogger::create(&path) {
+                    Ok(logger) => session
+                        .interpreter
+                        .set_tracer(trace::Tracer::new(Rc::new(logger))),
+                    Err(error) => {
+                        println!("Unable to write trace to \"{}\": {}", path, error);
+                        std::process::exit(74);
+                    }
+                },
+                None => return print_usage(),
+            },
+            "--record" => match args.next() {
+                Some(path) => record = Some(path),
+                None => return print_usage(),
+            },
+            "--replay" => match args.next() {
+                Some(path) => replay = Some(path),
+                None => return print_usage(),
+            },
+            "--scopes" => scopes = true,
+            "--dump-desugared" => dump_desugared = true,
+            "--check" => check = true,
+            "--print-ast" => print_ast = true,
+            "--tokens" => tokens = true,
+            "--lossy-utf8" => lossy_utf8 = true,
+            "--no-prelude" => {}
+            "--help" => return print_usage(),
+            "--error-format=human" => sarif = false,
+            "--error-format=sarif" => sarif = true,
+            "--backend=tree-walker" => session.backend = Backend::TreeWalker,
+            "--backend=vm" => session.backend = Backend::Vm,
+            "--lint=short-circuit" => session.lints.short_circuit_side_effects = true,
+            flag if flag.starts_with("--") => {
+                println!("Unknown flag \"{}\".", flag);
+                return print_usage();
+            }
+            _ => match script {
+                Some(_) => return print_usage(),
+                None => script = Some(arg),
+            },
+        }
+    }
+    // The profiler is driven by the tree-walker's hooks.
+    if explain_slow.is_some() && session.backend == Backend::Vm {
+        println!("--explain-slow only works with --backend=tree-walker.");
+        return print_usage();
+    }
+    if bench.is_some() && session.backend == Backend::Vm {
+        println!("--bench only works with --backend=tree-walker.");
+        return print_usage();
+    }
+    // The log goes to stdout, where a running script's output would break it.
+    if sarif && !check {
+        println!("--error-format=sarif only works with --check.");
+        return print_usage();
+    }
+
+    if record.is_some() {
+        session.interpreter.replay = Replay::recording();
+    } else if let Some(path) = &replay {
+        let trace = match fs::read_to_string(path) {
+            Ok(trace) => trace,
+            Err(error) => {
+                println!("Unable to read \"{}\": {}", path, error);
+                std::process::exit(66);
+            }
+        };
+        match Replay::from_json(&trace) {
+            Ok(replay) => session.interpreter.replay = replay,
+            Err(message) => {
+                println!("Unable to replay \"{}\": {}", path, message);
+                std::process::exit(66);
+            }
+        }
+    }
+
+    // The deadline covers the whole run, starting now.
+    session
+        .interpreter
+        .set_deadline(timeout.map(|timeout| Instant::now() + timeout));
+
+    if let Some(runs) = bench {
+        return run_benchmarks(script, &session, runs, lossy_utf8);
+    }
+
+    // With no script and input piped in, the input is the script.
+    let script = script.or_else(|| (!io::stdin().is_terminal()).then(|| STDIN.to_string()));
+    // Only a script's run is written out as a trace.
+    if record.is_some() && script.is_none() {
+        println!("--record needs a script; REPL sessions can't be recorded.");
+        std::process::exit(64);
+    }
+
+    match script {
+        Some(script) if tokens => print_tokens(&script, &session, lossy_utf8),
+        Some(script) if scopes => print_scopes(&script, &session, lossy_utf8),
+        Some(script) if dump_desugared => print_program(&script, &session, lossy_utf8, |program| {
+            SourcePrinter::program().print(program)
+        }),
+        Some(script) if print_ast => print_program(&script, &session, lossy_utf8, |program| {
+            AstPrinter.print(program)
+        }),
+        Some(script) if check => check_file(&script, &session, sarif, lossy_utf8),
+        Some(script) => {
+            let profiler = explain_slow.map(|top| {
+                let profiler = Rc::new(Profiler::new());
+                session.interpreter.add_hooks(profiler.clone());
+                (profiler, top)
+            });
+            run_file(&script, &session, record, profiler, lossy_utf8)
         }
-        l if l == 2 => run_file(&args[1]),
-        _ => run_prompt(),
+        None => run_prompt(&session),
     };
 }
 
-fn run_file(file_path_str: &String) {
-    errors::initialize_managed();
-    let file = fs::read_to_string(file_path_str).unwrap();
-    run(&file);
+// Times the script, or the built-in benchmark suite if there isn't one.
+fn run_benchmarks(script: Option<String>, session: &Session, runs: usize, lossy_utf8: bool) {
+    let measurements = match script {
+        Some(script) => {
+            let source = match read_script(&script, session, lossy_utf8) {
+                Some(source) => source,
+                None => {
+                    session.errors.print_all();
+                    std::process::exit(65);
+                }
+            };
+            benchmark::measure(&script, &source, runs).map(|measurement| vec![measurement])
+        }
+        None => benchmark::run_suite(runs),
+    };
+    match measurements {
+        Ok(measurements) => {
+            for measurement in measurements {
+                println!("{}", measurement);
+            }
+        }
+        Err(error) => {
+            println!("{}", error);
+            std::process::exit(70);
+        }
+    }
+}
+
+/// How many statements and functions `--explain-slow` shows by default.
+const DEFAULT_EXPLAIN_SLOW: usize = 5;
+
+fn print_usage() {
+    println!(
+        "Usage: jlox [--help] [--backend=tree-walker|vm] [--full-trace] [--max-errors N] [--error-format=human|sarif] [--lint=short-circuit] [--lossy-utf8] [--no-prelude] [--timeout SECONDS] [--allow-read DIR] [--allow-write DIR] [--max-string-length BYTES] [--max-collection-size N] [--max-call-depth N] [--max-steps N] [--explain-slow [N]] [--bench [RUNS]] [--tokens] [--scopes] [--dump-desugared] [--print-ast] [--check] [--record trace.json | --replay trace.json] [script | -]"
+    );
+    println!("       jlox gen --statements N [--seed S]");
+    println!("       jlox explain E001");
+    println!("       jlox builtins");
+    println!("       jlox test [--jobs N] [directory]");
+    println!("       jlox tutorial");
+    println!("       jlox difftest [--reference \"java -jar jlox.jar\" [--save]] [corpus]");
+}
+
+// Prints the extended explanation for an error code.
+fn explain(mut args: impl Iterator<Item = String>) {
+    let code = match (args.next(), args.next()) {
+        (Some(code), None) => code,
+        _ => return print_usage(),
+    };
+
+    match messages::find(&code) {
+        Some(id) => {
+            println!("{}: {}", id.code, id.template);
+            println!();
+            print!("{}", id.explanation);
+        }
+        None => {
+            println!("No explanation for error code \"{}\".", code);
+            std::process::exit(64);
+        }
+    }
+}
+
+// Prints the natives every session defines as a Markdown table, for the
+// standard library's documentation.
+fn print_builtins_reference() {
+    println!("| Function | Arguments | Description |");
+    println!("| --- | --- | --- |");
+    for native in Session::without_prelude().interpreter.natives() {
+        println!(
+            "| `{}` | {} | {} |",
+            native.name,
+            native.arity,
+            native.doc.replace('|', "\\|")
+        );
+    }
+}
+
+// Developer subcommand: prints a large, random-but-valid Lox program.
+fn gen(mut args: impl Iterator<Item = String>) {
+    let mut statements = None;
+    let mut seed = 0;
 
-    if errors::has_errors() || errors::has_runtime_error() {
-        errors::print_all();
-        let code = if errors::has_runtime_error() { 70 } else { 65 };
+    while let Some(arg) = args.next() {
+        let value = args.next().and_then(|value| value.parse::<u64>().ok());
+        match (arg.as_str(), value) {
+            ("--statements", Some(value)) => statements = Some(value as usize),
+            ("--seed", Some(value)) => seed = value,
+            _ => return print_usage(),
+        }
+    }
+
+    match statements {
+        Some(statements) => print!("{}", generator::generate(statements, seed)),
+        None => print_usage(),
+    }
+}
+
+// Developer subcommand: runs a corpus of scripts through this interpreter and a
+// reference implementation (or its recorded outputs), reporting any drift.
+fn difftest(mut args: impl Iterator<Item = String>) {
+    let mut reference = difftest::Reference::Recorded;
+    let mut save = false;
+    let mut corpus = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--reference" => match args.next() {
+                Some(command) if !command.trim().is_empty() => {
+                    let command = command.split_whitespace().map(String::from).collect();
+                    reference = difftest::Reference::Command(command);
+                }
+                _ => return print_usage(),
+            },
+            "--save" => save = true,
+            _ => match corpus {
+                Some(_) => return print_usage(),
+                None => corpus = Some(arg),
+            },
+        }
+    }
+    if save && matches!(reference, difftest::Reference::Recorded) {
+        return print_usage();
+    }
+
+    let corpus = corpus.unwrap_or_else(|| "test".to_string());
+    match difftest::run(Path::new(&corpus), &reference, save) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(error) => {
+            println!("Unable to run the differential test: {}", error);
+            std::process::exit(74);
+        }
+    }
+}
+
+// Runs the test files in a directory (`./test` by default), checking each
+// against its `.expected` file.
+fn test(mut args: impl Iterator<Item = String>) {
+    let mut jobs = thread::available_parallelism().map_or(1, |jobs| jobs.get());
+    let mut dir = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--jobs" => match args.next().and_then(|jobs| jobs.parse().ok()) {
+                Some(count) if count > 0 => jobs = count,
+                _ => return print_usage(),
+            },
+            _ => match dir {
+                Some(_) => return print_usage(),
+                None => dir = Some(arg),
+            },
+        }
+    }
+
+    let dir = dir.unwrap_or_else(|| "test".to_string());
+    match test_runner::run(Path::new(&dir), jobs) {
+        Ok(summary) if summary.failed == 0 => {}
+        Ok(_) => std::process::exit(1),
+        Err(error) => {
+            println!("Unable to run the tests: {}", error);
+            std::process::exit(74);
+        }
+    }
+}
+
+/// The script path that reads the script from stdin instead of a file.
+const STDIN: &str = "-";
+
+// The script's source, or None if it isn't valid UTF-8 (which is reported to
+// the session).
+fn read_script(file_path_str: &str, session: &Session, lossy_utf8: bool) -> Option<String> {
+    let bytes = match file_path_str {
+        STDIN => {
+            let mut bytes = vec![];
+            io::stdin().read_to_end(&mut bytes).unwrap();
+            bytes
+        }
+        path => fs::read(path).unwrap(),
+    };
+    source::decode(&bytes, lossy_utf8, &session.errors)
+}
+
+fn run_file(
+    file_path_str: &str,
+    session: &Session,
+    record: Option<String>,
+    profiler: Option<(Rc<Profiler>, usize)>,
+    lossy_utf8: bool,
+) {
+    if let Some(file) = read_script(file_path_str, session, lossy_utf8) {
+        session.run(&file);
+    }
+
+    if let Some((profiler, top)) = profiler {
+        for line in profiler.report(top) {
+            println!("{}", line);
+        }
+    }
+
+    // Write the trace out before exiting, so failing runs can be replayed too.
+    if let (Some(path), Some(trace)) = (record, session.interpreter.replay.to_json()) {
+        if let Err(error) = fs::write(&path, trace) {
+            println!("Unable to write trace \"{}\": {}", path, error);
+        }
+    }
+
+    let errors = &session.errors;
+    // Warnings are printed even when the run succeeds.
+    errors.print_all();
+    if errors.has_errors() || errors.has_runtime_error() {
+        let code = if errors.has_runtime_error() { 70 } else { 65 };
         std::process::exit(code);
     }
 }
 
-fn run_prompt() {
-    errors::initialize_immediate();
-    let mut line = get_user_input();
-    while line.is_ok() {
-        let v = line.unwrap();
-        match v.is_empty() {
-            true => {
-                line = get_user_input();
-                continue;
-            }
-            false => {
-                run(&v);
-                errors::reset_errors(); // don't want to crash our whole prompt
-                line = get_user_input();
+// Developer flag: prints the tokens the scanner makes of the script, one per
+// line, as "line:column Type "lexeme" literal", instead of running it.
+fn print_tokens(file_path_str: &str, session: &Session, lossy_utf8: bool) {
+    if let Some(file) = read_script(file_path_str, session, lossy_utf8) {
+        for token in session.tokens(&file) {
+            let literal = match &token.literal {
+                TokenLiteral::String(value) => format!("{:?}", value),
+                literal => literal.to_string(),
+            };
+            println!(
+                "{}:{} {} {:?} {}",
+                token.line, token.column, token.ty, token.lexeme, literal
+            );
+        }
+    }
+
+    let errors = &session.errors;
+    errors.print_all();
+    if errors.has_errors() {
+        std::process::exit(65);
+    }
+}
+
+// Developer flag: prints the resolver's scope tree for the script instead of
+// running it.
+fn print_scopes(file_path_str: &str, session: &Session, lossy_utf8: bool) {
+    if let Some(file) = read_script(file_path_str, session, lossy_utf8) {
+        for line in ScopeTree::render(&session.scopes(&file)) {
+            println!("{}", line);
+        }
+    }
+
+    let errors = &session.errors;
+    errors.print_all();
+    if errors.has_errors() {
+        std::process::exit(65);
+    }
+}
+
+// Developer flags: prints the script as the interpreter sees it after the
+// parser's desugaring (`for` loops become `while` loops, and so on), without
+// running it. `--dump-desugared` prints it back as Lox source, and
+// `--print-ast` as S-expressions.
+fn print_program(
+    file_path_str: &str,
+    session: &Session,
+    lossy_utf8: bool,
+    print: impl Fn(&[Statement]) -> String,
+) {
+    if let Some(file) = read_script(file_path_str, session, lossy_utf8) {
+        println!("{}", print(&session.parse(&file)));
+    }
+
+    let errors = &session.errors;
+    errors.print_all();
+    if errors.has_errors() {
+        std::process::exit(65);
+    }
+}
+
+// Checks the script for errors without running it: it's scanned, parsed, and
+// resolved, and the diagnostics are printed. Exits with 65 if there were any
+// errors, so it can be used as a linter.
+fn check_file(file_path_str: &str, session: &Session, sarif: bool, lossy_utf8: bool) {
+    if let Some(file) = read_script(file_path_str, session, lossy_utf8) {
+        session.parse(&file);
+    }
+
+    let errors = &session.errors;
+    if sarif {
+        print!("{}", sarif::render(errors, file_path_str));
+    } else {
+        errors.print_all();
+    }
+    if errors.has_errors() {
+        std::process::exit(65);
+    }
+}
+
+fn run_prompt(session: &Session) {
+    session.errors.set_immediate(true);
+    // The inputs that ran without errors, as a script, for `:save`.
+    let mut transcript = String::new();
+    let mut source = String::new();
+    while let Ok(line) = get_user_input() {
+        let at_end = line.is_empty();
+        if source.is_empty() && line.trim_start().starts_with(':') {
+            repl_command(session, line.trim(), &mut transcript);
+            continue;
+        }
+        source.push_str(&line);
+        // Keep reading until every bracket is closed.
+        if !at_end && session::is_incomplete(&source) {
+            continue;
+        }
+        if !source.trim().is_empty() {
+            if let Some(script) = session.run_repl(&source) {
+                transcript.push_str(&script);
             }
+            session.errors.reset_errors(); // don't want to crash our whole prompt
         }
+        if at_end {
+            break;
+        }
+        source.clear();
     }
 }
 
-fn run(source: &String) {
-    let scanner = Scanner::new(source);
-    let interpreter = Interpreter::new();
-    let tokens = scanner.scan_tokens();
-    // for token in &tokens {
-    //     println!("{:?}", token)
-    // }
-    let mut parser = Parser::new(tokens);
-    // If we were able to parse without errors, print the expression.
-    let statements = parser.parse();
-    interpreter.interpret(statements);
+// Runs a REPL command: `:save FILE` writes the transcript to the file, and
+// `:replay FILE` runs a file as if it had been typed in.
+fn repl_command(session: &Session, command: &str, transcript: &mut String) {
+    if command == ":builtins" {
+        return print_builtins(session);
+    }
+    match command
+        .split_once(' ')
+        .map(|(name, path)| (name, path.trim()))
+    {
+        Some((":save", path)) if !path.is_empty() => match fs::write(path, &*transcript) {
+            Ok(()) => println!("Saved the session to \"{}\".", path),
+            Err(error) => println!("Unable to write \"{}\": {}", path, error),
+        },
+        Some((":replay", path)) if !path.is_empty() => match fs::read_to_string(path) {
+            Ok(source) => {
+                if let Some(script) = session.run_repl(&source) {
+                    transcript.push_str(&script);
+                }
+                session.errors.reset_errors();
+            }
+            Err(error) => println!("Unable to read \"{}\": {}", path, error),
+        },
+        _ => println!(
+            "Unknown command \"{}\". Try :save FILE, :replay FILE, or :builtins.",
+            command
+        ),
+    }
+}
+
+// Lists the natives, with how many arguments each takes and what it does.
+fn print_builtins(session: &Session) {
+    let natives = session.interpreter.natives();
+    let width = natives
+        .iter()
+        .map(|native| native.name.len() + native.arity.to_string().len() + 2)
+        .max()
+        .unwrap_or(0);
+    for native in natives {
+        let signature = format!("{}({})", native.name, native.arity);
+        println!("{:<width$}  {}", signature, native.doc, width = width);
+    }
 }
 
 fn get_user_input() -> io::Result<String> {
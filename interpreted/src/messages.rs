@@ -0,0 +1,225 @@
+use std::{collections::HashMap, fmt};
+
+/// A user-facing diagnostic, identified by a stable code so it can be searched
+/// for, explained, and localized. `{}` placeholders in the template are filled
+/// in order with the message's arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageId {
+    pub code: &'static str,
+    pub template: &'static str,
+    /// A longer description of the error with an example, shown by
+    /// `jlox explain`. Stored in `explanations/<code>.md`.
+    pub explanation: &'static str,
+}
+
+impl MessageId {
+    /// Attaches the arguments for the template's `{}` placeholders.
+    pub fn with<T: ToString>(&'static self, args: &[T]) -> Message {
+        Message {
+            id: self,
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+}
+
+/// A diagnostic message along with its arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub id: &'static MessageId,
+    pub args: Box<[String]>,
+}
+
+impl Message {
+    pub fn code(&self) -> &'static str {
+        self.id.code
+    }
+
+    /// The message rendered with the built-in template.
+    pub fn text(&self) -> String {
+        fill(self.id.template, &self.args)
+    }
+}
+
+impl From<&'static MessageId> for Message {
+    fn from(id: &'static MessageId) -> Self {
+        Self {
+            id,
+            args: Box::new([]),
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text())
+    }
+}
+
+/// Replacement templates for some (or all) of the built-in messages, e.g. for
+/// an embedder that wants to show translated diagnostics.
+#[derive(Debug, Clone, Default)]
+pub struct MessageCatalog {
+    templates: HashMap<String, String>,
+}
+
+impl MessageCatalog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the template used for the message with the given code.
+    pub fn set(&mut self, code: impl Into<String>, template: impl Into<String>) {
+        self.templates.insert(code.into(), template.into());
+    }
+
+    /// Renders the message with this catalog's template for its code, falling
+    /// back to the built-in template.
+    pub fn render(&self, message: &Message) -> String {
+        match self.templates.get(message.code()) {
+            Some(template) => fill(template, &message.args),
+            None => message.text(),
+        }
+    }
+}
+
+/// Fills each `{}` in the template with the next argument. Placeholders
+/// without an argument are left as they are.
+fn fill(template: &str, args: &[String]) -> String {
+    let mut filled = String::new();
+    let mut args = args.iter();
+    let mut rest = template;
+    while let Some(idx) = rest.find("{}") {
+        filled.push_str(&rest[..idx]);
+        match args.next() {
+            Some(arg) => filled.push_str(arg),
+            None => filled.push_str("{}"),
+        }
+        rest = &rest[idx + 2..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Finds the built-in message with the given code (e.g. "E001").
+pub fn find(code: &str) -> Option<&'static MessageId> {
+    ALL.iter()
+        .copied()
+        .find(|id| id.code.eq_ignore_ascii_case(code))
+}
+
+macro_rules! messages {
+    ($($name:ident = $code:literal: $template:literal,)*) => {
+        $(pub const $name: &MessageId = &MessageId {
+            code: $code,
+            template: $template,
+            explanation: include_str!(concat!("explanations/", $code, ".md")),
+        };)*
+
+        /// Every built-in message, in code order.
+        pub const ALL: &[&MessageId] = &[$($name),*];
+    };
+}
+
+// Codes are never renumbered or reused, so new messages always take the next
+// free code, even if that puts them out of phase order.
+messages! {
+    // Scanning
+    UNEXPECTED_CHARACTER = "E001": "Unexpected character \"{}\".",
+
+    // Parsing
+    EXPECT_VARIABLE_NAME = "E002": "Expect variable name.",
+    EXPECT_SEMICOLON_AFTER_VARIABLE = "E003": "Expect ';' after variable declaration.",
+    EXPECT_LEFT_PAREN_AFTER_WHILE = "E004": "Expect '(' after 'while'.",
+    EXPECT_RIGHT_PAREN_AFTER_CONDITION = "E005": "Expect ')' after condition.",
+    EXPECT_LEFT_PAREN_AFTER_FOR = "E006": "Expect '(' after 'for'.",
+    EXPECT_SEMICOLON_AFTER_LOOP_CONDITION = "E007": "Expect ';' after loop condition.",
+    EXPECT_RIGHT_PAREN_AFTER_FOR_CLAUSES = "E008": "Expect ')' after for clauses.",
+    EXPECT_LEFT_PAREN_AFTER_IF = "E009": "Expect '(' after 'if'.",
+    EXPECT_RIGHT_PAREN_AFTER_IF_CONDITION = "E010": "Expect ')' after 'if' condition.",
+    EXPECT_SEMICOLON_AFTER_VALUE = "E011": "Expect ';' after value.",
+    EXPECT_SEMICOLON_AFTER_RETURN_VALUE = "E012": "Expect ';' after return value.",
+    EXPECT_FUNCTION_NAME = "E013": "Expect {} name.",
+    EXPECT_LEFT_PAREN_AFTER_FUNCTION_NAME = "E014": "Expect '(' after {} name.",
+    TOO_MANY_PARAMETERS = "E015": "Can't have more than 255 parameters.",
+    EXPECT_PARAMETER_NAME = "E016": "Expect parameter name.",
+    EXPECT_RIGHT_PAREN_AFTER_PARAMETERS = "E017": "Expect ')' after parameters.",
+    EXPECT_LEFT_BRACE_BEFORE_BODY = "E018": "Expect '{' before {} body.",
+    UNCLOSED_BLOCK = "E019": "Unclosed block: '{' opened at line {}.",
+    INVALID_ASSIGNMENT_TARGET = "E020": "Invalid assignment target.",
+    EXPECT_RIGHT_PAREN_AFTER_ARGUMENTS = "E021": "Expect ')' after arguments.",
+    EXPECT_RIGHT_PAREN_AFTER_EXPRESSION = "E022": "Expect ')' after expression.",
+    EXPECT_EXPRESSION = "E023": "Expect expression.",
+
+    // Runtime
+    OPERAND_MUST_BE_NUMBER = "E024": "Operand must be a number.",
+    OPERANDS_MUST_BE_NUMBERS_OR_STRINGS = "E025": "Operands must both be numbers or strings.",
+    NOT_CALLABLE = "E026": "'{}' is {}, not a function.",
+    WRONG_ARGUMENT_COUNT = "E027": "Expected {} arguments but got {} when calling {}.",
+    NESTED_RETURN = "E028": "Cannot use nested return values.",
+    UNABLE_TO_GET = "E029": "[internal] Unable to get '{}'.",
+    UNABLE_TO_ASSIGN = "E030": "[internal] Unable to assign '{}'.",
+
+    // Replay
+    REPLAY_LOG_UNAVAILABLE = "E031": "[internal] Unable to access the replay log.",
+    REPLAY_WRONG_INPUT = "E032": "Replay diverged: expected a '{}' input but the trace has '{}'.",
+    REPLAY_OUT_OF_INPUTS = "E033": "Replay diverged: the trace has no more inputs for '{}'.",
+
+    // Parsing
+    TOO_MANY_ARGUMENTS = "E034": "Can't have more than 255 arguments.",
+
+    // Runtime
+    TIMEIT_EXPECTS_FUNCTION = "E035": "timeit() expects a function that takes no arguments.",
+    TIMEIT_EXPECTS_ITERATIONS = "E036": "timeit() expects a positive whole number of iterations.",
+    DOFILE_EXPECTS_PATH = "E037": "dofile() expects a file path string.",
+    UNABLE_TO_READ_FILE = "E038": "Unable to read \"{}\": {}.",
+    DOFILE_HAS_ERRORS = "E039": "Unable to run \"{}\" because it has errors.",
+    UNDEFINED_VARIABLE = "E040": "Undefined variable '{}'.",
+    EXPECT_PRECISION = "E041": "{}() expects a whole number of decimal places from 0 to {}.",
+    FORMAT_NUMBER_EXPECTS_NUMBER = "E042": "format_number() expects a number to format.",
+    EXPECT_STRING = "E043": "{}() expects a string.",
+    PAD_EXPECTS_WIDTH = "E044": "{}() expects a whole number width.",
+    PAD_EXPECTS_CHARACTER = "E045": "{}() expects a single character to pad with.",
+    EXPECT_CHARACTER = "E046": "{}() expects a string holding a single character.",
+    UNHASHABLE_VALUE = "E047": "Can't hash {}: only nil, booleans, numbers, strings, and bytes can be hashed.",
+    EXPECT_PATH = "E048": "{}() expects a file path string.",
+    EXPECT_BYTES = "E049": "{}() expects bytes, e.g. from read_bytes().",
+    BYTE_INDEX_OUT_OF_RANGE = "E050": "Byte index {} is out of range for {} bytes.",
+    LEN_EXPECTS_SEQUENCE = "E051": "len() expects a string, bytes, or a list.",
+    UNABLE_TO_WRITE_FILE = "E052": "Unable to write \"{}\": {}.",
+    WRONG_VALUE_TYPE = "E053": "[internal] Expected a {} value but got a {}.",
+    UNSUPPORTED_BY_VM = "E054": "{} isn't supported by the vm backend yet.",
+    INTERRUPTED = "E055": "The script was stopped because {}.",
+    EXPECT_LEFT_PAREN_AFTER_FUN = "E056": "Expect '(' after 'fun'.",
+    NOT_ALLOWED = "E057": "Not allowed to {} \"{}\".",
+    NOT_INDEXABLE = "E058": "Can't index {}; only lists and maps can be indexed.",
+    LIST_INDEX_NOT_WHOLE = "E059": "A list index must be a whole number, not {}.",
+    LIST_INDEX_OUT_OF_RANGE = "E060": "Index {} is out of range for a list of length {}.",
+    EXPECT_LIST = "E061": "{}() expects a list.",
+    EXPECT_RIGHT_BRACKET_AFTER_ELEMENTS = "E062": "Expect ']' after list elements.",
+    EXPECT_RIGHT_BRACKET_AFTER_INDEX = "E063": "Expect ']' after index.",
+    EXPECT_MAP = "E064": "{}() expects a map.",
+    MISSING_KEY = "E065": "Key {} isn't in the map.",
+    EXPECT_COLON_AFTER_KEY = "E066": "Expect ':' after map key.",
+    EXPECT_RIGHT_BRACE_AFTER_ENTRIES = "E067": "Expect '}' after map entries.",
+    INVALID_UTF8 = "E068": "The file isn't valid UTF-8: invalid byte at offset {}.",
+    REPLACED_INVALID_UTF8 = "E069": "Replaced invalid UTF-8 with U+FFFD, starting at byte offset {}.",
+    EXPECT_NUMBER = "E070": "{}() expects a number.",
+    SUBSTR_EXPECTS_RANGE = "E071": "substr() expects a whole number start and length, neither below 0.",
+    SQRT_OF_NEGATIVE = "E072": "Can't take the square root of {}, which is negative.",
+    STRING_TOO_LONG = "E073": "Strings can't be longer than {} bytes, but this one would be {}.",
+    COLLECTION_TOO_LARGE = "E074": "Lists and maps can't have more than {} entries.",
+    RETURN_AT_TOP_LEVEL = "E075": "Can't return from top-level code.",
+    UNCLOSED_DELIMITER = "E076": "Unclosed '{}' opened at line {}.",
+    STACK_OVERFLOW = "E077": "Stack overflow.",
+    SHORT_CIRCUIT_SIDE_EFFECT = "E078": "This {} only happens if the left side of '{}' doesn't decide the result.",
+    DUPLICATE_KEY = "E079": "Key {} is already in the map.",
+    KEY_FIRST_USED = "E080": "Key {} is first given here.",
+    NO_PROPERTIES = "E081": "Only maps have properties, not {}.",
+    UNDEFINED_PROPERTY = "E082": "Undefined property '{}'.",
+    EXPECT_PROPERTY_NAME = "E083": "Expect property name after '.'.",
+    MISPLACED_PRAGMA = "E084": "'// {}' only applies at the top of a file, before any code.",
+    EMPTY_SEPARATOR = "E085": "{}() expects a separator that isn't empty.",
+    SORT_EXPECTS_LIKE_ELEMENTS = "E086": "sort() expects a list of only numbers or only strings.",
+    SORT_BY_EXPECTS_FUNCTION = "E087": "sort_by() expects a function taking the two values to compare.",
+    SORT_BY_EXPECTS_NUMBER = "E088": "sort_by() expects its function to return a number, not {}.",
+}
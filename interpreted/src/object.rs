@@ -1,126 +1,204 @@
-use std::{fmt::Debug, rc::Rc, sync::RwLock};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+    rc::Rc,
+};
 
 use downcast::{downcast, Any};
 
 use crate::{
-    exceptions::RuntimeException,
-    function::{LoxFunction, LoxNativeCallable},
+    exceptions::{RuntimeError, RuntimeException},
+    function::{Arity, LoxFunction},
+    hash,
     interpreter::Interpreter,
+    messages,
+    token::Token,
+    vm::VmClosure,
 };
 
-pub trait LoxObjectBase: Any + PrimitiveLoxObject + Debug {}
-pub trait PrimitiveLoxObject {
+/// A function, which is shared by reference. Each implementation
+/// implements `Display` with what `print` shows, and `Debug` with what
+/// identifies it.
+pub trait CallableLoxObject: Any + Debug + Display {
+    /// "Function" for functions declared in Lox, and "NativeCallable" for
+    /// natives.
     fn instance_name(&self) -> &'static str;
-}
 
-pub trait CallableLoxObject: Any + LoxObjectBase {
-    fn arity_self(&self) -> usize;
+    fn arity_self(&self) -> Arity;
+
+    /// A human readable description of the callable's signature, used when
+    /// reporting call errors (e.g. "fn add(a, b) declared on line 3").
+    fn signature_self(&self) -> String;
 
     fn call_self(
         &self,
         interpreter: &Interpreter,
+        paren: &Token,
         arguments: Vec<LoxObject>,
     ) -> Result<LoxObject, RuntimeException>;
 }
 
-downcast!(dyn LoxObjectBase);
 downcast!(dyn CallableLoxObject);
 
-#[derive(Debug, Clone)]
-pub struct LoxObject(pub Rc<RwLock<dyn LoxObjectBase>>);
+/// A Lox value. Nil, booleans, and numbers are held inline, and strings and
+/// bytes share their immutable contents between copies. Lists, maps, and
+/// functions are shared by reference, so every copy sees a change to one, and
+/// each is only equal to itself.
+#[derive(Clone)]
+pub enum LoxObject {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(Rc<str>),
+    /// An immutable run of bytes, e.g. the contents of a binary file.
+    Bytes(Rc<[u8]>),
+    List(Rc<RefCell<LoxList>>),
+    Map(Rc<RefCell<LoxMap>>),
+    Callable(Rc<dyn CallableLoxObject>),
+}
 
 impl LoxObject {
+    /// The name of the value's type, as error messages show it.
     pub fn instance_name(&self) -> &'static str {
-        if let Ok(v) = self.0.try_read() {
-            v.instance_name()
-        } else {
-            "Nil"
+        match self {
+            LoxObject::Nil => "Nil",
+            LoxObject::Boolean(_) => "Boolean",
+            LoxObject::Number(_) => "Number",
+            LoxObject::String(_) => "String",
+            LoxObject::Bytes(_) => "Bytes",
+            LoxObject::List(_) => "List",
+            LoxObject::Map(_) => "Map",
+            LoxObject::Callable(callable) => callable.instance_name(),
         }
     }
 
-    pub fn get_boolean(&self) -> bool {
-        if let Ok(val) = self.0.try_read() {
-            if let Ok(r) = val.downcast_ref::<LoxBoolean>() {
-                return r.0;
-            }
+    // The checked accessors report a RuntimeError at the token when the value
+    // isn't of the type. That error is an internal one, for when the
+    // interpreter is confused about a type; where a wrong type is the
+    // program's fault, callers replace it with an error that says why.
+
+    pub fn as_number(&self, token: &Token) -> Result<f64, RuntimeException> {
+        match self {
+            LoxObject::Number(value) => Ok(*value),
+            _ => Err(self.wrong_type(token, "Number")),
         }
-        false
     }
 
-    pub fn get_number(&self) -> f64 {
-        if let Ok(val) = self.0.try_read() {
-            if let Ok(r) = val.downcast_ref::<LoxNumber>() {
-                return r.0;
-            }
+    pub fn as_string(&self, token: &Token) -> Result<String, RuntimeException> {
+        match self {
+            LoxObject::String(value) => Ok(value.to_string()),
+            _ => Err(self.wrong_type(token, "String")),
         }
-        0.0
     }
 
-    pub fn get_string(&self) -> String {
-        if let Ok(val) = self.0.try_read() {
-            if let Ok(r) = val.downcast_ref::<LoxString>() {
-                return r.0.clone();
-            }
+    pub fn as_bytes(&self, token: &Token) -> Result<Rc<[u8]>, RuntimeException> {
+        match self {
+            LoxObject::Bytes(value) => Ok(value.clone()),
+            _ => Err(self.wrong_type(token, "Bytes")),
         }
-        "".to_string()
+    }
+
+    fn wrong_type(&self, token: &Token, expected: &str) -> RuntimeException {
+        RuntimeError::new(
+            token.clone(),
+            messages::WRONG_VALUE_TYPE.with(&[expected, self.instance_name()]),
+        )
     }
 
     pub fn is_truthy(&self) -> bool {
-        match self.instance_name() {
-            "Nil" => false,
-            "Boolean" => self.get_boolean(),
+        match self {
+            LoxObject::Nil => false,
+            LoxObject::Boolean(value) => *value,
             _ => true,
         }
     }
 
     pub fn stringify(&self) -> String {
-        match self.instance_name() {
-            "Nil" => "nil".to_string(),
-            "Number" => format!("{:.2}", self.get_number()),
-            "String" => self.get_string(),
-            "Boolean" => (if self.get_boolean() { "true" } else { "false" }).to_string(),
-            "NativeCallable" => "<native fn>".to_string(),
-            "Function" => {
-                if let Ok(fun_obj) = self.0.try_read() {
-                    if let Ok(fun_obj) = fun_obj.downcast_ref::<LoxFunction>() {
-                        return format!("<fn {}>", fun_obj.declaration.name.lexeme);
-                    }
-                }
+        self.to_string()
+    }
 
-                "<function>".to_string()
+    /// The internal representation of the value, including its type tag.
+    /// Unlike `stringify`, strings are quoted, numbers keep their full
+    /// precision, and functions show how many scopes deep their closure is.
+    pub fn inspect(&self) -> String {
+        match self {
+            LoxObject::Nil => "Nil".to_string(),
+            LoxObject::Number(value) => format!("Number({:?})", value),
+            LoxObject::String(value) => format!("String({:?})", value),
+            LoxObject::Boolean(value) => format!("Boolean({})", value),
+            LoxObject::Bytes(value) => format!("Bytes({:02x?})", value),
+            LoxObject::List(list) => {
+                let list = list.borrow();
+                let elements = list.nested(|| {
+                    let elements: Vec<String> =
+                        list.0.iter().map(|element| element.inspect()).collect();
+                    elements.join(", ")
+                });
+                format!("List([{}])", elements.unwrap_or("...".to_string()))
+            }
+            LoxObject::Map(map) => {
+                let map = map.borrow();
+                let entries = map.nested(|| {
+                    let entries: Vec<String> = map
+                        .entries
+                        .iter()
+                        .map(|(key, value)| format!("{}: {}", key.inspect(), value.inspect()))
+                        .collect();
+                    entries.join(", ")
+                });
+                format!("Map({{{}}})", entries.unwrap_or("...".to_string()))
+            }
+            LoxObject::Callable(callable) => {
+                if let Ok(function) = callable.downcast_ref::<LoxFunction>() {
+                    // The closure starts with the globals.
+                    return format!(
+                        "Function(name={}, arity={}, line={}, depth={})",
+                        function.declaration.name.lexeme,
+                        function.arity_self(),
+                        function.declaration.name.line,
+                        function.closure.len() - 1
+                    );
+                }
+                if let Ok(closure) = callable.downcast_ref::<VmClosure>() {
+                    return format!(
+                        "Function(name={}, arity={}, line={}, depth={})",
+                        closure.function.name,
+                        closure.arity_self(),
+                        closure.function.line,
+                        closure.function.depth
+                    );
+                }
+                format!("NativeCallable(arity={})", callable.arity_self())
             }
-            _ => unreachable!(),
         }
     }
 
-    pub fn is_callable(&self) -> bool {
-        match self.instance_name() {
-            "NativeCallable" => true,
-            "Function" => true,
-            _ => false,
+    /// What kind of value this is, for error messages, e.g. "a Number" or
+    /// "nil".
+    pub fn describe_type(&self) -> String {
+        match self {
+            LoxObject::Nil => "nil".to_string(),
+            LoxObject::Bytes(_) => "bytes".to_string(),
+            _ => format!("a {}", self.instance_name()),
         }
     }
 
-    pub fn arity(&self) -> usize {
-        match self.instance_name() {
-            "NativeCallable" => {
-                if let Ok(val) = self.0.try_read() {
-                    if let Ok(r) = val.downcast_ref::<LoxNativeCallable>() {
-                        return r.arity_self();
-                    }
-                }
+    pub fn is_callable(&self) -> bool {
+        matches!(self, LoxObject::Callable(_))
+    }
 
-                0
-            }
-            "Function" => {
-                if let Ok(val) = self.0.try_read() {
-                    if let Ok(r) = val.downcast_ref::<LoxFunction>() {
-                        return r.arity_self();
-                    }
-                }
+    pub fn arity(&self) -> Arity {
+        match self {
+            LoxObject::Callable(callable) => callable.arity_self(),
+            _ => unreachable!(),
+        }
+    }
 
-                0
-            }
+    pub fn signature(&self) -> String {
+        match self {
+            LoxObject::Callable(callable) => callable.signature_self(),
             _ => unreachable!(),
         }
     }
@@ -128,98 +206,272 @@ impl LoxObject {
     pub fn call(
         &self,
         interpreter: &Interpreter,
+        paren: &Token,
         arguments: Vec<LoxObject>,
     ) -> Result<LoxObject, RuntimeException> {
-        match self.instance_name() {
-            "NativeCallable" => {
-                if let Ok(val) = self.0.try_read() {
-                    if let Ok(r) = val.downcast_ref::<LoxNativeCallable>() {
-                        return r.call_self(interpreter, arguments);
-                    }
-                }
+        match self {
+            LoxObject::Callable(callable) => callable.call_self(interpreter, paren, arguments),
+            _ => unreachable!(),
+        }
+    }
+}
 
-                Ok(LoxNil::new())
-            }
-            "Function" => {
-                if let Ok(val) = self.0.try_read() {
-                    if let Ok(r) = val.downcast_ref::<LoxFunction>() {
-                        return r.call_self(interpreter, arguments);
-                    }
-                }
+/// How many bytes `print` shows before eliding the rest.
+const BYTES_PREVIEW: usize = 16;
 
-                Ok(LoxNil::new())
+/// Lox's `stringify` semantics, i.e. what `print` shows.
+impl Display for LoxObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxObject::Nil => write!(f, "nil"),
+            LoxObject::Boolean(value) => write!(f, "{}", value),
+            LoxObject::Number(value) => write!(f, "{:.2}", value),
+            LoxObject::String(value) => write!(f, "{}", value),
+            // "<bytes 3: 01 ab ff>", or "<bytes 1000: 00 01 ... 0f ...>".
+            LoxObject::Bytes(value) => {
+                write!(f, "<bytes {}:", value.len())?;
+                for byte in value.iter().take(BYTES_PREVIEW) {
+                    write!(f, " {:02x}", byte)?;
+                }
+                if value.len() > BYTES_PREVIEW {
+                    write!(f, " ...")?;
+                }
+                write!(f, ">")
             }
-            _ => unreachable!(),
+            LoxObject::List(list) => Display::fmt(&*list.borrow(), f),
+            LoxObject::Map(map) => Display::fmt(&*map.borrow(), f),
+            LoxObject::Callable(callable) => Display::fmt(callable, f),
         }
     }
 }
 
+impl Debug for LoxObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.inspect())
+    }
+}
+
 impl PartialEq for LoxObject {
     fn eq(&self, other: &Self) -> bool {
-        let self_ty = self.instance_name();
-        let other_ty = other.instance_name();
-        match (self_ty, other_ty) {
-            ("Nil", "Nil") => true,
-            ("Nil", _) => false,
-            ("Number", "Number") => self.get_number() == other.get_number(),
-            ("String", "String") => self.get_string() == other.get_string(),
+        match (self, other) {
+            (LoxObject::Nil, LoxObject::Nil) => true,
+            (LoxObject::Boolean(a), LoxObject::Boolean(b)) => a == b,
+            (LoxObject::Number(a), LoxObject::Number(b)) => a == b,
+            (LoxObject::String(a), LoxObject::String(b)) => a == b,
+            (LoxObject::Bytes(a), LoxObject::Bytes(b)) => a == b,
+            // Lists, maps, and functions are only equal to themselves.
+            (LoxObject::List(a), LoxObject::List(b)) => Rc::ptr_eq(a, b),
+            (LoxObject::Map(a), LoxObject::Map(b)) => Rc::ptr_eq(a, b),
+            (LoxObject::Callable(a), LoxObject::Callable(b)) => {
+                Rc::as_ptr(a) as *const () == Rc::as_ptr(b) as *const ()
+            }
             _ => false,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
-pub struct LoxBoolean(pub bool);
-impl LoxBoolean {
-    pub fn new(value: bool) -> LoxObject {
-        LoxObject(Rc::new(RwLock::new(LoxBoolean(value))))
+/// Numbers compare numerically and strings compare lexicographically (by
+/// code point). Any other pair of values is incomparable and returns None –
+/// natives that order values (like `sort`, `min`, and `max`) should turn that
+/// into a RuntimeError naming the offending types.
+impl PartialOrd for LoxObject {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (LoxObject::Number(a), LoxObject::Number(b)) => a.partial_cmp(b),
+            (LoxObject::String(a), LoxObject::String(b)) => Some(a.cmp(b)),
+            // Kept consistent with `eq`, so e.g. nil is equal to itself.
+            _ if self == other => Some(Ordering::Equal),
+            _ => None,
+        }
     }
 }
-impl LoxObjectBase for LoxBoolean {}
-impl PrimitiveLoxObject for LoxBoolean {
-    fn instance_name(&self) -> &'static str {
-        "Boolean"
+
+/// A growable list of values. Lists are shared by reference: assigning one, or
+/// passing it to a function, doesn't copy it, and it's only equal to itself.
+#[derive(Debug, Clone)]
+pub struct LoxList(pub Vec<LoxObject>);
+impl LoxList {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(elements: Vec<LoxObject>) -> LoxObject {
+        LoxObject::List(Rc::new(RefCell::new(LoxList(elements))))
     }
-}
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
-pub struct LoxNumber(pub f64);
-impl LoxNumber {
-    pub fn new(value: f64) -> LoxObject {
-        LoxObject(Rc::new(RwLock::new(LoxNumber(value))))
+    /// Runs `show` (which shows the elements) unless this list is already
+    /// being shown further out, i.e. it contains itself, in which case it
+    /// returns None.
+    pub fn nested<R>(&self, show: impl FnOnce() -> R) -> Option<R> {
+        nested(self as *const LoxList as *const (), show)
     }
 }
-impl LoxObjectBase for LoxNumber {}
-impl PrimitiveLoxObject for LoxNumber {
-    fn instance_name(&self) -> &'static str {
-        "Number"
+thread_local! {
+    // The lists and maps being shown, outermost first.
+    static SHOWING: RefCell<Vec<*const ()>> = const { RefCell::new(vec![]) };
+}
+// Runs `show` unless the container is already being shown further out.
+fn nested<R>(container: *const (), show: impl FnOnce() -> R) -> Option<R> {
+    if SHOWING.with(|showing| showing.borrow().contains(&container)) {
+        return None;
     }
+    SHOWING.with(|showing| showing.borrow_mut().push(container));
+    let shown = show();
+    SHOWING.with(|showing| showing.borrow_mut().pop());
+    Some(shown)
 }
-
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
-pub struct LoxString(pub String);
-impl LoxString {
-    pub fn new(value: String) -> LoxObject {
-        LoxObject(Rc::new(RwLock::new(LoxString(value))))
+/// How a value is shown inside a list or map: like `print` shows it, except
+/// that strings are quoted, so e.g. `"1"` and `1` can be told apart.
+pub fn show_element(element: &LoxObject) -> String {
+    match element {
+        LoxObject::String(string) => format!("{:?}", string),
+        _ => element.to_string(),
     }
 }
-impl LoxObjectBase for LoxString {}
-impl PrimitiveLoxObject for LoxString {
-    fn instance_name(&self) -> &'static str {
-        "String"
+impl Display for LoxList {
+    // `[1.00, "two", [3.00]]`. A list inside itself is shown as `[...]`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let elements = self.nested(|| {
+            let elements: Vec<String> = self.0.iter().map(show_element).collect();
+            elements.join(", ")
+        });
+        write!(f, "[{}]", elements.unwrap_or("...".to_string()))
     }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
-pub struct LoxNil;
-impl LoxNil {
+/// A map from keys to values, which keeps its keys in the order they were
+/// first added. Keys are compared with `==` and hashed like `hash()` does, so
+/// only values that can be hashed can be keys. Like lists, maps are shared by
+/// reference and only equal to themselves.
+#[derive(Debug, Clone, Default)]
+pub struct LoxMap {
+    entries: Vec<(LoxObject, LoxObject)>,
+    // The positions in `entries` of the keys with each hash.
+    buckets: HashMap<u32, Vec<usize>>,
+}
+impl LoxMap {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new() -> LoxObject {
-        LoxObject(Rc::new(RwLock::new(LoxNil)))
+        LoxObject::Map(Rc::new(RefCell::new(LoxMap::default())))
+    }
+
+    /// The value for the key, or None if the map doesn't have it.
+    pub fn get(&self, key: &LoxObject) -> Option<LoxObject> {
+        self.position(key)
+            .map(|position| self.entries[position].1.clone())
+    }
+
+    /// Sets the value for the key, adding the key if it's new. Returns false
+    /// (and leaves the map alone) if the key can't be hashed.
+    pub fn insert(&mut self, key: LoxObject, value: LoxObject) -> bool {
+        if let Some(position) = self.position(&key) {
+            self.entries[position].1 = value;
+            return true;
+        }
+        let hash = match hash::hash(&key) {
+            Some(hash) => hash,
+            None => return false,
+        };
+        self.buckets
+            .entry(hash)
+            .or_default()
+            .push(self.entries.len());
+        self.entries.push((key, value));
+        true
+    }
+
+    /// The keys, in the order they were added.
+    pub fn keys(&self) -> Vec<LoxObject> {
+        self.entries.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Like `LoxList::nested`.
+    pub fn nested<R>(&self, show: impl FnOnce() -> R) -> Option<R> {
+        nested(self as *const LoxMap as *const (), show)
+    }
+
+    fn position(&self, key: &LoxObject) -> Option<usize> {
+        let positions = self.buckets.get(&hash::hash(key)?)?;
+        positions
+            .iter()
+            .copied()
+            .find(|position| self.entries[*position].0 == *key)
     }
 }
-impl LoxObjectBase for LoxNil {}
-impl PrimitiveLoxObject for LoxNil {
-    fn instance_name(&self) -> &'static str {
-        "Nil"
+impl Display for LoxMap {
+    // `{"a": 1.00, 2.00: [3.00]}`. A map inside itself is shown as `{...}`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries = self.nested(|| {
+            let entries: Vec<String> = self
+                .entries
+                .iter()
+                .map(|(key, value)| format!("{}: {}", show_element(key), show_element(value)))
+                .collect();
+            entries.join(", ")
+        });
+        write!(f, "{{{}}}", entries.unwrap_or("...".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{messages::WRONG_VALUE_TYPE, token_type::TokenType};
+
+    fn token() -> Token {
+        Token::new(
+            TokenType::Plus,
+            "+",
+            crate::token::TokenLiteral::None,
+            7usize,
+        )
+    }
+
+    fn wrong_type(result: Result<impl Debug, RuntimeException>) -> Vec<String> {
+        match result {
+            Err(RuntimeException::RuntimeError(error)) => {
+                assert_eq!(error.message.id, WRONG_VALUE_TYPE);
+                assert_eq!(error.token.line, 7);
+                error.message.args.to_vec()
+            }
+            result => panic!("Expected a type error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn checked_accessors_return_values_of_their_type() {
+        assert_eq!(LoxObject::Number(1.5).as_number(&token()).unwrap(), 1.5);
+        assert_eq!(
+            LoxObject::String("lox".into()).as_string(&token()).unwrap(),
+            "lox"
+        );
+        assert_eq!(
+            &*LoxObject::Bytes(vec![1, 2].into())
+                .as_bytes(&token())
+                .unwrap(),
+            &[1, 2]
+        );
+    }
+
+    // These used to quietly return 0, "", and no bytes.
+    #[test]
+    fn checked_accessors_error_on_other_types() {
+        assert_eq!(
+            wrong_type(LoxObject::String("1".into()).as_number(&token())),
+            vec!["Number", "String"]
+        );
+        assert_eq!(
+            wrong_type(LoxObject::Nil.as_string(&token())),
+            vec!["String", "Nil"]
+        );
+        assert_eq!(
+            wrong_type(LoxObject::Boolean(true).as_bytes(&token())),
+            vec!["Bytes", "Boolean"]
+        );
     }
 }
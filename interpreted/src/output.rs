@@ -0,0 +1,39 @@
+use std::cell::RefCell;
+
+/// Where a script's output (from `print`, and natives like `debug`) goes.
+pub trait Output {
+    fn print(&self, text: &str);
+}
+
+/// Writes output to stdout.
+pub struct Stdout;
+
+impl Output for Stdout {
+    fn print(&self, text: &str) {
+        println!("{}", text);
+    }
+}
+
+/// Collects output in memory, e.g. to compare it against expected output in
+/// tests, or to send it somewhere other than stdout.
+#[derive(Debug, Default)]
+pub struct CapturedOutput {
+    lines: RefCell<Vec<String>>,
+}
+
+impl CapturedOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything printed so far, one entry per `print`.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.borrow().clone()
+    }
+}
+
+impl Output for CapturedOutput {
+    fn print(&self, text: &str) {
+        self.lines.borrow_mut().push(text.to_string());
+    }
+}
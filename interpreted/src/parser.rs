@@ -1,13 +1,21 @@
+use std::rc::Rc;
+
 use crate::{
-    errors,
-    expr::{Assign, Binary, Call, Expression, Grouping, Literal, Logical, Unary, Variable},
-    object::LoxNil,
+    const_eval,
+    errors::{Diagnostic, ErrorManager},
+    expr::{
+        Binary, Call, CompoundAssign, Expression, Function, Get, Grouping, Index, List, Literal,
+        Logical, Map, Unary, Variable,
+    },
+    messages::{self, Message},
+    object::show_element,
     stmt::{
-        BlockStmt, ExprStmt, FunStmt, IfStmt, PrintStmt, ReturnStmt, Statement, VariableStmt,
-        WhileStmt,
+        BlockStmt, DeferStmt, ExprStmt, FunStmt, IfStmt, MeasureStmt, PrintStmt, ReturnStmt,
+        Statement, VariableStmt, WhileStmt,
     },
     token::{Token, TokenLiteral},
     token_type::TokenType,
+    trace::{self, TraceEvent, Tracer},
 };
 
 #[derive(Debug)]
@@ -16,11 +24,30 @@ pub struct ParseError;
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    errors: Rc<ErrorManager>,
+    tracer: Tracer,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, current: 0 }
+    pub fn new(mut tokens: Vec<Token>, errors: Rc<ErrorManager>) -> Self {
+        // Parsing stops at the `Eof` token, so there must be one, even if the
+        // tokens came from somewhere other than `scan_tokens`.
+        if tokens.last().map(|token| token.ty) != Some(TokenType::Eof) {
+            let line = tokens.last().map_or(1, |token| token.line);
+            tokens.push(Token::new(TokenType::Eof, "", TokenLiteral::None, line));
+        }
+        Self {
+            tokens,
+            current: 0,
+            errors,
+            tracer: Tracer::default(),
+        }
+    }
+
+    /// Reports parse errors to the tracer, as well as to the `ErrorManager`.
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = tracer;
+        self
     }
 
     pub fn parse(&mut self) -> Vec<Statement> {
@@ -39,6 +66,15 @@ impl Parser {
         statements
     }
 
+    /// Parses the tokens as a single expression with nothing after it, like
+    /// `1 + 2` typed into the REPL. Returns None if they're anything else.
+    pub fn parse_expression(&mut self) -> Option<Expression> {
+        match self.expression() {
+            Ok(expression) if self.is_at_end() => Some(expression),
+            _ => None,
+        }
+    }
+
     // Parse an expression
     fn expression(&mut self) -> Result<Expression, ParseError> {
         self.assignment()
@@ -47,7 +83,10 @@ impl Parser {
     // Parse a declaration, and try to recover if possible using synchronize in
     // the case that we have a ParseError.
     fn declaration(&mut self) -> Option<Statement> {
-        let result = if self.matches(&[TokenType::Fun]) {
+        // Only a `fun` followed by a name declares a function. Anything else
+        // (like `fun (a) { ... }`) is left for the expression parser.
+        let result = if self.check(&TokenType::Fun) && self.check_next(&TokenType::Identifier) {
+            self.advance();
             self.function("function")
         } else if self.matches(&[TokenType::Var]) {
             self.var_declaration()
@@ -67,10 +106,9 @@ impl Parser {
 
     // Parse a variable declaration statement.
     fn var_declaration(&mut self) -> Result<Statement, ParseError> {
-        let name = match self.consume(TokenType::Identifier, "Expect variable name.") {
-            Ok(name) => name,
-            Err(parse_error) => return Err(parse_error),
-        };
+        let name = self
+            .consume(TokenType::Identifier, messages::EXPECT_VARIABLE_NAME)?
+            .clone();
 
         // An initializer statement is optional.
         let mut initializer = None;
@@ -85,7 +123,7 @@ impl Parser {
 
         if let Err(parse_error) = self.consume(
             TokenType::Semicolon,
-            "Expect ';' after variable declaration.",
+            messages::EXPECT_SEMICOLON_AFTER_VARIABLE,
         ) {
             return Err(parse_error);
         }
@@ -95,7 +133,11 @@ impl Parser {
 
     // Parse a while statement
     fn while_statement(&mut self) -> Result<Statement, ParseError> {
-        match self.consume(TokenType::LeftParen, "Expect '(' after 'while'.") {
+        let keyword = self.previous().clone();
+        match self.consume(
+            TokenType::LeftParen,
+            messages::EXPECT_LEFT_PAREN_AFTER_WHILE,
+        ) {
             Err(parse_error) => return Err(parse_error),
             _ => {}
         };
@@ -103,7 +145,10 @@ impl Parser {
             Ok(cond_expr) => cond_expr,
             Err(parse_error) => return Err(parse_error),
         };
-        match self.consume(TokenType::RightParen, "Expect ')' after condition.") {
+        match self.consume(
+            TokenType::RightParen,
+            messages::EXPECT_RIGHT_PAREN_AFTER_CONDITION,
+        ) {
             Err(parse_error) => return Err(parse_error),
             _ => {}
         };
@@ -112,7 +157,7 @@ impl Parser {
             Err(parse_error) => return Err(parse_error),
         };
 
-        Ok(WhileStmt::new(condition, body))
+        Ok(WhileStmt::new(keyword, condition, body))
     }
 
     // Parse a statement
@@ -132,6 +177,12 @@ impl Parser {
         if self.matches(&[TokenType::While]) {
             return self.while_statement();
         }
+        if self.matches(&[TokenType::Measure]) {
+            return self.measure_statement();
+        }
+        if self.matches(&[TokenType::Defer]) {
+            return self.defer_statement();
+        }
         if self.matches(&[TokenType::LeftBrace]) {
             return match self.block() {
                 Ok(statements) => Ok(BlockStmt::new(statements)),
@@ -143,7 +194,8 @@ impl Parser {
     }
 
     fn for_statement(&mut self) -> Result<Statement, ParseError> {
-        match self.consume(TokenType::LeftParen, "Expect '(' after 'for'.") {
+        let keyword = self.previous().clone();
+        match self.consume(TokenType::LeftParen, messages::EXPECT_LEFT_PAREN_AFTER_FOR) {
             Err(parse_error) => return Err(parse_error),
             _ => {}
         };
@@ -177,7 +229,10 @@ impl Parser {
         };
 
         // Make sure a semicolon exists after the loop condition.
-        match self.consume(TokenType::Semicolon, "Expect ';' after loop condition.") {
+        match self.consume(
+            TokenType::Semicolon,
+            messages::EXPECT_SEMICOLON_AFTER_LOOP_CONDITION,
+        ) {
             Err(parse_error) => return Err(parse_error),
             _ => {}
         };
@@ -193,7 +248,10 @@ impl Parser {
         };
 
         // Make sure a right paren exists after the increment's position.
-        match self.consume(TokenType::RightParen, "Expect ')' after for clauses.") {
+        match self.consume(
+            TokenType::RightParen,
+            messages::EXPECT_RIGHT_PAREN_AFTER_FOR_CLAUSES,
+        ) {
             Err(parse_error) => return Err(parse_error),
             _ => {}
         };
@@ -215,9 +273,9 @@ impl Parser {
         // Set the body to a while statement. If no condition was specified, set
         // the condition to 'True'.
         if let Some(condition) = condition {
-            body = WhileStmt::new(condition, body);
+            body = WhileStmt::new(keyword, condition, body);
         } else {
-            body = WhileStmt::new(Literal::new(TokenLiteral::True), body);
+            body = WhileStmt::new(keyword, Literal::new(TokenLiteral::True), body);
         };
 
         // If we have an initializer, it should run once before the entire loop.
@@ -230,16 +288,19 @@ impl Parser {
 
     // Parse an 'if' statement
     fn if_statement(&mut self) -> Result<Statement, ParseError> {
-        if let Err(parse_error) = self.consume(TokenType::LeftParen, "Expect '(' after 'if'.") {
+        if let Err(parse_error) =
+            self.consume(TokenType::LeftParen, messages::EXPECT_LEFT_PAREN_AFTER_IF)
+        {
             return Err(parse_error);
         }
         let condition = match self.expression() {
             Ok(expr) => expr,
             Err(parse_error) => return Err(parse_error),
         };
-        if let Err(parse_error) =
-            self.consume(TokenType::RightParen, "Expect ')' after 'if' condition.")
-        {
+        if let Err(parse_error) = self.consume(
+            TokenType::RightParen,
+            messages::EXPECT_RIGHT_PAREN_AFTER_IF_CONDITION,
+        ) {
             return Err(parse_error);
         }
         let then_branch = match self.statement() {
@@ -257,25 +318,45 @@ impl Parser {
         Ok(IfStmt::new(condition, then_branch, else_branch))
     }
 
+    // Parse a measure statement, which times the block after it.
+    fn measure_statement(&mut self) -> Result<Statement, ParseError> {
+        let keyword = self.previous().clone();
+        self.consume(
+            TokenType::LeftBrace,
+            messages::EXPECT_LEFT_BRACE_BEFORE_BODY.with(&["measure"]),
+        )?;
+
+        match self.block() {
+            Ok(body) => Ok(MeasureStmt::new(keyword, body)),
+            Err(parse_error) => Err(parse_error),
+        }
+    }
+
+    // Parse a defer statement. The body is any statement, usually a block or
+    // a single call.
+    fn defer_statement(&mut self) -> Result<Statement, ParseError> {
+        let keyword = self.previous().clone();
+        match self.statement() {
+            Ok(body) => Ok(DeferStmt::new(keyword, body)),
+            Err(parse_error) => Err(parse_error),
+        }
+    }
+
     // Parse a print statement
     fn print_statement(&mut self) -> Result<Statement, ParseError> {
-        let value = self.expression();
-        let consume_semi = self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        match (value, consume_semi) {
-            // Continue with no error
-            (Ok(value), Ok(_)) => Ok(PrintStmt::new(value)),
-            // Take the semi error
-            (Ok(_), Err(err))
-            // Take the value error
-            | (Err(err), Ok(_))
-            // Take the value error
-            | (Err(err), Err(_)) => Err(err),
+        // Bail out before looking for the semicolon, so a bad expression
+        // doesn't also report a missing semicolon.
+        let value = self.expression()?;
+
+        match self.consume(TokenType::Semicolon, messages::EXPECT_SEMICOLON_AFTER_VALUE) {
+            Ok(_) => Ok(PrintStmt::new(value)),
+            Err(parse_error) => Err(parse_error),
         }
     }
 
     // Parse a return statement
     fn return_statement(&mut self) -> Result<Statement, ParseError> {
-        let keyword = self.previous();
+        let keyword = self.previous().clone();
         // We know that there's a value if there's no semicolon (since
         // expressions can't start with semicolons).
         let value = if !self.check(&TokenType::Semicolon) {
@@ -288,80 +369,109 @@ impl Parser {
             Literal::new(TokenLiteral::None)
         };
 
-        match self.consume(TokenType::Semicolon, "Expect ';' after return value.") {
-            _ => Ok(ReturnStmt::new(keyword, value)),
-            Err(parse_error) => return Err(parse_error),
+        match self.consume(
+            TokenType::Semicolon,
+            messages::EXPECT_SEMICOLON_AFTER_RETURN_VALUE,
+        ) {
+            Ok(_) => Ok(ReturnStmt::new(keyword, value)),
+            Err(parse_error) => Err(parse_error),
         }
     }
 
     fn expression_statement(&mut self) -> Result<Statement, ParseError> {
-        let expr = self.expression();
-        let consume_semi = self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        match (expr, consume_semi) {
-            // Continue with no error
-            (Ok(expr), Ok(_)) => Ok(ExprStmt::new(expr)),
-            // Take the semi error
-            (Ok(_), Err(err))
-            // Take the value error
-            | (Err(err), Ok(_))
-            // Take the value error
-            | (Err(err), Err(_)) => Err(err),
+        // Bail out before looking for the semicolon, so a bad expression
+        // doesn't also report a missing semicolon.
+        let expr = self.expression()?;
+
+        match self.consume(TokenType::Semicolon, messages::EXPECT_SEMICOLON_AFTER_VALUE) {
+            Ok(_) => Ok(ExprStmt::new(expr)),
+            Err(parse_error) => Err(parse_error),
         }
     }
 
     fn function(&mut self, kind: impl Into<String>) -> Result<Statement, ParseError> {
         let kind: String = kind.into();
-        let name = match self.consume(TokenType::Identifier, format!("Expect {} name.", kind)) {
-            Ok(ident_token) => ident_token,
-            Err(parse_error) => return Err(parse_error),
-        };
-
-        if let Err(parse_error) = self.consume(
+        let name = self
+            .consume(
+                TokenType::Identifier,
+                messages::EXPECT_FUNCTION_NAME.with(&[&kind]),
+            )?
+            .clone();
+
+        self.consume(
             TokenType::LeftParen,
-            format!("Expect '(' after {} name.", kind),
-        ) {
-            return Err(parse_error);
+            messages::EXPECT_LEFT_PAREN_AFTER_FUNCTION_NAME.with(&[&kind]),
+        )?;
+
+        match self.parameters_and_body(&kind) {
+            Ok((parameters, body)) => Ok(FunStmt::new(name, parameters, body)),
+            Err(parse_error) => Err(parse_error),
+        }
+    }
+
+    // Parses a lambda, e.g. `fun (a, b) { return a + b; }`, after the `fun`.
+    fn lambda(&mut self) -> Result<Expression, ParseError> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftParen, messages::EXPECT_LEFT_PAREN_AFTER_FUN)?;
+
+        match self.parameters_and_body("function") {
+            Ok((parameters, body)) => Ok(Function::new(keyword, parameters, body)),
+            Err(parse_error) => Err(parse_error),
         }
+    }
 
+    // Parses a function's parameter list, after the opening paren, and its
+    // body.
+    fn parameters_and_body(
+        &mut self,
+        kind: &str,
+    ) -> Result<(Vec<Token>, Vec<Statement>), ParseError> {
         let mut parameters = vec![];
         if !self.check(&TokenType::RightParen) {
-            if parameters.len() >= 255 {
-                let next_token = self.peek();
-                return Err(self.error(next_token, "Can't have more than 255 parameters."));
-            }
-
             loop {
-                match self.consume(TokenType::Identifier, "Expect parameter name.") {
-                    Ok(param) => parameters.push(param),
-                    Err(parse_error) => return Err(parse_error),
+                // Report the error without bailing out – the parser isn't
+                // confused, so there's no need to synchronize.
+                if parameters.len() >= 255 {
+                    self.error(self.peek(), messages::TOO_MANY_PARAMETERS);
                 }
 
+                let param = self.consume(TokenType::Identifier, messages::EXPECT_PARAMETER_NAME)?;
+                parameters.push(param.clone());
+
                 if !self.matches(&[TokenType::Comma]) {
                     break;
                 }
+
+                // Tolerate a trailing comma before the closing paren.
+                if self.check(&TokenType::RightParen) {
+                    break;
+                }
             }
         }
 
-        if let Err(parse_error) =
-            self.consume(TokenType::RightParen, "Expect ')' after parameters.")
-        {
+        if let Err(parse_error) = self.consume(
+            TokenType::RightParen,
+            messages::EXPECT_RIGHT_PAREN_AFTER_PARAMETERS,
+        ) {
             return Err(parse_error);
         }
 
         if let Err(parse_error) = self.consume(
             TokenType::LeftBrace,
-            format!("Expect '{}' before {} body.", "{", kind),
+            messages::EXPECT_LEFT_BRACE_BEFORE_BODY.with(&[kind]),
         ) {
             return Err(parse_error);
         }
 
         match self.block() {
-            Ok(body) => Ok(FunStmt::new(name, parameters, body)),
-            Err(parse_error) => return Err(parse_error),
+            Ok(body) => Ok((parameters, body)),
+            Err(parse_error) => Err(parse_error),
         }
     }
 
+    // Parses the rest of a block, after its '{'.
     fn block(&mut self) -> Result<Vec<Statement>, ParseError> {
+        let brace = self.previous().clone();
         let mut statements = vec![];
 
         while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
@@ -370,7 +480,11 @@ impl Parser {
             }
         }
 
-        if let Err(parse_error) = self.consume(TokenType::RightBrace, "Expect '}' after block.") {
+        // The loop only stops at the closing brace or the end of the file.
+        if let Err(parse_error) = self.consume(
+            TokenType::RightBrace,
+            messages::UNCLOSED_BLOCK.with(&[brace.line]),
+        ) {
             return Err(parse_error);
         }
 
@@ -389,27 +503,31 @@ impl Parser {
 
         // If equals is the next token, 'expr' is the left-hand-side of an
         // assignment.
-        if self.matches(&[TokenType::Equal]) {
+        if self.matches(&[
+            TokenType::Equal,
+            TokenType::PlusEqual,
+            TokenType::MinusEqual,
+            TokenType::StarEqual,
+            TokenType::SlashEqual,
+        ]) {
             // The assignment.
-            let equals = self.previous();
+            let equals = self.previous().clone();
             // For the assignment's right-hand expr (value), we need to recurse.
             let value = match self.assignment() {
                 Ok(val) => val,
                 Err(parse_error) => return Err(parse_error),
             };
 
-            // If the left-hand-side is indeed a variable, we can proceed.
-            //
-            // NOTE: Maybe we should check this earlier (like at the top
-            // if-statement?)
-            if expr.name() == "Variable" {
-                if let Ok(variable) = expr.downcast_rc::<Variable>() {
-                    return Ok(Assign::new(variable.name.clone(), value));
-                }
+            // Only variables and elements can be assigned to. Anything else
+            // bails out.
+            let target = match expr.assign_target() {
+                Some(target) => target,
+                None => return Err(self.error(&equals, messages::INVALID_ASSIGNMENT_TARGET)),
+            };
+            if equals.ty == TokenType::Equal {
+                return Ok(target.assign(value));
             }
-
-            // Otherwise, bail out.
-            return Err(self.error(equals, "Invalid assignment target."));
+            return Ok(CompoundAssign::new(expr.clone(), equals, value));
         }
 
         // Return the equality expression if the next token isn't
@@ -424,7 +542,7 @@ impl Parser {
         };
 
         while self.matches(&[TokenType::Or]) {
-            let operator = self.previous();
+            let operator = self.previous().clone();
             let right = match self.and() {
                 Ok(expr) => expr,
                 Err(parse_error) => return Err(parse_error),
@@ -442,7 +560,7 @@ impl Parser {
         };
 
         while self.matches(&[TokenType::And]) {
-            let operator = self.previous();
+            let operator = self.previous().clone();
             let right = match self.equality() {
                 Ok(expr) => expr,
                 Err(parse_error) => return Err(parse_error),
@@ -457,71 +575,69 @@ impl Parser {
     // equality expression, it'll call and return comparison() which will match
     // anything with a higher precedence than equality.
     fn equality(&mut self) -> Result<Expression, ParseError> {
-        self.comparison().map(|mut expr| {
-            while self.matches(&[TokenType::BangEqual, TokenType::EqualEqual]) {
-                // Parse an equality expression
-                let operator = self.previous();
-                if let Ok(right) = self.comparison() {
-                    expr = Binary::new(expr, operator, right);
-                }
-            }
-            expr
-        })
+        let mut expr = self.comparison()?;
+
+        while self.matches(&[TokenType::BangEqual, TokenType::EqualEqual]) {
+            let operator = self.previous().clone();
+            let right = self.comparison()?;
+            expr = Binary::new(expr, operator, right);
+        }
+
+        Ok(expr)
     }
 
     // Matches anything with a higher precedence than equality.
     fn comparison(&mut self) -> Result<Expression, ParseError> {
-        self.term().map(|mut expr| {
-            while self.matches(&[
-                TokenType::Greater,
-                TokenType::GreaterEqual,
-                TokenType::Less,
-                TokenType::LessEqual,
-            ]) {
-                let operator = self.previous();
-                if let Ok(right) = self.term() {
-                    expr = Binary::new(expr, operator, right);
-                }
-            }
+        let mut expr = self.term()?;
+
+        while self.matches(&[
+            TokenType::Greater,
+            TokenType::GreaterEqual,
+            TokenType::Less,
+            TokenType::LessEqual,
+        ]) {
+            let operator = self.previous().clone();
+            let right = self.term()?;
+            expr = Binary::new(expr, operator, right);
+        }
 
-            expr
-        })
+        Ok(expr)
     }
 
     // Addition and subtraction
     fn term(&mut self) -> Result<Expression, ParseError> {
-        self.factor().map(|mut expr| {
-            while self.matches(&[TokenType::Plus, TokenType::Minus]) {
-                let operator = self.previous();
-                if let Ok(right) = self.factor() {
-                    expr = Binary::new(expr, operator, right);
-                }
-            }
-            expr
-        })
+        let mut expr = self.factor()?;
+
+        while self.matches(&[TokenType::Plus, TokenType::Minus]) {
+            let operator = self.previous().clone();
+            let right = self.factor()?;
+            expr = Binary::new(expr, operator, right);
+        }
+
+        Ok(expr)
     }
 
     // Multiplication and division
     fn factor(&mut self) -> Result<Expression, ParseError> {
-        self.unary().map(|mut expr| {
-            while self.matches(&[TokenType::Slash, TokenType::Star]) {
-                let operator = self.previous();
-                if let Ok(right) = self.unary() {
-                    expr = Binary::new(expr, operator, right);
-                }
-            }
+        let mut expr = self.unary()?;
 
-            expr
-        })
+        while self.matches(&[TokenType::Slash, TokenType::Star]) {
+            let operator = self.previous().clone();
+            let right = self.unary()?;
+            expr = Binary::new(expr, operator, right);
+        }
+
+        Ok(expr)
     }
 
     // Binary operators
     fn unary(&mut self) -> Result<Expression, ParseError> {
         if self.matches(&[TokenType::Bang, TokenType::Minus]) {
-            let operator = self.previous();
-            if let Ok(right) = self.unary() {
-                return Ok(Unary::new(operator, right));
-            }
+            let operator = self.previous().clone();
+            return match self.unary() {
+                Ok(right) => Ok(Unary::new(operator, right)),
+                Err(parse_error) => Err(parse_error),
+            };
         }
 
         self.call()
@@ -529,11 +645,16 @@ impl Parser {
 
     // Parsing an arguments list between parens, with zero-or-more arguments.
     fn finish_call(&mut self, callee: Expression) -> Result<Expression, ParseError> {
+        let opening_paren = self.previous().clone();
         let mut arguments = vec![];
 
         // If we have a right paren, this is never run (zero-argument fn).
         if !self.check(&TokenType::RightParen) {
             loop {
+                if arguments.len() >= 255 {
+                    self.error(self.peek(), messages::TOO_MANY_ARGUMENTS);
+                }
+
                 // If we don't have a right-paren, we expect at least one argument.
                 match self.expression() {
                     Ok(expr) => arguments.push(expr),
@@ -545,31 +666,38 @@ impl Parser {
                 if !self.matches(&[TokenType::Comma]) {
                     break;
                 }
+
+                // Tolerate a trailing comma before the closing paren.
+                if self.check(&TokenType::RightParen) {
+                    break;
+                }
             }
         }
 
-        match self.consume(TokenType::RightParen, "Expect ')' after arguments.") {
-            // If we have a right-paren, we can returna new Call expression.
-            Ok(paren) => Ok(Call::new(callee, paren, arguments)),
-            // Otherwise we have to return a ParseError.
-            Err(parse_error) => return Err(parse_error),
-        }
+        let paren = self.consume_closing(
+            TokenType::RightParen,
+            &opening_paren,
+            messages::EXPECT_RIGHT_PAREN_AFTER_ARGUMENTS,
+        )?;
+        Ok(Call::new(callee, paren.clone(), arguments))
     }
 
     fn call(&mut self) -> Result<Expression, ParseError> {
-        let mut expr = match self.primary() {
-            Ok(primary_expr) => primary_expr,
-            Err(parse_error) => return Err(parse_error),
-        };
+        let mut expr = self.primary()?;
 
         loop {
             if self.matches(&[TokenType::LeftParen]) {
-                match self.finish_call(expr) {
-                    Ok(finish_call_expr) => {
-                        expr = finish_call_expr;
-                    }
-                    Err(parse_error) => return Err(parse_error),
-                };
+                expr = self.finish_call(expr)?;
+            } else if self.matches(&[TokenType::LeftBracket]) {
+                let index = self.expression()?;
+                let bracket = self.consume(
+                    TokenType::RightBracket,
+                    messages::EXPECT_RIGHT_BRACKET_AFTER_INDEX,
+                )?;
+                expr = Index::new(expr, bracket.clone(), index);
+            } else if self.matches(&[TokenType::Dot]) {
+                let name = self.consume(TokenType::Identifier, messages::EXPECT_PROPERTY_NAME)?;
+                expr = Get::new(expr, name.clone());
             } else {
                 break;
             }
@@ -590,18 +718,37 @@ impl Parser {
             return Ok(Literal::new(TokenLiteral::None));
         }
         if self.matches(&[TokenType::Number, TokenType::String]) {
-            return Ok(Literal::new(self.previous().literal));
+            return Ok(Literal::new(self.previous().literal.clone()));
         }
         if self.matches(&[TokenType::Identifier]) {
-            return Ok(Variable::new(self.previous()));
+            return Ok(Variable::new(self.previous().clone()));
+        }
+        // In expression position, 'print' refers to the native print function
+        // rather than the print statement (e.g. 'var p = print;').
+        if self.matches(&[TokenType::Print]) {
+            return Ok(Variable::new(self.previous().clone()));
+        }
+        if self.matches(&[TokenType::Fun]) {
+            return self.lambda();
+        }
+        if self.matches(&[TokenType::LeftBracket]) {
+            return self.list();
+        }
+        // A brace can't start a block inside an expression, so here it
+        // starts a map.
+        if self.matches(&[TokenType::LeftBrace]) {
+            return self.map();
         }
         if self.matches(&[TokenType::LeftParen]) {
+            let opening_paren = self.previous().clone();
             // Try to end an expression. If we can't end it, we'll end up returning
             // an error.
             if let Ok(expression) = self.expression() {
-                if let Ok(_right_paren) =
-                    self.consume(TokenType::RightParen, "Expect ')' after expression.")
-                {
+                if let Ok(_right_paren) = self.consume_closing(
+                    TokenType::RightParen,
+                    &opening_paren,
+                    messages::EXPECT_RIGHT_PAREN_AFTER_EXPRESSION,
+                ) {
                     return Ok(Grouping::new(expression));
                 } else {
                     return Err(ParseError);
@@ -612,7 +759,95 @@ impl Parser {
         // If we have a valid token literal, return it as a literal token,
         // otherwise, return error.
         let err_token = self.peek();
-        Err(self.error(err_token, "Expect expression."))
+        Err(self.error(err_token, messages::EXPECT_EXPRESSION))
+    }
+
+    // Parses a list literal's elements, after the opening bracket.
+    fn list(&mut self) -> Result<Expression, ParseError> {
+        let bracket = self.previous().clone();
+        let mut elements = vec![];
+        if !self.check(&TokenType::RightBracket) {
+            loop {
+                elements.push(self.expression()?);
+
+                if !self.matches(&[TokenType::Comma]) {
+                    break;
+                }
+
+                // Tolerate a trailing comma before the closing bracket.
+                if self.check(&TokenType::RightBracket) {
+                    break;
+                }
+            }
+        }
+
+        match self.consume(
+            TokenType::RightBracket,
+            messages::EXPECT_RIGHT_BRACKET_AFTER_ELEMENTS,
+        ) {
+            Ok(_) => Ok(List::new(bracket, elements)),
+            Err(parse_error) => Err(parse_error),
+        }
+    }
+
+    // Parses a map literal's entries, after the opening brace.
+    fn map(&mut self) -> Result<Expression, ParseError> {
+        let brace = self.previous().clone();
+        let mut entries = vec![];
+        // Where each key starts, for pointing out duplicates.
+        let mut key_tokens = vec![];
+        if !self.check(&TokenType::RightBrace) {
+            loop {
+                key_tokens.push(self.peek().clone());
+                let key = self.expression()?;
+                self.consume(TokenType::Colon, messages::EXPECT_COLON_AFTER_KEY)?;
+                let value = self.expression()?;
+                entries.push((key, value));
+
+                if !self.matches(&[TokenType::Comma]) {
+                    break;
+                }
+
+                // Tolerate a trailing comma before the closing brace.
+                if self.check(&TokenType::RightBrace) {
+                    break;
+                }
+            }
+        }
+
+        match self.consume(
+            TokenType::RightBrace,
+            messages::EXPECT_RIGHT_BRACE_AFTER_ENTRIES,
+        ) {
+            Ok(_) => {
+                self.check_duplicate_keys(&entries, &key_tokens);
+                Ok(Map::new(brace, entries))
+            }
+            Err(parse_error) => Err(parse_error),
+        }
+    }
+
+    // Reports each constant key that's equal to an earlier one, since only
+    // the last of them would end up in the map. Keys that aren't constant
+    // can't be compared until they're evaluated.
+    fn check_duplicate_keys(&self, entries: &[(Expression, Expression)], key_tokens: &[Token]) {
+        let keys: Vec<&Expression> = entries.iter().map(|(key, _)| key).collect();
+        let values = const_eval::constant_values(&keys);
+        for (index, value) in values.iter().enumerate() {
+            let Some(value) = value else { continue };
+            let earlier = values[..index]
+                .iter()
+                .position(|earlier| earlier.as_ref() == Some(value));
+            if let Some(earlier) = earlier {
+                let (token, first) = (&key_tokens[index], &key_tokens[earlier]);
+                let key = show_element(value);
+                Diagnostic::error(token.line, messages::DUPLICATE_KEY.with(&[&key]))
+                    .at(format!("at \"{}\"", token.lexeme))
+                    .pointing_at(token)
+                    .note_at(first, messages::KEY_FIRST_USED.with(&[&key]))
+                    .emit(&self.errors);
+            }
+        }
     }
 
     // Checks to see if the current token has any of the passed types
@@ -626,9 +861,16 @@ impl Parser {
         false
     }
 
-    fn consume(&mut self, ty: TokenType, message: impl Into<String>) -> Result<Token, ParseError> {
+    // Consumes the current token if it has the expected type. Returns a
+    // reference, so callers only pay for a clone when they keep the token.
+    fn consume(
+        &mut self,
+        ty: TokenType,
+        message: impl Into<Message>,
+    ) -> Result<&Token, ParseError> {
         if self.check(&ty) {
-            return Ok(self.advance());
+            self.advance();
+            return Ok(self.previous());
         }
 
         let err_token = self.peek();
@@ -637,37 +879,76 @@ impl Parser {
         Err(self.error(err_token, message))
     }
 
+    // Consumes the token closing `opening`. Running out of tokens first means
+    // the opening one was never closed, which is reported with its line, as
+    // it's usually far from the end of the file.
+    fn consume_closing(
+        &mut self,
+        ty: TokenType,
+        opening: &Token,
+        message: impl Into<Message>,
+    ) -> Result<&Token, ParseError> {
+        if !self.is_at_end() {
+            return self.consume(ty, message);
+        }
+
+        let unclosed =
+            messages::UNCLOSED_DELIMITER.with(&[&opening.lexeme, &opening.line.to_string()]);
+        Err(self.error(self.peek(), unclosed))
+    }
+
     // Checks if the current token is equal to the passed type
-    fn check(&mut self, ty: &TokenType) -> bool {
+    fn check(&self, ty: &TokenType) -> bool {
         if self.is_at_end() {
             return false;
         }
         return &self.peek().ty == ty;
     }
 
+    // Checks if the token after the current one is equal to the passed type
+    fn check_next(&self, ty: &TokenType) -> bool {
+        match self.tokens.get(self.current + 1) {
+            Some(token) => &token.ty == ty,
+            None => false,
+        }
+    }
+
     // Consumes the current token and returns it.
-    fn advance(&mut self) -> Token {
+    fn advance(&mut self) -> &Token {
         if !self.is_at_end() {
             self.current = self.current + 1;
         }
         self.previous()
     }
 
-    // Returns the current token
-    fn peek(&mut self) -> Token {
-        unsafe { self.tokens.get_unchecked(self.current).clone() }
+    // Returns the current token. `advance` never moves past the `Eof` token,
+    // so there always is one.
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
     }
 
-    // Returns the previously consumed token
-    fn previous(&mut self) -> Token {
-        unsafe { self.tokens.get_unchecked(self.current - 1).clone() }
+    // Returns the previously consumed token, or the current one if nothing
+    // has been consumed yet.
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current.saturating_sub(1)]
     }
 
-    fn error(&mut self, token: Token, message: impl Into<String>) -> ParseError {
+    fn error(&self, token: &Token, message: impl Into<Message>) -> ParseError {
+        let message = message.into();
+        trace::emit(&self.tracer, || TraceEvent::ParseError {
+            line: token.line,
+            message: message.to_string(),
+        });
         if token.ty == TokenType::Eof {
-            errors::report(token.line, " at end", message);
+            Diagnostic::error(token.line, message)
+                .at("at end")
+                .pointing_at(token)
+                .emit(&self.errors);
         } else {
-            errors::report(token.line, format!("at \"{}\"", token.lexeme), message);
+            Diagnostic::error(token.line, message)
+                .at(format!("at \"{}\"", token.lexeme))
+                .pointing_at(token)
+                .emit(&self.errors);
         }
 
         ParseError
@@ -690,6 +971,8 @@ impl Parser {
                 | TokenType::Var
                 | TokenType::For
                 | TokenType::If
+                | TokenType::Defer
+                | TokenType::Measure
                 | TokenType::While
                 | TokenType::Print
                 | TokenType::Return => return,
@@ -701,7 +984,202 @@ impl Parser {
     }
 
     // Whether we're at the end of the token stream
-    fn is_at_end(&mut self) -> bool {
+    fn is_at_end(&self) -> bool {
         self.peek().ty == TokenType::Eof
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::Parser;
+    use crate::{ast_printer::AstPrinter, errors::ErrorManager, golden, scanner::Scanner};
+
+    // Parses the source, returning the rendered diagnostics.
+    fn parse_errors(source: &str) -> Vec<String> {
+        let errors = Rc::new(ErrorManager::new());
+        let tokens = Scanner::new(&source.to_string(), errors.clone()).scan_tokens();
+        Parser::new(tokens, errors.clone()).parse();
+        errors.messages()
+    }
+
+    #[test]
+    fn parses_nothing_from_no_tokens() {
+        let errors = Rc::new(ErrorManager::new());
+        assert!(Parser::new(vec![], errors.clone()).parse().is_empty());
+        assert!(!errors.has_errors());
+        assert!(Parser::new(vec![], errors.clone())
+            .parse_expression()
+            .is_none());
+        assert_eq!(
+            errors.messages(),
+            vec!["[line 1] Error[E023] at end: Expect expression."]
+        );
+    }
+
+    // The parsed statements as S-expressions, followed by any diagnostics.
+    #[test]
+    fn golden() {
+        golden::check_suite("parser", |source| {
+            let errors = Rc::new(ErrorManager::new());
+            let tokens = Scanner::new(&source.to_string(), errors.clone()).scan_tokens();
+            let statements = Parser::new(tokens, errors.clone()).parse();
+            let mut lines = vec![];
+            if !statements.is_empty() {
+                lines.push(AstPrinter.print(&statements));
+            }
+            lines.extend(errors.messages());
+            golden::lines(lines)
+        });
+    }
+
+    #[test]
+    fn reports_where_unclosed_brackets_were_opened() {
+        assert_eq!(
+            parse_errors("fun f() {\n  print 1;\n"),
+            vec!["[line 3] Error[E019] at end: Unclosed block: '{' opened at line 1."]
+        );
+        assert_eq!(
+            parse_errors("print (1 +\n  2"),
+            vec!["[line 2] Error[E076] at end: Unclosed '(' opened at line 1."]
+        );
+        assert_eq!(
+            parse_errors("print f(1,\n  2"),
+            vec!["[line 2] Error[E076] at end: Unclosed '(' opened at line 1."]
+        );
+        // A missing bracket that isn't at the end of the file is reported
+        // where it was expected, as before.
+        assert_eq!(
+            parse_errors("print (1;"),
+            vec!["[line 1] Error[E022] at \";\": Expect ')' after expression."]
+        );
+    }
+
+    fn names(prefix: &str, count: usize) -> String {
+        (0..count)
+            .map(|i| format!("{}{}", prefix, i))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    #[test]
+    fn allows_255_parameters() {
+        let source = format!("fun f({}) {{}}", names("p", 255));
+        assert_eq!(parse_errors(&source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_256_parameters() {
+        let source = format!("fun f({}) {{}}", names("p", 256));
+        assert_eq!(
+            parse_errors(&source),
+            vec!["[line 1] Error[E015] at \"p255\": Can't have more than 255 parameters."]
+        );
+    }
+
+    #[test]
+    fn allows_255_arguments() {
+        let source = format!("f({});", names("a", 255));
+        assert_eq!(parse_errors(&source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_256_arguments() {
+        let source = format!("f({});", names("a", 256));
+        assert_eq!(
+            parse_errors(&source),
+            vec!["[line 1] Error[E034] at \"a255\": Can't have more than 255 arguments."]
+        );
+    }
+
+    #[test]
+    fn reports_a_missing_semicolon_after_return() {
+        assert_eq!(
+            parse_errors("fun f() {\n  return 1\n  print 2;\n}"),
+            vec!["[line 3] Error[E012] at \"print\": Expect ';' after return value."]
+        );
+    }
+
+    #[test]
+    fn fun_followed_by_name_is_a_declaration() {
+        assert_eq!(parse_errors("fun f() {} f();"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn fun_without_name_is_parsed_as_an_expression() {
+        assert_eq!(
+            parse_errors("fun (a) { return a; }(1);"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            parse_errors("fun();"),
+            vec!["[line 1] Error[E018] at \";\": Expect '{' before function body."]
+        );
+    }
+
+    #[test]
+    fn reports_each_extra_parameter() {
+        let source = format!("fun f({}) {{}}", names("p", 257));
+        assert_eq!(parse_errors(&source).len(), 2);
+    }
+
+    #[test]
+    fn reports_unclosed_brackets() {
+        assert_eq!(
+            parse_errors("var list = [1, 2;"),
+            vec!["[line 1] Error[E062] at \";\": Expect ']' after list elements."]
+        );
+        assert_eq!(
+            parse_errors("list[0;"),
+            vec!["[line 1] Error[E063] at \";\": Expect ']' after index."]
+        );
+    }
+
+    #[test]
+    fn reports_malformed_map_entries() {
+        assert_eq!(
+            parse_errors("var ages = {\"ada\" 36};"),
+            vec!["[line 1] Error[E066] at \"36\": Expect ':' after map key."]
+        );
+        assert_eq!(
+            parse_errors("var ages = {\"ada\": 36 \"bob\": 41};"),
+            vec!["[line 1] Error[E067] at \"\"bob\"\": Expect '}' after map entries."]
+        );
+    }
+
+    #[test]
+    fn index_can_be_assigned_to() {
+        assert_eq!(parse_errors("list[0] = 1;"), Vec::<String>::new());
+        assert_eq!(
+            parse_errors("[1][0] = [2] = 3;"),
+            vec!["[line 1] Error[E020] at \"=\": Invalid assignment target."]
+        );
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    extern crate test;
+
+    use std::rc::Rc;
+
+    use test::Bencher;
+
+    use super::Parser;
+    use crate::{errors::ErrorManager, generator, scanner::Scanner};
+
+    // Parse time for a large generated program (run with `cargo +nightly bench`).
+    #[bench]
+    fn parse_large_program(b: &mut Bencher) {
+        let source = generator::generate(100, 42);
+        let errors = Rc::new(ErrorManager::new());
+        let tokens = Scanner::new(&source, errors.clone()).scan_tokens();
+
+        b.iter(|| {
+            let mut parser = Parser::new(tokens.clone(), errors.clone());
+            parser.parse()
+        });
+        assert!(!errors.has_errors());
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    expr::{
+        Assign, Binary, Call, CompoundAssign, ExprVisitor, Function, Get, Grouping, Index,
+        IndexAssign, List, Literal, Logical, Map, Set, Unary, Variable, VisitorTarget,
+    },
+    hooks::InterpreterHooks,
+    object::LoxObject,
+    stmt::{
+        BlockStmt, DeferStmt, ExprStmt, FunStmt, IfStmt, MeasureStmt, PrintStmt, ReturnStmt,
+        Statement, Stmt, StmtVisitor, StmtVisitorTarget, VariableStmt, WhileStmt,
+    },
+};
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Entry {
+    count: usize,
+    time: Duration,
+}
+
+/// Finds where a run spends its time, for `--explain-slow`. Registered as
+/// interpreter hooks, it tracks:
+///
+/// - Each line's statements: how often they ran, and their own time – from
+///   when one starts until the next statement starts, so time spent in
+///   statements inside a called function goes to those statements.
+/// - Each function: how often it was called, and the time from the call until
+///   it returned, including any calls it made (but counting a recursive
+///   function's time once).
+pub struct Profiler {
+    start: Instant,
+    // Keyed by line. Statements without any line information (e.g. `1;`) are
+    // grouped under None.
+    lines: RefCell<HashMap<Option<usize>, Entry>>,
+    functions: RefCell<HashMap<String, Entry>>,
+    // The line of the statement running right now, and when it started.
+    current: RefCell<Option<(Option<usize>, Instant)>>,
+    // The functions being called, and when each call started.
+    calls: RefCell<Vec<(String, Instant)>>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            lines: RefCell::new(HashMap::new()),
+            functions: RefCell::new(HashMap::new()),
+            current: RefCell::new(None),
+            calls: RefCell::new(vec![]),
+        }
+    }
+
+    // Charges the time since the running statement started to its line.
+    fn finish_statement(&self, now: Instant) {
+        if let Some((line, started)) = self.current.borrow_mut().take() {
+            let mut lines = self.lines.borrow_mut();
+            lines.entry(line).or_default().time += now - started;
+        }
+    }
+
+    /// Describes the `top` slowest lines and functions, slowest first.
+    pub fn report(&self, top: usize) -> Vec<String> {
+        let now = Instant::now();
+        self.finish_statement(now);
+        let total = now - self.start;
+
+        let mut report = vec![format!(
+            "Slowest statements (of {:.2} ms total):",
+            millis(total)
+        )];
+        let mut lines: Vec<(Option<usize>, Entry)> =
+            self.lines.borrow().iter().map(|(k, v)| (*k, *v)).collect();
+        lines.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(&b.0)));
+        for (line, entry) in lines.into_iter().take(top) {
+            let line = match line {
+                Some(line) => format!("line {}", line),
+                None => "(unknown line)".to_string(),
+            };
+            report.push(format!(
+                "    {} executed {}, {}",
+                line,
+                times(entry.count),
+                share(entry.time, total)
+            ));
+        }
+
+        let mut functions: Vec<(String, Entry)> = self
+            .functions
+            .borrow()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        if !functions.is_empty() {
+            report.push("Slowest functions:".to_string());
+        }
+        functions.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(&b.0)));
+        for (function, entry) in functions.into_iter().take(top) {
+            report.push(format!(
+                "    {} called {}, {}",
+                function,
+                times(entry.count),
+                share(entry.time, total)
+            ));
+        }
+
+        report
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InterpreterHooks for Profiler {
+    fn on_statement(&self, stmt: &Statement) {
+        // A block's time is its statements' time, and counting it too would
+        // count a loop's body line twice per iteration.
+        if let Stmt::Block(_) = **stmt {
+            return;
+        }
+        let now = Instant::now();
+        self.finish_statement(now);
+
+        let line = stmt.accept(&FirstLine);
+        self.lines.borrow_mut().entry(line).or_default().count += 1;
+        *self.current.borrow_mut() = Some((line, now));
+    }
+
+    fn on_call(&self, callee: &LoxObject, _arguments: &[LoxObject], _line: usize) {
+        let function = callee.to_string();
+        self.functions
+            .borrow_mut()
+            .entry(function.clone())
+            .or_default()
+            .count += 1;
+        self.calls.borrow_mut().push((function, Instant::now()));
+    }
+
+    fn on_return(&self, _callee: &LoxObject, _value: &LoxObject) {
+        let mut calls = self.calls.borrow_mut();
+        if let Some((function, started)) = calls.pop() {
+            // Only the outermost call of a recursive function counts, so its
+            // time isn't counted once per level of recursion.
+            if calls.iter().all(|(caller, _)| *caller != function) {
+                let mut functions = self.functions.borrow_mut();
+                functions.entry(function).or_default().time += started.elapsed();
+            }
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// "1 time", "1,000,000 times".
+fn times(count: usize) -> String {
+    let digits = count.to_string();
+    let mut grouped = String::new();
+    for (idx, digit) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    match count {
+        1 => format!("{} time", grouped),
+        _ => format!("{} times", grouped),
+    }
+}
+
+// "73% of runtime (12.34 ms)".
+fn share(time: Duration, total: Duration) -> String {
+    let percent = match total.is_zero() {
+        true => 0.0,
+        false => time.as_secs_f64() / total.as_secs_f64() * 100.0,
+    };
+    format!("{:.0}% of runtime ({:.2} ms)", percent, millis(time))
+}
+
+/// Finds the first line a statement has a token on. Literals don't keep their
+/// token, so a statement made only of literals has no line.
+struct FirstLine;
+
+impl ExprVisitor<Option<usize>> for &FirstLine {
+    fn visit_binary_expr(&self, expr: &Binary) -> Option<usize> {
+        Some(expr.left.accept(*self).unwrap_or(expr.operator.line))
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping) -> Option<usize> {
+        expr.expression.accept(*self)
+    }
+
+    fn visit_literal_expr(&self, _expr: &Literal) -> Option<usize> {
+        None
+    }
+
+    fn visit_unary_expr(&self, expr: &Unary) -> Option<usize> {
+        Some(expr.operator.line)
+    }
+
+    fn visit_variable_expr(&self, expr: &Variable) -> Option<usize> {
+        Some(expr.name.line)
+    }
+
+    fn visit_assign_expr(&self, expr: &Assign) -> Option<usize> {
+        Some(expr.name.line)
+    }
+
+    fn visit_logical_expr(&self, expr: &Logical) -> Option<usize> {
+        Some(expr.left.accept(*self).unwrap_or(expr.operator.line))
+    }
+
+    fn visit_call_expr(&self, expr: &Call) -> Option<usize> {
+        Some(expr.callee.accept(*self).unwrap_or(expr.paren.line))
+    }
+
+    fn visit_function_expr(&self, expr: &Function) -> Option<usize> {
+        Some(expr.keyword.line)
+    }
+
+    fn visit_list_expr(&self, expr: &List) -> Option<usize> {
+        Some(expr.bracket.line)
+    }
+
+    fn visit_map_expr(&self, expr: &Map) -> Option<usize> {
+        Some(expr.brace.line)
+    }
+
+    fn visit_index_expr(&self, expr: &Index) -> Option<usize> {
+        Some(expr.object.accept(*self).unwrap_or(expr.bracket.line))
+    }
+
+    fn visit_index_assign_expr(&self, expr: &IndexAssign) -> Option<usize> {
+        Some(expr.object.accept(*self).unwrap_or(expr.bracket.line))
+    }
+
+    fn visit_compound_assign_expr(&self, expr: &CompoundAssign) -> Option<usize> {
+        Some(expr.target.accept(*self).unwrap_or(expr.operator.line))
+    }
+
+    fn visit_get_expr(&self, expr: &Get) -> Option<usize> {
+        Some(expr.object.accept(*self).unwrap_or(expr.name.line))
+    }
+
+    fn visit_set_expr(&self, expr: &Set) -> Option<usize> {
+        Some(expr.object.accept(*self).unwrap_or(expr.name.line))
+    }
+}
+
+impl StmtVisitor<Option<usize>> for &FirstLine {
+    fn visit_expression_stmt(&self, stmt: &ExprStmt) -> Option<usize> {
+        stmt.expression.accept(*self)
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> Option<usize> {
+        stmt.expression.accept(*self)
+    }
+
+    fn visit_variable_stmt(&self, stmt: &VariableStmt) -> Option<usize> {
+        Some(stmt.name.line)
+    }
+
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> Option<usize> {
+        stmt.statements.iter().find_map(|stmt| stmt.accept(*self))
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> Option<usize> {
+        stmt.condition.accept(*self)
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> Option<usize> {
+        Some(stmt.keyword.line)
+    }
+
+    fn visit_fun_stmt(&self, stmt: &FunStmt) -> Option<usize> {
+        Some(stmt.name.line)
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Option<usize> {
+        Some(stmt.keyword.line)
+    }
+
+    fn visit_measure_stmt(&self, stmt: &MeasureStmt) -> Option<usize> {
+        Some(stmt.keyword.line)
+    }
+
+    fn visit_defer_stmt(&self, stmt: &DeferStmt) -> Option<usize> {
+        Some(stmt.keyword.line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{times, Profiler};
+    use crate::{output::CapturedOutput, session::Session};
+
+    #[test]
+    fn counts_statements_by_line_and_calls_by_function() {
+        let mut session = Session::new();
+        session.interpreter.output = Rc::new(CapturedOutput::new());
+        let profiler = Rc::new(Profiler::new());
+        session.interpreter.add_hooks(profiler.clone());
+        session.run(
+            &"fun square(n) {\n  return n * n;\n}\nfor (var i = 0; i < 10; i = i + 1) {\n  print square(i);\n}\n"
+                .to_string(),
+        );
+
+        let report = profiler.report(usize::MAX);
+        let find = |prefix: &str| report.iter().any(|line| line.starts_with(prefix));
+        assert!(find("Slowest statements"));
+        assert!(find("    line 2 executed 10 times, "));
+        assert!(find("    line 5 executed 10 times, "));
+        assert!(find("    <fn square> called 10 times, "));
+        assert_eq!(profiler.report(1).len(), 4);
+    }
+
+    #[test]
+    fn groups_digits_in_counts() {
+        assert_eq!(times(1), "1 time");
+        assert_eq!(times(999), "999 times");
+        assert_eq!(times(1000), "1,000 times");
+        assert_eq!(times(1234567), "1,234,567 times");
+    }
+}
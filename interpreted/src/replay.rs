@@ -0,0 +1,234 @@
+use std::{collections::VecDeque, sync::RwLock};
+
+use crate::messages::{self, Message};
+
+/// A single nondeterministic input observed while running a script, e.g. the
+/// value returned by one call to `clock()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayEvent {
+    pub kind: String,
+    pub value: f64,
+}
+
+#[derive(Debug)]
+enum ReplayLog {
+    /// Inputs come straight from the host, and aren't recorded.
+    Live,
+    /// Inputs come from the host, and are recorded in order.
+    Recording(Vec<ReplayEvent>),
+    /// Inputs are fed back from a previously recorded log.
+    Replaying(VecDeque<ReplayEvent>),
+}
+
+/// Captures (or feeds back) every nondeterministic input a script observes, so
+/// a run can be reproduced exactly with `--record` and `--replay`.
+#[derive(Debug)]
+pub struct Replay {
+    log: RwLock<ReplayLog>,
+}
+
+impl Replay {
+    /// Inputs are neither recorded nor replayed.
+    pub fn live() -> Self {
+        Self {
+            log: RwLock::new(ReplayLog::Live),
+        }
+    }
+
+    /// Inputs are taken from the host and recorded.
+    pub fn recording() -> Self {
+        Self {
+            log: RwLock::new(ReplayLog::Recording(vec![])),
+        }
+    }
+
+    /// Inputs are fed back from a trace previously written by `to_json`.
+    pub fn from_json(source: &str) -> Result<Self, String> {
+        let events = match parse_trace(source) {
+            Some(events) => events,
+            None => return Err("Malformed replay trace.".to_string()),
+        };
+
+        Ok(Self {
+            log: RwLock::new(ReplayLog::Replaying(events.into())),
+        })
+    }
+
+    /// Produces the next input of the given kind. When live or recording, the
+    /// value comes from `live`, otherwise it comes from the replayed trace –
+    /// in which case an error is returned if the script diverged from it.
+    pub fn number(&self, kind: &str, live: impl FnOnce() -> f64) -> Result<f64, Message> {
+        let mut log = match self.log.try_write() {
+            Ok(log) => log,
+            Err(_) => return Err(messages::REPLAY_LOG_UNAVAILABLE.into()),
+        };
+
+        match &mut *log {
+            ReplayLog::Live => Ok(live()),
+            ReplayLog::Recording(events) => {
+                let value = live();
+                events.push(ReplayEvent {
+                    kind: kind.to_string(),
+                    value,
+                });
+                Ok(value)
+            }
+            ReplayLog::Replaying(events) => match events.pop_front() {
+                Some(event) if event.kind == kind => Ok(event.value),
+                Some(event) => Err(messages::REPLAY_WRONG_INPUT.with(&[kind, &event.kind])),
+                None => Err(messages::REPLAY_OUT_OF_INPUTS.with(&[kind])),
+            },
+        }
+    }
+
+    /// Serializes the recorded inputs. Returns None unless recording.
+    pub fn to_json(&self) -> Option<String> {
+        let log = self.log.try_read().ok()?;
+        match &*log {
+            ReplayLog::Recording(events) => {
+                let events: Vec<String> = events
+                    .iter()
+                    .map(|event| {
+                        format!(
+                            "    {{\"kind\": \"{}\", \"value\": {:?}}}",
+                            event.kind, event.value
+                        )
+                    })
+                    .collect();
+                Some(format!(
+                    "{{\n  \"events\": [\n{}\n  ]\n}}\n",
+                    events.join(",\n")
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Parses the `{"events": [{"kind": "...", "value": 1.0}, ...]}` trace format
+/// written by `Replay::to_json`.
+fn parse_trace(source: &str) -> Option<Vec<ReplayEvent>> {
+    let mut reader = TraceReader {
+        chars: source.chars().collect(),
+        current: 0,
+    };
+
+    let mut events = vec![];
+    reader.expect('{')?;
+    if reader.string()? != "events" {
+        return None;
+    }
+    reader.expect(':')?;
+    reader.expect('[')?;
+    if !reader.matches(']') {
+        loop {
+            events.push(reader.event()?);
+            if !reader.matches(',') {
+                break;
+            }
+        }
+        reader.expect(']')?;
+    }
+    reader.expect('}')?;
+
+    reader.skip_whitespace();
+    if reader.current < reader.chars.len() {
+        return None;
+    }
+
+    Some(events)
+}
+
+struct TraceReader {
+    chars: Vec<char>,
+    current: usize,
+}
+
+impl TraceReader {
+    fn event(&mut self) -> Option<ReplayEvent> {
+        let mut kind = None;
+        let mut value = None;
+
+        self.expect('{')?;
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+            match key.as_str() {
+                "kind" => kind = Some(self.string()?),
+                "value" => value = Some(self.number()?),
+                _ => return None,
+            }
+
+            if !self.matches(',') {
+                break;
+            }
+        }
+        self.expect('}')?;
+
+        Some(ReplayEvent {
+            kind: kind?,
+            value: value?,
+        })
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            let c = *self.chars.get(self.current)?;
+            self.current += 1;
+            match c {
+                '"' => return Some(value),
+                '\\' => {
+                    let escaped = *self.chars.get(self.current)?;
+                    self.current += 1;
+                    value.push(escaped);
+                }
+                c => value.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<f64> {
+        self.skip_whitespace();
+        let start = self.current;
+        while let Some(c) = self.chars.get(self.current) {
+            if !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+                break;
+            }
+            self.current += 1;
+        }
+
+        self.chars[start..self.current]
+            .iter()
+            .collect::<String>()
+            .parse::<f64>()
+            .ok()
+    }
+
+    fn expect(&mut self, expected: char) -> Option<()> {
+        if self.matches(expected) {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn matches(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.get(self.current) == Some(&expected) {
+            self.current += 1;
+            return true;
+        }
+        false
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.chars.get(self.current) {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.current += 1;
+        }
+    }
+}
@@ -0,0 +1,623 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    effects,
+    errors::{Diagnostic, ErrorManager},
+    expr::{
+        Assign, Binary, Call, CompoundAssign, ExprVisitor, Expression, Function, Get, Grouping,
+        Index, IndexAssign, List, Literal, Logical, Map, Set, Unary, Variable, VisitorTarget,
+    },
+    interpreter::Interpreter,
+    messages,
+    scanner::Pragmas,
+    stmt::{
+        BlockStmt, DeferStmt, ExprStmt, FunStmt, IfStmt, MeasureStmt, PrintStmt, ReturnStmt,
+        Statement, StmtVisitor, StmtVisitorTarget, VariableStmt, WhileStmt,
+    },
+    token::Token,
+};
+
+/// Identifies a variable, assignment or call expression. Each one is given its own
+/// when it's made, so unlike its address, an id is never reused by another
+/// expression after the first is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExprId(u64);
+
+impl ExprId {
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ExprId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Where a variable reference finds its variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    /// In a scope this many scopes out from the innermost one.
+    Local(usize),
+    Global,
+}
+
+/// Optional warnings the resolver can give, about code that's valid but
+/// might not do what it looks like it does. All are off by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Lints {
+    /// Warn about assignments and calls on the right of `and` or `or`, which
+    /// only happen when the left side doesn't decide the result.
+    pub short_circuit_side_effects: bool,
+}
+
+impl Lints {
+    /// Every lint, which `// lox: strict` turns on for its file.
+    pub fn strict() -> Self {
+        Self {
+            short_circuit_side_effects: true,
+        }
+    }
+}
+
+/// A local scope as the resolver saw it, with the scopes nested inside it.
+/// Dumped by `--scopes`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopeTree {
+    /// What the scope is for, e.g. "block" or "fun f body".
+    pub kind: String,
+    pub bindings: Vec<ScopeBinding>,
+    pub children: Vec<ScopeTree>,
+}
+
+/// A variable declared in a scope.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopeBinding {
+    pub name: String,
+    /// The line it's declared on.
+    pub line: usize,
+    /// Its position in the scope, counting declarations in order.
+    pub slot: usize,
+    /// Whether a function declared inside its scope uses it, so it outlives
+    /// the scope in that function's closure.
+    pub captured: bool,
+}
+
+impl ScopeTree {
+    /// The tree as indented lines, e.g. `  x (line 3, slot 0, captured)`.
+    pub fn render(trees: &[ScopeTree]) -> Vec<String> {
+        let mut lines = vec![];
+        for tree in trees {
+            tree.render_into(0, &mut lines);
+        }
+        lines
+    }
+
+    fn render_into(&self, depth: usize, lines: &mut Vec<String>) {
+        let indent = "  ".repeat(depth);
+        lines.push(format!("{}{}", indent, self.kind));
+        for binding in &self.bindings {
+            lines.push(format!(
+                "{}  {} (line {}, slot {}{})",
+                indent,
+                binding.name,
+                binding.line,
+                binding.slot,
+                if binding.captured { ", captured" } else { "" }
+            ));
+        }
+        for child in &self.children {
+            child.render_into(depth + 1, lines);
+        }
+    }
+}
+
+// A scope being resolved.
+struct Scope {
+    // Names to whether they're defined yet (false while their initializer is
+    // resolved).
+    names: HashMap<String, bool>,
+    // How many functions deep the scope is.
+    function_depth: usize,
+    tree: ScopeTree,
+}
+
+/// Works out, before a program runs, which scope each variable reference
+/// refers to, and tells the interpreter so it can go straight to that scope.
+///
+/// The scopes mirror the ones the interpreter creates: one per block (and
+/// `measure` body), and two per function call – one for the parameters, and
+/// one for the body – on top of the scopes the function captured where it was
+/// declared.
+///
+/// Like at runtime, a variable is only visible after its declaration, so
+/// `var a = a;` in a block reads the `a` from an enclosing scope.
+pub struct Resolver<'a> {
+    interpreter: &'a Interpreter,
+    /// Where misplaced statements (like a `return` outside any function) are
+    /// reported.
+    errors: Rc<ErrorManager>,
+    scopes: RefCell<Vec<Scope>>,
+    function_depth: Cell<usize>,
+    top_level_return: bool,
+    lints: Lints,
+    // How many `defer` bodies the statement being resolved is in.
+    defer_depth: Cell<usize>,
+    // The outermost local scopes, once they've been resolved.
+    finished: RefCell<Vec<ScopeTree>>,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(interpreter: &'a Interpreter) -> Self {
+        Self {
+            interpreter,
+            errors: interpreter.errors.clone(),
+            scopes: RefCell::new(vec![]),
+            function_depth: Cell::new(0),
+            top_level_return: false,
+            lints: Lints::default(),
+            defer_depth: Cell::new(0),
+            finished: RefCell::new(vec![]),
+        }
+    }
+
+    /// Reports errors to the manager instead of the interpreter's, e.g. so
+    /// they can be attributed to a file first.
+    pub fn report_to(mut self, errors: Rc<ErrorManager>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// Lets the program `return` from its top level, for files run with
+    /// `dofile`, whose result is the returned value.
+    pub fn allowing_top_level_return(mut self) -> Self {
+        self.top_level_return = true;
+        self
+    }
+
+    /// Gives the warnings turned on in `lints` too.
+    pub fn with_lints(mut self, lints: Lints) -> Self {
+        self.lints = lints;
+        self
+    }
+
+    /// Applies the pragmas of the file being resolved, on top of the lints it
+    /// was given: a strict file gets every lint.
+    pub fn with_pragmas(mut self, pragmas: Pragmas) -> Self {
+        if pragmas.strict {
+            self.lints = Lints::strict();
+        }
+        self
+    }
+
+    /// The local scopes of everything resolved so far. Globals aren't
+    /// resolved, so they aren't in it.
+    pub fn scope_tree(&self) -> Vec<ScopeTree> {
+        self.finished.borrow().clone()
+    }
+
+    pub fn resolve(&self, statements: &[Statement]) {
+        for statement in statements {
+            statement.accept(self);
+        }
+    }
+
+    fn resolve_expr(&self, expr: &Expression) {
+        expr.accept(self);
+    }
+
+    fn begin_scope(&self, kind: impl Into<String>) {
+        self.scopes.borrow_mut().push(Scope {
+            names: HashMap::new(),
+            function_depth: self.function_depth.get(),
+            tree: ScopeTree {
+                kind: kind.into(),
+                bindings: vec![],
+                children: vec![],
+            },
+        });
+    }
+
+    fn end_scope(&self) {
+        let mut scopes = self.scopes.borrow_mut();
+        let tree = scopes.pop().unwrap().tree;
+        match scopes.last_mut() {
+            Some(enclosing) => enclosing.tree.children.push(tree),
+            None => self.finished.borrow_mut().push(tree),
+        }
+    }
+
+    // Globals aren't tracked: anything not found in a scope is global.
+    fn declare(&self, name: &Token) {
+        if let Some(scope) = self.scopes.borrow_mut().last_mut() {
+            // Redeclaring a name in the same scope is allowed, and until the
+            // new declaration is done the old one is still the one in use.
+            if scope.names.contains_key(&name.lexeme) {
+                return;
+            }
+            scope.names.insert(name.lexeme.clone(), false);
+            let slot = scope.tree.bindings.len();
+            scope.tree.bindings.push(ScopeBinding {
+                name: name.lexeme.clone(),
+                line: name.line,
+                slot,
+                captured: false,
+            });
+        }
+    }
+
+    fn define(&self, name: &Token) {
+        if let Some(scope) = self.scopes.borrow_mut().last_mut() {
+            scope.names.insert(name.lexeme.clone(), true);
+        }
+    }
+
+    fn resolve_local(&self, expr: ExprId, name: &Token) {
+        let mut scopes = self.scopes.borrow_mut();
+        let depth = scopes
+            .iter()
+            .rev()
+            .position(|scope| scope.names.get(&name.lexeme) == Some(&true));
+        let binding = match depth {
+            Some(depth) => {
+                let index = scopes.len() - 1 - depth;
+                let scope = &mut scopes[index];
+                // Used from a function inside the one it's declared in.
+                if scope.function_depth != self.function_depth.get() {
+                    for binding in &mut scope.tree.bindings {
+                        if binding.name == name.lexeme {
+                            binding.captured = true;
+                        }
+                    }
+                }
+                Binding::Local(depth)
+            }
+            None => Binding::Global,
+        };
+        self.interpreter.resolve(expr, binding);
+    }
+
+    fn resolve_function(&self, function: &FunStmt) {
+        let name = &function.name.lexeme;
+        self.function_depth.set(self.function_depth.get() + 1);
+        self.begin_scope(format!("fun {} parameters", name));
+        for param in &function.params {
+            self.declare(param);
+            self.define(param);
+        }
+        self.begin_scope(format!("fun {} body", name));
+        self.resolve(&function.body);
+        self.end_scope();
+        self.end_scope();
+        self.function_depth.set(self.function_depth.get() - 1);
+    }
+}
+
+impl ExprVisitor<()> for &Resolver<'_> {
+    fn visit_binary_expr(&self, expr: &Binary) {
+        self.resolve_expr(&expr.left);
+        self.resolve_expr(&expr.right);
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping) {
+        self.resolve_expr(&expr.expression);
+    }
+
+    fn visit_literal_expr(&self, _expr: &Literal) {}
+
+    fn visit_unary_expr(&self, expr: &Unary) {
+        self.resolve_expr(&expr.right);
+    }
+
+    fn visit_variable_expr(&self, expr: &Variable) {
+        self.resolve_local(expr.id, &expr.name);
+    }
+
+    fn visit_assign_expr(&self, expr: &Assign) {
+        self.resolve_expr(&expr.value);
+        self.resolve_local(expr.id, &expr.name);
+    }
+
+    fn visit_logical_expr(&self, expr: &Logical) {
+        self.resolve_expr(&expr.left);
+        self.resolve_expr(&expr.right);
+        if self.lints.short_circuit_side_effects {
+            if let Some(effect) = effects::side_effect(&expr.right) {
+                let message = messages::SHORT_CIRCUIT_SIDE_EFFECT
+                    .with(&[effect.kind.name(), &expr.operator.lexeme]);
+                Diagnostic::warning(effect.token.line, message)
+                    .at(format!("at \"{}\"", effect.token.lexeme))
+                    .pointing_at(&effect.token)
+                    .emit(&self.errors);
+            }
+        }
+    }
+
+    fn visit_call_expr(&self, expr: &Call) {
+        self.resolve_expr(&expr.callee);
+        for argument in &expr.arguments {
+            self.resolve_expr(argument);
+        }
+    }
+
+    fn visit_function_expr(&self, expr: &Function) {
+        self.resolve_function(&expr.declaration());
+    }
+
+    fn visit_list_expr(&self, expr: &List) {
+        for element in &expr.elements {
+            self.resolve_expr(element);
+        }
+    }
+
+    fn visit_map_expr(&self, expr: &Map) {
+        for (key, value) in &expr.entries {
+            self.resolve_expr(key);
+            self.resolve_expr(value);
+        }
+    }
+
+    fn visit_index_expr(&self, expr: &Index) {
+        self.resolve_expr(&expr.object);
+        self.resolve_expr(&expr.index);
+    }
+
+    fn visit_index_assign_expr(&self, expr: &IndexAssign) {
+        self.resolve_expr(&expr.object);
+        self.resolve_expr(&expr.index);
+        self.resolve_expr(&expr.value);
+    }
+
+    fn visit_compound_assign_expr(&self, expr: &CompoundAssign) {
+        // The target is read as well as written, so it resolves like a read.
+        self.resolve_expr(&expr.target);
+        self.resolve_expr(&expr.value);
+    }
+
+    fn visit_get_expr(&self, expr: &Get) {
+        self.resolve_expr(&expr.object);
+    }
+
+    fn visit_set_expr(&self, expr: &Set) {
+        self.resolve_expr(&expr.object);
+        self.resolve_expr(&expr.value);
+    }
+}
+
+impl StmtVisitor<()> for &Resolver<'_> {
+    fn visit_expression_stmt(&self, stmt: &ExprStmt) {
+        self.resolve_expr(&stmt.expression);
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) {
+        self.resolve_expr(&stmt.expression);
+    }
+
+    fn visit_variable_stmt(&self, stmt: &VariableStmt) {
+        self.declare(&stmt.name);
+        if let Some(initializer) = &stmt.initializer {
+            self.resolve_expr(initializer);
+        }
+        self.define(&stmt.name);
+    }
+
+    fn visit_block_stmt(&self, stmt: &BlockStmt) {
+        self.begin_scope("block");
+        self.resolve(&stmt.statements);
+        self.end_scope();
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) {
+        self.resolve_expr(&stmt.condition);
+        stmt.then_branch.accept(*self);
+        if let Some(else_branch) = &stmt.else_branch {
+            else_branch.accept(*self);
+        }
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) {
+        self.resolve_expr(&stmt.condition);
+        stmt.body.accept(*self);
+    }
+
+    fn visit_fun_stmt(&self, stmt: &FunStmt) {
+        // Defined before the body is resolved, so functions can recurse.
+        self.declare(&stmt.name);
+        self.define(&stmt.name);
+        self.resolve_function(stmt);
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) {
+        // A return in a deferred block only ends that block, so it's fine
+        // anywhere.
+        if !self.top_level_return && self.function_depth.get() == 0 && self.defer_depth.get() == 0 {
+            Diagnostic::error(stmt.keyword.line, messages::RETURN_AT_TOP_LEVEL)
+                .at(format!("at \"{}\"", stmt.keyword.lexeme))
+                .pointing_at(&stmt.keyword)
+                .emit(&self.errors);
+        }
+        self.resolve_expr(&stmt.value);
+    }
+
+    fn visit_measure_stmt(&self, stmt: &MeasureStmt) {
+        self.begin_scope("measure");
+        self.resolve(&stmt.body);
+        self.end_scope();
+    }
+
+    fn visit_defer_stmt(&self, stmt: &DeferStmt) {
+        self.defer_depth.set(self.defer_depth.get() + 1);
+        stmt.body.accept(*self);
+        self.defer_depth.set(self.defer_depth.get() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{expr::Expr, session::Session, stmt::Stmt};
+
+    // The statements in a block or function body.
+    fn body(statement: &Statement) -> &[Statement] {
+        match &**statement {
+            Stmt::Block(block) => &block.statements,
+            Stmt::Function(function) => &function.body,
+            _ => panic!("{:?} has no body", statement),
+        }
+    }
+
+    // The binding of a variable expression.
+    fn binding(session: &Session, expression: &Expression) -> Option<Binding> {
+        match &**expression {
+            Expr::Variable(variable) => session.interpreter.binding(variable.id),
+            _ => panic!("{:?} isn't a variable", expression),
+        }
+    }
+
+    // The binding of the variable printed by a print statement.
+    fn printed(session: &Session, print: &Statement) -> Option<Binding> {
+        match &**print {
+            Stmt::Print(print) => binding(session, &print.expression),
+            _ => panic!("{:?} isn't a print statement", print),
+        }
+    }
+
+    #[test]
+    fn reports_returns_outside_functions() {
+        let session = Session::new();
+        session.parse(
+            &"fun f() { return 1; }\nvar g = fun () { return 2; };\ndefer return;\nif (true) {\n  return 3;\n}"
+                .to_string(),
+        );
+        assert_eq!(
+            session.errors.messages(),
+            vec!["[line 5] Error[E075] at \"return\": Can't return from top-level code."]
+        );
+    }
+
+    #[test]
+    fn resolves_locals_by_how_many_scopes_out_they_are() {
+        let session = Session::new();
+        let statements = session.parse(
+            &"var a = 1;\n{\n  var b = 2;\n  {\n    print b;\n    print a;\n    var a = a;\n    print a;\n  }\n}"
+                .to_string(),
+        );
+        Resolver::new(&session.interpreter).resolve(&statements);
+
+        let outer = body(&statements[1]);
+        let inner = body(&outer[1]);
+        assert_eq!(printed(&session, &inner[0]), Some(Binding::Local(1)));
+        assert_eq!(printed(&session, &inner[1]), Some(Binding::Global));
+        assert_eq!(printed(&session, &inner[3]), Some(Binding::Local(0)));
+        // The initializer of `var a = a;` reads the global.
+        let Stmt::Variable(declaration) = &*inner[2] else {
+            panic!("{:?} isn't a declaration", inner[2]);
+        };
+        let initializer = declaration.initializer.as_ref().unwrap();
+        assert_eq!(binding(&session, initializer), Some(Binding::Global));
+    }
+
+    #[test]
+    fn bindings_of_dropped_trees_are_not_picked_up_by_new_ones() {
+        let session = Session::new();
+        for _ in 0..100 {
+            drop(session.parse(&"{\n  var a = 1;\n  print a;\n}".to_string()));
+            // Not resolved, so it has no binding of its own, even if it was
+            // made where a dropped one used to be.
+            let tokens = session.tokens(&"a".to_string());
+            let variable = crate::parser::Parser::new(tokens, session.errors.clone())
+                .parse_expression()
+                .unwrap();
+            assert_eq!(binding(&session, &variable), None);
+        }
+    }
+
+    #[test]
+    fn functions_see_the_scopes_they_were_declared_in() {
+        let session = Session::new();
+        let statements = session.parse(
+            &"{\n  var outer = 1;\n  fun f(param) {\n    print param;\n    print outer;\n  }\n}"
+                .to_string(),
+        );
+        Resolver::new(&session.interpreter).resolve(&statements);
+
+        let block = body(&statements[0]);
+        let function = body(&block[1]);
+        // The parameters are one scope out from the body.
+        assert_eq!(printed(&session, &function[0]), Some(Binding::Local(1)));
+        // The block is out past the body and the parameters.
+        assert_eq!(printed(&session, &function[1]), Some(Binding::Local(2)));
+    }
+
+    #[test]
+    fn records_the_scope_tree() {
+        let session = Session::new();
+        let statements = session.parse(
+            &"{
+  var kept = 1;
+  var local = 2;
+  fun f(x) {
+    var y = x + kept;
+    return fun () { return y; };
+  }
+  print local;
+}"
+            .to_string(),
+        );
+        let resolver = Resolver::new(&session.interpreter);
+        resolver.resolve(&statements);
+
+        assert_eq!(
+            ScopeTree::render(&resolver.scope_tree()),
+            vec![
+                "block",
+                "  kept (line 2, slot 0, captured)",
+                "  local (line 3, slot 1)",
+                "  f (line 4, slot 2)",
+                "  fun f parameters",
+                "    x (line 4, slot 0)",
+                "    fun f body",
+                "      y (line 5, slot 0, captured)",
+                "      fun anonymous parameters",
+                "        fun anonymous body",
+            ]
+        );
+    }
+
+    #[test]
+    fn lints_effects_that_depend_on_short_circuiting() {
+        let session = Session::new();
+        let source = "var a = 1;\nprint a and print_it(a);\nprint a or (a = 2);\nprint len(\"x\") and a;\nprint a and fun () { a = 3; };"
+            .to_string();
+        session.parse(&source);
+        assert!(session.errors.messages().is_empty());
+
+        let mut session = Session::new();
+        session.lints.short_circuit_side_effects = true;
+        session.parse(&source);
+        assert_eq!(
+            session.errors.messages(),
+            vec![
+                "[line 2] Warning[E078] at \")\": This call only happens if the left side of 'and' doesn't decide the result.",
+                "[line 3] Warning[E078] at \"a\": This assignment only happens if the left side of 'or' doesn't decide the result.",
+            ]
+        );
+    }
+
+    #[test]
+    fn strict_files_get_every_lint() {
+        let source = "// lox: strict\nvar a = 1;\nprint a or (a = 2);".to_string();
+        let session = Session::new();
+        session.parse(&source);
+        assert_eq!(
+            session.errors.messages(),
+            vec!["[line 3] Warning[E078] at \"a\": This assignment only happens if the left side of 'or' doesn't decide the result."]
+        );
+
+        // Only the file with the pragma is strict.
+        let session = Session::new();
+        session.parse(&"var a = 1;\nprint a or (a = 2);".to_string());
+        assert!(session.errors.messages().is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{
+    errors::{Diagnostic, ErrorManager, Severity},
+    messages,
+};
+
+/// The SARIF 2.1 log for the collected diagnostics (`--error-format=sarif`),
+/// for code-scanning UIs. Each message code is a rule, described by its
+/// template and explanation. Diagnostics are located in the file they were
+/// reported in, which is `script` unless they came from a file loaded with
+/// `dofile`.
+pub fn render(errors: &ErrorManager, script: &str) -> String {
+    let mut diagnostics = errors.diagnostics();
+    diagnostics.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+
+    let mut rules: Vec<&'static str> = vec![];
+    let mut results = vec![];
+    for diagnostic in &diagnostics {
+        let code = diagnostic.message.code();
+        let rule_index = match rules.iter().position(|rule| *rule == code) {
+            Some(index) => index,
+            None => {
+                rules.push(code);
+                rules.len() - 1
+            }
+        };
+        results.push(result(errors, diagnostic, rule_index, script));
+    }
+    let rules: Vec<String> = rules.iter().map(|code| rule(code)).collect();
+
+    format!(
+        concat!(
+            "{{\n",
+            "  \"$schema\": \"https://json.schemastore.org/sarif-2.1.0.json\",\n",
+            "  \"version\": \"2.1.0\",\n",
+            "  \"runs\": [\n",
+            "    {{\n",
+            "      \"tool\": {{\n",
+            "        \"driver\": {{\n",
+            "          \"name\": \"jlox\",\n",
+            "          \"version\": \"{}\",\n",
+            "          \"rules\": [{}]\n",
+            "        }}\n",
+            "      }},\n",
+            "      \"results\": [{}]\n",
+            "    }}\n",
+            "  ]\n",
+            "}}\n"
+        ),
+        env!("CARGO_PKG_VERSION"),
+        list(&rules, 12),
+        list(&results, 8),
+    )
+}
+
+// The items as the body of a JSON array, one per line at the indentation.
+fn list(items: &[String], indent: usize) -> String {
+    if items.is_empty() {
+        return String::new();
+    }
+    let items: Vec<String> = items
+        .iter()
+        .map(|item| format!("{}{}", " ".repeat(indent), item))
+        .collect();
+    format!("\n{}\n{}", items.join(",\n"), " ".repeat(indent - 2))
+}
+
+fn rule(code: &str) -> String {
+    let id = messages::find(code).unwrap();
+    // The explanation's first paragraph describes the problem; the rest is
+    // examples.
+    let description = id.explanation.split("\n\n").next().unwrap_or("");
+    format!(
+        "{{\"id\": \"{}\", \"shortDescription\": {{\"text\": {}}}, \"fullDescription\": {{\"text\": {}}}}}",
+        code,
+        json_string(id.template),
+        json_string(&description.replace('\n', " "))
+    )
+}
+
+fn result(
+    errors: &ErrorManager,
+    diagnostic: &Diagnostic,
+    rule_index: usize,
+    script: &str,
+) -> String {
+    let level = match diagnostic.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Note => "note",
+    };
+    let uri = diagnostic.file.as_deref().unwrap_or(script);
+    let related: Vec<String> = diagnostic
+        .notes
+        .iter()
+        .map(|note| {
+            format!(
+                "{{{}, \"message\": {{\"text\": {}}}}}",
+                physical_location(uri, note.line),
+                json_string(&errors.render_message(&note.message))
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"ruleId\": \"{}\", \"ruleIndex\": {}, \"level\": \"{}\", \"message\": {{\"text\": {}}}, \"locations\": [{{{}}}], \"relatedLocations\": [{}]}}",
+        diagnostic.message.code(),
+        rule_index,
+        level,
+        json_string(&errors.render_message(&diagnostic.message)),
+        physical_location(uri, diagnostic.line),
+        related.join(", ")
+    )
+}
+
+fn physical_location(uri: &str, line: usize) -> String {
+    format!(
+        "\"physicalLocation\": {{\"artifactLocation\": {{\"uri\": {}}}, \"region\": {{\"startLine\": {}}}}}",
+        json_string(uri),
+        line
+    )
+}
+
+/// The text as a quoted JSON string.
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::render;
+    use crate::{errors::ErrorManager, session::Session};
+
+    #[test]
+    fn renders_each_diagnostic_as_a_result_of_its_rule() {
+        let session = Session::new();
+        session.run(&"var = 1;\nprint \"a\" - 1;\nvar;".to_string());
+        let sarif = render(&session.errors, "scripts/main.lox");
+
+        assert!(sarif.contains("\"version\": \"2.1.0\""));
+        // One rule per code, however many times it's reported.
+        assert_eq!(sarif.matches("{\"id\": \"E002\"").count(), 1);
+        assert!(sarif.contains(
+            "{\"ruleId\": \"E002\", \"ruleIndex\": 0, \"level\": \"error\", \"message\": {\"text\": \"Expect variable name.\"}, \"locations\": [{\"physicalLocation\": {\"artifactLocation\": {\"uri\": \"scripts/main.lox\"}, \"region\": {\"startLine\": 1}}}], \"relatedLocations\": []}"
+        ));
+        assert!(sarif.contains("\"ruleIndex\": 0, \"level\": \"error\", \"message\": {\"text\": \"Expect variable name.\"}, \"locations\": [{\"physicalLocation\": {\"artifactLocation\": {\"uri\": \"scripts/main.lox\"}, \"region\": {\"startLine\": 3}}}]"));
+    }
+
+    #[test]
+    fn renders_no_results_without_diagnostics() {
+        let sarif = render(&Rc::new(ErrorManager::new()), "main.lox");
+        assert!(sarif.contains("\"rules\": []"));
+        assert!(sarif.contains("\"results\": []"));
+    }
+}
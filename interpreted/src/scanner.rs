@@ -1,44 +1,134 @@
+use std::{rc::Rc, sync::Arc};
+
 use crate::{
-    errors,
-    shared_traits::{CharAt, CharLen, Substring},
+    errors::{Diagnostic, ErrorManager},
+    messages,
     token::{Token, TokenLiteral},
     token_type::TokenType,
+    trace::{self, TraceEvent, Tracer},
 };
 
+/// Settings a file gives itself with directive comments at its top, before
+/// any code, e.g. `// lox: strict`. They only apply to that file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pragmas {
+    /// Turns on every lint while resolving the file.
+    pub strict: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct Scanner {
-    source: String,
+    // The source as scanned, shared with the error manager for quoting.
+    source: Arc<str>,
     tokens: Vec<Token>,
+    // Positions in characters, which token spans and columns count in.
     start: usize,
     current: usize,
+    // The same positions in bytes, for reading the source without walking
+    // it from the start.
+    start_offset: usize,
+    current_offset: usize,
     line: usize,
+    // Where the current line starts, and the column the current lexeme
+    // starts at.
+    line_start: usize,
+    start_column: usize,
+    pragmas: Pragmas,
+    errors: Rc<ErrorManager>,
+    tracer: Tracer,
 }
 
 impl Scanner {
-    pub fn new(source: &String) -> Self {
-        let source = source.clone();
+    pub fn new(source: &String, errors: Rc<ErrorManager>) -> Self {
+        // Windows (`\r\n`) and classic Mac (`\r`) line endings become `\n`,
+        // so they count as one line each and never end up in strings.
+        let source: Arc<str> = if source.contains('\r') {
+            source.replace("\r\n", "\n").replace('\r', "\n").into()
+        } else {
+            source.as_str().into()
+        };
+        // Token spans index into the source as scanned, so that's what
+        // diagnostics quote.
+        errors.set_source(source.clone());
         Self {
             source,
             tokens: Vec::new(),
             start: 0,
             current: 0,
+            start_offset: 0,
+            current_offset: 0,
             line: 1,
+            line_start: 0,
+            start_column: 1,
+            pragmas: Pragmas::default(),
+            errors,
+            tracer: Tracer::default(),
         }
     }
 
-    pub fn scan_tokens(mut self) -> Vec<Token> {
+    /// Reports the scan's start and end to the tracer.
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = tracer;
+        self
+    }
+
+    pub fn scan_tokens(self) -> Vec<Token> {
+        self.scan_with_pragmas().0
+    }
+
+    /// Scans the tokens, and returns them with the pragmas the source gives
+    /// itself, for configuring the rest of its compilation.
+    pub fn scan_with_pragmas(mut self) -> (Vec<Token>, Pragmas) {
+        trace::emit(&self.tracer, || TraceEvent::ScanStart);
         while !self.is_at_end() {
             // We are at the beginning of the next lexeme.
             self.start = self.current;
+            self.start_offset = self.current_offset;
+            self.start_column = self.column_of(self.start);
             self.scan_token();
         }
 
-        self.tokens.push(Token::new(
-            TokenType::Eof,
-            "",
-            TokenLiteral::None,
-            self.line,
-        ));
+        self.tokens.push(
+            Token::new(TokenType::Eof, "", TokenLiteral::None, self.line)
+                .spanning(self.column_of(self.current), self.current..self.current),
+        );
+
+        trace::emit(&self.tracer, || TraceEvent::ScanEnd {
+            tokens: self.tokens.len(),
+        });
+        let pragmas = self.pragmas;
+        (self.tokens(), pragmas)
+    }
+
+    /// Scans from `start` (a place in the source between tokens, on `line`
+    /// at `column`) until `stop` accepts a token, or to the end of the
+    /// source. The accepted token is the last one returned. Unlike
+    /// `scan_tokens`, no `Eof` token is added.
+    pub fn scan_tokens_from(
+        mut self,
+        start: usize,
+        line: usize,
+        column: usize,
+        mut stop: impl FnMut(&Token) -> bool,
+    ) -> Vec<Token> {
+        self.current = start;
+        self.current_offset = self
+            .source
+            .char_indices()
+            .nth(start)
+            .map_or(self.source.len(), |(offset, _)| offset);
+        self.line = line;
+        self.line_start = (start + 1).saturating_sub(column);
+        while !self.is_at_end() {
+            self.start = self.current;
+            self.start_offset = self.current_offset;
+            self.start_column = self.column_of(self.start);
+            let scanned = self.tokens.len();
+            self.scan_token();
+            if self.tokens.len() > scanned && stop(&self.tokens[scanned]) {
+                break;
+            }
+        }
 
         self.tokens()
     }
@@ -50,12 +140,33 @@ impl Scanner {
             ')' => self.add_etoken(TokenType::RightParen),
             '{' => self.add_etoken(TokenType::LeftBrace),
             '}' => self.add_etoken(TokenType::RightBrace),
+            '[' => self.add_etoken(TokenType::LeftBracket),
+            ']' => self.add_etoken(TokenType::RightBracket),
             ',' => self.add_etoken(TokenType::Comma),
             '.' => self.add_etoken(TokenType::Dot),
-            '-' => self.add_etoken(TokenType::Minus),
-            '+' => self.add_etoken(TokenType::Plus),
+            '-' => {
+                if self.matches('=') {
+                    self.add_etoken(TokenType::MinusEqual)
+                } else {
+                    self.add_etoken(TokenType::Minus)
+                }
+            }
+            '+' => {
+                if self.matches('=') {
+                    self.add_etoken(TokenType::PlusEqual)
+                } else {
+                    self.add_etoken(TokenType::Plus)
+                }
+            }
+            ':' => self.add_etoken(TokenType::Colon),
             ';' => self.add_etoken(TokenType::Semicolon),
-            '*' => self.add_etoken(TokenType::Star),
+            '*' => {
+                if self.matches('=') {
+                    self.add_etoken(TokenType::StarEqual)
+                } else {
+                    self.add_etoken(TokenType::Star)
+                }
+            }
             '!' => {
                 if self.matches('=') {
                     self.add_etoken(TokenType::BangEqual)
@@ -90,12 +201,15 @@ impl Scanner {
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
+                    self.comment();
+                } else if self.matches('=') {
+                    self.add_etoken(TokenType::SlashEqual);
                 } else {
                     self.add_etoken(TokenType::Slash);
                 }
             }
             // Whitespace – No op
-            ' ' | '\r' | '\t' => {}
+            ' ' | '\t' => {}
             // Whitespace (newline) – increment the current line count
             '\n' => self.increment_line(),
             // The start of a string
@@ -103,21 +217,41 @@ impl Scanner {
             c => match c {
                 c if Self::is_digit(c) => self.number(),
                 c if Self::is_alpha(c) => self.identifier(),
-                _ => errors::error(self.line, format!("Unexpected character \"{:?}\".", c)),
+                _ => Diagnostic::error(
+                    self.line,
+                    messages::UNEXPECTED_CHARACTER.with(&[format!("{:?}", c)]),
+                )
+                .emit(&self.errors),
             },
         }
     }
 
+    // Handles directives written as comments, like `// lox: ignore-next-line`.
+    fn comment(&mut self) {
+        let text = self.text(self.start_offset + 2, self.current_offset);
+        match text.trim() {
+            "lox: ignore-next-line" => self.errors.suppress_line(self.line + 1),
+            // A pragma is about the whole file, so it has to come before the
+            // code, where it's seen first.
+            "lox: strict" if self.tokens.is_empty() => self.pragmas.strict = true,
+            "lox: strict" => {
+                Diagnostic::warning(self.line, messages::MISPLACED_PRAGMA.with(&["lox: strict"]))
+                    .emit(&self.errors)
+            }
+            _ => {}
+        }
+    }
+
     fn identifier(&mut self) {
         while Self::is_alphanumeric(self.peek()) {
             self.advance();
         }
 
-        let value = self.source.substring(self.start, self.current).to_string();
-        if let Some(reserved_token_type) = Self::get_reserved_token_type(value.clone()) {
-            self.add_token(reserved_token_type, value);
+        let text = self.text(self.start_offset, self.current_offset);
+        if let Some(reserved_token_type) = Self::get_reserved_token_type(&text) {
+            self.add_etoken(reserved_token_type);
         } else {
-            self.add_token(TokenType::Identifier, value);
+            self.add_token(TokenType::Identifier, text);
         }
     }
 
@@ -139,14 +273,12 @@ impl Scanner {
             }
 
             value = self
-                .source
-                .substring(self.start, self.current)
+                .text(self.start_offset, self.current_offset)
                 .parse::<f64>()
                 .unwrap();
         } else {
             value = self
-                .source
-                .substring(self.start, self.current)
+                .text(self.start_offset, self.current_offset)
                 .parse::<i64>()
                 .unwrap() as f64
         }
@@ -156,10 +288,9 @@ impl Scanner {
 
     fn string(&mut self) {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
+            if self.advance() == '\n' {
                 self.increment_line();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -170,10 +301,7 @@ impl Scanner {
         self.advance();
 
         // Trim the surrounding quotes.
-        let value = self
-            .source
-            .substring(self.start + 1, self.current - 1)
-            .to_string();
+        let value = self.text(self.start_offset + 1, self.current_offset - 1);
 
         self.add_token(TokenType::String, value);
     }
@@ -195,42 +323,42 @@ impl Scanner {
 
     // Like advance, but it doesn't consume the character. (Lookahead)
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            self.source.char_at(self.current).unwrap()
-        }
+        self.source[self.current_offset..]
+            .chars()
+            .next()
+            .unwrap_or('\0')
     }
 
     // Like peek, but checks next-next character
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.char_length() {
-            '\0'
-        } else {
-            self.source.char_at(self.current + 1).unwrap()
-        }
+        self.source[self.current_offset..]
+            .chars()
+            .nth(1)
+            .unwrap_or('\0')
     }
 
     // Conditional advance, only consumes if the expected character matches
     fn matches(&mut self, expected: char) -> bool {
-        if self.is_at_end() || self.source.char_at(self.current) != Some(expected) {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
         // Advance only if it matches
-        self.current = self.current + 1;
+        self.advance();
         true
     }
 
     // Consumes the next character in the source file and returns it.
     fn advance(&mut self) -> char {
-        let current = self.source.char_at(self.current).unwrap();
+        let current = self.peek();
         self.current = self.current + 1;
+        self.current_offset = self.current_offset + current.len_utf8();
         current
     }
 
-    // Increments the line number
+    // Increments the line number, just after consuming a newline
     fn increment_line(&mut self) {
         self.line = self.line + 1;
+        self.line_start = self.current;
     }
 
     // Adds a new token to our tokens list (without an associated literal)
@@ -240,37 +368,66 @@ impl Scanner {
 
     // Adds a new token to our tokens list
     fn add_token(&mut self, ty: impl Into<TokenType>, literal: impl Into<TokenLiteral>) {
-        let text = self.source.substring(self.start, self.current);
-        self.tokens
-            .push(Token::new(ty, text, literal.into(), self.line));
+        let text = self.text(self.start_offset, self.current_offset);
+        self.tokens.push(
+            Token::new(ty, text, literal.into(), self.line)
+                .spanning(self.start_column, self.start..self.current),
+        );
     }
 
     // Whether we've consumed all of the characters or not.
     fn is_at_end(&self) -> bool {
-        self.current > self.source.char_length()
+        self.current_offset >= self.source.len()
+    }
+
+    // The source from one byte offset up to (not including) another.
+    fn text(&self, start: usize, end: usize) -> String {
+        self.source[start..end.max(start)].to_string()
+    }
+
+    // The column (counting from 1) of a position on the current line.
+    fn column_of(&self, position: usize) -> usize {
+        position.saturating_sub(self.line_start) + 1
     }
 
     // If the identifier passed in has the value as a reserved word, then we
-    // pass back the tokentype for that reserved word
-    fn get_reserved_token_type(name: String) -> Option<TokenType> {
-        match name.as_str() {
-            "and" => Some(TokenType::And),
-            "class" => Some(TokenType::Class),
-            "else" => Some(TokenType::Else),
-            "false" => Some(TokenType::False),
-            "for" => Some(TokenType::For),
-            "fun" => Some(TokenType::Fun),
-            "if" => Some(TokenType::If),
-            "nil" => Some(TokenType::Nil),
-            "or" => Some(TokenType::Or),
-            "print" => Some(TokenType::Print),
-            "return" => Some(TokenType::Return),
-            "super" => Some(TokenType::Super),
-            "this" => Some(TokenType::This),
-            "true" => Some(TokenType::True),
-            "var" => Some(TokenType::Var),
-            "while" => Some(TokenType::While),
-            _ => None,
+    // pass back the tokentype for that reserved word. Checks the first
+    // character and the length before comparing anything, so most identifiers
+    // are rejected after a couple of byte comparisons (and nothing allocates).
+    fn get_reserved_token_type(name: &str) -> Option<TokenType> {
+        let bytes = name.as_bytes();
+        let (ty, keyword) = match (bytes.first()?, bytes.len()) {
+            (b'a', 3) => (TokenType::And, "and"),
+            (b'c', 5) => (TokenType::Class, "class"),
+            (b'd', 5) => (TokenType::Defer, "defer"),
+            (b'e', 4) => (TokenType::Else, "else"),
+            (b'f', 3) => match bytes[1] {
+                b'o' => (TokenType::For, "for"),
+                b'u' => (TokenType::Fun, "fun"),
+                _ => return None,
+            },
+            (b'f', 5) => (TokenType::False, "false"),
+            (b'i', 2) => (TokenType::If, "if"),
+            (b'm', 7) => (TokenType::Measure, "measure"),
+            (b'n', 3) => (TokenType::Nil, "nil"),
+            (b'o', 2) => (TokenType::Or, "or"),
+            (b'p', 5) => (TokenType::Print, "print"),
+            (b'r', 6) => (TokenType::Return, "return"),
+            (b's', 5) => (TokenType::Super, "super"),
+            (b't', 4) => match bytes[1] {
+                b'h' => (TokenType::This, "this"),
+                b'r' => (TokenType::True, "true"),
+                _ => return None,
+            },
+            (b'v', 3) => (TokenType::Var, "var"),
+            (b'w', 5) => (TokenType::While, "while"),
+            _ => return None,
+        };
+
+        if name == keyword {
+            Some(ty)
+        } else {
+            None
         }
     }
 
@@ -279,3 +436,168 @@ impl Scanner {
         self.tokens
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::Range, rc::Rc};
+
+    use super::Scanner;
+    use crate::{errors::ErrorManager, golden, token::TokenLiteral};
+
+    // Each token as "line type lexeme literal", followed by any diagnostics.
+    #[test]
+    fn golden() {
+        golden::check_suite("scanner", |source| {
+            let errors = Rc::new(ErrorManager::new());
+            let tokens = Scanner::new(&source.to_string(), errors.clone()).scan_tokens();
+            let mut lines: Vec<String> = tokens
+                .iter()
+                .map(|token| format!("{} {}", token.line, token.to_string()))
+                .collect();
+            lines.extend(errors.messages());
+            golden::lines(lines)
+        });
+    }
+
+    #[test]
+    fn records_each_tokens_column_and_span() {
+        let source = "var a =\n  \"b\nc\" + 1;";
+        let errors = Rc::new(ErrorManager::new());
+        let tokens = Scanner::new(&source.to_string(), errors.clone()).scan_tokens();
+
+        let positions: Vec<(&str, usize, usize, Range<usize>)> = tokens
+            .iter()
+            .map(|token| {
+                (
+                    token.lexeme.as_str(),
+                    token.line,
+                    token.column,
+                    token.span.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                ("var", 1, 1, 0..3),
+                ("a", 1, 5, 4..5),
+                ("=", 1, 7, 6..7),
+                // A string's line is where it ends, but its column is where
+                // it starts.
+                ("\"b\nc\"", 3, 3, 10..15),
+                ("+", 3, 4, 16..17),
+                ("1", 3, 6, 18..19),
+                (";", 3, 7, 19..20),
+                ("", 3, 8, 20..20),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_stay_right_deep_into_a_large_source() {
+        // Long enough that looking characters up by walking the source from
+        // the start each time would take minutes.
+        let line = "var eleve = \"\u{e9}\u{1f600}\"; // comment\u{e9}\n";
+        let lines = 50_000;
+        let source = line.repeat(lines);
+        let errors = Rc::new(ErrorManager::new());
+        let tokens = Scanner::new(&source, errors.clone()).scan_tokens();
+
+        let end = line.chars().count() * lines;
+        let eof = tokens.last().unwrap();
+        assert_eq!(
+            (eof.line, eof.column, eof.span.clone()),
+            (lines + 1, 1, end..end)
+        );
+        let string = &tokens[tokens.len() - 3];
+        let start = end - line.chars().count() + 12;
+        assert_eq!(string.lexeme, "\"\u{e9}\u{1f600}\"");
+        assert_eq!((string.line, string.column), (lines, 13));
+        assert_eq!(string.span, start..start + 4);
+        assert!(!errors.has_errors());
+    }
+
+    #[test]
+    fn reads_pragmas_from_the_top_of_the_file() {
+        let pragmas = |source: &str| {
+            let errors = Rc::new(ErrorManager::new());
+            let (_, pragmas) =
+                Scanner::new(&source.to_string(), errors.clone()).scan_with_pragmas();
+            (pragmas.strict, errors.messages())
+        };
+        assert_eq!(pragmas("// lox: strict\nprint 1;"), (true, vec![]));
+        assert_eq!(
+            pragmas("// A script.\n\n//   lox: strict  \nprint 1;"),
+            (true, vec![])
+        );
+        assert_eq!(pragmas("print 1;"), (false, vec![]));
+        assert_eq!(
+            pragmas("print 1;\n// lox: strict"),
+            (
+                false,
+                vec!["[line 2] Warning[E084] : '// lox: strict' only applies at the top of a file, before any code.".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn counts_lines_the_same_whatever_the_line_endings() {
+        let source = "var a = \"one\r\ntwo\rthree\";\r\n// comment\r\rprint a;\nprint 1;\r\n";
+        let errors = Rc::new(ErrorManager::new());
+        let tokens = Scanner::new(&source.to_string(), errors.clone()).scan_tokens();
+
+        let lines: Vec<(String, usize)> = tokens
+            .iter()
+            .map(|token| (token.lexeme.clone(), token.line))
+            .collect();
+        let string = "\"one\ntwo\nthree\"".to_string();
+        assert_eq!(
+            lines,
+            vec![
+                ("var".to_string(), 1),
+                ("a".to_string(), 1),
+                ("=".to_string(), 1),
+                (string, 3),
+                (";".to_string(), 3),
+                ("print".to_string(), 6),
+                ("a".to_string(), 6),
+                (";".to_string(), 6),
+                ("print".to_string(), 7),
+                ("1".to_string(), 7),
+                (";".to_string(), 7),
+                ("".to_string(), 8),
+            ]
+        );
+        assert_eq!(
+            tokens[3].literal,
+            TokenLiteral::String("one\ntwo\nthree".to_string())
+        );
+        assert!(!errors.has_errors());
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    extern crate test;
+
+    use std::{rc::Rc, sync::Arc};
+
+    use test::Bencher;
+
+    use super::Scanner;
+    use crate::errors::ErrorManager;
+
+    // Scan time for keyword-heavy source (run with `cargo +nightly bench`).
+    #[bench]
+    fn scan_keywords(b: &mut Bencher) {
+        let source =
+            "fun f(a, b) { if (a and b or nil) { return true; } else { return false; } }\n\
+                      var i = 0; while (i < 10) { print this; i = super; }\n\
+                      for (var j = 0; j < 1; j = j + 1) { var class_ = fun_; }\n"
+                .repeat(10);
+        let errors = Rc::new(ErrorManager::new());
+
+        b.iter(|| Scanner::new(&source, errors.clone()).scan_tokens());
+        assert!(!errors.has_errors());
+    }
+}
@@ -0,0 +1,381 @@
+use std::{ops::Range, rc::Rc};
+
+use crate::{errors::ErrorManager, scanner::Scanner, token::Token, token_type::TokenType};
+
+/// What a token is, for highlighting. Tokens are classified by what the
+/// scanner makes of them, without resolving anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Keyword,
+    Identifier,
+    String,
+    Number,
+    Operator,
+    Punctuation,
+}
+
+impl TokenKind {
+    /// The kind of token the scanner produced, or `None` for the end of the
+    /// source.
+    pub fn of(ty: TokenType) -> Option<Self> {
+        Some(match ty {
+            _ if ty.is_keyword() => TokenKind::Keyword,
+            _ if ty.is_operator() => TokenKind::Operator,
+            TokenType::Identifier => TokenKind::Identifier,
+            TokenType::String => TokenKind::String,
+            TokenType::Number => TokenKind::Number,
+            TokenType::Eof => return None,
+            _ => TokenKind::Punctuation,
+        })
+    }
+
+    /// The kind's name in an LSP semantic tokens legend.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenKind::Keyword => "keyword",
+            TokenKind::Identifier => "variable",
+            TokenKind::String => "string",
+            TokenKind::Number => "number",
+            TokenKind::Operator => "operator",
+            TokenKind::Punctuation => "punctuation",
+        }
+    }
+}
+
+/// A token encoded the way the LSP encodes semantic tokens: positioned
+/// relative to the token before it (lines and columns count from 0), so an
+/// edit only changes the tokens around it. A token spanning lines (a
+/// multi-line string) covers the rest of its first line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SemanticToken {
+    pub delta_line: usize,
+    /// From the previous token's column if they're on the same line, or else
+    /// from the start of the line.
+    pub delta_start: usize,
+    pub length: usize,
+    pub kind: TokenKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemanticTokens {
+    /// Identifies the result, for asking for a delta from it later.
+    pub result_id: u64,
+    pub tokens: Vec<SemanticToken>,
+}
+
+/// Replaces the characters in `span` of the source with `text`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextEdit {
+    pub span: Range<usize>,
+    pub text: String,
+}
+
+/// The changes to the previous result's tokens: `deleted` of them are
+/// replaced with `tokens`, starting at `start`. (The LSP counts these in
+/// integers, five to a token.)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemanticTokensDelta {
+    pub result_id: u64,
+    pub start: usize,
+    pub deleted: usize,
+    pub tokens: Vec<SemanticToken>,
+}
+
+/// Semantic tokens for one document, kept up to date as it's edited. After
+/// the first (`full`) result, each edit is answered with a delta: only the
+/// tokens from just before the edit until the scanner is back in step with
+/// the previous tokens are scanned again, so a keystroke in a long file
+/// doesn't rescan all of it.
+///
+/// Offsets are in characters, in the source with its line endings as `\n`.
+#[derive(Debug, Default)]
+pub struct SemanticTokensCache {
+    source: Vec<char>,
+    tokens: Vec<Token>,
+    result_id: u64,
+}
+
+impl SemanticTokensCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All of the source's tokens, starting over with it as the document.
+    pub fn full(&mut self, source: &str) -> SemanticTokens {
+        let source = source.replace("\r\n", "\n").replace('\r', "\n");
+        self.tokens = scan(&source, 0, 1, 1, |_| false);
+        self.source = source.chars().collect();
+        self.result_id += 1;
+        SemanticTokens {
+            result_id: self.result_id,
+            tokens: encode(&self.tokens, 0..self.tokens.len()),
+        }
+    }
+
+    /// Applies the edit, and returns how the tokens changed since the result
+    /// `previous_result_id`. That has to be the latest result (as it is in
+    /// the LSP), or the edit has to fit in the source, or there's no delta,
+    /// and the editor should ask for the `full` tokens again.
+    pub fn delta(
+        &mut self,
+        previous_result_id: u64,
+        edit: &TextEdit,
+    ) -> Option<SemanticTokensDelta> {
+        let Range { start, end } = edit.span;
+        if previous_result_id != self.result_id || start > end || end > self.source.len() {
+            return None;
+        }
+
+        let text: Vec<char> = edit
+            .text
+            .replace("\r\n", "\n")
+            .replace('\r', "\n")
+            .chars()
+            .collect();
+        let shift = text.len() as isize - (end - start) as isize;
+        let new_lines = text.iter().filter(|c| **c == '\n').count() as isize
+            - self.source[start..end]
+                .iter()
+                .filter(|c| **c == '\n')
+                .count() as isize;
+        let edit_end = start + text.len();
+        self.source.splice(start..end, text);
+
+        // Tokens ending right before the edit can run on into it (`1.` and
+        // `5` make `1.5`), so the rescan starts after the last token that
+        // ends further back than that. Between two tokens is the only place
+        // the scanner can start from, as anywhere else could be in a comment.
+        let first = self
+            .tokens
+            .iter()
+            .position(|token| token.span.end + 1 >= start)
+            .unwrap_or(self.tokens.len());
+        let (from, line, column) = match first.checked_sub(1) {
+            Some(previous) => end_of(&self.tokens[previous]),
+            None => (0, 1, 1),
+        };
+
+        // The rescan stops at the first token past the edit that the
+        // previous scan also found (shifted by the edit), as from there on
+        // the scanner will find the same tokens again. It has to be on a
+        // later line than the edit, so the tokens after it keep their
+        // columns.
+        let source: String = self.source.iter().collect();
+        let tokens = &self.tokens;
+        let mut rest = tokens.len();
+        let mut rescanned = scan(&source, from, line, column, |token| {
+            if token.span.start < edit_end
+                || !self.source[edit_end..token.span.start].contains(&'\n')
+            {
+                return false;
+            }
+            let old_start = (token.span.start as isize - shift) as usize;
+            match tokens.binary_search_by_key(&old_start, |old| old.span.start) {
+                Ok(index)
+                    if tokens[index].ty == token.ty && tokens[index].lexeme == token.lexeme =>
+                {
+                    rest = index;
+                    true
+                }
+                _ => false,
+            }
+        });
+        if rest < tokens.len() {
+            rescanned.pop();
+        }
+
+        let followed = usize::from(rest < tokens.len());
+        let mut tail: Vec<Token> = self.tokens.drain(first..).skip(rest - first).collect();
+        for token in &mut tail {
+            token.span = (token.span.start as isize + shift) as usize
+                ..(token.span.end as isize + shift) as usize;
+            token.line = (token.line as isize + new_lines) as usize;
+        }
+        let inserted = rescanned.len();
+        self.tokens.extend(rescanned);
+        self.tokens.extend(tail);
+
+        // The first token after the rescanned ones is re-encoded too (if
+        // there is one), as the token before it changed.
+        self.result_id += 1;
+        Some(SemanticTokensDelta {
+            result_id: self.result_id,
+            start: first,
+            deleted: rest - first + followed,
+            tokens: encode(&self.tokens, first..first + inserted + followed),
+        })
+    }
+}
+
+// Scans the source from a place between tokens, leaving out the `Eof`.
+fn scan(
+    source: &str,
+    from: usize,
+    line: usize,
+    column: usize,
+    stop: impl FnMut(&Token) -> bool,
+) -> Vec<Token> {
+    // The scanner can't take an empty source.
+    if source.is_empty() {
+        return vec![];
+    }
+    let mut tokens = Scanner::new(&source.to_string(), Rc::new(ErrorManager::new()))
+        .scan_tokens_from(from, line, column, stop);
+    if tokens.last().map(|token| token.ty) == Some(TokenType::Eof) {
+        tokens.pop();
+    }
+    tokens
+}
+
+// The line a token starts on (a token's line is the one it ends on).
+fn start_line(token: &Token) -> usize {
+    token.line - token.lexeme.matches('\n').count()
+}
+
+// Where the token ends, as (offset, line, column).
+fn end_of(token: &Token) -> (usize, usize, usize) {
+    let column = match token.lexeme.rfind('\n') {
+        Some(newline) => token.lexeme[newline + 1..].chars().count() + 1,
+        None => token.column + token.lexeme.chars().count(),
+    };
+    (token.span.end, token.line, column)
+}
+
+// Encodes the tokens in the range, each relative to the one before it.
+fn encode(tokens: &[Token], range: Range<usize>) -> Vec<SemanticToken> {
+    let mut encoded = vec![];
+    for index in range {
+        let token = &tokens[index];
+        let (line, column) = (start_line(token), token.column);
+        let (delta_line, delta_start) = match index.checked_sub(1) {
+            Some(previous) => {
+                let previous = &tokens[previous];
+                match line - start_line(previous) {
+                    0 => (0, column - previous.column),
+                    lines => (lines, column - 1),
+                }
+            }
+            None => (line - 1, column - 1),
+        };
+        encoded.push(SemanticToken {
+            delta_line,
+            delta_start,
+            length: token.lexeme.chars().take_while(|c| *c != '\n').count(),
+            kind: TokenKind::of(token.ty).unwrap(),
+        });
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SemanticToken, SemanticTokensCache, TextEdit, TokenKind};
+
+    fn edit(span: std::ops::Range<usize>, text: &str) -> TextEdit {
+        TextEdit {
+            span,
+            text: text.to_string(),
+        }
+    }
+
+    // The source with the edit made, for comparing against a full scan.
+    fn edited(source: &str, edit: &TextEdit) -> String {
+        let chars: Vec<char> = source.chars().collect();
+        let before: String = chars[..edit.span.start].iter().collect();
+        let after: String = chars[edit.span.end..].iter().collect();
+        before + &edit.text + &after
+    }
+
+    #[test]
+    fn classifies_and_positions_tokens_relative_to_the_one_before() {
+        let tokens = SemanticTokensCache::new()
+            .full("var a = \"x\ny\";\n  print a + 1;")
+            .tokens;
+        let token = |delta_line, delta_start, length, kind| SemanticToken {
+            delta_line,
+            delta_start,
+            length,
+            kind,
+        };
+        assert_eq!(
+            tokens,
+            vec![
+                token(0, 0, 3, TokenKind::Keyword),
+                token(0, 4, 1, TokenKind::Identifier),
+                token(0, 2, 1, TokenKind::Operator),
+                token(0, 2, 2, TokenKind::String),
+                token(1, 2, 1, TokenKind::Punctuation),
+                token(1, 2, 5, TokenKind::Keyword),
+                token(0, 6, 1, TokenKind::Identifier),
+                token(0, 2, 1, TokenKind::Operator),
+                token(0, 2, 1, TokenKind::Number),
+                token(0, 1, 1, TokenKind::Punctuation),
+            ]
+        );
+    }
+
+    #[test]
+    fn deltas_give_the_same_tokens_as_scanning_everything() {
+        let source =
+            "var a = 1;\n// note\nfun f(b) {\n  return b * 2.5;\n}\nprint \"s\";\nprint f(a);\n";
+        let cases = [
+            // Typing in an identifier, and joining tokens up.
+            edit(4..5, "ab"),
+            edit(8..9, "1."),
+            edit(9..9, ".5"),
+            edit(3..4, ""),
+            // Starting a comment or a string swallows what comes after.
+            edit(11..11, "/"),
+            edit(0..0, "// "),
+            edit(21..21, "\""),
+            edit(59..59, "\""),
+            // Inside a comment, and across lines.
+            edit(14..15, "x y"),
+            edit(10..30, ""),
+            edit(30..30, "\n\n  var c = \"\n\";\n"),
+            // At the very end, and everything at once.
+            edit(73..73, " print 3;"),
+            edit(0..73, ""),
+            edit(0..73, "x"),
+        ];
+        for case in cases {
+            let mut cache = SemanticTokensCache::new();
+            let mut tokens = cache.full(source).tokens;
+            let delta = cache.delta(1, &case).unwrap();
+            tokens.splice(delta.start..delta.start + delta.deleted, delta.tokens);
+
+            let expected = SemanticTokensCache::new()
+                .full(&edited(source, &case))
+                .tokens;
+            assert_eq!(tokens, expected, "{:?}", case);
+            assert_eq!(delta.result_id, 2);
+        }
+    }
+
+    #[test]
+    fn only_sends_the_tokens_around_an_edit() {
+        let source = "print 1;\n".repeat(1000);
+        let mut cache = SemanticTokensCache::new();
+        let full = cache.full(&source);
+
+        // `print 1;` on line 501 becomes `print 12;`.
+        let delta = cache.delta(full.result_id, &edit(4507..4507, "2")).unwrap();
+        assert_eq!(delta.start, 1501);
+        assert_eq!(delta.deleted, 3);
+        assert_eq!(delta.tokens.len(), 3);
+
+        // Edits carry on from the latest result.
+        let next = cache.delta(delta.result_id, &edit(4507..4508, "")).unwrap();
+        assert_eq!(next.tokens, full.tokens[1501..1504]);
+    }
+
+    #[test]
+    fn has_no_delta_from_an_old_result_or_past_the_end() {
+        let mut cache = SemanticTokensCache::new();
+        let first = cache.full("print 1;");
+        cache.full("print 2;");
+        assert_eq!(cache.delta(first.result_id, &edit(0..0, " ")), None);
+        assert_eq!(cache.delta(first.result_id + 1, &edit(8..9, " ")), None);
+    }
+}
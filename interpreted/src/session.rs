@@ -0,0 +1,699 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    compiler,
+    errors::{Diagnostic, ErrorManager},
+    expr::Expression,
+    hooks::InterpreterHooks,
+    interpreter::Interpreter,
+    object::LoxObject,
+    output::CapturedOutput,
+    parser::Parser,
+    resolver::{Lints, Resolver, ScopeTree},
+    scanner::Scanner,
+    stmt::{PrintStmt, Program, Statement, Stmt},
+    token::Token,
+    token_type::TokenType,
+    vm::Vm,
+};
+
+/// Which engine runs a session's code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Walks the syntax tree directly. Supports every feature.
+    #[default]
+    TreeWalker,
+    /// Compiles to bytecode and runs it on a stack-based VM.
+    Vm,
+}
+
+/// An isolated interpreter, with its own global environment and its own
+/// diagnostics. Running source in one session never affects another.
+pub struct Session {
+    pub errors: Rc<ErrorManager>,
+    pub interpreter: Interpreter,
+    pub backend: Backend,
+    /// The optional warnings given about code the session runs. The prelude
+    /// is never linted.
+    pub lints: Lints,
+}
+
+/// Utilities written in Lox, which every session defines before running
+/// anything else.
+const PRELUDE: &str = include_str!("prelude.lox");
+
+/// The file the prelude's functions say they're from in errors.
+const PRELUDE_FILE: &str = "<prelude>";
+
+impl Session {
+    /// A session with the prelude already defined.
+    pub fn new() -> Self {
+        let session = Self::without_prelude();
+        session.load_prelude();
+        session
+    }
+
+    /// A session with nothing defined but the natives.
+    pub fn without_prelude() -> Self {
+        let errors = Rc::new(ErrorManager::new());
+        let interpreter = Interpreter::new(errors.clone());
+        Self {
+            errors,
+            interpreter,
+            backend: Backend::default(),
+            lints: Lints::default(),
+        }
+    }
+
+    // Runs the prelude, like `dofile` would, so errors in its functions name
+    // it as their file.
+    fn load_prelude(&self) {
+        let errors = Rc::new(ErrorManager::new());
+        let tokens = Scanner::new(&PRELUDE.to_string(), errors.clone()).scan_tokens();
+        let statements = Parser::new(tokens, errors.clone()).parse();
+        Resolver::new(&self.interpreter)
+            .report_to(errors.clone())
+            .resolve(&statements);
+        let file = self.interpreter.swap_file(Some(PRELUDE_FILE.to_string()));
+        let result = self.interpreter.interpret(&statements);
+        self.interpreter.swap_file(file);
+        debug_assert!(!errors.has_errors() && result.is_ok());
+    }
+
+    /// Scans, parses, resolves, and interprets the source in this session,
+    /// on the session's backend. Any errors are reported to the session's
+    /// `ErrorManager`; if scanning, parsing, or resolving reported one, none of
+    /// the source runs.
+    pub fn run(&self, source: &String) {
+        let statements = self.parse(source);
+        self.execute(&statements);
+    }
+
+    /// Runs source typed into the REPL. A bare expression, like `1 + 2`, has
+    /// its value printed; anything else runs just like `run`.
+    ///
+    /// Returns the source as it would be written in a script, if it ran
+    /// without errors, for `:save`. Errors from earlier input must have been
+    /// reset first.
+    pub fn run_repl(&self, source: &String) -> Option<String> {
+        let script = match self.bare_expression(source) {
+            Some(expression) => {
+                let statements = self.interpreter.transform(vec![PrintStmt::new(expression)]);
+                self.resolver().resolve(&statements);
+                self.execute(&statements);
+                // On its own line, the semicolon can't end up in a comment.
+                let source = source.trim();
+                match source.contains("//") {
+                    true => format!("print {}\n;\n", source),
+                    false => format!("print {};\n", source),
+                }
+            }
+            None => {
+                self.run(source);
+                format!("{}\n", source.trim_end())
+            }
+        };
+        match self.errors.has_errors() || self.errors.has_runtime_error() {
+            true => None,
+            false => Some(script),
+        }
+    }
+
+    // The source as an expression, if it's exactly one. Anything else (even
+    // an invalid expression) is left for `run`, so errors are only reported
+    // once.
+    fn bare_expression(&self, source: &String) -> Option<Expression> {
+        let scratch = Rc::new(ErrorManager::new());
+        let tokens = Scanner::new(source, scratch.clone()).scan_tokens();
+        let expression = Parser::new(tokens, scratch.clone()).parse_expression();
+        match scratch.has_errors() {
+            true => None,
+            false => expression,
+        }
+    }
+
+    // Runs parsed and resolved statements on the session's backend, reporting
+    // a runtime error to the session's `ErrorManager`.
+    fn execute(&self, statements: &Program) {
+        // Like jlox and clox, neither backend runs code that the scanner,
+        // parser, or resolver reported errors in.
+        if self.errors.has_errors() {
+            return;
+        }
+        let result = match self.backend {
+            Backend::TreeWalker => self.interpreter.interpret(statements),
+            Backend::Vm => match compiler::compile(statements, &self.errors) {
+                Some(script) => Vm::new(&self.interpreter).interpret(script),
+                None => Ok(()),
+            },
+        };
+        if let Err(runtime_error) = result {
+            self.errors.runtime_error(runtime_error);
+        }
+    }
+
+    /// Scans the source, reporting any errors to the session's `ErrorManager`.
+    pub fn tokens(&self, source: &String) -> Vec<Token> {
+        self.scanner(source).scan_tokens()
+    }
+
+    /// Scans, parses, transforms (with the interpreter's transformers), and
+    /// resolves the source, reporting any errors to the session's
+    /// `ErrorManager`.
+    pub fn parse(&self, source: &String) -> Vec<Statement> {
+        let (tokens, pragmas) = self.scanner(source).scan_with_pragmas();
+        let statements = self.interpreter.transform(self.parser(tokens).parse());
+        self.resolver().with_pragmas(pragmas).resolve(&statements);
+        statements
+    }
+
+    /// Scans, parses, and resolves the source without running it, returning
+    /// the local scopes the resolver found.
+    pub fn scopes(&self, source: &String) -> Vec<ScopeTree> {
+        let (tokens, pragmas) = self.scanner(source).scan_with_pragmas();
+        let statements = self.interpreter.transform(self.parser(tokens).parse());
+        let resolver = self.resolver().with_pragmas(pragmas);
+        resolver.resolve(&statements);
+        resolver.scope_tree()
+    }
+
+    // The scanner and parser report to the session's `ErrorManager`, and trace
+    // to its interpreter's tracer.
+    fn scanner(&self, source: &String) -> Scanner {
+        Scanner::new(source, self.errors.clone()).with_tracer(self.interpreter.tracer().clone())
+    }
+
+    fn parser(&self, tokens: Vec<Token>) -> Parser {
+        Parser::new(tokens, self.errors.clone()).with_tracer(self.interpreter.tracer().clone())
+    }
+
+    fn resolver(&self) -> Resolver<'_> {
+        Resolver::new(&self.interpreter).with_lints(self.lints)
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether the source ends inside an unclosed `(`, `[` or `{`, in which case
+/// the REPL keeps reading lines before running it.
+pub fn is_incomplete(source: &String) -> bool {
+    let tokens = Scanner::new(source, Rc::new(ErrorManager::new())).scan_tokens();
+    let mut depth = 0;
+    for token in tokens {
+        match token.ty {
+            TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+/// Everything one run of a program produced, for hosts (like a web
+/// playground) that display results themselves.
+#[derive(Debug)]
+pub struct RunReport {
+    /// What the program printed, with a newline after each `print`.
+    pub stdout: String,
+    pub diagnostics: Vec<Diagnostic>,
+    /// The value of the program's last statement, if it's an expression
+    /// statement that ran without errors.
+    pub value: Option<LoxObject>,
+    /// How long scanning, parsing, and interpreting took.
+    pub duration: Duration,
+    pub stats: RunStats,
+}
+
+/// Counts of what the interpreter did during a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunStats {
+    /// Statements executed, including ones run repeatedly by loops and calls.
+    pub statements: usize,
+    /// Calls to functions and natives.
+    pub calls: usize,
+}
+
+#[derive(Default)]
+struct StatsHooks {
+    statements: Cell<usize>,
+    calls: Cell<usize>,
+}
+
+impl InterpreterHooks for StatsHooks {
+    fn on_statement(&self, _stmt: &Statement) {
+        self.statements.set(self.statements.get() + 1);
+    }
+
+    fn on_call(&self, _callee: &LoxObject, _arguments: &[LoxObject], _line: usize) {
+        self.calls.set(self.calls.get() + 1);
+    }
+}
+
+/// Runs the source in a fresh session, capturing its output instead of
+/// printing it.
+pub fn run_to_report(source: &str) -> RunReport {
+    let start = Instant::now();
+    let mut session = Session::new();
+    let output = Rc::new(CapturedOutput::new());
+    session.interpreter.output = output.clone();
+    let stats = Rc::new(StatsHooks::default());
+    session.interpreter.add_hooks(stats.clone());
+
+    let mut statements = session.parse(&source.to_string());
+    // Evaluate a trailing expression statement separately, to keep its value.
+    let last_expression = match statements.last().map(|last| &**last) {
+        Some(Stmt::Expression(last)) => {
+            let expression = last.expression.clone();
+            statements.pop();
+            Some(expression)
+        }
+        _ => None,
+    };
+
+    let errors = &session.errors;
+    if !errors.has_errors() {
+        if let Err(runtime_error) = session.interpreter.interpret(&statements) {
+            errors.runtime_error(runtime_error);
+        }
+    }
+    let mut value = None;
+    if let Some(expression) = last_expression {
+        if !errors.has_errors() && !errors.has_runtime_error() {
+            match session.interpreter.evaluate(&expression) {
+                Ok(result) => value = Some(result),
+                Err(runtime_error) => errors.runtime_error(runtime_error),
+            }
+        }
+    }
+
+    let mut stdout = String::new();
+    for line in output.lines() {
+        stdout.push_str(&line);
+        stdout.push('\n');
+    }
+
+    RunReport {
+        stdout,
+        diagnostics: errors.diagnostics(),
+        value,
+        duration: start.elapsed(),
+        stats: RunStats {
+            statements: stats.statements.get(),
+            calls: stats.calls.get(),
+        },
+    }
+}
+
+pub type SessionId = usize;
+
+/// Manages any number of independent sessions in one process, e.g. for a
+/// playground server hosting one REPL per user.
+pub struct SessionManager {
+    sessions: HashMap<SessionId, Session>,
+    next_id: SessionId,
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self {
+            sessions: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Creates a new session, returning its id.
+    pub fn create(&mut self) -> SessionId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sessions.insert(id, Session::new());
+        id
+    }
+
+    pub fn get(&self, id: SessionId) -> Option<&Session> {
+        self.sessions.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: SessionId) -> Option<&mut Session> {
+        self.sessions.get_mut(&id)
+    }
+
+    /// Runs the source in the given session, returning the diagnostics it
+    /// produced. Returns None if there's no session with that id.
+    pub fn run(&self, id: SessionId, source: &String) -> Option<Vec<String>> {
+        let session = self.sessions.get(&id)?;
+        session.errors.clear();
+        session.run(source);
+        Some(session.errors.messages())
+    }
+
+    /// Drops the session and everything defined in it. Returns whether the
+    /// session existed.
+    pub fn close(&mut self, id: SessionId) -> bool {
+        self.sessions.remove(&id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, sync::atomic::Ordering, time::Instant};
+
+    use super::{is_incomplete, run_to_report, Backend, RunStats, Session, SessionManager};
+    use crate::{
+        errors::Snippet, exceptions::RuntimeException, object::LoxObject, output::CapturedOutput,
+    };
+
+    #[test]
+    fn reports_output_value_and_stats() {
+        let report =
+            run_to_report("fun double(n) { return n * 2; }\nprint double(1);\ndouble(2) + 1;");
+        assert_eq!(report.stdout, "2.00\n");
+        assert!(report.diagnostics.is_empty());
+        assert_eq!(report.value, Some(LoxObject::Number(5.0)));
+        // The declaration, the print, and the return run twice.
+        assert_eq!(
+            report.stats,
+            RunStats {
+                statements: 4,
+                calls: 2
+            }
+        );
+    }
+
+    #[test]
+    fn reports_diagnostics_instead_of_a_value() {
+        let report = run_to_report("print \"before\";\n-\"a\";");
+        assert_eq!(report.stdout, "before\n");
+        assert_eq!(report.value, None);
+        assert_eq!(report.diagnostics.len(), 1);
+        assert_eq!(report.diagnostics[0].line, 2);
+        assert_eq!(report.diagnostics[0].message.code(), "E024");
+
+        // Only a trailing expression statement has a value.
+        assert_eq!(run_to_report("var a = 1;").value, None);
+    }
+
+    #[test]
+    fn one_parse_runs_any_number_of_times() {
+        let mut session = Session::new();
+        let output = Rc::new(CapturedOutput::new());
+        session.interpreter.output = output.clone();
+
+        let program = session
+            .parse(&"var n = 0;\n{\n  var step = 2;\n  n = n + step;\n}\nprint n;".to_string());
+        assert!(session.interpreter.interpret(&program).is_ok());
+        assert!(session.interpreter.interpret(&program).is_ok());
+        assert_eq!(output.lines(), vec!["2.00", "2.00"]);
+    }
+
+    #[test]
+    fn interpret_returns_the_error_without_reporting_it() {
+        let session = Session::new();
+        let program = session.parse(
+            &"print 1;
+print -\"a\";"
+                .to_string(),
+        );
+        match session.interpreter.interpret(&program) {
+            Err(RuntimeException::RuntimeError(error)) => {
+                assert_eq!(error.token.line, 2);
+                assert_eq!(error.message.code(), "E024");
+            }
+            _ => panic!("expected a runtime error"),
+        }
+        assert!(!session.errors.has_runtime_error());
+        assert!(session.errors.messages().is_empty());
+    }
+
+    #[test]
+    fn resolver_errors_stop_the_script_from_running() {
+        let mut session = Session::new();
+        let output = Rc::new(CapturedOutput::new());
+        session.interpreter.output = output.clone();
+        session.run(&"print \"ran\";\nif (true) return;".to_string());
+        assert!(output.lines().is_empty());
+        assert_eq!(
+            session.errors.messages(),
+            vec!["[line 2] Error[E075] at \"return\": Can't return from top-level code."]
+        );
+
+        let report = run_to_report("print \"ran\";\nif (true) return;");
+        assert_eq!(report.stdout, "");
+        assert_eq!(report.diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn diagnostics_quote_the_line_they_point_at() {
+        let session = Session::new();
+        session.run(&"var a = 1;\n\tprint (a +;".to_string());
+        assert_eq!(
+            session.errors.diagnostics()[0].snippet,
+            Some(Snippet {
+                text: "\tprint (a +;".to_string(),
+                column: 12,
+                width: 1,
+            })
+        );
+
+        let session = Session::new();
+        session.run(&"var a = 1;\nprint -\"a\";".to_string());
+        assert_eq!(
+            session.errors.diagnostics()[0].snippet,
+            Some(Snippet {
+                text: "print -\"a\";".to_string(),
+                column: 7,
+                width: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn duplicate_map_keys_quote_both_keys() {
+        let session = Session::new();
+        session.run(&"var m = {\n  \"a\": 1,\n  (\"a\"): 2\n};".to_string());
+        let diagnostic = &session.errors.diagnostics()[0];
+        assert_eq!(diagnostic.message.code(), "E079");
+        assert_eq!(
+            diagnostic.snippet,
+            Some(Snippet {
+                text: "  (\"a\"): 2".to_string(),
+                column: 3,
+                width: 1,
+            })
+        );
+        assert_eq!(
+            diagnostic.notes[0].snippet,
+            Some(Snippet {
+                text: "  \"a\": 1,".to_string(),
+                column: 3,
+                width: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn a_past_deadline_stops_an_endless_loop_on_both_backends() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut session = Session::new();
+            session.backend = backend;
+            session.interpreter.set_deadline(Some(Instant::now()));
+            session.run(&"var n = 0;\nwhile (true) {\n  n = n + 1;\n}".to_string());
+            assert_eq!(
+                session.errors.messages(),
+                vec!["[line 2] Error[E055] (while): The script was stopped because it ran out of time."],
+                "{:?}",
+                backend
+            );
+        }
+    }
+
+    #[test]
+    fn blocking_natives_check_for_interrupts() {
+        let session = Session::new();
+        session
+            .interpreter
+            .interrupt_handle()
+            .store(true, Ordering::Relaxed);
+        session.run(&"fun f() {}\nprint timeit(f, 1000000000);".to_string());
+        assert_eq!(
+            session.errors.messages(),
+            vec!["[line 2] Error[E055] ()): The script was stopped because it was interrupted."]
+        );
+    }
+
+    #[test]
+    fn repl_prints_bare_expressions() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut session = Session::new();
+            session.backend = backend;
+            let output = Rc::new(CapturedOutput::new());
+            session.interpreter.output = output.clone();
+            for source in [
+                "var a = 1;",
+                "a + 2",
+                "a + 2;",
+                "fun (x) { return x; }(\"hi\")",
+            ] {
+                session.run_repl(&source.to_string());
+            }
+            assert_eq!(output.lines(), vec!["3.00", "hi"], "{:?}", backend);
+            assert!(session.errors.messages().is_empty());
+        }
+
+        // Errors are reported once, as they are for any other source.
+        let session = Session::new();
+        session.run_repl(&"1 +".to_string());
+        assert_eq!(
+            session.errors.messages(),
+            vec!["[line 1] Error[E023] at end: Expect expression."]
+        );
+    }
+
+    #[test]
+    fn repl_input_that_ran_is_returned_as_script_source() {
+        let session = Session::new();
+        let mut transcript = String::new();
+        for source in [
+            "var a = 1;\n",
+            "a + 1\n",
+            "a + // comment\n1\n",
+            "print b;\n",
+        ] {
+            if let Some(script) = session.run_repl(&source.to_string()) {
+                transcript.push_str(&script);
+            }
+            session.errors.reset_errors();
+        }
+        assert_eq!(
+            transcript,
+            "var a = 1;\nprint a + 1;\nprint a + // comment\n1\n;\n"
+        );
+
+        // The transcript runs as a script, printing what the REPL printed.
+        let mut replayed = Session::new();
+        let output = Rc::new(CapturedOutput::new());
+        replayed.interpreter.output = output.clone();
+        replayed.run(&transcript);
+        assert_eq!(output.lines(), vec!["2.00", "2.00"]);
+    }
+
+    #[test]
+    fn sessions_start_with_the_prelude() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut session = Session::new();
+            session.backend = backend;
+            let output = Rc::new(CapturedOutput::new());
+            session.interpreter.output = output.clone();
+            session.run(
+                &"print join(map(range(1, 4), fun (n) { return n * n; }), \", \");\nprint split(\"a,b,,c\", \",\");\nprint index_of(\"hello\", \"ll\");\nprint reverse(filter(range(0, 6), fun (n) { return n > 3; }));"
+                    .to_string(),
+            );
+            assert_eq!(
+                output.lines(),
+                vec![
+                    "1.00, 4.00, 9.00",
+                    "[\"a\", \"b\", \"\", \"c\"]",
+                    "2.00",
+                    "[5.00, 4.00]"
+                ],
+                "{:?}",
+                backend
+            );
+        }
+
+        // Errors inside the prelude's functions say they came from it.
+        let session = Session::new();
+        session.run(&"print starts_with(1, \"a\");".to_string());
+        assert_eq!(
+            session.errors.messages(),
+            vec!["[line 9 in <prelude>] Error[E043] ()): substr() expects a string.\n    in <native fn> called on line 9 in <prelude>\n    in <fn starts_with> called on line 1"]
+        );
+
+        let session = Session::without_prelude();
+        session.run(&"print range;".to_string());
+        assert_eq!(
+            session.errors.messages(),
+            vec!["[line 1] Error[E040] (range): Undefined variable 'range'."]
+        );
+    }
+
+    #[test]
+    fn managed_sessions_keep_their_globals_and_errors_to_themselves() {
+        let mut sessions = SessionManager::new();
+        let first = sessions.create();
+        let second = sessions.create();
+
+        assert_eq!(sessions.run(first, &"var a = 1;".to_string()), Some(vec![]));
+        assert_eq!(
+            sessions.run(second, &"a;".to_string()),
+            Some(vec![
+                "[line 1] Error[E040] (a): Undefined variable 'a'.".to_string()
+            ])
+        );
+        // The second session's error isn't reported in the first, which
+        // still has its variable.
+        assert!(sessions.get(first).unwrap().errors.messages().is_empty());
+        assert_eq!(sessions.run(first, &"a = 2;".to_string()), Some(vec![]));
+
+        assert!(sessions.close(second));
+        assert_eq!(sessions.run(second, &"a;".to_string()), None);
+        assert_eq!(sessions.len(), 1);
+    }
+
+    #[test]
+    fn input_with_unclosed_brackets_is_incomplete() {
+        assert!(is_incomplete(&"fun f() {\n".to_string()));
+        assert!(is_incomplete(&"print (1 +\n".to_string()));
+        assert!(!is_incomplete(&"fun f() {\n}\n".to_string()));
+        assert!(!is_incomplete(&"print 1;".to_string()));
+        assert!(is_incomplete(&"var list = [\n  1,\n".to_string()));
+        assert!(!is_incomplete(&"var list = [\n  1,\n];".to_string()));
+        // Extra closing brackets can't be completed, so they're run (and
+        // reported) right away.
+        assert!(!is_incomplete(&"}".to_string()));
+        // Brackets in strings and comments don't count.
+        assert!(!is_incomplete(&"print \"{\"; // (".to_string()));
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    extern crate test;
+
+    use test::Bencher;
+
+    use super::Session;
+
+    // What loading the prelude adds to startup (run with `cargo +nightly bench`).
+    #[bench]
+    fn start_with_prelude(b: &mut Bencher) {
+        b.iter(Session::new);
+    }
+
+    #[bench]
+    fn start_without_prelude(b: &mut Bencher) {
+        b.iter(Session::without_prelude);
+    }
+}
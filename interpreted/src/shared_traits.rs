@@ -14,8 +14,9 @@ pub trait CharLen {
 }
 
 impl CharLen for String {
+    // The index of the last character (0 for an empty string too).
     fn char_length(&self) -> usize {
-        self.chars().count() - 1
+        self.chars().count().saturating_sub(1)
     }
 }
 
@@ -47,7 +48,3 @@ impl Substring for str {
         }
     }
 }
-
-pub trait Named {
-    fn name(&self) -> &'static str;
-}
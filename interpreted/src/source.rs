@@ -0,0 +1,71 @@
+use crate::{
+    errors::{Diagnostic, ErrorManager},
+    messages,
+};
+
+/// The byte order mark some editors start UTF-8 files with.
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// A script's text, from the bytes of its file. A leading byte order mark is
+/// dropped. Invalid UTF-8 is reported (with the byte offset of the first bad
+/// byte, and its line) and gives None, unless `lossy` is set, in which case
+/// bad sequences are replaced with U+FFFD and reported as a warning.
+pub fn decode(bytes: &[u8], lossy: bool, errors: &ErrorManager) -> Option<String> {
+    let (skipped, text) = match bytes.strip_prefix(BOM) {
+        Some(text) => (BOM.len(), text),
+        None => (0, bytes),
+    };
+    let error = match std::str::from_utf8(text) {
+        Ok(source) => return Some(source.to_string()),
+        Err(error) => error,
+    };
+
+    let valid = &text[..error.valid_up_to()];
+    let line = valid.iter().filter(|byte| **byte == b'\n').count() + 1;
+    let offset = (skipped + error.valid_up_to()).to_string();
+    if lossy {
+        Diagnostic::warning(line, messages::REPLACED_INVALID_UTF8.with(&[offset])).emit(errors);
+        Some(String::from_utf8_lossy(text).into_owned())
+    } else {
+        Diagnostic::error(line, messages::INVALID_UTF8.with(&[offset])).emit(errors);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use crate::errors::ErrorManager;
+
+    #[test]
+    fn strips_a_byte_order_mark() {
+        let errors = ErrorManager::new();
+        assert_eq!(
+            decode(b"\xEF\xBB\xBFprint 1;", false, &errors),
+            Some("print 1;".to_string())
+        );
+        assert!(errors.messages().is_empty());
+    }
+
+    #[test]
+    fn reports_where_invalid_utf8_starts() {
+        let bytes = b"\xEF\xBB\xBFprint 1;\nprint \"caf\xE9\";";
+        let errors = ErrorManager::new();
+        assert_eq!(decode(bytes, false, &errors), None);
+        assert_eq!(
+            errors.messages(),
+            vec!["[line 2] Error[E068] : The file isn't valid UTF-8: invalid byte at offset 22."]
+        );
+
+        let errors = ErrorManager::new();
+        assert_eq!(
+            decode(bytes, true, &errors),
+            Some("print 1;\nprint \"caf\u{FFFD}\";".to_string())
+        );
+        assert!(!errors.has_errors());
+        assert_eq!(
+            errors.messages(),
+            vec!["[line 2] Warning[E069] : Replaced invalid UTF-8 with U+FFFD, starting at byte offset 22."]
+        );
+    }
+}
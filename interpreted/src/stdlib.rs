@@ -0,0 +1,419 @@
+//! Native functions for working with strings and numbers, and for asking about
+//! the interpreter itself, registered in every interpreter's globals.
+
+use crate::{
+    exceptions::{RuntimeError, RuntimeException},
+    function::{Arity, NativeContext, NativeFn},
+    interpreter::Interpreter,
+    messages,
+    object::LoxObject,
+};
+
+/// Defines the natives in the interpreter's globals.
+pub fn define(interpreter: &Interpreter) {
+    let natives: [(&str, Arity, NativeFn, &str); 12] = [
+        (
+            "substr",
+            Arity::between(2, 3),
+            native_substr,
+            "The characters of a string from a start index, up to a length (or to the end).",
+        ),
+        (
+            "parse_number",
+            Arity::exactly(1),
+            native_parse_number,
+            "The number a string holds, like \"2.5\" or \" -3 \", or nil if it doesn't hold one.",
+        ),
+        (
+            "to_string",
+            Arity::exactly(1),
+            native_to_string,
+            "A value as `print` would show it.",
+        ),
+        (
+            "abs",
+            Arity::exactly(1),
+            native_abs,
+            "A number without its sign.",
+        ),
+        (
+            "floor",
+            Arity::exactly(1),
+            native_floor,
+            "The largest whole number no greater than a number.",
+        ),
+        (
+            "ceil",
+            Arity::exactly(1),
+            native_ceil,
+            "The smallest whole number no less than a number.",
+        ),
+        (
+            "sqrt",
+            Arity::exactly(1),
+            native_sqrt,
+            "The square root of a number that isn't negative.",
+        ),
+        (
+            "min",
+            Arity::at_least(1),
+            native_min,
+            "The smallest of any number of numbers.",
+        ),
+        (
+            "max",
+            Arity::at_least(1),
+            native_max,
+            "The largest of any number of numbers.",
+        ),
+        (
+            "random",
+            Arity::exactly(0),
+            native_random,
+            "A random number from 0 up to (but not including) 1.",
+        ),
+        (
+            "version",
+            Arity::exactly(0),
+            native_version,
+            "The interpreter's version, e.g. \"0.1.0\".",
+        ),
+        (
+            "features",
+            Arity::exactly(0),
+            native_features,
+            "A map from the name of each optional feature to whether this build has it.",
+        ),
+    ];
+    for (name, arity, native, doc) in natives {
+        interpreter.define_native(name, arity, doc, native);
+    }
+}
+
+/// Native Substr Function: the characters of a string from a start index, up
+/// to a length (or to the end, if there's no length). It stops early at the
+/// end of the string.
+fn native_substr(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let string = match args[0].as_string(context.paren) {
+        Ok(string) => string,
+        Err(_) => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::EXPECT_STRING.with(&["substr"]),
+            ))
+        }
+    };
+    let length = match args.get(2) {
+        Some(length) => count(context, length),
+        None => Some(usize::MAX),
+    };
+    let (start, length) = match (count(context, &args[1]), length) {
+        (Some(start), Some(length)) => (start, length),
+        _ => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::SUBSTR_EXPECTS_RANGE,
+            ))
+        }
+    };
+    Ok(LoxObject::String(
+        string
+            .chars()
+            .skip(start)
+            .take(length)
+            .collect::<String>()
+            .into(),
+    ))
+}
+
+// The value as a count of characters, if it's a whole number that isn't
+// negative.
+fn count(context: &NativeContext, value: &LoxObject) -> Option<usize> {
+    match value.as_number(context.paren) {
+        Ok(number) if number >= 0.0 && number.fract() == 0.0 => Some(number as usize),
+        _ => None,
+    }
+}
+
+/// Native Parse Number Function: the number a string holds, like `"2.5"` or
+/// `" -3 "`, or nil if it doesn't hold one.
+fn native_parse_number(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let string = match args[0].as_string(context.paren) {
+        Ok(string) => string,
+        Err(_) => {
+            return Err(RuntimeError::new(
+                context.paren.clone(),
+                messages::EXPECT_STRING.with(&["parse_number"]),
+            ))
+        }
+    };
+    // Only plain decimal numbers, not Rust's "inf", "NaN", or "1e3".
+    let string = string.trim();
+    let digits = string.strip_prefix(['-', '+']).unwrap_or(string);
+    let plain = digits.chars().any(|c| c.is_ascii_digit())
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '.');
+    match string.parse::<f64>() {
+        Ok(number) if plain => Ok(LoxObject::Number(number)),
+        _ => Ok(LoxObject::Nil),
+    }
+}
+
+/// Native To String Function: a value as `print` would show it.
+fn native_to_string(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    Ok(LoxObject::String(
+        context.interpreter.stringify(&args[0]).into(),
+    ))
+}
+
+/// Native Abs Function: a number without its sign.
+fn native_abs(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    math("abs", context, &args[0], f64::abs)
+}
+
+/// Native Floor Function: the largest whole number no greater than a number.
+fn native_floor(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    math("floor", context, &args[0], f64::floor)
+}
+
+/// Native Ceil Function: the smallest whole number no less than a number.
+fn native_ceil(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    math("ceil", context, &args[0], f64::ceil)
+}
+
+/// Native Sqrt Function: the square root of a number that isn't negative.
+fn native_sqrt(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    match args[0] {
+        LoxObject::Number(number) if number < 0.0 => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::SQRT_OF_NEGATIVE.with(&[args[0].stringify()]),
+        )),
+        _ => math("sqrt", context, &args[0], f64::sqrt),
+    }
+}
+
+/// Native Min Function: the smallest of any number of numbers.
+fn native_min(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    fold("min", context, &args, f64::min)
+}
+
+/// Native Max Function: the largest of any number of numbers.
+fn native_max(
+    context: &NativeContext,
+    args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    fold("max", context, &args, f64::max)
+}
+
+// Combines the arguments, which must all be numbers, with the function.
+fn fold(
+    name: &str,
+    context: &NativeContext,
+    args: &[LoxObject],
+    function: fn(f64, f64) -> f64,
+) -> Result<LoxObject, RuntimeException> {
+    let mut numbers = vec![];
+    for arg in args {
+        match arg.as_number(context.paren) {
+            Ok(number) => numbers.push(number),
+            Err(_) => {
+                return Err(RuntimeError::new(
+                    context.paren.clone(),
+                    messages::EXPECT_NUMBER.with(&[name]),
+                ))
+            }
+        }
+    }
+    Ok(LoxObject::Number(
+        numbers.into_iter().reduce(function).unwrap(),
+    ))
+}
+
+// Applies the function to the argument, which must be a number.
+fn math(
+    name: &str,
+    context: &NativeContext,
+    value: &LoxObject,
+    function: fn(f64) -> f64,
+) -> Result<LoxObject, RuntimeException> {
+    match value.as_number(context.paren) {
+        Ok(number) => Ok(LoxObject::Number(function(number))),
+        Err(_) => Err(RuntimeError::new(
+            context.paren.clone(),
+            messages::EXPECT_NUMBER.with(&[name]),
+        )),
+    }
+}
+
+/// Native Random Function: a random number from 0 up to (but not including)
+/// 1. Like `clock`, it's an input that `--record` saves and `--replay` reuses.
+fn native_random(
+    context: &NativeContext,
+    _args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let interpreter = context.interpreter;
+    let value = interpreter
+        .replay
+        .number("random", || interpreter.rng.borrow_mut().unit());
+
+    match value {
+        Ok(value) => Ok(LoxObject::Number(value)),
+        Err(message) => Err(RuntimeError::new(context.paren.clone(), message)),
+    }
+}
+
+/// The optional features `features()` reports on, and whether this build has
+/// each one. Values are reference counted rather than garbage collected, and
+/// there are no networking natives, so "gc" and "net" are never enabled.
+const FEATURES: [(&str, bool); 4] = [
+    ("vm", true),
+    ("trace", cfg!(feature = "trace")),
+    ("gc", false),
+    ("net", false),
+];
+
+/// Native Version Function: the interpreter's version, e.g. "0.1.0".
+fn native_version(
+    _context: &NativeContext,
+    _args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    Ok(LoxObject::String(env!("CARGO_PKG_VERSION").into()))
+}
+
+/// Native Features Function: a map from the name of each optional feature to
+/// whether this build has it, so scripts can check before relying on one.
+fn native_features(
+    context: &NativeContext,
+    _args: Vec<LoxObject>,
+) -> Result<LoxObject, RuntimeException> {
+    let entries = FEATURES
+        .iter()
+        .map(|(name, enabled)| {
+            (
+                LoxObject::String(name.to_string().into()),
+                LoxObject::Boolean(*enabled),
+            )
+        })
+        .collect();
+    context.interpreter.build_map(context.paren, entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{output::CapturedOutput, replay::Replay, session::Session};
+
+    // What the script prints, or its errors if it fails.
+    fn run(source: &str) -> Vec<String> {
+        let mut session = Session::new();
+        let output = Rc::new(CapturedOutput::new());
+        session.interpreter.output = output.clone();
+        session.run(&source.to_string());
+        let mut lines = output.lines();
+        lines.extend(session.errors.messages());
+        lines
+    }
+
+    #[test]
+    fn string_functions() {
+        assert_eq!(
+            run("print substr(\"héllo\", 1, 3);\nprint substr(\"abc\", 2, 10);\nprint substr(\"abc\", 5, 1);\nprint substr(\"héllo\", 2);"),
+            vec!["éll", "c", "", "llo"]
+        );
+        assert_eq!(
+            run("print parse_number(\" -2.5 \") + 1;\nprint parse_number(\"1e3\");\nprint parse_number(\"inf\");\nprint parse_number(\".\");"),
+            vec!["-1.50", "nil", "nil", "nil"]
+        );
+        assert_eq!(
+            run("set_precision(1);\nprint to_string(2) + \"!\";\nprint to_string([true]);"),
+            vec!["2.0!", "[true]"]
+        );
+        assert_eq!(
+            run("print substr(\"abc\", 0.5, 1);"),
+            vec!["[line 1] Error[E071] ()): substr() expects a whole number start and length, neither below 0.\n    in <native fn> called on line 1"]
+        );
+    }
+
+    #[test]
+    fn math_functions() {
+        assert_eq!(
+            run("print abs(-2);\nprint floor(-2.5);\nprint ceil(2.1);\nprint sqrt(16);"),
+            vec!["2.00", "-3.00", "3.00", "4.00"]
+        );
+        assert_eq!(
+            run("print min(3);\nprint min(3, -1, 2);\nprint max(3, -1, 2);\nprint max(1, \"2\");"),
+            vec!["3.00", "-1.00", "3.00", "[line 4] Error[E070] ()): max() expects a number.\n    in <native fn> called on line 4"]
+        );
+        assert_eq!(
+            run("print max();"),
+            vec!["[line 1] Error[E027] ()): Expected at least 1 arguments but got 0 when calling <native fn>."]
+        );
+        assert_eq!(
+            run("print floor(\"2\");"),
+            vec!["[line 1] Error[E070] ()): floor() expects a number.\n    in <native fn> called on line 1"]
+        );
+        assert_eq!(
+            run("print sqrt(-4);"),
+            vec!["[line 1] Error[E072] ()): Can't take the square root of -4.00, which is negative.\n    in <native fn> called on line 1"]
+        );
+    }
+
+    #[test]
+    fn interpreter_version_and_features() {
+        assert_eq!(
+            run("print version();"),
+            vec![env!("CARGO_PKG_VERSION").to_string()]
+        );
+        assert_eq!(
+            run("var features = features();\nprint features[\"vm\"];\nprint features[\"net\"];\nprint keys(features);"),
+            vec!["true", "false", "[\"vm\", \"trace\", \"gc\", \"net\"]"]
+        );
+        assert_eq!(
+            run("print features()[\"trace\"];"),
+            vec![cfg!(feature = "trace").to_string()]
+        );
+    }
+
+    #[test]
+    fn random_numbers_are_in_range_and_replayable() {
+        let source = "var i = 0;\nwhile (i < 100) {\n  var r = random();\n  if (r < 0 or r >= 1) print r;\n  i = i + 1;\n}\nprint random();";
+        let mut session = Session::new();
+        session.interpreter.replay = Replay::recording();
+        let output = Rc::new(CapturedOutput::new());
+        session.interpreter.output = output.clone();
+        session.run(&source.to_string());
+        assert_eq!(output.lines().len(), 1);
+
+        let trace = session.interpreter.replay.to_json().unwrap();
+        let mut replayed = Session::new();
+        replayed.interpreter.replay = Replay::from_json(&trace).unwrap();
+        let replayed_output = Rc::new(CapturedOutput::new());
+        replayed.interpreter.output = replayed_output.clone();
+        replayed.run(&source.to_string());
+        assert_eq!(replayed_output.lines(), output.lines());
+    }
+}
@@ -1,9 +1,22 @@
-use std::{fmt::Debug, rc::Rc};
-
-use crate::{expr::Expression, shared_traits::Named, token::Token};
-use downcast::{downcast, Any};
-
-pub trait Stmt: Any + Debug + Named {}
+use std::rc::Rc;
+
+use crate::{expr::Expression, token::Token};
+
+/// A statement. Each kind's fields are in a struct of its own, which is what
+/// visitors get, so `accept` dispatches with a plain match.
+#[derive(Debug)]
+pub enum Stmt {
+    Expression(ExprStmt),
+    Print(PrintStmt),
+    Variable(VariableStmt),
+    Block(BlockStmt),
+    If(IfStmt),
+    While(WhileStmt),
+    Function(FunStmt),
+    Return(ReturnStmt),
+    Measure(MeasureStmt),
+    Defer(DeferStmt),
+}
 
 pub trait StmtVisitor<T> {
     fn visit_expression_stmt(&self, stmt: &ExprStmt) -> T;
@@ -14,31 +27,36 @@ pub trait StmtVisitor<T> {
     fn visit_while_stmt(&self, stmt: &WhileStmt) -> T;
     fn visit_fun_stmt(&self, stmt: &FunStmt) -> T;
     fn visit_return_stmt(&self, stmt: &ReturnStmt) -> T;
+    fn visit_measure_stmt(&self, stmt: &MeasureStmt) -> T;
+    fn visit_defer_stmt(&self, stmt: &DeferStmt) -> T;
 }
 
 pub trait StmtVisitorTarget {
     fn accept<T>(&self, visitor: impl StmtVisitor<T>) -> T;
 }
 
-impl StmtVisitorTarget for Rc<dyn Stmt> {
+impl StmtVisitorTarget for Rc<Stmt> {
     fn accept<T>(&self, visitor: impl StmtVisitor<T>) -> T {
-        match self.name() {
-            "Expression" => visitor.visit_expression_stmt(self.downcast_ref::<ExprStmt>().unwrap()),
-            "If" => visitor.visit_if_stmt(self.downcast_ref::<IfStmt>().unwrap()),
-            "Print" => visitor.visit_print_stmt(self.downcast_ref::<PrintStmt>().unwrap()),
-            "Variable" => visitor.visit_variable_stmt(self.downcast_ref::<VariableStmt>().unwrap()),
-            "Block" => visitor.visit_block_stmt(self.downcast_ref::<BlockStmt>().unwrap()),
-            "While" => visitor.visit_while_stmt(self.downcast_ref::<WhileStmt>().unwrap()),
-            "Function" => visitor.visit_fun_stmt(self.downcast_ref::<FunStmt>().unwrap()),
-            "Return" => visitor.visit_return_stmt(self.downcast_ref::<ReturnStmt>().unwrap()),
-            _ => unreachable!(),
+        match &**self {
+            Stmt::Expression(stmt) => visitor.visit_expression_stmt(stmt),
+            Stmt::Print(stmt) => visitor.visit_print_stmt(stmt),
+            Stmt::Variable(stmt) => visitor.visit_variable_stmt(stmt),
+            Stmt::Block(stmt) => visitor.visit_block_stmt(stmt),
+            Stmt::If(stmt) => visitor.visit_if_stmt(stmt),
+            Stmt::While(stmt) => visitor.visit_while_stmt(stmt),
+            Stmt::Function(stmt) => visitor.visit_fun_stmt(stmt),
+            Stmt::Return(stmt) => visitor.visit_return_stmt(stmt),
+            Stmt::Measure(stmt) => visitor.visit_measure_stmt(stmt),
+            Stmt::Defer(stmt) => visitor.visit_defer_stmt(stmt),
         }
     }
 }
 
-downcast!(dyn Stmt);
+pub type Statement = Rc<Stmt>;
 
-pub type Statement = Rc<dyn Stmt>;
+/// A parsed script's top-level statements. The interpreter only borrows a
+/// program, so one parse can be resolved once and run any number of times.
+pub type Program = [Statement];
 
 #[derive(Debug, Clone)]
 pub struct ExprStmt {
@@ -47,13 +65,7 @@ pub struct ExprStmt {
 
 impl ExprStmt {
     pub fn new(expression: Expression) -> Statement {
-        Rc::new(ExprStmt { expression })
-    }
-}
-impl Stmt for ExprStmt {}
-impl Named for ExprStmt {
-    fn name(&self) -> &'static str {
-        "Expression"
+        Rc::new(Stmt::Expression(ExprStmt { expression }))
     }
 }
 
@@ -64,13 +76,7 @@ pub struct PrintStmt {
 
 impl PrintStmt {
     pub fn new(expression: Expression) -> Statement {
-        Rc::new(PrintStmt { expression })
-    }
-}
-impl Stmt for PrintStmt {}
-impl Named for PrintStmt {
-    fn name(&self) -> &'static str {
-        "Print"
+        Rc::new(Stmt::Print(PrintStmt { expression }))
     }
 }
 
@@ -82,13 +88,7 @@ pub struct VariableStmt {
 
 impl VariableStmt {
     pub fn new(name: Token, initializer: Option<Expression>) -> Statement {
-        Rc::new(VariableStmt { name, initializer })
-    }
-}
-impl Stmt for VariableStmt {}
-impl Named for VariableStmt {
-    fn name(&self) -> &'static str {
-        "Variable"
+        Rc::new(Stmt::Variable(VariableStmt { name, initializer }))
     }
 }
 
@@ -99,13 +99,7 @@ pub struct BlockStmt {
 
 impl BlockStmt {
     pub fn new(statements: Vec<Statement>) -> Statement {
-        Rc::new(BlockStmt { statements })
-    }
-}
-impl Stmt for BlockStmt {}
-impl Named for BlockStmt {
-    fn name(&self) -> &'static str {
-        "Block"
+        Rc::new(Stmt::Block(BlockStmt { statements }))
     }
 }
 
@@ -122,35 +116,29 @@ impl IfStmt {
         then_branch: Statement,
         else_branch: Option<Statement>,
     ) -> Statement {
-        Rc::new(IfStmt {
+        Rc::new(Stmt::If(IfStmt {
             condition,
             then_branch,
             else_branch,
-        })
-    }
-}
-impl Stmt for IfStmt {}
-impl Named for IfStmt {
-    fn name(&self) -> &'static str {
-        "If"
+        }))
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct WhileStmt {
+    /// The `while` (or, once desugared, `for`) keyword.
+    pub keyword: Token,
     pub condition: Expression,
     pub body: Statement,
 }
 
 impl WhileStmt {
-    pub fn new(condition: Expression, body: Statement) -> Statement {
-        Rc::new(WhileStmt { condition, body })
-    }
-}
-impl Stmt for WhileStmt {}
-impl Named for WhileStmt {
-    fn name(&self) -> &'static str {
-        "While"
+    pub fn new(keyword: Token, condition: Expression, body: Statement) -> Statement {
+        Rc::new(Stmt::While(WhileStmt {
+            keyword,
+            condition,
+            body,
+        }))
     }
 }
 
@@ -163,13 +151,7 @@ pub struct FunStmt {
 
 impl FunStmt {
     pub fn new(name: Token, params: Vec<Token>, body: Vec<Statement>) -> Statement {
-        Rc::new(FunStmt { name, params, body })
-    }
-}
-impl Stmt for FunStmt {}
-impl Named for FunStmt {
-    fn name(&self) -> &'static str {
-        "Function"
+        Rc::new(Stmt::Function(FunStmt { name, params, body }))
     }
 }
 
@@ -181,12 +163,34 @@ pub struct ReturnStmt {
 
 impl ReturnStmt {
     pub fn new(keyword: Token, value: Expression) -> Statement {
-        Rc::new(ReturnStmt { keyword, value })
+        Rc::new(Stmt::Return(ReturnStmt { keyword, value }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MeasureStmt {
+    pub keyword: Token,
+    pub body: Vec<Statement>,
+}
+
+impl MeasureStmt {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(keyword: Token, body: Vec<Statement>) -> Statement {
+        Rc::new(Stmt::Measure(MeasureStmt { keyword, body }))
     }
 }
-impl Stmt for ReturnStmt {}
-impl Named for ReturnStmt {
-    fn name(&self) -> &'static str {
-        "Return"
+
+/// A statement to run when the enclosing block (or function, or script) is
+/// left.
+#[derive(Debug, Clone)]
+pub struct DeferStmt {
+    pub keyword: Token,
+    pub body: Statement,
+}
+
+impl DeferStmt {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(keyword: Token, body: Statement) -> Statement {
+        Rc::new(Stmt::Defer(DeferStmt { keyword, body }))
     }
 }
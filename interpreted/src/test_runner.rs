@@ -0,0 +1,139 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::{output::CapturedOutput, session::Session};
+
+/// How many test files passed, failed, or had no `.expected` file to check
+/// against.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+// What running one test file produced.
+enum Outcome {
+    Passed,
+    Failed { expected: String, actual: String },
+    Skipped,
+}
+
+/// Runs every `.lox` file in the directory that has a `.expected` file next to
+/// it, checking what it prints (followed by any diagnostics, like the golden
+/// tests) against that file. Files run on `jobs` threads at once, each in its
+/// own session, and are reported in order once they've all finished.
+pub fn run(dir: &Path, jobs: usize) -> io::Result<Summary> {
+    let tests = tests(dir)?;
+    let outcomes = Mutex::new(Vec::with_capacity(tests.len()));
+    let next = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, tests.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(test) = tests.get(index) else {
+                    break;
+                };
+                let outcome = run_test(test);
+                outcomes.lock().unwrap().push((index, outcome));
+            });
+        }
+    });
+
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(index, _)| *index);
+
+    let mut summary = Summary::default();
+    for (index, outcome) in outcomes {
+        let test = tests[index].display();
+        match outcome? {
+            Outcome::Passed => {
+                println!("ok    {}", test);
+                summary.passed += 1;
+            }
+            Outcome::Failed { expected, actual } => {
+                println!("FAIL  {}", test);
+                println!("--- expected");
+                print!("{}", expected);
+                println!("--- actual");
+                print!("{}", actual);
+                summary.failed += 1;
+            }
+            Outcome::Skipped => {
+                println!("skip  {} (no .expected file)", test);
+                summary.skipped += 1;
+            }
+        }
+    }
+
+    println!();
+    println!(
+        "{} passed, {} failed, {} skipped",
+        summary.passed, summary.failed, summary.skipped
+    );
+    Ok(summary)
+}
+
+// The directory's test files, sorted so reports are stable between runs.
+fn tests(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut tests = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "lox") {
+            tests.push(path);
+        }
+    }
+    tests.sort();
+    Ok(tests)
+}
+
+fn run_test(test: &Path) -> io::Result<Outcome> {
+    let expected = match fs::read_to_string(test.with_extension("expected")) {
+        Ok(expected) => expected,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Outcome::Skipped),
+        Err(error) => return Err(error),
+    };
+    let source = fs::read_to_string(test)?;
+
+    // Sessions share no state, so each thread can run its own.
+    let mut session = Session::new();
+    let output = Rc::new(CapturedOutput::new());
+    session.interpreter.output = output.clone();
+    session.run(&source);
+
+    let mut actual = String::new();
+    for line in output.lines().into_iter().chain(session.errors.messages()) {
+        actual.push_str(&line);
+        actual.push('\n');
+    }
+
+    match actual == expected {
+        true => Ok(Outcome::Passed),
+        false => Ok(Outcome::Failed { expected, actual }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::run;
+
+    #[test]
+    fn runs_the_interpreter_goldens_on_several_threads() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/golden/interpreter");
+        let serial = run(&dir, 1).unwrap();
+        assert_eq!(serial.failed, 0);
+        assert!(serial.passed > 0);
+        assert_eq!(run(&dir, 4).unwrap(), serial);
+    }
+}
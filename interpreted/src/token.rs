@@ -1,5 +1,19 @@
+//! Tokens, as the scanner produces them. A scan always ends with exactly one
+//! `Eof` token, and every other token's lexeme is the exact source text at its
+//! span (after line endings are normalized to `\n`). Only `String` and
+//! `Number` tokens carry a literal: a string's contents without its quotes, or
+//! a number's value.
+//!
+//! Lines, columns, and spans are `usize`s, as wide as the address space, so
+//! they're right for any source that fits in memory, including ones past
+//! 4 GiB on 64-bit targets. The scanner reads the source by byte offset, so
+//! it takes time linear in the source's length, and keeps one copy of it
+//! (shared with the error manager) rather than a copy per character.
+use std::ops::Range;
+
 use crate::token_type::TokenType;
 
+/// The value of a literal token.
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum TokenLiteral {
     String(String),
@@ -36,9 +50,16 @@ impl Into<TokenLiteral> for f64 {
 #[derive(Debug, Clone)]
 pub struct Token {
     pub ty: TokenType,
+    /// The token's text in the source. Empty for `Eof`.
     pub lexeme: String,
     pub literal: TokenLiteral,
+    /// The line (from 1) the lexeme starts on.
     pub line: usize,
+    /// The column (in characters, from 1) the lexeme starts at, or 0 for
+    /// tokens that weren't scanned from source.
+    pub column: usize,
+    /// The lexeme's character offsets in the scanned source.
+    pub span: Range<usize>,
 }
 
 impl Token {
@@ -53,8 +74,17 @@ impl Token {
             lexeme: lexeme.into(),
             literal: literal.into(),
             line: line.into(),
+            column: 0,
+            span: 0..0,
         }
     }
+
+    /// Where the token was scanned from in the source.
+    pub fn spanning(mut self, column: usize, span: Range<usize>) -> Self {
+        self.column = column;
+        self.span = span;
+        self
+    }
 }
 
 impl ToString for Token {
@@ -62,3 +92,53 @@ impl ToString for Token {
         self.ty.to_string() + " " + self.lexeme.as_str() + " " + self.literal.to_string().as_str()
     }
 }
+
+/// Adapters for iterators over scanned tokens, e.g. `tokens.iter().significant()`.
+pub trait TokenIterator<'a>: Iterator<Item = &'a Token> + Sized {
+    /// Every token but the `Eof` that ends a scan.
+    fn significant(self) -> impl Iterator<Item = &'a Token> {
+        self.filter(|token| token.ty != TokenType::Eof)
+    }
+
+    /// Only the tokens of the given type.
+    fn of_type(self, ty: TokenType) -> impl Iterator<Item = &'a Token> {
+        self.filter(move |token| token.ty == ty)
+    }
+
+    /// Only keywords, e.g. for highlighting.
+    fn keywords(self) -> impl Iterator<Item = &'a Token> {
+        self.filter(|token| token.ty.is_keyword())
+    }
+
+    /// Only operators.
+    fn operators(self) -> impl Iterator<Item = &'a Token> {
+        self.filter(|token| token.ty.is_operator())
+    }
+}
+
+impl<'a, I: Iterator<Item = &'a Token>> TokenIterator<'a> for I {}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::TokenIterator;
+    use crate::{errors::ErrorManager, scanner::Scanner, token_type::TokenType};
+
+    #[test]
+    fn filters_scanned_tokens() {
+        let source = "var x = a and b + 1;".to_string();
+        let tokens = Scanner::new(&source, Rc::new(ErrorManager::new())).scan_tokens();
+
+        fn lexemes<'a>(tokens: impl Iterator<Item = &'a super::Token>) -> Vec<&'a str> {
+            tokens.map(|token| token.lexeme.as_str()).collect()
+        }
+        assert_eq!(tokens.iter().significant().count(), tokens.len() - 1);
+        assert_eq!(lexemes(tokens.iter().keywords()), ["var", "and"]);
+        assert_eq!(lexemes(tokens.iter().operators()), ["=", "+"]);
+        assert_eq!(
+            lexemes(tokens.iter().of_type(TokenType::Identifier)),
+            ["x", "a", "b"]
+        );
+    }
+}
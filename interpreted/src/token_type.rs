@@ -1,14 +1,25 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+//! The kinds of token the scanner produces. Every token has exactly one type;
+//! a type's name (`TokenType::name`) is the variant's name, is unique, and
+//! round-trips through `str::parse`, so tools can store and exchange token
+//! types as strings.
+use std::{fmt, str::FromStr};
+
+/// What a token is. Types are ordered by declaration, which groups them as
+/// the comments below do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum TokenType {
     // Single-character tokens.
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
     Plus,
+    Colon,
     Semicolon,
     Slash,
     Star,
@@ -22,6 +33,10 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    MinusEqual,
+    PlusEqual,
+    SlashEqual,
+    StarEqual,
 
     // Literals.
     Identifier,
@@ -31,11 +46,13 @@ pub enum TokenType {
     // Keywords.
     And,
     Class,
+    Defer,
     Else,
     False,
     Fun,
     For,
     If,
+    Measure,
     Nil,
     Or,
     Print,
@@ -50,48 +67,235 @@ pub enum TokenType {
     Eof,
 }
 
-impl ToString for TokenType {
-    fn to_string(&self) -> String {
+impl TokenType {
+    /// Every token type, in declaration order.
+    pub const ALL: [TokenType; 48] = [
+        TokenType::LeftParen,
+        TokenType::RightParen,
+        TokenType::LeftBrace,
+        TokenType::RightBrace,
+        TokenType::LeftBracket,
+        TokenType::RightBracket,
+        TokenType::Comma,
+        TokenType::Dot,
+        TokenType::Minus,
+        TokenType::Plus,
+        TokenType::Colon,
+        TokenType::Semicolon,
+        TokenType::Slash,
+        TokenType::Star,
+        TokenType::Bang,
+        TokenType::BangEqual,
+        TokenType::Equal,
+        TokenType::EqualEqual,
+        TokenType::Greater,
+        TokenType::GreaterEqual,
+        TokenType::Less,
+        TokenType::LessEqual,
+        TokenType::MinusEqual,
+        TokenType::PlusEqual,
+        TokenType::SlashEqual,
+        TokenType::StarEqual,
+        TokenType::Identifier,
+        TokenType::String,
+        TokenType::Number,
+        TokenType::And,
+        TokenType::Class,
+        TokenType::Defer,
+        TokenType::Else,
+        TokenType::False,
+        TokenType::Fun,
+        TokenType::For,
+        TokenType::If,
+        TokenType::Measure,
+        TokenType::Nil,
+        TokenType::Or,
+        TokenType::Print,
+        TokenType::Return,
+        TokenType::Super,
+        TokenType::This,
+        TokenType::True,
+        TokenType::Var,
+        TokenType::While,
+        TokenType::Eof,
+    ];
+
+    /// The type's name, e.g. `"LeftParen"`.
+    pub fn name(&self) -> &'static str {
         match self {
-            TokenType::LeftParen => "LeftParen".to_string(),
-            TokenType::RightParen => "RightParen".to_string(),
-            TokenType::LeftBrace => "LeftBrace".to_string(),
-            TokenType::RightBrace => "RightBrace".to_string(),
-            TokenType::Comma => "Comma".to_string(),
-            TokenType::Dot => "Dot".to_string(),
-            TokenType::Minus => "Minus".to_string(),
-            TokenType::Plus => "Plus".to_string(),
-            TokenType::Semicolon => "Semicolon".to_string(),
-            TokenType::Slash => "Slash".to_string(),
-            TokenType::Star => "Star".to_string(),
-            TokenType::Bang => "Bang".to_string(),
-            TokenType::BangEqual => "BangEqual".to_string(),
-            TokenType::Equal => "Equal".to_string(),
-            TokenType::EqualEqual => "EqualEqual".to_string(),
-            TokenType::Greater => "Greater".to_string(),
-            TokenType::GreaterEqual => "GreaterEqual".to_string(),
-            TokenType::Less => "Less".to_string(),
-            TokenType::LessEqual => "LessEqual".to_string(),
-            TokenType::Identifier => "Identifier".to_string(),
-            TokenType::String => "String".to_string(),
-            TokenType::Number => "Number".to_string(),
-            TokenType::And => "And".to_string(),
-            TokenType::Class => "Class".to_string(),
-            TokenType::Else => "Else".to_string(),
-            TokenType::False => "False".to_string(),
-            TokenType::Fun => "Fun".to_string(),
-            TokenType::For => "For".to_string(),
-            TokenType::If => "If".to_string(),
-            TokenType::Nil => "Nil".to_string(),
-            TokenType::Or => "Or".to_string(),
-            TokenType::Print => "Print".to_string(),
-            TokenType::Return => "Return".to_string(),
-            TokenType::Super => "Super".to_string(),
-            TokenType::This => "This".to_string(),
-            TokenType::True => "True".to_string(),
-            TokenType::Var => "Var".to_string(),
-            TokenType::While => "While".to_string(),
-            TokenType::Eof => "Eof".to_string(),
+            TokenType::LeftParen => "LeftParen",
+            TokenType::RightParen => "RightParen",
+            TokenType::LeftBrace => "LeftBrace",
+            TokenType::RightBrace => "RightBrace",
+            TokenType::LeftBracket => "LeftBracket",
+            TokenType::RightBracket => "RightBracket",
+            TokenType::Comma => "Comma",
+            TokenType::Dot => "Dot",
+            TokenType::Minus => "Minus",
+            TokenType::Plus => "Plus",
+            TokenType::Colon => "Colon",
+            TokenType::Semicolon => "Semicolon",
+            TokenType::Slash => "Slash",
+            TokenType::Star => "Star",
+            TokenType::Bang => "Bang",
+            TokenType::BangEqual => "BangEqual",
+            TokenType::Equal => "Equal",
+            TokenType::EqualEqual => "EqualEqual",
+            TokenType::Greater => "Greater",
+            TokenType::GreaterEqual => "GreaterEqual",
+            TokenType::Less => "Less",
+            TokenType::LessEqual => "LessEqual",
+            TokenType::MinusEqual => "MinusEqual",
+            TokenType::PlusEqual => "PlusEqual",
+            TokenType::SlashEqual => "SlashEqual",
+            TokenType::StarEqual => "StarEqual",
+            TokenType::Identifier => "Identifier",
+            TokenType::String => "String",
+            TokenType::Number => "Number",
+            TokenType::And => "And",
+            TokenType::Class => "Class",
+            TokenType::Defer => "Defer",
+            TokenType::Else => "Else",
+            TokenType::False => "False",
+            TokenType::Fun => "Fun",
+            TokenType::For => "For",
+            TokenType::If => "If",
+            TokenType::Measure => "Measure",
+            TokenType::Nil => "Nil",
+            TokenType::Or => "Or",
+            TokenType::Print => "Print",
+            TokenType::Return => "Return",
+            TokenType::Super => "Super",
+            TokenType::This => "This",
+            TokenType::True => "True",
+            TokenType::Var => "Var",
+            TokenType::While => "While",
+            TokenType::Eof => "Eof",
+        }
+    }
+
+    /// Whether the type is a reserved word. Keywords are only ever scanned
+    /// from their own spelling, so they never appear as identifiers.
+    pub fn is_keyword(&self) -> bool {
+        self.keyword().is_some()
+    }
+
+    /// How a keyword is spelled in source, or `None` for anything else.
+    pub fn keyword(&self) -> Option<&'static str> {
+        Some(match self {
+            TokenType::And => "and",
+            TokenType::Class => "class",
+            TokenType::Defer => "defer",
+            TokenType::Else => "else",
+            TokenType::False => "false",
+            TokenType::Fun => "fun",
+            TokenType::For => "for",
+            TokenType::If => "if",
+            TokenType::Measure => "measure",
+            TokenType::Nil => "nil",
+            TokenType::Or => "or",
+            TokenType::Print => "print",
+            TokenType::Return => "return",
+            TokenType::Super => "super",
+            TokenType::This => "this",
+            TokenType::True => "true",
+            TokenType::Var => "var",
+            TokenType::While => "while",
+            _ => return None,
+        })
+    }
+
+    /// Whether the type is an arithmetic, comparison, logical, or assignment
+    /// operator spelled with symbols. `and` and `or` are keywords instead.
+    pub fn is_operator(&self) -> bool {
+        matches!(
+            self,
+            TokenType::Minus
+                | TokenType::Plus
+                | TokenType::Slash
+                | TokenType::Star
+                | TokenType::Bang
+                | TokenType::BangEqual
+                | TokenType::Equal
+                | TokenType::EqualEqual
+                | TokenType::Greater
+                | TokenType::GreaterEqual
+                | TokenType::Less
+                | TokenType::LessEqual
+                | TokenType::MinusEqual
+                | TokenType::PlusEqual
+                | TokenType::SlashEqual
+                | TokenType::StarEqual
+        )
+    }
+
+    /// The arithmetic a compound assignment operator does before it assigns,
+    /// e.g. `Plus` for `+=`, or `None` for anything else.
+    pub fn compound_operator(&self) -> Option<TokenType> {
+        Some(match self {
+            TokenType::MinusEqual => TokenType::Minus,
+            TokenType::PlusEqual => TokenType::Plus,
+            TokenType::SlashEqual => TokenType::Slash,
+            TokenType::StarEqual => TokenType::Star,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for TokenType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The name `TokenType::from_str` didn't recognize.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownTokenType(pub String);
+
+impl fmt::Display for UnknownTokenType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown token type \"{}\".", self.0)
+    }
+}
+
+impl std::error::Error for UnknownTokenType {}
+
+impl FromStr for TokenType {
+    type Err = UnknownTokenType;
+
+    /// The type with the given name, as returned by `TokenType::name`.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        TokenType::ALL
+            .into_iter()
+            .find(|ty| ty.name() == name)
+            .ok_or_else(|| UnknownTokenType(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenType;
+
+    #[test]
+    fn names_round_trip() {
+        for ty in TokenType::ALL {
+            assert_eq!(ty.name().parse::<TokenType>(), Ok(ty));
+            assert_eq!(ty.to_string(), ty.name());
+        }
+        assert_eq!(
+            "Plus".parse::<TokenType>().map(|ty| ty.is_operator()),
+            Ok(true)
+        );
+        assert!("plus".parse::<TokenType>().is_err());
+    }
+
+    #[test]
+    fn keywords_and_operators_are_disjoint() {
+        for ty in TokenType::ALL {
+            assert!(!(ty.is_keyword() && ty.is_operator()), "{}", ty);
         }
+        assert_eq!(TokenType::While.keyword(), Some("while"));
+        assert!(!TokenType::Identifier.is_keyword());
+        assert!(!TokenType::Dot.is_operator());
     }
 }
@@ -0,0 +1,235 @@
+//! Structured logging of what the interpreter does internally, for debugging
+//! interpreter issues: a user who hits one can run their script with
+//! `--trace trace.jsonl` and send the file along.
+//!
+//! Logging is only compiled in with the `trace` feature
+//! (`cargo run --features trace`). Without it, `emit` does nothing and events
+//! are never even built, so the hot paths that report them cost nothing.
+//!
+//! Values are reference counted rather than garbage collected, so there are no
+//! GC events to report.
+
+use std::fmt;
+#[cfg(feature = "trace")]
+use std::{cell::RefCell, fs::File, io::Write, rc::Rc};
+
+#[cfg(feature = "trace")]
+use crate::sarif::json_string;
+
+/// Something that happened inside the interpreter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    ScanStart,
+    ScanEnd {
+        tokens: usize,
+    },
+    ParseError {
+        line: usize,
+        message: String,
+    },
+    /// A block scope was entered. `depth` counts the scopes visible to the
+    /// current call, including the one just entered and the globals.
+    ScopePush {
+        depth: usize,
+    },
+    /// A block scope was exited. `depth` is what's left. When a call returns,
+    /// the scopes still left in it go away along with the call, without a
+    /// `ScopePop` each.
+    ScopePop {
+        depth: usize,
+    },
+    CallEnter {
+        function: String,
+        line: usize,
+    },
+    CallExit {
+        function: String,
+    },
+}
+
+/// Receives every event a session emits, once installed with
+/// `Interpreter::set_tracer`.
+#[cfg(feature = "trace")]
+pub trait Logger {
+    fn log(&self, event: &TraceEvent);
+}
+
+/// Where one session's events go. Each stage that emits events (the scanner,
+/// parser, interpreter, and VM) is given a clone, so sessions on the same
+/// thread never trace into each other. Without the `trace` feature it's
+/// empty, and emitting to it does nothing.
+#[derive(Clone, Default)]
+pub struct Tracer {
+    #[cfg(feature = "trace")]
+    logger: Option<Rc<dyn Logger>>,
+}
+
+impl Tracer {
+    /// A tracer that sends every event to the logger.
+    #[cfg(feature = "trace")]
+    pub fn new(logger: Rc<dyn Logger>) -> Self {
+        Self {
+            logger: Some(logger),
+        }
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Tracer")
+    }
+}
+
+/// Reports an event to the tracer's logger, if it has one. The event is only
+/// built if it'll be logged.
+#[inline]
+pub fn emit(tracer: &Tracer, event: impl FnOnce() -> TraceEvent) {
+    #[cfg(feature = "trace")]
+    if let Some(logger) = &tracer.logger {
+        logger.log(&event());
+    }
+    #[cfg(not(feature = "trace"))]
+    let _ = (tracer, event);
+}
+
+#[cfg(feature = "trace")]
+impl TraceEvent {
+    /// The event as one line of JSON, e.g.
+    /// `{"event": "call_enter", "function": "<fn f>", "line": 3}`.
+    pub fn to_json(&self) -> String {
+        match self {
+            TraceEvent::ScanStart => r#"{"event": "scan_start"}"#.to_string(),
+            TraceEvent::ScanEnd { tokens } => {
+                format!(r#"{{"event": "scan_end", "tokens": {}}}"#, tokens)
+            }
+            TraceEvent::ParseError { line, message } => format!(
+                r#"{{"event": "parse_error", "line": {}, "message": {}}}"#,
+                line,
+                json_string(message)
+            ),
+            TraceEvent::ScopePush { depth } => {
+                format!(r#"{{"event": "scope_push", "depth": {}}}"#, depth)
+            }
+            TraceEvent::ScopePop { depth } => {
+                format!(r#"{{"event": "scope_pop", "depth": {}}}"#, depth)
+            }
+            TraceEvent::CallEnter { function, line } => format!(
+                r#"{{"event": "call_enter", "function": {}, "line": {}}}"#,
+                json_string(function),
+                line
+            ),
+            TraceEvent::CallExit { function } => format!(
+                r#"{{"event": "call_exit", "function": {}}}"#,
+                json_string(function)
+            ),
+        }
+    }
+}
+
+/// Writes each event to a file as a line of JSON.
+#[cfg(feature = "trace")]
+pub struct JsonLinesLogger {
+    file: RefCell<File>,
+}
+
+#[cfg(feature = "trace")]
+impl JsonLinesLogger {
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            file: RefCell::new(File::create(path)?),
+        })
+    }
+}
+
+#[cfg(feature = "trace")]
+impl Logger for JsonLinesLogger {
+    fn log(&self, event: &TraceEvent) {
+        // A trace is best-effort; failing to write it shouldn't stop the
+        // script.
+        let _ = writeln!(self.file.borrow_mut(), "{}", event.to_json());
+    }
+}
+
+#[cfg(all(test, feature = "trace"))]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{Logger, TraceEvent, Tracer};
+    use crate::session::Session;
+
+    #[derive(Default)]
+    struct Collect(RefCell<Vec<TraceEvent>>);
+
+    impl Logger for Collect {
+        fn log(&self, event: &TraceEvent) {
+            self.0.borrow_mut().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn logs_scanning_scopes_and_calls() {
+        // The prelude is loaded before the tracer is set, so it isn't logged.
+        let mut session = Session::new();
+        let events = Rc::new(Collect::default());
+        session.interpreter.set_tracer(Tracer::new(events.clone()));
+        session.run(&"fun f() { { } }\nf();".to_string());
+
+        assert_eq!(
+            *events.0.borrow(),
+            vec![
+                TraceEvent::ScanStart,
+                TraceEvent::ScanEnd { tokens: 13 },
+                TraceEvent::CallEnter {
+                    function: "<fn f>".to_string(),
+                    line: 2
+                },
+                // The parameters' scope, the body's, and the inner block's.
+                TraceEvent::ScopePush { depth: 2 },
+                TraceEvent::ScopePush { depth: 3 },
+                TraceEvent::ScopePush { depth: 4 },
+                TraceEvent::ScopePop { depth: 3 },
+                TraceEvent::ScopePop { depth: 2 },
+                TraceEvent::CallExit {
+                    function: "<fn f>".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            events.0.borrow()[2].to_json(),
+            r#"{"event": "call_enter", "function": "<fn f>", "line": 2}"#
+        );
+    }
+
+    #[test]
+    fn sessions_trace_to_their_own_loggers() {
+        let mut first = Session::new();
+        let first_events = Rc::new(Collect::default());
+        first
+            .interpreter
+            .set_tracer(Tracer::new(first_events.clone()));
+        let mut second = Session::new();
+        let second_events = Rc::new(Collect::default());
+        second
+            .interpreter
+            .set_tracer(Tracer::new(second_events.clone()));
+
+        second.run(&"var = 1;".to_string());
+        first.run(&"print 1;".to_string());
+
+        assert_eq!(
+            *first_events.0.borrow(),
+            vec![TraceEvent::ScanStart, TraceEvent::ScanEnd { tokens: 4 }]
+        );
+        assert_eq!(
+            *second_events.0.borrow(),
+            vec![
+                TraceEvent::ScanStart,
+                TraceEvent::ScanEnd { tokens: 5 },
+                TraceEvent::ParseError {
+                    line: 1,
+                    message: "Expect variable name.".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use crate::{
+    expr::{
+        Assign, AssignTarget, Binary, Call, CompoundAssign, ExprVisitor, Expression, Function, Get,
+        Grouping, Index, IndexAssign, List, Literal, Logical, Map, Set, Unary, Variable,
+        VisitorTarget,
+    },
+    stmt::{
+        BlockStmt, DeferStmt, ExprStmt, FunStmt, IfStmt, MeasureStmt, PrintStmt, ReturnStmt,
+        Statement, StmtVisitor, StmtVisitorTarget, VariableStmt, WhileStmt,
+    },
+};
+
+/// Rewrites a program after it's parsed and before it's resolved, for
+/// embedders adding sugar of their own (e.g. expanding calls to a "macro"
+/// function) without changing the parser.
+///
+/// There's a method per kind of node. Each one's default rebuilds the node from
+/// its transformed children, so an implementation only overrides the nodes it
+/// rewrites, and whatever it returns is used in place of the node. Lists of
+/// statements (the program, blocks, and function bodies) go through
+/// `transform_statements`, so a statement can also be removed or expanded into
+/// several.
+///
+/// Transformers are registered with `Interpreter::add_transformer`, and run in
+/// the order they were registered.
+pub trait AstTransformer {
+    fn transform_statements(&self, statements: &[Statement]) -> Vec<Statement> {
+        statements
+            .iter()
+            .map(|stmt| self.transform_stmt(stmt))
+            .collect()
+    }
+
+    /// Dispatches to the method for the statement's kind.
+    fn transform_stmt(&self, stmt: &Statement) -> Statement {
+        stmt.accept(&Dispatch(self))
+    }
+
+    /// Dispatches to the method for the expression's kind.
+    fn transform_expr(&self, expr: &Expression) -> Expression {
+        expr.accept(&Dispatch(self))
+    }
+
+    fn transform_expression_stmt(&self, stmt: &ExprStmt) -> Statement {
+        ExprStmt::new(self.transform_expr(&stmt.expression))
+    }
+
+    fn transform_print_stmt(&self, stmt: &PrintStmt) -> Statement {
+        PrintStmt::new(self.transform_expr(&stmt.expression))
+    }
+
+    fn transform_variable_stmt(&self, stmt: &VariableStmt) -> Statement {
+        VariableStmt::new(
+            stmt.name.clone(),
+            stmt.initializer
+                .as_ref()
+                .map(|initializer| self.transform_expr(initializer)),
+        )
+    }
+
+    fn transform_block_stmt(&self, stmt: &BlockStmt) -> Statement {
+        BlockStmt::new(self.transform_statements(&stmt.statements))
+    }
+
+    fn transform_if_stmt(&self, stmt: &IfStmt) -> Statement {
+        IfStmt::new(
+            self.transform_expr(&stmt.condition),
+            self.transform_stmt(&stmt.then_branch),
+            stmt.else_branch
+                .as_ref()
+                .map(|else_branch| self.transform_stmt(else_branch)),
+        )
+    }
+
+    fn transform_while_stmt(&self, stmt: &WhileStmt) -> Statement {
+        WhileStmt::new(
+            stmt.keyword.clone(),
+            self.transform_expr(&stmt.condition),
+            self.transform_stmt(&stmt.body),
+        )
+    }
+
+    fn transform_fun_stmt(&self, stmt: &FunStmt) -> Statement {
+        FunStmt::new(
+            stmt.name.clone(),
+            stmt.params.clone(),
+            self.transform_statements(&stmt.body),
+        )
+    }
+
+    fn transform_return_stmt(&self, stmt: &ReturnStmt) -> Statement {
+        ReturnStmt::new(stmt.keyword.clone(), self.transform_expr(&stmt.value))
+    }
+
+    fn transform_measure_stmt(&self, stmt: &MeasureStmt) -> Statement {
+        MeasureStmt::new(stmt.keyword.clone(), self.transform_statements(&stmt.body))
+    }
+
+    fn transform_defer_stmt(&self, stmt: &DeferStmt) -> Statement {
+        DeferStmt::new(stmt.keyword.clone(), self.transform_stmt(&stmt.body))
+    }
+
+    fn transform_binary(&self, expr: &Binary) -> Expression {
+        Binary::new(
+            self.transform_expr(&expr.left),
+            expr.operator.clone(),
+            self.transform_expr(&expr.right),
+        )
+    }
+
+    fn transform_grouping(&self, expr: &Grouping) -> Expression {
+        Grouping::new(self.transform_expr(&expr.expression))
+    }
+
+    fn transform_literal(&self, expr: &Literal) -> Expression {
+        Literal::new(expr.value.clone())
+    }
+
+    fn transform_unary(&self, expr: &Unary) -> Expression {
+        Unary::new(expr.operator.clone(), self.transform_expr(&expr.right))
+    }
+
+    fn transform_variable(&self, expr: &Variable) -> Expression {
+        Variable::new(expr.name.clone())
+    }
+
+    fn transform_assign(&self, expr: &Assign) -> Expression {
+        Assign::new(expr.name.clone(), self.transform_expr(&expr.value))
+    }
+
+    fn transform_logical(&self, expr: &Logical) -> Expression {
+        Logical::new(
+            self.transform_expr(&expr.left),
+            expr.operator.clone(),
+            self.transform_expr(&expr.right),
+        )
+    }
+
+    fn transform_call(&self, expr: &Call) -> Expression {
+        Call::new(
+            self.transform_expr(&expr.callee),
+            expr.paren.clone(),
+            expr.arguments
+                .iter()
+                .map(|argument| self.transform_expr(argument))
+                .collect(),
+        )
+    }
+
+    fn transform_function(&self, expr: &Function) -> Expression {
+        Function::new(
+            expr.keyword.clone(),
+            expr.params.clone(),
+            self.transform_statements(&expr.body),
+        )
+    }
+
+    fn transform_list(&self, expr: &List) -> Expression {
+        List::new(
+            expr.bracket.clone(),
+            expr.elements
+                .iter()
+                .map(|element| self.transform_expr(element))
+                .collect(),
+        )
+    }
+
+    fn transform_map(&self, expr: &Map) -> Expression {
+        Map::new(
+            expr.brace.clone(),
+            expr.entries
+                .iter()
+                .map(|(key, value)| (self.transform_expr(key), self.transform_expr(value)))
+                .collect(),
+        )
+    }
+
+    fn transform_index(&self, expr: &Index) -> Expression {
+        Index::new(
+            self.transform_expr(&expr.object),
+            expr.bracket.clone(),
+            self.transform_expr(&expr.index),
+        )
+    }
+
+    fn transform_index_assign(&self, expr: &IndexAssign) -> Expression {
+        IndexAssign::new(
+            self.transform_expr(&expr.object),
+            expr.bracket.clone(),
+            self.transform_expr(&expr.index),
+            self.transform_expr(&expr.value),
+        )
+    }
+
+    fn transform_compound_assign(&self, expr: &CompoundAssign) -> Expression {
+        // The target is rebuilt from its parts, so it stays assignable.
+        let target = match expr.target() {
+            AssignTarget::Variable(_) => expr.target.clone(),
+            AssignTarget::Index(index) => Index::new(
+                self.transform_expr(&index.object),
+                index.bracket.clone(),
+                self.transform_expr(&index.index),
+            ),
+            AssignTarget::Property(get) => {
+                Get::new(self.transform_expr(&get.object), get.name.clone())
+            }
+        };
+        CompoundAssign::new(
+            target,
+            expr.operator.clone(),
+            self.transform_expr(&expr.value),
+        )
+    }
+
+    fn transform_get(&self, expr: &Get) -> Expression {
+        Get::new(self.transform_expr(&expr.object), expr.name.clone())
+    }
+
+    fn transform_set(&self, expr: &Set) -> Expression {
+        Set::new(
+            self.transform_expr(&expr.object),
+            expr.name.clone(),
+            self.transform_expr(&expr.value),
+        )
+    }
+}
+
+// Routes the visitors' dispatch on node kinds to a transformer's methods.
+struct Dispatch<'a, T: ?Sized>(&'a T);
+
+impl<T: AstTransformer + ?Sized> StmtVisitor<Statement> for &Dispatch<'_, T> {
+    fn visit_expression_stmt(&self, stmt: &ExprStmt) -> Statement {
+        self.0.transform_expression_stmt(stmt)
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> Statement {
+        self.0.transform_print_stmt(stmt)
+    }
+
+    fn visit_variable_stmt(&self, stmt: &VariableStmt) -> Statement {
+        self.0.transform_variable_stmt(stmt)
+    }
+
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> Statement {
+        self.0.transform_block_stmt(stmt)
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> Statement {
+        self.0.transform_if_stmt(stmt)
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> Statement {
+        self.0.transform_while_stmt(stmt)
+    }
+
+    fn visit_fun_stmt(&self, stmt: &FunStmt) -> Statement {
+        self.0.transform_fun_stmt(stmt)
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Statement {
+        self.0.transform_return_stmt(stmt)
+    }
+
+    fn visit_measure_stmt(&self, stmt: &MeasureStmt) -> Statement {
+        self.0.transform_measure_stmt(stmt)
+    }
+
+    fn visit_defer_stmt(&self, stmt: &DeferStmt) -> Statement {
+        self.0.transform_defer_stmt(stmt)
+    }
+}
+
+impl<T: AstTransformer + ?Sized> ExprVisitor<Expression> for &Dispatch<'_, T> {
+    fn visit_binary_expr(&self, expr: &Binary) -> Expression {
+        self.0.transform_binary(expr)
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping) -> Expression {
+        self.0.transform_grouping(expr)
+    }
+
+    fn visit_literal_expr(&self, expr: &Literal) -> Expression {
+        self.0.transform_literal(expr)
+    }
+
+    fn visit_unary_expr(&self, expr: &Unary) -> Expression {
+        self.0.transform_unary(expr)
+    }
+
+    fn visit_variable_expr(&self, expr: &Variable) -> Expression {
+        self.0.transform_variable(expr)
+    }
+
+    fn visit_assign_expr(&self, expr: &Assign) -> Expression {
+        self.0.transform_assign(expr)
+    }
+
+    fn visit_logical_expr(&self, expr: &Logical) -> Expression {
+        self.0.transform_logical(expr)
+    }
+
+    fn visit_call_expr(&self, expr: &Call) -> Expression {
+        self.0.transform_call(expr)
+    }
+
+    fn visit_function_expr(&self, expr: &Function) -> Expression {
+        self.0.transform_function(expr)
+    }
+
+    fn visit_list_expr(&self, expr: &List) -> Expression {
+        self.0.transform_list(expr)
+    }
+
+    fn visit_map_expr(&self, expr: &Map) -> Expression {
+        self.0.transform_map(expr)
+    }
+
+    fn visit_index_expr(&self, expr: &Index) -> Expression {
+        self.0.transform_index(expr)
+    }
+
+    fn visit_index_assign_expr(&self, expr: &IndexAssign) -> Expression {
+        self.0.transform_index_assign(expr)
+    }
+
+    fn visit_compound_assign_expr(&self, expr: &CompoundAssign) -> Expression {
+        self.0.transform_compound_assign(expr)
+    }
+
+    fn visit_get_expr(&self, expr: &Get) -> Expression {
+        self.0.transform_get(expr)
+    }
+
+    fn visit_set_expr(&self, expr: &Set) -> Expression {
+        self.0.transform_set(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::AstTransformer;
+    use crate::{
+        expr::{Binary, Call, Expr, Expression, Literal, Variable},
+        output::CapturedOutput,
+        session::Session,
+        stmt::{PrintStmt, Statement, Stmt},
+        token::{Token, TokenLiteral},
+        token_type::TokenType,
+    };
+
+    // `__LINE__` becomes the line it's on, and `square(x)` becomes `x * x`.
+    struct Macros;
+
+    impl AstTransformer for Macros {
+        fn transform_variable(&self, expr: &Variable) -> Expression {
+            match expr.name.lexeme.as_str() {
+                "__LINE__" => Literal::new(TokenLiteral::Number(expr.name.line as f64)),
+                _ => Variable::new(expr.name.clone()),
+            }
+        }
+
+        fn transform_call(&self, expr: &Call) -> Expression {
+            match &*expr.callee {
+                Expr::Variable(callee)
+                    if callee.name.lexeme == "square" && expr.arguments.len() == 1 =>
+                {
+                    let operand = self.transform_expr(&expr.arguments[0]);
+                    let star = Token {
+                        ty: TokenType::Star,
+                        lexeme: "*".to_string(),
+                        ..expr.paren.clone()
+                    };
+                    Binary::new(operand.clone(), star, operand)
+                }
+                _ => Call::new(
+                    self.transform_expr(&expr.callee),
+                    expr.paren.clone(),
+                    expr.arguments
+                        .iter()
+                        .map(|argument| self.transform_expr(argument))
+                        .collect(),
+                ),
+            }
+        }
+    }
+
+    // Every expression statement also prints its value.
+    struct Echo;
+
+    impl AstTransformer for Echo {
+        fn transform_statements(&self, statements: &[Statement]) -> Vec<Statement> {
+            let mut transformed = vec![];
+            for stmt in statements {
+                transformed.push(self.transform_stmt(stmt));
+                if let Stmt::Expression(stmt) = &**stmt {
+                    transformed.push(PrintStmt::new(stmt.expression.clone()));
+                }
+            }
+            transformed
+        }
+    }
+
+    #[test]
+    fn rewrites_programs_before_they_are_resolved() {
+        let mut session = Session::new();
+        let output = Rc::new(CapturedOutput::new());
+        session.interpreter.output = output.clone();
+        session.interpreter.add_transformer(Rc::new(Macros));
+        session.run(
+            &"fun f(n) {\n  return square(n + 1);\n}\nprint f(2);\nprint __LINE__;".to_string(),
+        );
+        assert_eq!(output.lines(), vec!["9.00", "5.00"]);
+        assert!(!session.errors.has_errors());
+
+        // Transformers run in the order they were registered, on statements as
+        // well as expressions.
+        session.interpreter.add_transformer(Rc::new(Echo));
+        session.run(&"square(3);".to_string());
+        assert_eq!(output.lines(), vec!["9.00", "5.00", "9.00"]);
+    }
+}
@@ -0,0 +1,277 @@
+use std::{io::BufRead, rc::Rc};
+
+use crate::{messages, output::CapturedOutput, session::Session};
+
+/// One step of `jlox tutorial`: what to write, and what running it should
+/// print.
+pub struct Lesson {
+    pub title: &'static str,
+    pub instructions: &'static str,
+    /// What the program should print, one entry per `print`.
+    pub expected: &'static [&'static str],
+    /// Words the program has to use, so that e.g. the loop lesson can't be
+    /// passed with three print statements.
+    pub must_use: &'static [&'static str],
+    /// Shown when the program runs but prints the wrong thing.
+    pub hint: &'static str,
+}
+
+pub const LESSONS: &[Lesson] = &[
+    Lesson {
+        title: "Printing",
+        instructions: "Print the string \"Hello, world!\" with the print statement.",
+        expected: &["Hello, world!"],
+        must_use: &["print"],
+        hint: "Strings go in double quotes: print \"Hello, world!\";",
+    },
+    Lesson {
+        title: "Variables",
+        instructions: "Declare a variable called name holding \"Lox\", then print \"Hello, \" + name.",
+        expected: &["Hello, Lox"],
+        must_use: &["var", "name"],
+        hint: "Declare it with: var name = \"Lox\";",
+    },
+    Lesson {
+        title: "Control flow",
+        instructions: "Declare var temperature = 30; then use an if statement to print \"hot\" when it's above 25, and \"cold\" otherwise.",
+        expected: &["hot"],
+        must_use: &["if", "else", "temperature"],
+        hint: "The condition goes in parentheses: if (temperature > 25) print \"hot\"; else print \"cold\";",
+    },
+    Lesson {
+        title: "Loops",
+        instructions: "Use a for loop to print the numbers 1 to 3.",
+        expected: &["1.00", "2.00", "3.00"],
+        must_use: &["for"],
+        hint: "A for loop has an initializer, a condition, and an increment: for (var i = 1; i <= 3; i = i + 1) print i;",
+    },
+    Lesson {
+        title: "Functions",
+        instructions: "Declare a function square(n) that returns n * n, then print square(4).",
+        expected: &["16.00"],
+        must_use: &["fun", "return", "square"],
+        hint: "Return the value instead of printing it inside the function: fun square(n) { return n * n; }",
+    },
+];
+
+/// How an attempt at a lesson went.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Passed,
+    /// The program had scan, parse, or runtime errors. Includes hints for the
+    /// errors beginners commonly make.
+    Errors {
+        messages: Vec<String>,
+        hints: Vec<&'static str>,
+    },
+    /// The program didn't use a word the lesson is about.
+    Missing(&'static str),
+    WrongOutput {
+        actual: Vec<String>,
+    },
+}
+
+/// Runs the attempt in a fresh session and checks it against the lesson.
+pub fn check(lesson: &Lesson, source: &str) -> Verdict {
+    let mut session = Session::new();
+    let output = Rc::new(CapturedOutput::new());
+    session.interpreter.output = output.clone();
+    session.run(&source.to_string());
+
+    let errors = &session.errors;
+    if errors.has_errors() || errors.has_runtime_error() {
+        let mut hints = vec![];
+        for diagnostic in errors.diagnostics() {
+            if let Some(hint) = hint(diagnostic.message.code()) {
+                if !hints.contains(&hint) {
+                    hints.push(hint);
+                }
+            }
+        }
+        return Verdict::Errors {
+            messages: errors.messages(),
+            hints,
+        };
+    }
+
+    let words: Vec<&str> = source
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .collect();
+    if let Some(word) = lesson.must_use.iter().find(|word| !words.contains(word)) {
+        return Verdict::Missing(word);
+    }
+
+    let actual = output.lines();
+    if actual == lesson.expected {
+        Verdict::Passed
+    } else {
+        Verdict::WrongOutput { actual }
+    }
+}
+
+// Hints for the mistakes people new to Lox make most.
+fn hint(code: &str) -> Option<&'static str> {
+    let id = messages::find(code)?;
+    let hint = if id == messages::EXPECT_SEMICOLON_AFTER_VALUE
+        || id == messages::EXPECT_SEMICOLON_AFTER_VARIABLE
+        || id == messages::EXPECT_SEMICOLON_AFTER_RETURN_VALUE
+    {
+        "Every statement ends with a semicolon."
+    } else if id == messages::EXPECT_LEFT_PAREN_AFTER_IF
+        || id == messages::EXPECT_LEFT_PAREN_AFTER_FOR
+        || id == messages::EXPECT_LEFT_PAREN_AFTER_WHILE
+    {
+        "Conditions go in parentheses, e.g. if (x > 1) ..."
+    } else if id == messages::UNCLOSED_BLOCK {
+        "Every { needs a matching }."
+    } else if id == messages::UNDEFINED_VARIABLE {
+        "Variables have to be declared with var before they're used."
+    } else if id == messages::OPERANDS_MUST_BE_NUMBERS_OR_STRINGS {
+        "+ adds two numbers or joins two strings, but can't mix them."
+    } else if id == messages::UNEXPECTED_CHARACTER {
+        "Strings use double quotes, not single quotes."
+    } else {
+        return None;
+    };
+    Some(hint)
+}
+
+/// Walks through every lesson, reading attempts from `input` until each one
+/// passes (or is skipped). An attempt ends at an empty line.
+pub fn run(mut input: impl BufRead) {
+    println!("Welcome to the Lox tutorial!");
+    println!("Type your code, then an empty line to run it. Type \"skip\" to move on, or \"quit\" to stop.");
+
+    for (idx, lesson) in LESSONS.iter().enumerate() {
+        println!();
+        println!("Lesson {} of {}: {}", idx + 1, LESSONS.len(), lesson.title);
+        println!("{}", lesson.instructions);
+
+        loop {
+            let source = match read_attempt(&mut input) {
+                Some(source) => source,
+                None => return,
+            };
+            match source.trim() {
+                "" => continue,
+                "skip" => break,
+                "quit" => return,
+                _ => {}
+            }
+
+            match check(lesson, &source) {
+                Verdict::Passed => {
+                    println!("Correct!");
+                    break;
+                }
+                Verdict::Errors { messages, hints } => {
+                    for message in messages {
+                        println!("{}", message);
+                    }
+                    for hint in hints {
+                        println!("Hint: {}", hint);
+                    }
+                }
+                Verdict::Missing(word) => {
+                    println!("Not quite: this lesson is about using \"{}\".", word);
+                }
+                Verdict::WrongOutput { actual } => {
+                    println!("Not quite. Expected:");
+                    for line in lesson.expected {
+                        println!("    {}", line);
+                    }
+                    println!("but your program printed:");
+                    for line in actual {
+                        println!("    {}", line);
+                    }
+                    println!("Hint: {}", lesson.hint);
+                }
+            }
+            println!("Try again, or type \"skip\".");
+        }
+    }
+
+    println!();
+    println!("That's the end of the tutorial. Run jlox with no arguments for a REPL.");
+}
+
+// Reads lines up to an empty line (or the end of input). Returns None at the
+// end of input.
+fn read_attempt(input: &mut impl BufRead) -> Option<String> {
+    use std::io::{stdout, Write};
+
+    let mut source = String::new();
+    loop {
+        print!("{}", if source.is_empty() { "> " } else { ". " });
+        let _ = stdout().flush();
+
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) | Err(_) if source.is_empty() => return None,
+            Ok(0) | Err(_) => return Some(source),
+            Ok(_) => {}
+        }
+        if line.trim().is_empty() {
+            return Some(source);
+        }
+        // A single command doesn't need the empty line after it.
+        if source.is_empty() && matches!(line.trim(), "skip" | "quit") {
+            return Some(line);
+        }
+        source.push_str(&line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_lesson_has_a_passing_solution() {
+        let solutions = [
+            "print \"Hello, world!\";",
+            "var name = \"Lox\";\nprint \"Hello, \" + name;",
+            "var temperature = 30;\nif (temperature > 25) print \"hot\"; else print \"cold\";",
+            "for (var i = 1; i <= 3; i = i + 1) print i;",
+            "fun square(n) { return n * n; }\nprint square(4);",
+        ];
+        assert_eq!(solutions.len(), LESSONS.len());
+        for (lesson, solution) in LESSONS.iter().zip(solutions) {
+            assert_eq!(check(lesson, solution), Verdict::Passed, "{}", lesson.title);
+        }
+    }
+
+    #[test]
+    fn reports_wrong_output_missing_words_and_errors() {
+        let loops = &LESSONS[3];
+        assert_eq!(
+            check(loops, "print 1; print 2; print 3;"),
+            Verdict::Missing("for")
+        );
+        assert_eq!(
+            check(loops, "for (var i = 0; i < 3; i = i + 1) print i;"),
+            Verdict::WrongOutput {
+                actual: vec!["0.00".to_string(), "1.00".to_string(), "2.00".to_string()]
+            }
+        );
+
+        match check(&LESSONS[1], "var name = \"Lox\"\nprint \"Hello, \" + nam;") {
+            Verdict::Errors { hints, .. } => {
+                assert_eq!(hints, vec!["Every statement ends with a semicolon."])
+            }
+            verdict => panic!("Expected errors, got {:?}", verdict),
+        }
+    }
+
+    #[test]
+    fn reads_attempts_up_to_an_empty_line() {
+        let mut input = "fun f() {\n  return 1;\n}\n\nskip\nlast".as_bytes();
+        assert_eq!(
+            read_attempt(&mut input),
+            Some("fun f() {\n  return 1;\n}\n".to_string())
+        );
+        assert_eq!(read_attempt(&mut input), Some("skip\n".to_string()));
+        assert_eq!(read_attempt(&mut input), Some("last".to_string()));
+        assert_eq!(read_attempt(&mut input), None);
+    }
+}
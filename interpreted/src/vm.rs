@@ -0,0 +1,763 @@
+//! A stack-based VM that runs the bytecode from `compiler`, as an alternative
+//! to the tree-walking interpreter (`--backend=vm`).
+//!
+//! The VM shares the interpreter's globals, natives, output, and error
+//! reporting, so a script prints the same on either backend. What it replaces
+//! is the execution itself: locals live in slots of one value stack instead of
+//! in environment maps, and closures reach the locals they captured through
+//! upvalues, which are moved off the stack when their scope ends.
+
+use std::{cell::RefCell, fmt, rc::Rc, time::Instant};
+
+use crate::{
+    compiler::{Chunk, Op, UpvalueSource, VmFunction},
+    exceptions::{RuntimeError, RuntimeException, TraceFrame},
+    function::Arity,
+    interpreter::Interpreter,
+    messages,
+    object::{CallableLoxObject, LoxList, LoxObject},
+    token::Token,
+    trace::{self, TraceEvent},
+};
+
+/// A captured variable: a stack slot while its scope is running, and its own
+/// value once the scope has ended.
+#[derive(Debug)]
+enum Upvalue {
+    Open(usize),
+    Closed(LoxObject),
+}
+
+/// A compiled function along with the variables it captured.
+#[derive(Clone)]
+pub struct VmClosure {
+    pub function: Rc<VmFunction>,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl VmClosure {
+    #[allow(clippy::new_ret_no_self)]
+    fn new(function: Rc<VmFunction>, upvalues: Vec<Rc<RefCell<Upvalue>>>) -> LoxObject {
+        LoxObject::Callable(Rc::new(VmClosure { function, upvalues }))
+    }
+}
+
+impl fmt::Debug for VmClosure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VmClosure")
+            .field("function", &self.function)
+            .field("upvalues", &self.upvalues.len())
+            .finish()
+    }
+}
+
+impl fmt::Display for VmClosure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<fn {}>", self.function.name)
+    }
+}
+
+// Natives that take a function (like `timeit`) call it through here.
+impl CallableLoxObject for VmClosure {
+    fn instance_name(&self) -> &'static str {
+        "Function"
+    }
+
+    fn arity_self(&self) -> Arity {
+        Arity::exactly(self.function.params.len())
+    }
+
+    fn signature_self(&self) -> String {
+        self.function.signature()
+    }
+
+    fn call_self(
+        &self,
+        interpreter: &Interpreter,
+        paren: &Token,
+        arguments: Vec<LoxObject>,
+    ) -> Result<LoxObject, RuntimeException> {
+        interpreter.enter_call(paren)?;
+        // Runs on the calling VM's stack, which it lent to the interpreter,
+        // so the closure's open upvalues still point at the right slots.
+        let mut vm = Vm::new(interpreter);
+        vm.stack = interpreter.vm_stack.take();
+        let result = vm.call_closure(self.clone(), arguments);
+        interpreter.vm_stack.replace(vm.stack);
+        interpreter.exit_call();
+        result
+    }
+}
+
+struct CallFrame {
+    closure: VmClosure,
+    // The next instruction to run.
+    ip: usize,
+    // The stack slot holding the function being called. Its locals follow.
+    base: usize,
+    // The line the function was called from, for stack traces.
+    line: usize,
+}
+
+pub struct Vm<'a> {
+    interpreter: &'a Interpreter,
+    stack: Vec<LoxObject>,
+    frames: Vec<CallFrame>,
+    // The upvalues still pointing at stack slots.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    // When each running `measure` block started.
+    measures: Vec<Instant>,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(interpreter: &'a Interpreter) -> Self {
+        Self {
+            interpreter,
+            stack: vec![],
+            frames: vec![],
+            open_upvalues: vec![],
+            measures: vec![],
+        }
+    }
+
+    /// Runs the compiled script, reporting any runtime error to the
+    /// interpreter's `ErrorManager`.
+    /// Runs the compiled script, returning the runtime error that stopped it,
+    /// if one did.
+    pub fn interpret(&mut self, script: Rc<VmFunction>) -> Result<(), RuntimeException> {
+        let closure = VmClosure {
+            function: script,
+            upvalues: vec![],
+        };
+        self.stack
+            .push(LoxObject::Callable(Rc::new(closure.clone())));
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base: 0,
+            line: 0,
+        });
+
+        let depth = self.interpreter.call_depth.get();
+        let result = self.run().map(|_| ());
+        // Calls abandoned by a runtime error never returned.
+        self.interpreter.call_depth.set(depth);
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.measures.clear();
+        result
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frame().closure.function.chunk
+    }
+
+    // The token the instruction at this index of the running chunk was
+    // compiled from.
+    fn token(&self, at: usize) -> &Token {
+        &self.chunk().tokens[at]
+    }
+
+    fn pop(&mut self) -> LoxObject {
+        self.stack.pop().unwrap()
+    }
+
+    fn peek(&self) -> &LoxObject {
+        self.stack.last().unwrap()
+    }
+
+    // Calls the closure from outside of any running bytecode, returning once
+    // it returns.
+    fn call_closure(
+        &mut self,
+        closure: VmClosure,
+        arguments: Vec<LoxObject>,
+    ) -> Result<LoxObject, RuntimeException> {
+        let base = self.stack.len();
+        self.stack
+            .push(LoxObject::Callable(Rc::new(closure.clone())));
+        self.stack.extend(arguments);
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base,
+            line: 0,
+        });
+        let depth = self.interpreter.call_depth.get();
+        let result = self.run();
+        if result.is_err() {
+            self.interpreter.call_depth.set(depth);
+            self.close_upvalues(base);
+            self.stack.truncate(base);
+        }
+        result
+    }
+
+    // Runs until the first frame returns, returning its value.
+    fn run(&mut self) -> Result<LoxObject, RuntimeException> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let at = frame.ip;
+            let op = frame.closure.function.chunk.code[at];
+            frame.ip += 1;
+
+            let result = match op {
+                Op::Constant(index) => {
+                    let value = self.chunk().constants[index].clone();
+                    self.stack.push(value);
+                    Ok(())
+                }
+                Op::Nil => {
+                    self.stack.push(LoxObject::Nil);
+                    Ok(())
+                }
+                Op::True => {
+                    self.stack.push(LoxObject::Boolean(true));
+                    Ok(())
+                }
+                Op::False => {
+                    self.stack.push(LoxObject::Boolean(false));
+                    Ok(())
+                }
+                Op::Pop => {
+                    self.pop();
+                    Ok(())
+                }
+                Op::Dup => {
+                    let value = self.peek().clone();
+                    self.stack.push(value);
+                    Ok(())
+                }
+                Op::DupTwo => {
+                    let top = self.stack.len();
+                    self.stack.extend_from_within(top - 2..);
+                    Ok(())
+                }
+                Op::GetLocal(slot) => {
+                    let value = self.stack[self.frame().base + slot].clone();
+                    self.stack.push(value);
+                    Ok(())
+                }
+                Op::SetLocal(slot) => {
+                    let slot = self.frame().base + slot;
+                    self.stack[slot] = self.peek().clone();
+                    Ok(())
+                }
+                Op::GetGlobal => match self.interpreter.environment.get_global(self.token(at)) {
+                    Ok(value) => {
+                        self.stack.push(value);
+                        Ok(())
+                    }
+                    Err(runtime_error) => Err(runtime_error),
+                },
+                Op::DefineGlobal => {
+                    let value = self.pop();
+                    let name = &self.token(at).lexeme;
+                    self.interpreter.environment.define(name, value);
+                    Ok(())
+                }
+                Op::SetGlobal => {
+                    let value = self.peek().clone();
+                    self.interpreter
+                        .environment
+                        .assign_global(self.token(at), value)
+                }
+                Op::GetUpvalue(index) => {
+                    let value = match &*self.frame().closure.upvalues[index].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                    Ok(())
+                }
+                Op::SetUpvalue(index) => {
+                    let value = self.peek().clone();
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                    Ok(())
+                }
+                Op::Equal => {
+                    let (left, right) = self.pop_operands();
+                    self.stack.push(LoxObject::Boolean(left == right));
+                    Ok(())
+                }
+                Op::NotEqual => {
+                    let (left, right) = self.pop_operands();
+                    self.stack.push(LoxObject::Boolean(left != right));
+                    Ok(())
+                }
+                Op::Greater => self.compare(at, |left, right| left > right),
+                Op::GreaterEqual => self.compare(at, |left, right| left >= right),
+                Op::Less => self.compare(at, |left, right| left < right),
+                Op::LessEqual => self.compare(at, |left, right| left <= right),
+                Op::Add => self.add(at),
+                Op::Subtract => self.arithmetic(at, |left, right| left - right),
+                Op::Multiply => self.arithmetic(at, |left, right| left * right),
+                Op::Divide => self.arithmetic(at, |left, right| left / right),
+                Op::Not => {
+                    let value = self.pop();
+                    self.stack.push(LoxObject::Boolean(!value.is_truthy()));
+                    Ok(())
+                }
+                Op::Negate => {
+                    let value = self.pop();
+                    match self
+                        .interpreter
+                        .check_number_operand(self.token(at), &value)
+                    {
+                        Ok(value) => {
+                            self.stack.push(LoxObject::Number(-value));
+                            Ok(())
+                        }
+                        Err(runtime_error) => Err(runtime_error),
+                    }
+                }
+                Op::Print => {
+                    let value = self.pop();
+                    let interpreter = self.interpreter;
+                    interpreter.output.print(&interpreter.stringify(&value));
+                    Ok(())
+                }
+                Op::BuildList(count) => {
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    match self
+                        .interpreter
+                        .check_collection_size(self.token(at), count)
+                    {
+                        Ok(()) => {
+                            self.stack.push(LoxList::new(elements));
+                            Ok(())
+                        }
+                        Err(runtime_error) => Err(runtime_error),
+                    }
+                }
+                Op::BuildMap(count) => {
+                    let values = self.stack.split_off(self.stack.len() - count * 2);
+                    let entries = values
+                        .chunks(2)
+                        .map(|entry| (entry[0].clone(), entry[1].clone()))
+                        .collect();
+                    match self.interpreter.build_map(self.token(at), entries) {
+                        Ok(map) => {
+                            self.stack.push(map);
+                            Ok(())
+                        }
+                        Err(runtime_error) => Err(runtime_error),
+                    }
+                }
+                Op::GetIndex => {
+                    let index = self.pop();
+                    let list = self.pop();
+                    match self.interpreter.get_index(self.token(at), &list, &index) {
+                        Ok(element) => {
+                            self.stack.push(element);
+                            Ok(())
+                        }
+                        Err(runtime_error) => Err(runtime_error),
+                    }
+                }
+                Op::GetProperty => {
+                    let object = self.pop();
+                    match self.interpreter.get_property(self.token(at), &object) {
+                        Ok(value) => {
+                            self.stack.push(value);
+                            Ok(())
+                        }
+                        Err(runtime_error) => Err(runtime_error),
+                    }
+                }
+                Op::SetProperty => {
+                    let value = self.pop();
+                    let object = self.pop();
+                    match self
+                        .interpreter
+                        .set_property(self.token(at), &object, value.clone())
+                    {
+                        Ok(()) => {
+                            self.stack.push(value);
+                            Ok(())
+                        }
+                        Err(runtime_error) => Err(runtime_error),
+                    }
+                }
+                Op::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let list = self.pop();
+                    match self
+                        .interpreter
+                        .set_index(self.token(at), &list, &index, value.clone())
+                    {
+                        Ok(()) => {
+                            self.stack.push(value);
+                            Ok(())
+                        }
+                        Err(runtime_error) => Err(runtime_error),
+                    }
+                }
+                Op::Jump(target) => {
+                    self.frames.last_mut().unwrap().ip = target;
+                    Ok(())
+                }
+                Op::Loop(target) => match self.interpreter.step(self.token(at)) {
+                    Ok(()) => {
+                        self.frames.last_mut().unwrap().ip = target;
+                        Ok(())
+                    }
+                    Err(runtime_error) => Err(runtime_error),
+                },
+                Op::JumpIfFalse(target) => {
+                    if !self.peek().is_truthy() {
+                        self.frames.last_mut().unwrap().ip = target;
+                    }
+                    Ok(())
+                }
+                Op::Call { arguments, callee } => self.call(at, arguments, callee),
+                Op::Closure(index) => {
+                    let function = self.chunk().functions[index].clone();
+                    let base = self.frame().base;
+                    let mut upvalues = vec![];
+                    for source in &function.upvalues {
+                        upvalues.push(match source {
+                            UpvalueSource::Local(slot) => self.capture_upvalue(base + slot),
+                            UpvalueSource::Upvalue(index) => {
+                                self.frame().closure.upvalues[*index].clone()
+                            }
+                        });
+                    }
+                    self.stack.push(VmClosure::new(function, upvalues));
+                    Ok(())
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                    Ok(())
+                }
+                Op::Return => {
+                    let value = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.base);
+                    self.stack.truncate(frame.base);
+                    if self.frames.is_empty() {
+                        return Ok(value);
+                    }
+                    self.interpreter.exit_call();
+                    trace::emit(self.interpreter.tracer(), || TraceEvent::CallExit {
+                        function: frame.closure.to_string(),
+                    });
+                    self.stack.push(value);
+                    Ok(())
+                }
+                Op::StartMeasure => {
+                    self.measures.push(Instant::now());
+                    Ok(())
+                }
+                Op::EndMeasure => self.end_measure(at),
+            };
+
+            if let Err(runtime_error) = result {
+                return Err(self.with_trace(runtime_error));
+            }
+        }
+    }
+
+    // The two operands of a binary instruction, left first.
+    fn pop_operands(&mut self) -> (LoxObject, LoxObject) {
+        let right = self.pop();
+        let left = self.pop();
+        (left, right)
+    }
+
+    fn number_operands(&mut self, at: usize) -> Result<(f64, f64), RuntimeException> {
+        let (left, right) = self.pop_operands();
+        self.interpreter
+            .check_number_operands(self.token(at), &left, &right)
+    }
+
+    fn compare(
+        &mut self,
+        at: usize,
+        compare: fn(f64, f64) -> bool,
+    ) -> Result<(), RuntimeException> {
+        match self.number_operands(at) {
+            Ok((left, right)) => {
+                self.stack.push(LoxObject::Boolean(compare(left, right)));
+                Ok(())
+            }
+            Err(runtime_error) => Err(runtime_error),
+        }
+    }
+
+    fn arithmetic(
+        &mut self,
+        at: usize,
+        apply: fn(f64, f64) -> f64,
+    ) -> Result<(), RuntimeException> {
+        match self.number_operands(at) {
+            Ok((left, right)) => {
+                self.stack.push(LoxObject::Number(apply(left, right)));
+                Ok(())
+            }
+            Err(runtime_error) => Err(runtime_error),
+        }
+    }
+
+    fn add(&mut self, at: usize) -> Result<(), RuntimeException> {
+        let (left, right) = self.pop_operands();
+        let operator = self.token(at);
+        let sum = match self
+            .interpreter
+            .check_number_operands(operator, &left, &right)
+        {
+            Ok((left, right)) => LoxObject::Number(left + right),
+            _ if matches!(
+                (&left, &right),
+                (LoxObject::String(_), LoxObject::String(_))
+            ) =>
+            {
+                match (left.as_string(operator), right.as_string(operator)) {
+                    (Ok(left), Ok(right)) => {
+                        let length = left.len() + right.len();
+                        self.interpreter.check_string_length(operator, length)?;
+                        LoxObject::String((left + right.as_str()).into())
+                    }
+                    (Err(err), _) | (_, Err(err)) => return Err(err),
+                }
+            }
+            _ => {
+                return Err(RuntimeError::new(
+                    operator.clone(),
+                    messages::OPERANDS_MUST_BE_NUMBERS_OR_STRINGS,
+                ))
+            }
+        };
+        self.stack.push(sum);
+        Ok(())
+    }
+
+    fn call(&mut self, at: usize, arguments: usize, callee: usize) -> Result<(), RuntimeException> {
+        let base = self.stack.len() - arguments - 1;
+        let function = self.stack[base].clone();
+        let paren = self.token(at).clone();
+        self.interpreter.step(&paren)?;
+
+        let closure = match &function {
+            LoxObject::Callable(callable) => callable.downcast_ref::<VmClosure>().ok().cloned(),
+            _ => None,
+        };
+        if let Some(closure) = closure {
+            let arity = closure.function.params.len();
+            if arguments != arity {
+                return Err(wrong_argument_count(
+                    &paren,
+                    Arity::exactly(arity),
+                    arguments,
+                    closure.function.signature(),
+                ));
+            }
+            self.interpreter.enter_call(&paren)?;
+            trace::emit(self.interpreter.tracer(), || TraceEvent::CallEnter {
+                function: function.stringify(),
+                line: paren.line,
+            });
+            self.frames.push(CallFrame {
+                closure,
+                ip: 0,
+                base,
+                line: paren.line,
+            });
+            return Ok(());
+        }
+
+        if !function.is_callable() {
+            let callee = self.chunk().constants[callee].clone();
+            return Err(RuntimeError::new(
+                paren,
+                messages::NOT_CALLABLE.with(&[callee.stringify(), function.describe_type()]),
+            ));
+        }
+        if !function.arity().accepts(arguments) {
+            return Err(wrong_argument_count(
+                &paren,
+                function.arity(),
+                arguments,
+                function.signature(),
+            ));
+        }
+
+        // Natives, and functions declared by scripts run with `dofile`, which
+        // the tree-walking interpreter runs. The stack is lent to the
+        // interpreter meanwhile, for any closures a native calls back into.
+        let values = self.stack.split_off(base + 1);
+        self.stack.truncate(base);
+        self.interpreter
+            .vm_stack
+            .replace(std::mem::take(&mut self.stack));
+        trace::emit(self.interpreter.tracer(), || TraceEvent::CallEnter {
+            function: function.stringify(),
+            line: paren.line,
+        });
+        let result = function.call(self.interpreter, &paren, values);
+        trace::emit(self.interpreter.tracer(), || TraceEvent::CallExit {
+            function: function.stringify(),
+        });
+        self.stack = self.interpreter.vm_stack.take();
+        match result {
+            Ok(value) => {
+                self.stack.push(value);
+                Ok(())
+            }
+            Err(RuntimeException::RuntimeError(mut runtime_error)) => {
+                runtime_error.trace.push(TraceFrame {
+                    function: function.stringify(),
+                    line: paren.line,
+                    file: None,
+                });
+                Err(RuntimeException::RuntimeError(runtime_error))
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    // The upvalue for the stack slot, shared by every closure that captures
+    // the slot while it's open.
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let open = self
+            .open_upvalues
+            .iter()
+            .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(open) if open == slot));
+        if let Some(upvalue) = open {
+            return upvalue.clone();
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    // Moves the values of the upvalues for slots `from` and above off the
+    // stack, since the scope they're in is ending.
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(slot) if slot >= from => {
+                    *upvalue = Upvalue::Closed(stack[slot].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    fn end_measure(&mut self, at: usize) -> Result<(), RuntimeException> {
+        let start = self.measures.pop().unwrap();
+        // Timings go through the replay log, so replayed runs print the same.
+        let elapsed = self
+            .interpreter
+            .replay
+            .number("measure", || start.elapsed().as_secs_f64() * 1000.0);
+        let keyword = self.token(at);
+        match elapsed {
+            Ok(elapsed) => {
+                self.interpreter.output.print(&format!(
+                    "measure (line {}): {:.3} ms",
+                    keyword.line, elapsed
+                ));
+                Ok(())
+            }
+            Err(message) => Err(RuntimeError::new(keyword.clone(), message)),
+        }
+    }
+
+    // Adds the calls the error unwinds through to its stack trace.
+    fn with_trace(&self, runtime_error: RuntimeException) -> RuntimeException {
+        match runtime_error {
+            RuntimeException::RuntimeError(mut runtime_error) => {
+                // The first frame is the script's.
+                for frame in self.frames.iter().skip(1).rev() {
+                    runtime_error.trace.push(TraceFrame {
+                        function: frame.closure.to_string(),
+                        line: frame.line,
+                        file: None,
+                    });
+                }
+                RuntimeException::RuntimeError(runtime_error)
+            }
+            result => result,
+        }
+    }
+}
+
+fn wrong_argument_count(
+    paren: &Token,
+    arity: Arity,
+    arguments: usize,
+    signature: String,
+) -> RuntimeException {
+    RuntimeError::new(
+        paren.clone(),
+        messages::WRONG_ARGUMENT_COUNT.with(&[arity.to_string(), arguments.to_string(), signature]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        golden,
+        output::CapturedOutput,
+        session::{Backend, Session},
+    };
+
+    // Everything the script prints on the VM, followed by any diagnostics.
+    fn run_vm(source: &str) -> Vec<String> {
+        let mut session = Session::new();
+        session.backend = Backend::Vm;
+        let output = Rc::new(CapturedOutput::new());
+        session.interpreter.output = output.clone();
+        session.run(&source.to_string());
+
+        let mut lines = output.lines();
+        lines.extend(session.errors.messages());
+        lines
+    }
+
+    // The VM prints the same as the tree-walker, so it shares its golden
+    // files, except for the cases using features it doesn't support.
+    #[test]
+    fn matches_the_interpreter_golden_suite() {
+        let unsupported = ["defer", "defer_error_in_deferred", "defer_runtime_error"];
+        golden::check_suite_against("interpreter", &unsupported, |source| {
+            golden::lines(run_vm(source))
+        });
+    }
+
+    #[test]
+    fn natives_call_back_into_closures_over_live_locals() {
+        let lines = run_vm(
+            "{\n  var calls = 0;\n  fun bump() { calls = calls + 1; }\n  timeit(bump, 3);\n  print calls;\n}",
+        );
+        assert_eq!(lines, vec!["3.00"]);
+    }
+
+    #[test]
+    fn unsupported_features_stop_the_script_from_running() {
+        let lines = run_vm("print \"before\";\ndefer print \"deferred\";");
+        assert_eq!(
+            lines,
+            vec!["[line 2] Error[E054] at \"defer\": defer isn't supported by the vm backend yet."]
+        );
+    }
+}